@@ -0,0 +1,147 @@
+//! Batch remote commands into a single POSIX sh script.
+//!
+//! Each step runs in its own subshell (like a separate `ssh host cmd`) and
+//! the script stops at the first failing step. Markers written to stderr
+//! attribute a failure to the step that caused it. The script arrives on
+//! `sh -s`'s stdin, so steps get /dev/null as theirs and cannot read the
+//! rest of the script.
+
+use anyhow::{Result, anyhow};
+use log::debug;
+
+use crate::remote;
+
+const STEP_MARKER: &str = "##bsdeploy-step";
+const FAILED_MARKER: &str = "##bsdeploy-failed";
+
+struct Step {
+    label: String,
+    command: String,
+}
+
+/// An ordered list of remote commands executed in one SSH round trip.
+#[derive(Default)]
+pub struct Batch {
+    steps: Vec<Step>,
+}
+
+impl Batch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append a step. `label` is used in error messages.
+    pub fn add(&mut self, label: impl Into<String>, command: impl Into<String>) -> &mut Self {
+        self.steps.push(Step {
+            label: label.into(),
+            command: command.into(),
+        });
+        self
+    }
+
+    fn script(&self) -> String {
+        let mut script = String::from("#!/bin/sh\n");
+        for (idx, step) in self.steps.iter().enumerate() {
+            script.push_str(&format!("echo '{} {}' >&2\n", STEP_MARKER, idx));
+            script.push_str(&format!(
+                "(\n{}\n) </dev/null || {{ rc=$?; echo \"{} {} $rc\" >&2; exit $rc; }}\n",
+                step.command, FAILED_MARKER, idx
+            ));
+        }
+        script
+    }
+
+    /// Run all steps on the host, stopping at the first failure.
+    pub fn run(&self, host: &str) -> Result<()> {
+        if self.steps.is_empty() {
+            return Ok(());
+        }
+        debug!("SSH [{}] Executing batch of {} steps", host, self.steps.len());
+
+        let (success, stderr) = remote::run_script(host, &self.script())?;
+        if success {
+            return Ok(());
+        }
+
+        match failed_step(&stderr) {
+            Some((idx, output)) if idx < self.steps.len() => {
+                let step = &self.steps[idx];
+                Err(anyhow!(
+                    "Step '{}' failed on {}: {}. Error: {}",
+                    step.label, host, step.command, output.trim()
+                ))
+            }
+            _ => Err(anyhow!("Batch failed on {}: {}", host, strip_markers(&stderr).trim())),
+        }
+    }
+}
+
+/// Index of the failed step and the stderr it produced.
+fn failed_step(stderr: &str) -> Option<(usize, String)> {
+    let idx: usize = stderr
+        .lines()
+        .rev()
+        .find_map(|line| line.strip_prefix(FAILED_MARKER))?
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()?;
+
+    let start_marker = format!("{} {}", STEP_MARKER, idx);
+    let output = stderr
+        .lines()
+        .skip_while(|line| *line != start_marker)
+        .skip(1)
+        .take_while(|line| !line.starts_with(FAILED_MARKER))
+        .collect::<Vec<_>>()
+        .join("\n");
+    Some((idx, output))
+}
+
+fn strip_markers(stderr: &str) -> String {
+    stderr
+        .lines()
+        .filter(|line| !line.starts_with(STEP_MARKER) && !line.starts_with(FAILED_MARKER))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::remote::mock::{self, MockExecutor};
+
+    #[test]
+    fn test_script_wraps_each_step() {
+        let mut batch = Batch::new();
+        batch.add("create dir", "mkdir -p /tmp/a").add("mount", "mount -t devfs devfs /tmp/a");
+        let script = batch.script();
+        assert!(script.starts_with("#!/bin/sh\n"));
+        assert!(script.contains("echo '##bsdeploy-step 0' >&2\n(\nmkdir -p /tmp/a\n) </dev/null || "));
+        assert!(script.contains("echo \"##bsdeploy-failed 1 $rc\" >&2; exit $rc; }"));
+    }
+
+    #[test]
+    fn test_failed_step_attributes_output() {
+        let stderr = "##bsdeploy-step 0\n##bsdeploy-step 1\nmount: /tmp/a: Operation not permitted\n##bsdeploy-failed 1 1\n";
+        let (idx, output) = failed_step(stderr).unwrap();
+        assert_eq!(idx, 1);
+        assert_eq!(output, "mount: /tmp/a: Operation not permitted");
+        assert!(failed_step("##bsdeploy-step 0\n").is_none());
+    }
+
+    #[test]
+    fn test_run_reports_failed_step() {
+        let mock = MockExecutor::new();
+        mock.fail("sh -s", "##bsdeploy-step 0\n##bsdeploy-step 1\nmount: busy\n##bsdeploy-failed 1 1\n");
+        let mut batch = Batch::new();
+        batch.add("create dir", "mkdir -p /tmp/a").add("mount", "mount -t devfs devfs /tmp/a");
+
+        let err = mock::with_executor(mock.clone(), || batch.run("host")).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Step 'mount' failed on host: mount -t devfs devfs /tmp/a. Error: mount: busy"
+        );
+        assert_eq!(mock.commands(), ["sh -s"]);
+    }
+}
@@ -0,0 +1,202 @@
+//! Output for CI pipelines: GitHub Actions annotations for errors and
+//! warnings, and a Markdown summary of a deploy, written to the job
+//! summary on GitHub Actions and into a log section on GitLab CI.
+
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::sync::LazyLock;
+use std::time::Duration;
+
+use crate::config::Config;
+use crate::ui;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ci {
+    GitHub,
+    GitLab,
+}
+
+static DETECTED: LazyLock<Option<Ci>> = LazyLock::new(|| {
+    let set = |name: &str| std::env::var(name).is_ok_and(|v| v == "true");
+    if set("GITHUB_ACTIONS") {
+        Some(Ci::GitHub)
+    } else if set("GITLAB_CI") {
+        Some(Ci::GitLab)
+    } else {
+        None
+    }
+});
+
+/// The CI system bsdeploy runs under, from its environment variables.
+pub fn detect() -> Option<Ci> {
+    *DETECTED
+}
+
+/// Message of a GitHub workflow command
+fn escape_data(s: &str) -> String {
+    s.replace('%', "%25").replace('\r', "%0D").replace('\n', "%0A")
+}
+
+/// Property value of a GitHub workflow command
+fn escape_property(s: &str) -> String {
+    escape_data(s).replace(':', "%3A").replace(',', "%2C")
+}
+
+/// `::error::`/`::warning::` line GitHub Actions shows as an annotation,
+/// titled with the host the message is about.
+pub fn annotation(level: &str, host: Option<&str>, msg: &str) -> String {
+    let title = host.map(|h| format!(" title={}", escape_property(h))).unwrap_or_default();
+    format!("::{}{}::{}", level, title, escape_data(msg))
+}
+
+fn format_duration(d: Duration) -> String {
+    let secs = d.as_secs();
+    if secs < 60 { format!("{}s", secs) } else { format!("{}m {}s", secs / 60, secs % 60) }
+}
+
+/// Markdown table cell
+fn cell(s: &str) -> String {
+    s.replace('|', "\\|").replace('\n', " ")
+}
+
+/// What happened on one host during a deploy
+pub struct HostOutcome {
+    pub host: String,
+    /// The new jail, or the error that stopped the deploy on this host
+    pub result: Result<String, String>,
+    pub image: String,
+    pub duration: Duration,
+}
+
+/// Results of a deploy, for the job summary
+pub struct DeploySummary {
+    service: String,
+    hosts: Vec<String>,
+    url: Option<String>,
+    pub revision: Option<String>,
+    pub outcomes: Vec<HostOutcome>,
+}
+
+impl DeploySummary {
+    pub fn new(config: &Config) -> Self {
+        let url = config.proxy.as_ref().map(|p| {
+            format!(
+                "{}://{}{}",
+                if p.tls { "https" } else { "http" },
+                p.hostname,
+                p.path_prefix.as_deref().unwrap_or("")
+            )
+        });
+        Self { service: config.service.clone(), hosts: config.hosts.clone(), url, revision: None, outcomes: Vec::new() }
+    }
+
+    fn markdown(&self, error: Option<&str>, duration: Duration) -> String {
+        let mut md = match error {
+            None => format!("### ✅ Deployed {}\n\n", self.service),
+            Some(_) => format!("### ❌ Deploy of {} failed\n\n", self.service),
+        };
+        md.push_str("| Host | Result | Jail | Image | Duration |\n|------|--------|------|-------|----------|\n");
+        for host in &self.hosts {
+            match self.outcomes.iter().find(|o| &o.host == host) {
+                Some(o) => {
+                    let (result, jail) = match &o.result {
+                        Ok(jail) => ("✅ deployed".to_string(), format!("`{}`", jail)),
+                        Err(e) => (format!("❌ {}", cell(e)), String::new()),
+                    };
+                    md.push_str(&format!(
+                        "| {} | {} | {} | `{}` | {} |\n",
+                        host,
+                        result,
+                        jail,
+                        o.image,
+                        format_duration(o.duration)
+                    ));
+                }
+                None => md.push_str(&format!("| {} | ⏭ not deployed | | | |\n", host)),
+            }
+        }
+        md.push('\n');
+        let mut facts = Vec::new();
+        if let Some(revision) = &self.revision {
+            facts.push(format!("Revision: `{}`", revision));
+        }
+        facts.push(format!("Duration: {}", format_duration(duration)));
+        if let Some(url) = &self.url {
+            facts.push(format!("URL: {}", url));
+        }
+        md.push_str(&facts.join(" · "));
+        md.push('\n');
+        if let Some(error) = error {
+            md.push_str(&format!("\n```\n{}\n```\n", error));
+        }
+        md
+    }
+
+    /// Write the summary where the CI system shows it. Failures to write
+    /// it are only logged.
+    pub fn publish(&self, ci: Ci, error: Option<&str>, duration: Duration) {
+        let md = self.markdown(error, duration);
+        match ci {
+            Ci::GitHub => {
+                let Ok(path) = std::env::var("GITHUB_STEP_SUMMARY") else { return };
+                let written = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&path)
+                    .and_then(|mut f| writeln!(f, "{}", md));
+                if let Err(e) = written {
+                    log::warn!("Could not write the job summary to {}: {}", path, e);
+                }
+            }
+            Ci::GitLab => {
+                // A collapsible section of the job log
+                let now = std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .map_or(0, |d| d.as_secs());
+                ui::print_output(&format!("\x1b[0Ksection_start:{}:bsdeploy_summary\r\x1b[0KDeploy summary", now));
+                ui::print_output(&md);
+                ui::print_output(&format!("\x1b[0Ksection_end:{}:bsdeploy_summary\r\x1b[0K", now));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_annotation() {
+        assert_eq!(annotation("error", Some("deploy@web1"), "Jail failed\n50% done"), "::error title=deploy@web1::Jail failed%0A50%25 done");
+        assert_eq!(annotation("warning", None, "a: b"), "::warning::a: b");
+        assert_eq!(escape_property("a:b,c"), "a%3Ab%2Cc");
+    }
+
+    #[test]
+    fn test_markdown() {
+        let config = Config::from_str(
+            "service: app\nhosts: [web1, web2, web3]\nproxy: { hostname: app.example.com, port: 3000, tls: true }\n",
+        )
+        .unwrap();
+        let mut summary = DeploySummary::new(&config);
+        summary.revision = Some("4f1c2d9e8b7a".into());
+        summary.outcomes.push(HostOutcome {
+            host: "web1".into(),
+            result: Ok("app-20260101-120000".into()),
+            image: "aaaaaaaaaaaa".into(),
+            duration: Duration::from_secs(42),
+        });
+        summary.outcomes.push(HostOutcome {
+            host: "web2".into(),
+            result: Err("Port already in use | 3000".into()),
+            image: "aaaaaaaaaaaa".into(),
+            duration: Duration::from_secs(7),
+        });
+        let md = summary.markdown(Some("Port already in use"), Duration::from_secs(75));
+        assert!(md.starts_with("### ❌ Deploy of app failed\n"));
+        assert!(md.contains("| web1 | ✅ deployed | `app-20260101-120000` | `aaaaaaaaaaaa` | 42s |\n"));
+        assert!(md.contains("| web2 | ❌ Port already in use \\| 3000 |  | `aaaaaaaaaaaa` | 7s |\n"));
+        assert!(md.contains("| web3 | ⏭ not deployed | | | |\n"));
+        assert!(md.contains("Revision: `4f1c2d9e8b7a` · Duration: 1m 15s · URL: https://app.example.com\n"));
+    }
+}
@@ -0,0 +1,250 @@
+//! Record of what the last deploy applied on a host: a summary of the
+//! configuration and checksums of the files it wrote. Setup and deploy
+//! compare it with the host to warn about drift, e.g. a hand-edited Caddy
+//! site or a data directory that is no longer mounted.
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+
+use crate::config::Config;
+use crate::constants::*;
+use crate::{pf, proxy, remote, shell, ui};
+
+use super::parallel_hosts;
+
+pub(super) fn state_path(config: &Config) -> String {
+    format!("{}/{}/applied.json", CONFIG_DIR, config.service)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+struct DataMount {
+    host_path: String,
+    jail_path: String,
+}
+
+/// The parts of the configuration that end up on the host
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq)]
+struct Summary {
+    env_keys: Vec<String>,
+    packages: Vec<String>,
+    data_directories: Vec<DataMount>,
+    /// `hostname:port` and server of the proxy, if any
+    proxy: Option<String>,
+}
+
+impl Summary {
+    fn of(config: &Config) -> Self {
+        let mut env_keys: Vec<String> = config
+            .env
+            .clear
+            .iter()
+            .flat_map(|map| map.keys().cloned())
+            .chain(config.env.secret.iter().cloned())
+            .collect();
+        env_keys.sort();
+        let data_directories = config
+            .data_directories
+            .iter()
+            .map(|d| d.get_paths())
+            .filter(|(host_path, _)| !host_path.is_empty())
+            .map(|(host_path, jail_path)| DataMount { host_path, jail_path })
+            .collect();
+        let proxy = config
+            .proxy
+            .as_ref()
+            .map(|p| format!("{}:{} {:?}{}", p.hostname, p.port, p.server, if p.tls { " tls" } else { "" }));
+        Self { env_keys, packages: config.packages.clone(), data_directories, proxy }
+    }
+
+    fn hash(&self) -> String {
+        let json = serde_json::to_string(self).unwrap_or_default();
+        hex::encode(Sha256::digest(json.as_bytes()))
+    }
+}
+
+/// What `applied.json` holds
+#[derive(Debug, Serialize, Deserialize)]
+struct Applied {
+    config_hash: String,
+    applied_at: String,
+    #[serde(flatten)]
+    summary: Summary,
+    /// SHA256 of the files the deploy wrote, by path
+    files: BTreeMap<String, String>,
+}
+
+/// Files a deploy writes for the service on a host, as the active jail
+/// sees them.
+fn managed_files(config: &Config) -> Vec<String> {
+    let mut files = vec![format!("{}/{}{}", ACTIVE_DIR, config.service, JAIL_ENV_FILE)];
+    files.extend(proxy::site_path(config));
+    if config.bind.is_some() {
+        files.push(pf::rules_path(&config.service));
+    }
+    if config.logs.rotate.is_some() {
+        files.push(super::deploy::newsyslog_path(config));
+    }
+    files
+}
+
+/// `<path> <sha256>` for each of `files`, `-` for missing ones.
+fn checksums_command(config: &Config, files: &[String]) -> String {
+    let paths: Vec<String> = files.iter().map(|f| shell::escape(f)).collect();
+    format!(
+        "for f in {}; do echo \"$f $({}sha256 -q \"$f\" 2>/dev/null || echo -)\"; done",
+        paths.join(" "),
+        shell::escalation_prefix(config.doas)
+    )
+}
+
+fn parse_checksums(output: &str) -> BTreeMap<String, String> {
+    output
+        .lines()
+        .filter_map(|l| l.split_once(' '))
+        .map(|(path, sum)| (path.to_string(), sum.trim().to_string()))
+        .collect()
+}
+
+/// Record the configuration just deployed to `host`.
+pub(super) fn record(config: &Config, host: &str) -> Result<()> {
+    let files = managed_files(config);
+    let output = remote::run_with_output(host, &checksums_command(config, &files))?;
+    let summary = Summary::of(config);
+    let applied = Applied {
+        config_hash: summary.hash(),
+        applied_at: chrono::Local::now().to_rfc3339(),
+        summary,
+        files: parse_checksums(&output).into_iter().filter(|(_, sum)| sum != "-").collect(),
+    };
+    remote::ensure_dir(host, &format!("{}/{}", CONFIG_DIR, config.service), config.doas)?;
+    remote::write_file(host, &serde_json::to_string_pretty(&applied)?, &state_path(config), config.doas)
+}
+
+/// Differences between the record and the host: edited or removed files
+/// and data directories no longer mounted in the active jail.
+fn drift(applied: &Applied, checksums: &BTreeMap<String, String>, active: Option<&str>, mounts: &[&str]) -> Vec<String> {
+    let mut found = Vec::new();
+    for (path, recorded) in &applied.files {
+        match checksums.get(path).map(String::as_str) {
+            Some("-") => found.push(format!("{} was removed since the last deploy", path)),
+            Some(sum) if sum != recorded => found.push(format!("{} was edited since the last deploy", path)),
+            _ => {}
+        }
+    }
+    if let Some(root) = active {
+        for dir in &applied.summary.data_directories {
+            let target = format!("{}/{}", root, dir.jail_path.trim_start_matches('/'));
+            if !mounts.contains(&target.as_str()) {
+                found.push(format!("data directory {} is no longer mounted on {}", dir.host_path, target));
+            }
+        }
+    }
+    found
+}
+
+/// Parts of the configuration that changed since the last deploy.
+fn changes(applied: &Applied, current: &Summary) -> Vec<&'static str> {
+    let recorded = &applied.summary;
+    [
+        ("env keys", recorded.env_keys != current.env_keys),
+        ("packages", recorded.packages != current.packages),
+        ("data directories", recorded.data_directories != current.data_directories),
+        ("proxy", recorded.proxy != current.proxy),
+    ]
+    .into_iter()
+    .filter_map(|(name, changed)| changed.then_some(name))
+    .collect()
+}
+
+/// Drift and configuration changes on `host`; nothing before the first
+/// deploy.
+fn check_host(config: &Config, host: &str) -> Result<(Vec<String>, Vec<&'static str>)> {
+    let json = remote::query(host, &format!("cat {} 2>/dev/null || true", state_path(config)))?;
+    let Ok(applied) = serde_json::from_str::<Applied>(&json) else {
+        return Ok((Vec::new(), Vec::new()));
+    };
+    let files: Vec<String> = applied.files.keys().cloned().collect();
+    let script = format!(
+        "{}; echo '== active'; readlink {}/{} 2>/dev/null; echo '== mounts'; mount -p | awk '{{print $2}}'",
+        if files.is_empty() { "true".to_string() } else { checksums_command(config, &files) },
+        ACTIVE_DIR,
+        config.service
+    );
+    let output = remote::run_with_output(host, &script)?;
+    let (checksums, rest) = output.split_once("== active").unwrap_or((&output, ""));
+    let (active, mounts) = rest.split_once("== mounts").unwrap_or((rest, ""));
+    let active = active.trim();
+    let mounts: Vec<&str> = mounts.lines().map(str::trim).collect();
+
+    let drift = drift(&applied, &parse_checksums(checksums), (!active.is_empty()).then_some(active), &mounts);
+    let changes = if applied.config_hash == Summary::of(config).hash() {
+        Vec::new()
+    } else {
+        changes(&applied, &Summary::of(config))
+    };
+    Ok((drift, changes))
+}
+
+/// Warn about drift from the last deploy on every host. Never fails: the
+/// check is advisory.
+pub(super) fn check(config: &Config) {
+    let results = parallel_hosts(&config.hosts, config.parallelism, |host| check_host(config, host));
+    for (host, result) in config.hosts.iter().zip(results) {
+        match result {
+            Ok((drift, changes)) => {
+                for found in drift {
+                    ui::print_warning(&format!("{}: {}", host, found));
+                }
+                if !changes.is_empty() {
+                    ui::print_step(&format!("{}: changed since the last deploy: {}", host, changes.join(", ")));
+                }
+            }
+            Err(e) => log::debug!("Drift check failed on {}: {:#}", host, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_drift() {
+        let config = Config::from_str(
+            "service: app\nhosts: [a]\npackages: [curl]\ndata_directories:\n  - /var/db/app/storage: /app/storage\n",
+        )
+        .unwrap();
+        let summary = Summary::of(&config);
+        let applied = Applied {
+            config_hash: summary.hash(),
+            applied_at: String::new(),
+            summary,
+            files: BTreeMap::from([
+                ("/usr/local/etc/caddy/conf.d/app.caddy".to_string(), "aaa".to_string()),
+                ("/usr/local/etc/bsdeploy/app/pf.rdr".to_string(), "bbb".to_string()),
+                ("/usr/local/bsdeploy/active/app/etc/bsdeploy.env".to_string(), "ccc".to_string()),
+            ]),
+        };
+        let checksums = parse_checksums(
+            "/usr/local/etc/caddy/conf.d/app.caddy abd\n\
+             /usr/local/etc/bsdeploy/app/pf.rdr -\n\
+             /usr/local/bsdeploy/active/app/etc/bsdeploy.env ccc\n",
+        );
+        let root = "/usr/local/bsdeploy/jails/app-20260101-120000";
+        assert_eq!(
+            drift(&applied, &checksums, Some(root), &["/", &format!("{}/dev", root)]),
+            [
+                "/usr/local/etc/bsdeploy/app/pf.rdr was removed since the last deploy",
+                "/usr/local/etc/caddy/conf.d/app.caddy was edited since the last deploy",
+                "data directory /var/db/app/storage is no longer mounted on /usr/local/bsdeploy/jails/app-20260101-120000/app/storage",
+            ]
+        );
+        assert!(drift(&applied, &checksums, Some(root), &[&format!("{}/app/storage", root)]).len() == 2);
+
+        assert!(changes(&applied, &Summary::of(&config)).is_empty());
+        let config = Config::from_str("service: app\nhosts: [a]\nenv:\n  secret: [API_KEY]\n").unwrap();
+        assert_eq!(changes(&applied, &Summary::of(&config)), ["env keys", "packages", "data directories"]);
+    }
+}
@@ -0,0 +1,110 @@
+use anyhow::Result;
+use clap::Subcommand;
+
+use crate::config::Config;
+use crate::constants::BASE_DIR;
+use crate::{jail, remote, ui};
+
+#[derive(Subcommand)]
+pub enum BaseAction {
+    /// List installed base systems on each host
+    List,
+    /// Remove base systems not referenced by any image or jail
+    Prune {
+        /// Only show what would be removed
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Apply freebsd-update patches to an installed base system
+    Update {
+        /// Base version to update (e.g. 14.1-RELEASE)
+        version: String,
+    },
+}
+
+pub fn run(config: &Config, action: BaseAction) -> Result<()> {
+    match action {
+        BaseAction::List => list(config),
+        BaseAction::Prune { dry_run } => prune(config, dry_run),
+        BaseAction::Update { version } => update(config, &version),
+    }
+}
+
+fn list(config: &Config) -> Result<()> {
+    for host in &config.hosts {
+        ui::print_output("");
+        ui::print_output(&format!("Host: {}", host));
+        ui::print_output(&"─".repeat(60));
+
+        let versions = jail::list_bases(host)?;
+        if versions.is_empty() {
+            ui::print_output("  No base systems installed");
+            continue;
+        }
+
+        let referenced = jail::referenced_base_versions(host)?;
+        for version in &versions {
+            let size = remote::run_with_output_timeout(
+                host,
+                &format!("du -sh {}/{} 2>/dev/null | awk '{{print $1}}'", BASE_DIR, version),
+                remote::Timeout::Command,
+            )
+            .map(|s| s.trim().to_string())
+            .unwrap_or_else(|_| "-".to_string());
+            let marker = if referenced.contains(version) { "in use" } else { "unused" };
+            ui::print_output(&format!("  {:<24} {:>8}  {}", version, size, marker));
+        }
+    }
+    ui::print_output("");
+    Ok(())
+}
+
+fn prune(config: &Config, dry_run: bool) -> Result<()> {
+    ui::print_step(&format!("Pruning unused base systems on {} hosts", config.hosts.len()));
+
+    for host in &config.hosts {
+        let referenced = jail::referenced_base_versions(host)?;
+        let unused: Vec<String> = jail::list_bases(host)?
+            .into_iter()
+            .filter(|v| !referenced.contains(v))
+            .collect();
+
+        if unused.is_empty() {
+            ui::print_success(&format!("{}: nothing to prune", host));
+            continue;
+        }
+
+        for version in unused {
+            if dry_run {
+                ui::print_output(&format!("  [{}] would remove base {}", host, version));
+                continue;
+            }
+            let spinner = ui::create_spinner(&format!("[{}] Removing base {}...", host, version));
+            match jail::remove_base(host, &version, config.doas) {
+                Ok(()) => {
+                    spinner.finish_and_clear();
+                    ui::print_success(&format!("{}: removed base {}", host, version));
+                }
+                Err(e) => {
+                    spinner.finish_and_clear();
+                    ui::print_error(&format!("{}: could not remove base {}: {}", host, version, e));
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn update(config: &Config, version: &str) -> Result<()> {
+    ui::print_step(&format!("Updating base {} on {} hosts", version, config.hosts.len()));
+
+    for host in &config.hosts {
+        let spinner = ui::create_spinner(&format!("[{}] Running freebsd-update on base {}...", host, version));
+        jail::update_base(host, version, config.doas)?;
+        spinner.finish_with_message(format!("Update complete for {}", host));
+        ui::print_success(&format!("{} base {} updated", host, version));
+    }
+
+    Ok(())
+}
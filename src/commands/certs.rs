@@ -0,0 +1,148 @@
+use anyhow::{Context, Result, bail};
+use chrono::{NaiveDateTime, Utc};
+use clap::Subcommand;
+
+use crate::config::{Config, SslConfig};
+use crate::{proxy, remote, shell, ui};
+
+use super::parallel_hosts;
+
+#[derive(Subcommand)]
+pub enum CertsAction {
+    /// Upload the certificates from the proxy.ssl environment variables and reload the proxy
+    Push,
+    /// Show subject, issuer and expiry of the installed certificate
+    Show,
+    /// Fail when the installed certificate expires soon
+    Check {
+        /// Warn when fewer days than this are left
+        #[arg(long, default_value_t = 30)]
+        days: i64,
+    },
+}
+
+pub fn run(config: &Config, action: CertsAction) -> Result<()> {
+    let ssl = match config.proxy.as_ref().and_then(|p| p.ssl.as_ref()) {
+        Some(ssl) => ssl,
+        None => bail!("No manually managed certificates: proxy.ssl is not set (ACME certificates are renewed by Caddy)"),
+    };
+    match action {
+        CertsAction::Push => push(config, ssl),
+        CertsAction::Show => show(config),
+        CertsAction::Check { days } => check(config, days),
+    }
+}
+
+/// The installed certificate, as reported by `openssl x509`
+#[derive(Debug, PartialEq)]
+struct Certificate {
+    subject: String,
+    issuer: String,
+    not_after: NaiveDateTime,
+}
+
+impl Certificate {
+    fn parse(output: &str) -> Result<Self> {
+        let field = |name: &str| {
+            output
+                .lines()
+                .find_map(|l| l.trim().strip_prefix(name)?.strip_prefix('='))
+                .map(|v| v.trim().to_string())
+                .with_context(|| format!("Unexpected openssl output: {}", output.trim()))
+        };
+        let not_after = field("notAfter")?;
+        let date = not_after.trim_end_matches(" GMT");
+        Ok(Self {
+            subject: field("subject")?,
+            issuer: field("issuer")?,
+            not_after: NaiveDateTime::parse_from_str(date, "%b %e %H:%M:%S %Y")
+                .with_context(|| format!("Unexpected certificate expiry: {}", not_after))?,
+        })
+    }
+
+    fn days_left(&self) -> i64 {
+        (self.not_after - Utc::now().naive_utc()).num_days()
+    }
+}
+
+fn read_certificate(config: &Config, host: &str) -> Result<Certificate> {
+    let path = proxy::certificate_path(config);
+    let output = remote::run_with_output(
+        host,
+        &format!("{}openssl x509 -in {} -noout -subject -issuer -enddate", shell::escalation_prefix(config.doas), path),
+    )
+    .with_context(|| format!("Failed to read {}", path))?;
+    Certificate::parse(&output)
+}
+
+fn push(config: &Config, ssl: &SslConfig) -> Result<()> {
+    for host in proxy::hosts(config) {
+        let spinner = ui::create_spinner(&format!("[{}] Updating TLS certificates...", host));
+        let result = proxy::push_certificates(config, &host, ssl);
+        spinner.finish_and_clear();
+        result?;
+        ui::print_success(&format!("{}: certificates updated", host));
+    }
+    Ok(())
+}
+
+fn show(config: &Config) -> Result<()> {
+    let hosts = proxy::hosts(config);
+    let results = parallel_hosts(&hosts, config.parallelism, |host| read_certificate(config, host));
+    for (host, result) in hosts.iter().zip(results) {
+        let cert = result?;
+        ui::print_step(&format!("{}: {}", host, proxy::certificate_path(config)));
+        ui::print_output(&format!("  Subject: {}", cert.subject));
+        ui::print_output(&format!("  Issuer:  {}", cert.issuer));
+        ui::print_output(&format!("  Expires: {} UTC ({} days)", cert.not_after, cert.days_left()));
+    }
+    Ok(())
+}
+
+fn check(config: &Config, days: i64) -> Result<()> {
+    let hosts = proxy::hosts(config);
+    let results = parallel_hosts(&hosts, config.parallelism, |host| read_certificate(config, host));
+
+    let mut failed = 0;
+    for (host, result) in hosts.iter().zip(results) {
+        match result {
+            Ok(cert) if cert.days_left() < days => {
+                failed += 1;
+                ui::print_warning(&format!(
+                    "{}: certificate for {} expires in {} days ({} UTC)",
+                    host,
+                    cert.subject,
+                    cert.days_left(),
+                    cert.not_after
+                ));
+            }
+            Ok(cert) => ui::print_success(&format!("{}: valid for {} more days", host, cert.days_left())),
+            Err(e) => {
+                failed += 1;
+                ui::print_error(&format!("{}: {:#}", host, e));
+            }
+        }
+    }
+    if failed > 0 {
+        bail!("Certificate check failed on {} of {} hosts; rotate with `bsdeploy certs push`", failed, hosts.len());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_certificate() {
+        let cert = Certificate::parse(
+            "subject=CN = example.com\nissuer=C = US, O = Let's Encrypt, CN = R3\nnotAfter=Jan  5 12:30:00 2027 GMT\n",
+        )
+        .unwrap();
+        assert_eq!(cert.subject, "CN = example.com");
+        assert_eq!(cert.issuer, "C = US, O = Let's Encrypt, CN = R3");
+        assert_eq!(cert.not_after.to_string(), "2027-01-05 12:30:00");
+
+        assert!(Certificate::parse("unable to load certificate\n").is_err());
+    }
+}
@@ -1,25 +1,41 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 use indicatif::ProgressBar;
 use serde::Serialize;
+use std::path::Path;
+use std::time::Instant;
+use std::process::{Command, Stdio};
 
-use crate::config::Config;
+use crate::config::{Config, LogCompression, RestartPolicy, RotateConfig, SyncStrategy, SyslogConfig};
 use crate::constants::*;
-use crate::{caddy, image, jail, remote, shell, ui};
+use crate::metrics::DeployMetrics;
+use crate::{ci, exit, helper, image, jail, pf, proxy, rcd, remote, shell, ui};
 
 /// Metadata stored in each jail for boot persistence
 #[derive(Serialize)]
-struct JailMetadata {
+pub(super) struct JailMetadata {
     service: String,
     jail_name: String,
     ip: String,
     user: Option<String>,
     start_commands: Vec<String>,
+    /// daemon(8) command lines of the start commands, run by the rc.d script
+    processes: Vec<String>,
+    /// Start syslogd in the jail to forward its messages
+    syslogd: bool,
     env_file: String,
     app_dir: String,
     data_directories: Vec<DataDirectoryMapping>,
     base_version: String,
     image_path: Option<String>,
     zfs: bool,
+    /// Git commit of the deployed working tree; `-dirty` with local changes
+    revision: Option<String>,
+    /// Services the rc.d scripts start, and wait for, before this one
+    depends_on: Vec<String>,
+    /// Seconds the rc.d scripts wait for `depends_on`
+    depends_wait: u64,
+    /// Port in the jail that accepts connections once the service is up
+    health_port: Option<u16>,
 }
 
 #[derive(Serialize)]
@@ -28,13 +44,87 @@ struct DataDirectoryMapping {
     jail_path: String,
 }
 
-pub fn run(config: &Config) -> Result<()> {
+/// `overwrite` regenerates proxy sites that were edited by hand.
+pub fn run(config: &Config, base_tarball: Option<&Path>, artifact: Option<&Path>, overwrite: bool) -> Result<()> {
+    let started = Instant::now();
+    let metrics = DeployMetrics::new();
+    let mut summary = ci::DeploySummary::new(config);
+    let result = deploy(config, base_tarball, artifact, overwrite, &metrics, &mut summary);
+    if let Some(metrics_config) = &config.metrics {
+        metrics.send(metrics_config, &config.service, result.is_ok());
+    }
+    if let Some(ci) = ci::detect() {
+        let error = result.as_ref().err().map(|e| format!("{:#}", e));
+        summary.publish(ci, error.as_deref(), started.elapsed());
+    }
+    result
+}
+
+fn deploy(
+    config: &Config,
+    base_tarball: Option<&Path>,
+    artifact: Option<&Path>,
+    overwrite: bool,
+    metrics: &DeployMetrics,
+    summary: &mut ci::DeploySummary,
+) -> Result<()> {
     ui::print_step(&format!("Running deploy for {} hosts", config.hosts.len()));
 
-    for host in &config.hosts {
+    // CLI flag takes precedence over the config file
+    let base_tarball = base_tarball.or_else(|| {
+        config
+            .jail
+            .as_ref()
+            .and_then(|j| j.base_tarball.as_deref())
+    });
+    if let Some(tarball) = base_tarball
+        && !tarball.is_file()
+    {
+        anyhow::bail!("Base tarball not found: {}", tarball.display());
+    }
+
+    let artifact = artifact.or(config.sync.artifact.as_deref());
+    if let Some(artifact) = artifact
+        && !artifact.is_file()
+    {
+        anyhow::bail!("Artifact not found: {}", artifact.display());
+    }
+
+    // rsync is only needed on the hosts when syncing the working tree
+    let sync_tools: &[&str] = match (&config.sync.strategy, artifact) {
+        (SyncStrategy::Rsync, None) => &["rsync"],
+        _ => &[],
+    };
+    metrics.phase("preflight", || -> Result<()> {
+        crate::preflight::run(config, sync_tools)?;
+        let app_size = crate::preflight::app_size(artifact)?;
+        crate::preflight::check_disk_space(config, app_size, |host| determine_base_version(config, host))
+    })?;
+    super::applied::check(config);
+    super::host_manifest::check(config)?;
+
+    // 1-3. Base systems and images, prepared on all hosts concurrently
+    let prepared = metrics.phase("images", || prepare_images(config, base_tarball, metrics))?;
+
+    // The application upload has no cross-host ordering either
+    metrics.phase("stage", || stage_application(config, artifact))?;
+
+    // An artifact may have been built from any commit
+    let revision = if artifact.is_none() { git_revision() } else { None };
+    summary.revision = revision.clone();
+
+    for (i, (host, image)) in config.hosts.iter().zip(prepared).enumerate() {
         let spinner = ui::create_spinner(&format!("Deploying to {}", host));
 
-        deploy_to_host(config, host, &spinner)?;
+        let host_started = Instant::now();
+        let result = deploy_to_host(config, host, &image, revision.as_deref(), overwrite, metrics, &spinner);
+        summary.outcomes.push(ci::HostOutcome {
+            host: host.clone(),
+            result: result.as_ref().map(String::clone).map_err(|e| format!("{:#}", e)),
+            image: image.image_path.rsplit('/').next().unwrap_or_default().to_string(),
+            duration: host_started.elapsed(),
+        });
+        result.map_err(|e| exit::partial(e, i, config.hosts.len()))?;
 
         spinner.finish_with_message(format!("Deploy complete for {}", host));
         ui::print_success(&format!("{} deployed successfully", host));
@@ -43,144 +133,323 @@ pub fn run(config: &Config) -> Result<()> {
     Ok(())
 }
 
-fn deploy_to_host(config: &Config, host: &str, spinner: &ProgressBar) -> Result<()> {
+/// Commit of the local working tree, with `-dirty` if it has uncommitted
+/// changes. None outside a git repository.
+fn git_revision() -> Option<String> {
+    let git = |args: &[&str]| {
+        let output = Command::new("git").args(args).stderr(Stdio::null()).output().ok()?;
+        output.status.success().then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
+    };
+    let sha = git(&["rev-parse", "HEAD"])?;
+    let dirty = git(&["status", "--porcelain"]).is_some_and(|status| !status.is_empty());
+    Some(if dirty { format!("{}-dirty", sha) } else { sha })
+}
+
+/// Base version and image prepared on a host before its jail is created
+struct PreparedImage {
+    base_version: String,
+    image_path: String,
+}
+
+/// Ensure base systems and images on all hosts, building on up to
+/// `config.parallelism` hosts at once. Image builds have no cross-host
+/// ordering constraints and dominate the cost of a first deploy.
+fn prepare_images(config: &Config, base_tarball: Option<&Path>, metrics: &DeployMetrics) -> Result<Vec<PreparedImage>> {
+    let spinners = ui::HostSpinners::new(&config.hosts, "Waiting to prepare the image...");
+
+    let results = super::parallel_hosts(&config.hosts, config.parallelism, |host| {
+        let result = prepare_image(config, host, base_tarball, metrics, spinners.start(host));
+        spinners.done(host, &result, "Image ready");
+        result
+    });
+
+    match collect_host_results(config, results, "image preparation") {
+        Ok(prepared) => {
+            spinners.finish("Images ready");
+            Ok(prepared)
+        }
+        Err(e) => {
+            spinners.clear();
+            Err(e)
+        }
+    }
+}
+
+fn prepare_image(
+    config: &Config,
+    host: &str,
+    base_tarball: Option<&Path>,
+    metrics: &DeployMetrics,
+    spinner: &ProgressBar,
+) -> Result<PreparedImage> {
     // 1. Determine Base Version
+    spinner.set_message(format!("[{}] Determining base version...", host));
     let base_version = determine_base_version(config, host)?;
-    let subnet = config
-        .jail
-        .as_ref()
-        .and_then(|j| j.ip_range.as_deref())
-        .unwrap_or(DEFAULT_IP_RANGE);
 
     // 2. Ensure base system
     spinner.set_message(format!("[{}] Ensuring base system {}...", host, base_version));
-    jail::ensure_base(host, &base_version, config.doas)?;
+    jail::ensure_base(host, &base_version, base_tarball, config.doas)?;
 
     // 3. Ensure Image (Base + Packages + Mise)
     spinner.set_message(format!("[{}] Checking image...", host));
-    let image_path = image::ensure_image(config, host, &base_version, spinner)?;
+    let (image_path, built) = image::ensure_image(config, host, &base_version, spinner)?;
+    metrics.image(built);
+
+    Ok(PreparedImage { base_version, image_path })
+}
+
+/// Unwrap per-host results, reporting every failed host. Returns the
+/// first error if any host failed.
+fn collect_host_results<T>(config: &Config, results: Vec<Result<T>>, step: &str) -> Result<Vec<T>> {
+    let mut values = Vec::new();
+    let mut first_error = None;
+    for (host, result) in config.hosts.iter().zip(results) {
+        match result {
+            Ok(v) => values.push(v),
+            Err(e) => {
+                ui::print_error(&format!("{}: {} failed: {}", host, step, e));
+                first_error.get_or_insert(e);
+            }
+        }
+    }
+    match first_error {
+        Some(e) => Err(e),
+        None => Ok(values),
+    }
+}
+
+fn staging_path(config: &Config) -> String {
+    format!("{}/{}", STAGING_DIR, config.service)
+}
+
+/// Data directories below /app, as `/relative` paths. They are mounted from
+/// the host and must never be overwritten by the application upload.
+fn app_data_excludes(config: &Config) -> Vec<String> {
+    config
+        .data_directories
+        .iter()
+        .filter_map(|entry| {
+            let (_, jail_path) = entry.get_paths();
+            let rel = jail_path.strip_prefix(JAIL_APP_DIR)?.strip_prefix('/')?;
+            (!rel.is_empty()).then(|| format!("/{}", rel))
+        })
+        .collect()
+}
+
+/// Upload the application into every host's staging directory, on up to
+/// `config.parallelism` hosts at once. Each jail is later filled with a
+/// local copy, so the serial per-host deploy does no network transfer.
+fn stage_application(config: &Config, artifact: Option<&Path>) -> Result<()> {
+    let spinners = ui::HostSpinners::new(&config.hosts, "Waiting to upload the application...");
+
+    let results = super::parallel_hosts(&config.hosts, config.parallelism, |host| {
+        spinners.start(host).set_message(format!("[{}] Uploading application...", host));
+        let result = stage_on_host(config, host, artifact);
+        spinners.done(host, &result, "Application uploaded");
+        result
+    });
+
+    match collect_host_results(config, results, "application upload") {
+        Ok(_) => {
+            spinners.finish("Application uploaded");
+            Ok(())
+        }
+        Err(e) => {
+            spinners.clear();
+            Err(e)
+        }
+    }
+}
+
+fn stage_on_host(config: &Config, host: &str, artifact: Option<&Path>) -> Result<()> {
+    let cmd_prefix = shell::escalation_prefix(config.doas);
+    let staging = staging_path(config);
+    let excludes = app_data_excludes(config);
+
+    // rsync updates the previous upload in place; archives start from scratch
+    if artifact.is_some() || config.sync.strategy != SyncStrategy::Rsync {
+        remote::run(host, &format!("{}rm -rf {}", cmd_prefix, staging))?;
+    }
+    remote::ensure_dir(host, &staging, config.doas)?;
+
+    if let Some(artifact) = artifact {
+        // tar detects the compression (gzip, xz, zstd, ...) itself
+        let extract_cmd = format!("{}tar -xf - -C {}", cmd_prefix, shell::escape(&staging));
+        remote::run_from_file(host, artifact, &extract_cmd)
+            .with_context(|| format!("Failed to extract artifact {}", artifact.display()))?;
+    } else {
+        match config.sync.strategy {
+            SyncStrategy::Rsync => remote::sync(host, ".", &staging, &excludes, &config.sync, config.doas)?,
+            SyncStrategy::Git => {
+                // tar patterns are relative to the archive root
+                let patterns: Vec<String> = excludes.iter().map(|e| e.trim_start_matches('/').to_string()).collect();
+                remote::sync_git_archive(host, &staging, &patterns, config.doas)?
+            }
+        }
+    }
+
+    // Archives may still contain data directories
+    for ex in &excludes {
+        remote::run(host, &format!("{}rm -rf {}", cmd_prefix, shell::escape(&format!("{}{}", staging, ex))))?;
+    }
+    Ok(())
+}
+
+fn deploy_to_host(
+    config: &Config,
+    host: &str,
+    image: &PreparedImage,
+    revision: Option<&str>,
+    overwrite: bool,
+    metrics: &DeployMetrics,
+    spinner: &ProgressBar,
+) -> Result<String> {
+    let base_version = &image.base_version;
+    let image_path = &image.image_path;
+    let subnet = config.jail_ip_range(host);
+
+    if helper::is_enabled() {
+        spinner.set_message(format!("[{}] Checking remote helper...", host));
+        helper::ensure_installed(host, config.doas)?;
+    }
+
+    spinner.set_message(format!("[{}] Checking boot scripts...", host));
+    if let Some(old) = rcd::ensure_current(config, host)? {
+        ui::print_success(&format!(
+            "{}: updated the rc.d scripts ({} → version {})",
+            host,
+            rcd::describe_version(&old),
+            rcd::RCD_VERSION
+        ));
+    }
 
     // 4. Create Jail from Image
     spinner.set_message(format!("[{}] Creating new jail from image...", host));
-    let jail_info = jail::create(
-        host,
-        &config.service,
-        &base_version,
-        subnet,
-        Some(&image_path),
-        &config.data_directories,
-        config.doas,
-    )?;
+    let jail_info = metrics.phase("create", || {
+        jail::create(
+            host,
+            &config.service,
+            base_version,
+            subnet,
+            Some(image_path),
+            &config.data_directories,
+            config.doas,
+        )
+    })?;
     spinner.set_message(format!(
         "[{}] Jail created: {} ({})",
         host, jail_info.name, jail_info.ip
     ));
 
-    let cmd_prefix = if config.doas { "doas " } else { "" };
-
     // Run remaining deployment steps, cleaning up the jail on failure
     let result = deploy_jail_steps(
         config,
         host,
         &jail_info,
-        &base_version,
-        &image_path,
-        cmd_prefix,
+        image,
+        revision,
+        overwrite,
+        metrics,
         spinner,
     );
 
     if let Err(ref e) = result {
         spinner.set_message(format!("[{}] Deployment failed, cleaning up jail {}...", host, jail_info.name));
-        cleanup_failed_jail(host, &jail_info, cmd_prefix);
+        cleanup_failed_jail(host, &jail_info, config.doas);
         spinner.set_message(format!("[{}] Cleanup complete. Error: {}", host, e));
     }
 
-    result
+    result.map(|()| jail_info.name)
 }
 
-/// Execute deployment steps after jail creation. Returns error if any step fails.
+/// Execute deployment steps after jail creation, timing each phase in
+/// `metrics`. Returns error if any step fails.
+#[allow(clippy::too_many_arguments)]
 fn deploy_jail_steps(
     config: &Config,
     host: &str,
     jail_info: &jail::JailInfo,
-    base_version: &str,
-    image_path: &str,
-    cmd_prefix: &str,
+    image: &PreparedImage,
+    revision: Option<&str>,
+    overwrite: bool,
+    metrics: &DeployMetrics,
     spinner: &ProgressBar,
 ) -> Result<()> {
-    // 5. Start Jail (Phase 1: Inherit IP for build hooks)
-    start_jail_build_phase(config, host, jail_info, cmd_prefix, spinner)?;
+    let cmd_prefix = shell::escalation_prefix(config.doas);
 
-    // 6. Sync application code
-    sync_application(config, host, jail_info, cmd_prefix, spinner)?;
+    metrics.phase("build", || -> Result<()> {
+        // 5. Start Jail (Phase 1: Inherit IP for build hooks)
+        start_jail_build_phase(config, host, jail_info, cmd_prefix, spinner)?;
 
-    // 7. Configure environment
-    configure_environment(config, host, jail_info, cmd_prefix)?;
+        // 6. Sync application code
+        sync_application(config, host, jail_info, cmd_prefix, spinner)?;
 
-    // 8. Run before_start hooks
-    run_before_start_hooks(config, host, jail_info, cmd_prefix, spinner)?;
+        // 7. Configure environment
+        configure_environment(config, host, jail_info, cmd_prefix)?;
 
-    // 9. Restart jail with private networking
-    restart_jail_production(config, host, jail_info, cmd_prefix, spinner)?;
+        // 8. Run before_start hooks
+        run_before_start_hooks(config, host, jail_info, cmd_prefix, spinner)
+    })?;
 
-    // 10. Start services
-    start_services(config, host, jail_info, cmd_prefix, spinner)?;
+    metrics.phase("start", || -> Result<()> {
+        // 9. Restart jail with private networking
+        restart_jail_production(config, host, jail_info, cmd_prefix, spinner)?;
 
-    // 10.5. Write jail metadata and update active symlink (for boot persistence)
-    write_metadata_and_activate(config, host, jail_info, base_version, image_path, cmd_prefix, spinner)?;
+        // 9.5. Forward syslog to the collector
+        if let Some(syslog) = &config.syslog {
+            start_syslogd(config, syslog, host, jail_info, cmd_prefix, spinner)?;
+        }
 
-    // 11. Update proxy configuration
-    update_proxy(config, host, jail_info, cmd_prefix, spinner)?;
+        // 10. Start services
+        start_services(config, host, jail_info, cmd_prefix, spinner)
+    })?;
 
-    // 12. Stop old jails
-    stop_old_jails(config, host, jail_info, cmd_prefix, spinner)?;
+    metrics.phase("switch", || -> Result<()> {
+        // 10.5. Write jail metadata and update active symlink (for boot persistence)
+        write_metadata_and_activate(
+            config,
+            host,
+            jail_info,
+            &image.base_version,
+            &image.image_path,
+            revision,
+            spinner,
+        )?;
 
-    // 13. Prune old jails
-    prune_old_jails(config, host, jail_info, cmd_prefix, spinner)?;
+        // 10.6. Rotate the logs of the new jail
+        install_log_rotation(config, host, jail_info, cmd_prefix, spinner)?;
 
-    Ok(())
-}
+        // 11. Update proxy configuration
+        update_proxy(config, host, jail_info, overwrite, spinner)
+    })?;
 
-/// Clean up a failed jail deployment: stop jail, remove IP alias, unmount, remove directory
-fn cleanup_failed_jail(host: &str, jail_info: &jail::JailInfo, cmd_prefix: &str) {
-    // Stop jail if running
-    remote::run(host, &format!("{}jail -r {} 2>/dev/null", cmd_prefix, jail_info.name)).ok();
+    metrics.phase("cleanup", || -> Result<()> {
+        // 12. Stop old jails
+        stop_old_jails(config, host, jail_info, cmd_prefix, spinner)?;
 
-    // Remove IP alias
-    if !jail_info.ip.is_empty() {
-        remote::run(
-            host,
-            &format!("{}ifconfig lo1 inet {} -alias 2>/dev/null", cmd_prefix, jail_info.ip),
-        ).ok();
-    }
-
-    // Unmount all filesystems under jail path
-    let mount_check = format!("mount | grep '{}' | awk '{{print $3}}'", jail_info.path);
-    if let Ok(mounts) = remote::run_with_output(host, &mount_check) {
-        // Unmount in reverse order (deepest first)
-        for mnt in mounts.lines().rev() {
-            let mnt = mnt.trim();
-            if !mnt.is_empty() {
-                remote::run(host, &format!("{}umount -f {}", cmd_prefix, mnt)).ok();
-            }
-        }
-    }
+        // 13. Prune old jails
+        prune_old_jails(config, host, jail_info, spinner)
+    })?;
 
-    // Remove jail directory or ZFS dataset
-    if let Ok(Some(dataset)) = remote::get_zfs_dataset(host, &jail_info.path) {
-        remote::run(host, &format!("{}zfs destroy -r {}", cmd_prefix, dataset)).ok();
+    // 14. Record what was applied, for drift detection
+    if let Err(e) = super::applied::record(config, host) {
+        ui::print_warning(&format!("{}: could not record the applied configuration: {:#}", host, e));
     }
 
-    // Remove directory (handles non-ZFS case or if ZFS destroy failed)
-    remote::run(host, &format!("{}chflags -R noschg {}", cmd_prefix, jail_info.path)).ok();
-    remote::run(host, &format!("{}rm -rf {}", cmd_prefix, jail_info.path)).ok();
+    Ok(())
+}
+
+/// Clean up a failed jail deployment: stop jail, remove IP alias, unmount, remove directory
+fn cleanup_failed_jail(host: &str, jail_info: &jail::JailInfo, doas: bool) {
+    let ip = Some(jail_info.ip.as_str()).filter(|ip| !ip.is_empty());
+    jail::teardown(host, &jail_info.name, ip, doas).ok();
 }
 
 fn determine_base_version(config: &Config, host: &str) -> Result<String> {
-    if let Some(j) = &config.jail {
-        if let Some(v) = &j.base_version {
-            return Ok(v.clone());
-        }
+    if let Some(j) = &config.jail
+        && let Some(v) = &j.base_version
+    {
+        return Ok(v.clone());
     }
 
     let os_release = remote::get_os_release(host)?;
@@ -235,28 +504,16 @@ fn sync_application(
     cmd_prefix: &str,
     spinner: &ProgressBar,
 ) -> Result<()> {
-    spinner.set_message(format!("[{}] Syncing app to jail...", host));
+    spinner.set_message(format!("[{}] Copying app into jail...", host));
 
     let app_dir = JAIL_APP_DIR;
     let host_app_dir = format!("{}{}", jail_info.path, JAIL_APP_DIR);
 
-    remote::run(host, &format!("{}mkdir -p {}", cmd_prefix, host_app_dir))?;
-
-    // Build excludes for data directories inside app
-    let mut excludes = Vec::new();
-    for entry in &config.data_directories {
-        let (_, jail_path) = entry.get_paths();
-        if jail_path.starts_with(app_dir) {
-            if let Some(rel) = jail_path.strip_prefix(app_dir) {
-                let rel = rel.trim_start_matches('/');
-                if !rel.is_empty() {
-                    excludes.push(format!("/{}", rel));
-                }
-            }
-        }
-    }
-
-    remote::sync(host, ".", &host_app_dir, &excludes, config.doas)?;
+    remote::ensure_dir(host, &host_app_dir, config.doas)?;
+    remote::run(
+        host,
+        &format!("{}cp -Rp {}/. {}/", cmd_prefix, staging_path(config), host_app_dir),
+    )?;
 
     // Set ownership
     if let Some(user) = &config.user {
@@ -351,30 +608,108 @@ fn run_before_start_hooks(
     for cmd in &config.before_start {
         spinner.set_message(format!("[{}] Jail: Running {}...", host, cmd));
 
-        let full_cmd = format!(
-            "bash -c 'source {} && cd {} && {}'",
-            JAIL_ENV_FILE, app_dir, cmd
-        );
-
-        let exec_cmd = if let Some(user) = &config.user {
-            let safe_user = shell::escape(user);
-            format!(
-                "{}jexec {} su - {} -c \"{}\"",
-                cmd_prefix,
-                jail_info.name,
-                safe_user,
-                full_cmd.replace("\"", "\\\"")
-            )
+        let exec_cmd = before_start_command(config, &jail_info.name, cmd_prefix, cmd);
+        let exec_cmd = if config.ssh.forward_agent {
+            with_agent_in_jail(&exec_cmd, jail_info, config.user.as_deref(), config.doas)
         } else {
-            format!("{}jexec {} {}", cmd_prefix, jail_info.name, full_cmd)
+            exec_cmd
         };
 
-        remote::run(host, &exec_cmd)?;
+        remote::run_timeout(host, &exec_cmd, remote::Timeout::Build)?;
     }
 
     Ok(())
 }
 
+/// Host command running a `before_start` hook in the jail, with the
+/// variables forwarded from the SSH session.
+fn before_start_command(config: &Config, jail: &str, cmd_prefix: &str, cmd: &str) -> String {
+    // Variables forwarded from the SSH session, expanded on the host
+    let mut forwarded: Vec<(&str, String)> = config
+        .ssh
+        .forwarded_env()
+        .map(|name| (name, format!("${}", name)))
+        .collect();
+    if config.ssh.forward_agent {
+        forwarded.push(("SSH_AUTH_SOCK", "$agent_sock".to_string()));
+    }
+
+    let full_cmd = format!(
+        "bash -c 'source {} && cd {} && {}'",
+        JAIL_ENV_FILE, JAIL_APP_DIR, cmd
+    );
+
+    if let Some(user) = &config.user {
+        // su - starts with a clean environment, so the values are passed
+        // as arguments of the shell and exported inside it
+        let safe_user = shell::escape(user);
+        let exports: String = forwarded
+            .iter()
+            .enumerate()
+            .map(|(i, (name, _))| format!("export {}=\\\"\\${{{}}}\\\" && ", name, i + 1))
+            .collect();
+        let args: String = forwarded.iter().map(|(_, value)| format!(" \"{}\"", value)).collect();
+        format!(
+            "{}jexec {} su - {} -c \"{}{}\"{}",
+            cmd_prefix,
+            jail,
+            safe_user,
+            exports,
+            full_cmd.replace("\"", "\\\""),
+            if args.is_empty() { String::new() } else { format!(" sh{}", args) }
+        )
+    } else if !forwarded.is_empty() {
+        // doas/sudo drop the environment, so it is set inside the jail
+        let assignments: Vec<String> = forwarded
+            .iter()
+            .map(|(name, value)| format!("{}=\"{}\"", name, value))
+            .collect();
+        format!("{}jexec {} env {} {}", cmd_prefix, jail, assignments.join(" "), full_cmd)
+    } else {
+        format!("{}jexec {} {}", cmd_prefix, jail, full_cmd)
+    }
+}
+
+/// Jail path the forwarded agent socket directory is mounted at
+const JAIL_AGENT_DIR: &str = "/var/run/bsdeploy-agent";
+
+/// Wrap `command` so the forwarded agent socket of this SSH session is
+/// reachable inside the jail as `$agent_sock` while it runs. sshd creates
+/// the socket and its directory owned by the SSH user, so with a jail
+/// `user` both are handed to that user's uid for the run and given back
+/// afterwards.
+fn with_agent_in_jail(command: &str, jail_info: &jail::JailInfo, user: Option<&str>, doas: bool) -> String {
+    let cmd_prefix = shell::escalation_prefix(doas);
+    let mnt = format!("{}{}", jail_info.path, JAIL_AGENT_DIR);
+    let (grant, restore) = match user {
+        Some(user) => (
+            format!(
+                "owner=$(stat -f %u \"$SSH_AUTH_SOCK\"); {p}chown \"$({p}jexec {jail} id -u {user})\" \"$SSH_AUTH_SOCK\" \"$(dirname \"$SSH_AUTH_SOCK\")\"; ",
+                p = cmd_prefix,
+                jail = jail_info.name,
+                user = shell::escape(user)
+            ),
+            format!(
+                "{p}chown \"$owner\" \"$SSH_AUTH_SOCK\" \"$(dirname \"$SSH_AUTH_SOCK\")\"; ",
+                p = cmd_prefix
+            ),
+        ),
+        None => (String::new(), String::new()),
+    };
+    format!(
+        "agent_sock=; if [ -S \"$SSH_AUTH_SOCK\" ] && {dir_cmd} && {p}mount_nullfs \"$(dirname \"$SSH_AUTH_SOCK\")\" {mnt}; then agent_sock={dir}/$(basename \"$SSH_AUTH_SOCK\"); {grant}fi; \
+         {command}; status=$?; \
+         if [ -n \"$agent_sock\" ]; then {restore}{p}umount -f {mnt}; fi; exit $status",
+        p = cmd_prefix,
+        dir_cmd = remote::ensure::dir_command(&mnt, doas),
+        mnt = shell::escape(&mnt),
+        dir = JAIL_AGENT_DIR,
+        grant = grant,
+        restore = restore,
+        command = command
+    )
+}
+
 fn restart_jail_production(
     config: &Config,
     host: &str,
@@ -402,8 +737,8 @@ fn restart_jail_production(
         let jail_run_dir = format!("{}{}/{}", jail_info.path, RUN_DIR, safe_service);
         let jail_log_dir = format!("{}{}/{}", jail_info.path, LOG_DIR, safe_service);
 
-        remote::run(host, &format!("{}mkdir -p {}", cmd_prefix, jail_run_dir))?;
-        remote::run(host, &format!("{}mkdir -p {}", cmd_prefix, jail_log_dir))?;
+        remote::ensure_dir(host, &jail_run_dir, config.doas)?;
+        remote::ensure_dir(host, &jail_log_dir, config.doas)?;
         remote::run(
             host,
             &format!(
@@ -423,44 +758,311 @@ fn restart_jail_production(
     Ok(())
 }
 
-fn start_services(
+/// Directories of the pid and log files inside the jail
+fn process_dirs(config: &Config) -> (String, String) {
+    if config.user.is_some() {
+        (format!("{}/{}", RUN_DIR, config.service), format!("{}/{}", LOG_DIR, config.service))
+    } else {
+        ("/var/run".to_string(), "/var/log".to_string())
+    }
+}
+
+/// Pid file of the `index`th start command, as seen inside the jail
+fn pid_path(config: &Config, index: usize) -> String {
+    format!("{}/{}.pid", process_dirs(config).0, config.process_name(index))
+}
+
+/// Pid file of daemon(8) supervising the `index`th start command, which
+/// reopens the log on SIGHUP after newsyslog rotated it and is stopped
+/// instead of the command when it restarts it
+fn supervisor_pid_path(config: &Config, index: usize) -> String {
+    format!("{}/daemon-{}.pid", process_dirs(config).0, config.process_name(index))
+}
+
+/// Start count and time of the last start of the `index`th start command,
+/// as seen inside the jail
+fn starts_path(config: &Config, index: usize) -> String {
+    format!("{}/{}.starts", process_dirs(config).0, config.process_name(index))
+}
+
+/// Shell snippet counting a start of the `index`th start command in its
+/// starts file as `<count> <epoch>`. It runs on every start, whether by
+/// deploy, the rc.d script or daemon(8) restarting the command.
+fn record_start(config: &Config, index: usize) -> String {
+    let path = starts_path(config, index);
+    format!(
+        "{{ read -r n _ < {path}; }} 2>/dev/null; echo \"$(( ${{n:-0}} + 1 )) $(date +%s)\" > {path} 2>/dev/null; ",
+        path = path
+    )
+}
+
+/// Whether daemon(8) writes the log of the start commands (`-o`), rather
+/// than their shell through `log_writer`
+fn daemon_writes_log(config: &Config, index: usize) -> bool {
+    !config.logs.timestamps && log_paths(config, index).1.is_none()
+}
+
+/// Log file of the `index`th start command unless it sets its own, as
+/// seen inside the jail
+pub(super) fn service_log_path(config: &Config, index: usize) -> String {
+    format!("{}/{}.log", process_dirs(config).1, config.process_name(index))
+}
+
+/// Log files of a start command inside the jail: the output log and, with
+/// stderr split off, the error log.
+pub(super) fn log_paths(config: &Config, index: usize) -> (String, Option<String>) {
+    let start = &config.start[index];
+    let log = start.log().map_or_else(|| service_log_path(config, index), str::to_string);
+    let error_log = match start.error_log() {
+        Some(path) => Some(path.to_string()),
+        None if config.logs.split_stderr => Some(match log.strip_suffix(".log") {
+            Some(stem) => format!("{}.error.log", stem),
+            None => format!("{}.error", log),
+        }),
+        None => None,
+    };
+    (log, error_log)
+}
+
+/// syslog.conf rule forwarding the selected messages to the collector
+fn syslog_forward_rule(syslog: &SyslogConfig) -> String {
+    format!("# Generated by bsdeploy\n{}\t@{}\n", syslog.selector, syslog.forward)
+}
+
+/// Add the forwarding rule to the jail's syslog.d and start syslogd, which
+/// a jail does not run by default. `-s` keeps it from listening on the
+/// network; it still sends.
+fn start_syslogd(
     config: &Config,
+    syslog: &SyslogConfig,
     host: &str,
     jail_info: &jail::JailInfo,
     cmd_prefix: &str,
     spinner: &ProgressBar,
 ) -> Result<()> {
-    let app_dir = JAIL_APP_DIR;
+    spinner.set_message(format!("[{}] Jail: Forwarding syslog to {}...", host, syslog.forward));
+    let syslog_d = format!("{}/etc/syslog.d", jail_info.path);
+    remote::ensure_dir(host, &syslog_d, config.doas)?;
+    remote::write_file(host, &syslog_forward_rule(syslog), &format!("{}/bsdeploy.conf", syslog_d), config.doas)?;
+    remote::run(host, &format!("{}jexec {} /usr/sbin/syslogd -s", cmd_prefix, jail_info.name))
+        .with_context(|| format!("Failed to start syslogd in {}", jail_info.name))
+}
 
-    for cmd in &config.start {
-        spinner.set_message(format!("[{}] Jail: Starting service...", host));
+/// Shell function appending its input to the file in `$1` line by line,
+/// reopening it for every line so a rotated log is picked up.
+fn log_writer(timestamps: bool) -> &'static str {
+    if timestamps {
+        "w() { while IFS= read -r l || [ -n \"$l\" ]; do printf \"%(%Y-%m-%dT%H:%M:%S%z)T %s\\n\" -1 \"$l\" >>\"$1\"; done; }; "
+    } else {
+        "w() { while IFS= read -r l || [ -n \"$l\" ]; do printf \"%s\\n\" \"$l\" >>\"$1\"; done; }; "
+    }
+}
 
-        let safe_service = shell::escape(&config.service);
-        let (pid_file, log_file) = if config.user.is_some() {
-            (
-                format!("{}/{}/service.pid", RUN_DIR, safe_service),
-                format!("{}/{}/service.log", LOG_DIR, safe_service),
-            )
-        } else {
-            (
-                "/var/run/service.pid".to_string(),
-                "/var/log/service.log".to_string(),
-            )
+/// daemon(8) command line running the `index`th start command inside the
+/// jail. Merged output without timestamps goes through daemon's own `-o`;
+/// otherwise the command's shell sends stdout and stderr through
+/// `log_writer`. `restart: always` has daemon restart the command (`-r`,
+/// `-R`). daemon cannot tell failures apart and runs the command as `user`
+/// while it stays root, so for `on-failure` the shell restarts the command
+/// itself until it exits successfully.
+fn daemon_command(config: &Config, index: usize) -> String {
+    let start = &config.start[index];
+    let (log, error_log) = log_paths(config, index);
+    let restarts = start.restart() != RestartPolicy::Never;
+
+    let mut daemon_cmd = format!("daemon -f -p {}", pid_path(config, index));
+    let mut redirect = record_start(config, index);
+    let rotated = daemon_writes_log(config, index) && config.logs.rotate.is_some();
+    if restarts {
+        if start.restart() == RestartPolicy::Always {
+            match start.restart_delay() {
+                Some(delay) => daemon_cmd.push_str(&format!(" -R {}", delay)),
+                None => daemon_cmd.push_str(" -r"),
+            }
+        }
+        if !rotated {
+            daemon_cmd.push_str(&format!(" -P {}", supervisor_pid_path(config, index)));
+        }
+    }
+    if daemon_writes_log(config, index) {
+        daemon_cmd.push_str(&format!(" -o {}", log));
+        if rotated {
+            daemon_cmd.push_str(&format!(" -H -P {}", supervisor_pid_path(config, index)));
+        }
+        if config.syslog.as_ref().is_some_and(|s| s.output) {
+            // service → myapp, service-worker → myapp-worker
+            let tag = config.process_name(index).replacen("service", &config.service, 1);
+            daemon_cmd.push_str(&format!(" -S -T {}", tag));
+        }
+    } else {
+        redirect.push_str(log_writer(config.logs.timestamps));
+        let stderr = match error_log {
+            Some(error_log) => format!("2> >(w {})", error_log),
+            None => "2>&1".to_string(),
         };
+        redirect.push_str(&format!("exec > >(w {}) {}; ", log, stderr));
+    }
+    if let Some(u) = &config.user {
+        daemon_cmd.push_str(&format!(" -u {}", shell::escape(u)));
+    }
+
+    // The command runs in the background so a TERM from the supervisor
+    // reaches the trap at once, which passes it on
+    let command = match start.restart() {
+        RestartPolicy::OnFailure => format!(
+            "trap \"pkill -P \\$c; kill \\$c 2>/dev/null; exit 143\" TERM; \
+             while :; do {{ {}; }} & c=$!; wait $c && exit 0; sleep {}; {}done",
+            start.command(),
+            start.restart_delay().unwrap_or(1),
+            record_start(config, index)
+        ),
+        _ => start.command().to_string(),
+    };
+    format!(
+        "{} bash -c '{}source {} && cd {} && {}'",
+        daemon_cmd,
+        redirect,
+        JAIL_ENV_FILE,
+        JAIL_APP_DIR,
+        command
+    )
+}
+
+/// newsyslog entry file of the service on the host
+pub(super) fn newsyslog_path(config: &Config) -> String {
+    format!("{}/bsdeploy-{}.conf", NEWSYSLOG_CONF_DIR, config.service)
+}
+
+/// newsyslog entries for the logs of the start commands, reached through
+/// the active symlink so they follow the running jail. `owner` is the
+/// `uid:gid` of the jail user the rotated files are created for.
+fn newsyslog_entries(config: &Config, rotate: &RotateConfig, owner: Option<&str>) -> String {
+    let mut entries = String::from("# Generated by bsdeploy; rewritten on every deploy\n");
+    let mut seen: Vec<String> = Vec::new();
+    for index in 0..config.start.len() {
+        let (log, error_log) = log_paths(config, index);
+        for path in std::iter::once(log).chain(error_log) {
+            if seen.contains(&path) {
+                continue;
+            }
+            // daemon(8) is told to reopen its log; log_writer opens the
+            // file for every line and needs no signal
+            let mut flags: String = rotate.compress.flag().into_iter().collect();
+            let pid_file = if daemon_writes_log(config, index) {
+                format!("\t{}/{}{}", ACTIVE_DIR, config.service, supervisor_pid_path(config, index))
+            } else {
+                flags.push('N');
+                String::new()
+            };
+            entries.push_str(&format!(
+                "{}/{}{}\t{}640\t{}\t{}\t{}\t{}{}\n",
+                ACTIVE_DIR,
+                config.service,
+                path,
+                owner.map(|o| format!("{}\t", o)).unwrap_or_default(),
+                rotate.keep,
+                rotate.size_kb.map_or("*".to_string(), |s| s.to_string()),
+                rotate.when.as_deref().unwrap_or("*"),
+                if flags.is_empty() { "-" } else { &flags },
+                pid_file
+            ));
+            seen.push(path);
+        }
+    }
+    entries
+}
+
+/// newsyslog entry file `setup` writes for the service's logs on the host
+/// itself, outside the jails. Service names have no dots, so it cannot
+/// clash with another service's `newsyslog_path`.
+pub(super) fn host_newsyslog_path(config: &Config) -> String {
+    format!("{}/bsdeploy-{}.host.conf", NEWSYSLOG_CONF_DIR, config.service)
+}
+
+/// newsyslog entry for the host's log directory of the service, where the
+/// rc.d scripts keep `boot.log`, rotated like the jail logs (daily when
+/// `logs.rotate` is not set). Nothing there is signalled, so files held
+/// open are not reopened. Caddy's access logs have no entry: Caddy keeps
+/// them open without reopening them and rotates them itself
+/// (`roll_size`/`roll_keep`).
+pub(super) fn host_newsyslog_entries(config: &Config) -> String {
+    let (keep, size_kb, when, compress) = match &config.logs.rotate {
+        Some(rotate) => (rotate.keep, rotate.size_kb, rotate.when.as_deref(), rotate.compress),
+        None => (7, None, Some("@T00"), LogCompression::Gzip),
+    };
+    let when = match (size_kb, when) {
+        (None, None) => Some("@T00"),
+        (_, when) => when,
+    };
+    let flags: String = std::iter::once('G').chain(compress.flag()).chain(['N']).collect();
+    format!(
+        "# Generated by bsdeploy setup\n{}/{}/*.log\t{}640\t{}\t{}\t{}\t{}\n",
+        LOG_DIR,
+        config.service,
+        config.user.as_ref().map(|u| format!("{u}:{u}\t", u = u)).unwrap_or_default(),
+        keep,
+        size_kb.map_or("*".to_string(), |s| s.to_string()),
+        when.unwrap_or("*"),
+        flags
+    )
+}
 
-        let mut daemon_cmd = format!("daemon -f -p {} -o {}", pid_file, log_file);
-        if let Some(u) = &config.user {
-            daemon_cmd.push_str(&format!(" -u {}", shell::escape(u)));
+/// Install the newsyslog entries of the service on the host, or remove
+/// them when rotation is not configured.
+fn install_log_rotation(
+    config: &Config,
+    host: &str,
+    jail_info: &jail::JailInfo,
+    cmd_prefix: &str,
+    spinner: &ProgressBar,
+) -> Result<()> {
+    let Some(rotate) = &config.logs.rotate else {
+        remote::run(host, &format!("{}rm -f {}", cmd_prefix, newsyslog_path(config)))?;
+        return Ok(());
+    };
+    spinner.set_message(format!("[{}] Installing log rotation...", host));
+
+    // The user only exists inside the jail, so its ids are used
+    let owner = match &config.user {
+        Some(user) => {
+            let ids = remote::run_with_output(
+                host,
+                &format!("{}jexec {} sh -c 'echo $(id -u {u}):$(id -g {u})'", cmd_prefix, jail_info.name, u = shell::escape(user)),
+            )?;
+            Some(ids.trim().to_string())
         }
+        None => None,
+    };
+    remote::ensure_dir(host, NEWSYSLOG_CONF_DIR, config.doas)?;
+    remote::write_file(host, &newsyslog_entries(config, rotate, owner.as_deref()), &newsyslog_path(config), config.doas)
+}
 
-        let full_cmd = format!(
-            "{} bash -c 'source {} && cd {} && {}'",
-            daemon_cmd, JAIL_ENV_FILE, app_dir, cmd
-        );
+fn start_services(
+    config: &Config,
+    host: &str,
+    jail_info: &jail::JailInfo,
+    cmd_prefix: &str,
+    spinner: &ProgressBar,
+) -> Result<()> {
+    for (index, start) in config.start.iter().enumerate() {
+        spinner.set_message(format!("[{}] Jail: Starting {}...", host, config.process_name(index)));
+
+        // Log files outside the service's log directory are created up
+        // front, owned by the user the command runs as
+        let (log, error_log) = log_paths(config, index);
+        let custom = [start.log().map(|_| log), start.error_log().and(error_log)];
+        for path in custom.into_iter().flatten() {
+            let file = format!("{}{}", jail_info.path, path);
+            let mut create = format!("{p}mkdir -p $(dirname {f}) && {p}touch {f}", p = cmd_prefix, f = file);
+            if let Some(user) = &config.user {
+                create.push_str(&format!(" && {}chown {} {}", cmd_prefix, shell::escape(user), file));
+            }
+            remote::run(host, &create)?;
+        }
 
         remote::run(
             host,
-            &format!("{}jexec {} {}", cmd_prefix, jail_info.name, full_cmd),
+            &format!("{}jexec {} {}", cmd_prefix, jail_info.name, daemon_command(config, index)),
         )?;
     }
 
@@ -473,37 +1075,12 @@ fn write_metadata_and_activate(
     jail_info: &jail::JailInfo,
     base_version: &str,
     image_path: &str,
-    cmd_prefix: &str,
+    revision: Option<&str>,
     spinner: &ProgressBar,
 ) -> Result<()> {
     spinner.set_message(format!("[{}] Writing jail metadata...", host));
 
-    // Build data directory mappings
-    let data_dirs: Vec<DataDirectoryMapping> = config
-        .data_directories
-        .iter()
-        .map(|d| {
-            let (host_path, jail_path) = d.get_paths();
-            DataDirectoryMapping {
-                host_path,
-                jail_path,
-            }
-        })
-        .collect();
-
-    let metadata = JailMetadata {
-        service: config.service.clone(),
-        jail_name: jail_info.name.clone(),
-        ip: jail_info.ip.clone(),
-        user: config.user.clone(),
-        start_commands: config.start.clone(),
-        env_file: JAIL_ENV_FILE.to_string(),
-        app_dir: JAIL_APP_DIR.to_string(),
-        data_directories: data_dirs,
-        base_version: base_version.to_string(),
-        image_path: Some(image_path.to_string()),
-        zfs: jail_info.zfs,
-    };
+    let metadata = JailMetadata::new(config, jail_info, base_version, Some(image_path), revision);
 
     let metadata_json = serde_json::to_string_pretty(&metadata)?;
     let metadata_path = format!("{}/.bsdeploy.json", jail_info.path);
@@ -513,43 +1090,107 @@ fn write_metadata_and_activate(
     spinner.set_message(format!("[{}] Updating active symlink...", host));
     let symlink_path = format!("{}/{}", ACTIVE_DIR, config.service);
 
-    // Remove old symlink if exists, then create new one
-    remote::run(host, &format!("{}rm -f {}", cmd_prefix, symlink_path))?;
-    remote::run(
-        host,
-        &format!("{}ln -s {} {}", cmd_prefix, jail_info.path, symlink_path),
-    )?;
+    remote::ensure_symlink(host, &jail_info.path, &symlink_path, config.doas)?;
 
     Ok(())
 }
 
+impl JailMetadata {
+    /// Metadata of `jail_info`, deployed from `config`
+    pub(super) fn new(
+        config: &Config,
+        jail_info: &jail::JailInfo,
+        base_version: &str,
+        image_path: Option<&str>,
+        revision: Option<&str>,
+    ) -> Self {
+        let data_directories = config
+            .data_directories
+            .iter()
+            .map(|d| {
+                let (host_path, jail_path) = d.get_paths();
+                DataDirectoryMapping {
+                    host_path,
+                    jail_path,
+                }
+            })
+            .collect();
+
+        Self {
+            service: config.service.clone(),
+            jail_name: jail_info.name.clone(),
+            ip: jail_info.ip.clone(),
+            user: config.user.clone(),
+            start_commands: config.start.iter().map(|s| s.command().to_string()).collect(),
+            processes: (0..config.start.len()).map(|i| daemon_command(config, i)).collect(),
+            syslogd: config.syslog.is_some(),
+            env_file: JAIL_ENV_FILE.to_string(),
+            app_dir: JAIL_APP_DIR.to_string(),
+            data_directories,
+            base_version: base_version.to_string(),
+            image_path: image_path.map(str::to_string),
+            zfs: jail_info.zfs,
+            revision: revision.map(str::to_string),
+            depends_on: config.boot.services.clone(),
+            depends_wait: config.boot.wait,
+            health_port: health_port(config),
+        }
+    }
+}
+
+/// The proxied port, or the first redirected TCP port of a service
+/// without a proxy; services that depend on this one wait for it at boot.
+fn health_port(config: &Config) -> Option<u16> {
+    match (&config.proxy, &config.bind) {
+        (Some(proxy), _) => Some(proxy.port),
+        (None, Some(bind)) => bind.ports.first().copied(),
+        (None, None) => None,
+    }
+}
+
 fn update_proxy(
     config: &Config,
     host: &str,
     jail_info: &jail::JailInfo,
-    cmd_prefix: &str,
+    overwrite: bool,
     spinner: &ProgressBar,
 ) -> Result<()> {
-    if let Some(proxy) = &config.proxy {
+    if config.proxy.is_some() {
         spinner.set_message(format!("[{}] Switching traffic to {}...", host, jail_info.ip));
-
-        // Update SSL certificates if configured (they may have been rotated)
-        if let Some(ssl) = &proxy.ssl {
-            spinner.set_message(format!("[{}] Updating TLS certificates...", host));
-            caddy::write_ssl_certificates(config, host, ssl)?;
-        }
-
-        let backend = format!("{}:{}", jail_info.ip, proxy.port);
-        let proxy_conf_content = caddy::generate_caddyfile(proxy, &config.service, &backend);
-
-        let caddy_conf_path = format!("{}/{}.caddy", CADDY_CONF_DIR, config.service);
-        remote::write_file(host, &proxy_conf_content, &caddy_conf_path, config.doas)?;
-        remote::run(host, &format!("{}service caddy reload", cmd_prefix))?;
+        proxy::switch(config, host, &jail_info.ip, overwrite, spinner)?;
+    } else if let Some(bind) = &config.bind {
+        spinner.set_message(format!("[{}] Redirecting ports to {}...", host, jail_info.ip));
+        pf::switch(config, bind, host, &jail_info.ip)?;
     }
-
     Ok(())
 }
 
+/// Stop every start command of a jail: TERM to all of them, then KILL to
+/// those still running after 10 seconds. Supervising daemon(8) processes
+/// come first, so they cannot restart a command that was just stopped.
+/// Looks in both pid directories, as the jail may have been deployed with
+/// or without `user`. A forwarding syslogd is stopped as well.
+fn stop_script(config: &Config) -> String {
+    format!(
+        "pids=\"\"; \
+         for f in /var/run/daemon-service*.pid {run}/daemon-service*.pid \
+             /var/run/service.pid /var/run/service-*.pid {run}/service.pid {run}/service-*.pid; do \
+             [ -f \"$f\" ] && pkill -F \"$f\" && pids=\"$pids $f\"; \
+         done; \
+         count=0; \
+         while [ -n \"$pids\" ] && [ $count -lt 20 ]; do \
+             alive=\"\"; \
+             for f in $pids; do pkill -0 -F \"$f\" >/dev/null 2>&1 && alive=\"$alive $f\"; done; \
+             pids=$alive; \
+             [ -n \"$pids\" ] && sleep 0.5; \
+             count=$((count+1)); \
+         done; \
+         for f in $pids; do pkill -9 -F \"$f\"; done; \
+         [ -f /var/run/syslog.pid ] && pkill -F /var/run/syslog.pid; true",
+        run = format!("{}/{}", RUN_DIR, config.service)
+    )
+}
+
 fn stop_old_jails(
     config: &Config,
     host: &str,
@@ -561,7 +1202,7 @@ fn stop_old_jails(
 
     let ls_cmd = format!("ls {}/ | grep '^{}-' || true", JAILS_DIR, config.service);
 
-    if let Ok(ls_out) = remote::run_with_output(host, &ls_cmd) {
+    if let Ok(ls_out) = remote::query(host, &ls_cmd) {
         let existing_jails: Vec<String> = ls_out
             .lines()
             .map(|s| s.trim().to_string())
@@ -571,30 +1212,7 @@ fn stop_old_jails(
         for jname in existing_jails {
             spinner.set_message(format!("[{}] Stopping service in jail {}...", host, jname));
 
-            let safe_service = shell::escape(&config.service);
-            let pid_file = if config.user.is_some() {
-                format!("{}/{}/service.pid", RUN_DIR, safe_service)
-            } else {
-                "/var/run/service.pid".to_string()
-            };
-
-            let stop_cmd = format!(
-                "if [ -f {0} ]; then \
-                    pkill -F {0}; \
-                    count=0; \
-                    while [ -f {0} ] && pkill -0 -F {0} >/dev/null 2>&1; do \
-                        sleep 0.5; \
-                        count=$((count+1)); \
-                        if [ $count -ge 20 ]; then \
-                            pkill -9 -F {0}; \
-                            break; \
-                        fi; \
-                    done; \
-                fi",
-                pid_file
-            );
-
-            let exec_cmd = format!("{}jexec {} sh -c '{}'", cmd_prefix, jname, stop_cmd);
+            let exec_cmd = format!("{}jexec {} sh -c '{}'", cmd_prefix, jname, stop_script(config));
             remote::run(host, &exec_cmd).ok();
         }
     }
@@ -606,14 +1224,13 @@ fn prune_old_jails(
     config: &Config,
     host: &str,
     jail_info: &jail::JailInfo,
-    cmd_prefix: &str,
     spinner: &ProgressBar,
 ) -> Result<()> {
     spinner.set_message(format!("[{}] Pruning old jails...", host));
 
     let ls_cmd = format!("ls {}/ | grep '^{}-' || true", JAILS_DIR, config.service);
 
-    if let Ok(ls_out) = remote::run_with_output(host, &ls_cmd) {
+    if let Ok(ls_out) = remote::query(host, &ls_cmd) {
         let mut jails: Vec<String> = ls_out
             .lines()
             .map(|s| s.trim().to_string())
@@ -635,45 +1252,7 @@ fn prune_old_jails(
                     host, jname
                 ));
 
-                let jpath = format!("{}/{}", JAILS_DIR, jname);
-
-                // Stop jail if running
-                remote::run(host, &format!("{}jail -r {} 2>/dev/null", cmd_prefix, jname)).ok();
-
-                // Cleanup IP alias
-                let info_cmd = format!("jls -j {} ip4.addr 2>/dev/null || echo '-'", jname);
-                if let Ok(jip) = remote::run_with_output(host, &info_cmd) {
-                    let jip = jip.trim();
-                    if jip != "-" && !jip.is_empty() {
-                        remote::run(
-                            host,
-                            &format!("{}ifconfig lo1 inet {} -alias 2>/dev/null", cmd_prefix, jip),
-                        )
-                        .ok();
-                    }
-                }
-
-                // Unmount all under jpath
-                let mount_check = format!("mount | grep '{}' | awk '{{print $3}}'", jpath);
-                if let Ok(mounts) = remote::run_with_output(host, &mount_check) {
-                    for mnt in mounts.lines().rev() {
-                        if !mnt.trim().is_empty() {
-                            remote::run(
-                                host,
-                                &format!("{}umount -f {}", cmd_prefix, mnt.trim()),
-                            )
-                            .ok();
-                        }
-                    }
-                }
-
-                // Remove dir or ZFS dataset
-                if let Ok(Some(dataset)) = remote::get_zfs_dataset(host, &jpath) {
-                    remote::run(host, &format!("{}zfs destroy -r {}", cmd_prefix, dataset)).ok();
-                }
-
-                remote::run(host, &format!("{}chflags -R noschg {}", cmd_prefix, jpath)).ok();
-                remote::run(host, &format!("{}rm -rf {}", cmd_prefix, jpath)).ok();
+                jail::teardown(host, jname, None, config.doas).ok();
             }
         }
     }
@@ -693,6 +1272,8 @@ mod tests {
             ip: "10.0.0.2".to_string(),
             user: Some("deploy".to_string()),
             start_commands: vec!["bin/rails server".to_string()],
+            processes: vec![],
+            syslogd: false,
             env_file: "/etc/bsdeploy.env".to_string(),
             app_dir: "/app".to_string(),
             data_directories: vec![DataDirectoryMapping {
@@ -702,6 +1283,10 @@ mod tests {
             base_version: "14.1-RELEASE".to_string(),
             image_path: Some("/usr/local/bsdeploy/images/abc123".to_string()),
             zfs: true,
+            revision: Some("4f1c2d9e8b7a".to_string()),
+            depends_on: vec!["postgres".to_string()],
+            depends_wait: 60,
+            health_port: Some(3000),
         };
 
         let json = serde_json::to_string_pretty(&metadata).unwrap();
@@ -716,6 +1301,111 @@ mod tests {
         assert!(json.contains(r#""app_dir": "/app""#));
         assert!(json.contains(r#""base_version": "14.1-RELEASE""#));
         assert!(json.contains(r#""zfs": true"#));
+        assert!(json.contains(r#""revision": "4f1c2d9e8b7a""#));
+        assert!(json.contains("\"depends_on\": [\n    \"postgres\"\n  ]"));
+        assert!(json.contains(r#""health_port": 3000"#));
+    }
+
+    #[test]
+    fn test_daemon_command() {
+        let config = Config::from_str("service: myapp\nhosts: [a]\nuser: app\nstart: [bin/web]\n").unwrap();
+        assert_eq!(
+            daemon_command(&config, 0),
+            "daemon -f -p /var/run/bsdeploy/myapp/service.pid -o /var/log/bsdeploy/myapp/service.log -u app \
+             bash -c '{ read -r n _ < /var/run/bsdeploy/myapp/service.starts; } 2>/dev/null; \
+             echo \"$(( ${n:-0} + 1 )) $(date +%s)\" > /var/run/bsdeploy/myapp/service.starts 2>/dev/null; \
+             source /etc/bsdeploy.env && cd /app && bin/web'"
+        );
+
+        let config = Config::from_str(
+            "service: myapp\nhosts: [a]\nuser: app\nlogs: { split_stderr: true }\nstart:\n  - bin/web\n  - { command: bin/worker, log: /var/log/worker.log, error_log: /var/log/worker.err }\n",
+        )
+        .unwrap();
+        let web = daemon_command(&config, 0);
+        assert!(web.starts_with("daemon -f -p /var/run/bsdeploy/myapp/service.pid -u app bash -c '{ read -r n _"));
+        assert!(web.contains("/var/run/bsdeploy/myapp/service.starts 2>/dev/null; w() {"));
+        assert!(web.contains(
+            "exec > >(w /var/log/bsdeploy/myapp/service.log) 2> >(w /var/log/bsdeploy/myapp/service.error.log); source"
+        ));
+        assert!(!web.contains("%z"));
+        assert_eq!(
+            log_paths(&config, 1),
+            ("/var/log/worker.log".to_string(), Some("/var/log/worker.err".to_string()))
+        );
+        assert!(daemon_command(&config, 1).starts_with("daemon -f -p /var/run/bsdeploy/myapp/service-1.pid -u app"));
+
+        let config = Config::from_str(
+            "service: myapp\nhosts: [a]\nstart:\n  - { command: bin/web, restart: always }\n  - { command: bin/worker, restart: on-failure, restart_delay: 5 }\n",
+        )
+        .unwrap();
+        assert!(daemon_command(&config, 0).starts_with(
+            "daemon -f -p /var/run/service.pid -r -P /var/run/daemon-service.pid -o /var/log/service.log bash"
+        ));
+        let worker = daemon_command(&config, 1);
+        assert!(worker.starts_with("daemon -f -p /var/run/service-1.pid -P /var/run/daemon-service-1.pid -o"));
+        assert!(worker.contains(
+            "cd /app && trap \"pkill -P \\$c; kill \\$c 2>/dev/null; exit 143\" TERM; while :; do { bin/worker; } & c=$!; wait $c && exit 0; sleep 5; "
+        ));
+
+        let config = Config::from_str("service: myapp\nhosts: [a]\nlogs: { timestamps: true }\nstart: [bin/web]\n").unwrap();
+        let web = daemon_command(&config, 0);
+        assert!(web.contains("printf \"%(%Y-%m-%dT%H:%M:%S%z)T %s\\n\" -1"));
+        assert!(web.contains("exec > >(w /var/log/service.log) 2>&1; source"));
+    }
+
+    #[test]
+    fn test_syslog() {
+        let config = Config::from_str(
+            "service: myapp\nhosts: [a]\nsyslog: { forward: 'logs.internal:5514', output: true }\nstart: [bin/web, bin/jobs]\n",
+        )
+        .unwrap();
+        assert_eq!(syslog_forward_rule(config.syslog.as_ref().unwrap()), "# Generated by bsdeploy\n*.*\t@logs.internal:5514\n");
+        assert!(daemon_command(&config, 0).contains("-o /var/log/service.log -S -T myapp bash"));
+        assert!(daemon_command(&config, 1).contains("-o /var/log/service-1.log -S -T myapp-1 bash"));
+    }
+
+    #[test]
+    fn test_newsyslog_entries() {
+        let config = Config::from_str(
+            "service: myapp\nhosts: [a]\nuser: app\nlogs:\n  rotate: { size_kb: 10240, keep: 5 }\nstart:\n  - bin/web\n  - { command: bin/worker, error_log: /var/log/worker.err }\n",
+        )
+        .unwrap();
+        assert!(daemon_command(&config, 0).starts_with(
+            "daemon -f -p /var/run/bsdeploy/myapp/service.pid -o /var/log/bsdeploy/myapp/service.log \
+             -H -P /var/run/bsdeploy/myapp/daemon-service.pid -u app "
+        ));
+        let entries = newsyslog_entries(&config, config.logs.rotate.as_ref().unwrap(), Some("1001:1001"));
+        let active = "/usr/local/bsdeploy/active/myapp";
+        assert_eq!(
+            entries.lines().skip(1).collect::<Vec<_>>(),
+            [
+                format!(
+                    "{a}/var/log/bsdeploy/myapp/service.log\t1001:1001\t640\t5\t10240\t*\tZ\t{a}/var/run/bsdeploy/myapp/daemon-service.pid",
+                    a = active
+                ),
+                format!("{}/var/log/bsdeploy/myapp/service-1.log\t1001:1001\t640\t5\t10240\t*\tZN", active),
+                format!("{}/var/log/worker.err\t1001:1001\t640\t5\t10240\t*\tZN", active),
+            ]
+        );
+
+        let config = Config::from_str(
+            "service: myapp\nhosts: [a]\nlogs:\n  rotate: { when: '@T00', compress: none }\nstart: [bin/web]\n",
+        )
+        .unwrap();
+        let entries = newsyslog_entries(&config, config.logs.rotate.as_ref().unwrap(), None);
+        assert!(entries.ends_with(
+            "/usr/local/bsdeploy/active/myapp/var/log/service.log\t640\t7\t*\t@T00\t-\t/usr/local/bsdeploy/active/myapp/var/run/daemon-service.pid\n"
+        ));
+    }
+
+    #[test]
+    fn test_host_newsyslog_entries() {
+        let config = Config::from_str("service: myapp\nhosts: [a]\nuser: app\n").unwrap();
+        assert_eq!(host_newsyslog_path(&config), "/usr/local/etc/newsyslog.conf.d/bsdeploy-myapp.host.conf");
+        assert!(host_newsyslog_entries(&config).ends_with("\n/var/log/bsdeploy/myapp/*.log\tapp:app\t640\t7\t*\t@T00\tGZN\n"));
+
+        let config = Config::from_str("service: myapp\nhosts: [a]\nlogs:\n  rotate: { size_kb: 1024, keep: 3, compress: zstd }\n").unwrap();
+        assert!(host_newsyslog_entries(&config).ends_with("\n/var/log/bsdeploy/myapp/*.log\t640\t3\t1024\t*\tGYN\n"));
     }
 
     #[test]
@@ -726,12 +1416,18 @@ mod tests {
             ip: "10.0.0.2".to_string(),
             user: None,
             start_commands: vec!["bin/server".to_string()],
+            processes: vec![],
+            syslogd: false,
             env_file: "/etc/bsdeploy.env".to_string(),
             app_dir: "/app".to_string(),
             data_directories: vec![],
             base_version: "14.1-RELEASE".to_string(),
             image_path: None,
             zfs: false,
+            revision: None,
+            depends_on: vec![],
+            depends_wait: 60,
+            health_port: None,
         };
 
         let json = serde_json::to_string_pretty(&metadata).unwrap();
@@ -754,12 +1450,18 @@ mod tests {
                 "bin/sidekiq".to_string(),
                 "bin/cable".to_string(),
             ],
+            processes: vec![],
+            syslogd: false,
             env_file: "/etc/bsdeploy.env".to_string(),
             app_dir: "/app".to_string(),
             data_directories: vec![],
             base_version: "14.1-RELEASE".to_string(),
             image_path: None,
             zfs: false,
+            revision: None,
+            depends_on: vec![],
+            depends_wait: 60,
+            health_port: None,
         };
 
         let json = serde_json::to_string_pretty(&metadata).unwrap();
@@ -791,6 +1493,8 @@ mod tests {
             ip: "10.0.0.2".to_string(),
             user: None,
             start_commands: vec![],
+            processes: vec![],
+            syslogd: false,
             env_file: "/etc/bsdeploy.env".to_string(),
             app_dir: "/app".to_string(),
             data_directories: vec![
@@ -806,6 +1510,10 @@ mod tests {
             base_version: "14.1-RELEASE".to_string(),
             image_path: None,
             zfs: false,
+            revision: None,
+            depends_on: vec![],
+            depends_wait: 60,
+            health_port: None,
         };
 
         let json = serde_json::to_string_pretty(&metadata).unwrap();
@@ -816,4 +1524,65 @@ mod tests {
         assert!(json.contains("/var/db/bsdeploy/myapp/uploads"));
         assert!(json.contains("/app/public/uploads"));
     }
+
+    #[test]
+    fn test_app_data_excludes() {
+        let config = Config::from_str(
+            r#"
+service: myapp
+hosts: [a]
+data_directories:
+  - /app/storage
+  - /var/db/uploads
+  - /var/db/myapp/cache: /app/tmp/cache
+  - /application
+"#,
+        )
+        .unwrap();
+        assert_eq!(app_data_excludes(&config), ["/storage", "/tmp/cache"]);
+    }
+
+    #[test]
+    fn test_with_agent_in_jail() {
+        let jail_info = jail::JailInfo {
+            name: "app-1".into(),
+            path: "/usr/local/bsdeploy/jails/app-1".into(),
+            ip: "10.0.0.2".into(),
+            zfs: false,
+        };
+        let cmd = with_agent_in_jail("jexec app-1 true", &jail_info, Some("deploy"), true);
+        assert!(cmd.contains("doas mount_nullfs \"$(dirname \"$SSH_AUTH_SOCK\")\" /usr/local/bsdeploy/jails/app-1/var/run/bsdeploy-agent"));
+        assert!(cmd.contains("doas chown \"$(doas jexec app-1 id -u deploy)\" \"$SSH_AUTH_SOCK\""));
+        assert!(cmd.contains("doas chown \"$owner\" \"$SSH_AUTH_SOCK\""));
+        let cmd = with_agent_in_jail("jexec app-1 true", &jail_info, None, false);
+        assert!(!cmd.contains("chown"));
+    }
+
+    #[test]
+    fn test_before_start_command() {
+        let config = Config::from_str(
+            "service: app\nhosts: [web1]\nuser: deploy\nssh:\n  forward_agent: true\n  send_env: [GITHUB_TOKEN]\nbefore_start: [bundle install]\n",
+        )
+        .unwrap();
+        // The values reach su's shell as arguments, so quotes in them cannot break the command
+        assert_eq!(
+            before_start_command(&config, "app-1", "doas ", "bundle install"),
+            "doas jexec app-1 su - deploy -c \"export GITHUB_TOKEN=\\\"\\${1}\\\" && export SSH_AUTH_SOCK=\\\"\\${2}\\\" && \
+             bash -c 'source /etc/bsdeploy.env && cd /app && bundle install'\" sh \"$GITHUB_TOKEN\" \"$agent_sock\""
+        );
+
+        let config = Config::from_str("service: app\nhosts: [web1]\nuser: deploy\n").unwrap();
+        assert_eq!(
+            before_start_command(&config, "app-1", "", "true"),
+            "jexec app-1 su - deploy -c \"bash -c 'source /etc/bsdeploy.env && cd /app && true'\""
+        );
+    }
+
+    #[test]
+    fn test_health_port() {
+        let port = |extra: &str| health_port(&Config::from_str(&format!("service: app\nhosts: [a]\n{}", extra)).unwrap());
+        assert_eq!(port("proxy: { hostname: app.example.com, port: 3000 }\n"), Some(3000));
+        assert_eq!(port("proxy: false\nbind: { ports: [5432, 5433] }\n"), Some(5432));
+        assert_eq!(port("proxy: false\nbind: { udp_ports: [53] }\n"), None);
+    }
 }
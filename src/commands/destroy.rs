@@ -1,10 +1,12 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 
 use crate::config::Config;
 use crate::constants::*;
-use crate::{remote, ui};
+use crate::{helper, jail, pf, proxy, rcd, remote, shell, ui};
 
-pub fn run(config: &Config) -> Result<()> {
+/// Remove the service from every host. `purge_proxy` also deletes the
+/// proxy logs and disables the proxy on hosts with no other site.
+pub fn run(config: &Config, purge_proxy: bool) -> Result<()> {
     ui::print_step(&format!(
         "Destroying all resources for service {} on {} hosts",
         config.service,
@@ -14,7 +16,7 @@ pub fn run(config: &Config) -> Result<()> {
     for host in &config.hosts {
         let spinner = ui::create_spinner(&format!("Destroying resources on {}", host));
 
-        destroy_host(config, host, &spinner)?;
+        destroy_host(config, host, purge_proxy, &spinner)?;
 
         spinner.finish_with_message(format!("Resources destroyed for {}", host));
         ui::print_success(&format!("{} resources cleaned up", host));
@@ -23,17 +25,44 @@ pub fn run(config: &Config) -> Result<()> {
     Ok(())
 }
 
-fn destroy_host(config: &Config, host: &str, spinner: &indicatif::ProgressBar) -> Result<()> {
-    let cmd_prefix = if config.doas { "doas " } else { "" };
+fn destroy_host(config: &Config, host: &str, purge_proxy: bool, spinner: &indicatif::ProgressBar) -> Result<()> {
+    let cmd_prefix = shell::escalation_prefix(config.doas);
 
     // 1. Find and remove jails
-    remove_jails(config, host, cmd_prefix, spinner)?;
+    remove_jails(config, host, spinner)?;
 
     // 2. Remove active symlink
     remove_active_symlink(config, host, cmd_prefix, spinner)?;
 
-    // 3. Remove Caddy proxy config
-    remove_proxy_config(config, host, cmd_prefix, spinner)?;
+    // 3. Remove proxy config
+    spinner.set_message(format!("[{}] Removing proxy configuration...", host));
+    proxy::remove(config, host, purge_proxy);
+    if config.bind.is_some() {
+        pf::remove(config, host);
+    }
+
+    // 4. Remove log rotation
+    remote::run(
+        host,
+        &format!(
+            "{}rm -f {} {}",
+            cmd_prefix,
+            super::deploy::newsyslog_path(config),
+            super::deploy::host_newsyslog_path(config)
+        ),
+    )
+    .ok();
+
+    // 5. Remove staged application
+    spinner.set_message(format!("[{}] Removing staged application...", host));
+    let staging_path = format!("{}/{}", STAGING_DIR, config.service);
+    remote::run(host, &format!("{}rm -rf {}", cmd_prefix, staging_path)).ok();
+
+    // 6. Forget the applied configuration
+    remote::run(host, &format!("{}rm -f {}", cmd_prefix, super::applied::state_path(config))).ok();
+
+    // 7. Remove the service's rc.d script
+    rcd::remove_service_script(config, host);
 
     Ok(())
 }
@@ -41,59 +70,26 @@ fn destroy_host(config: &Config, host: &str, spinner: &indicatif::ProgressBar) -
 fn remove_jails(
     config: &Config,
     host: &str,
-    cmd_prefix: &str,
     spinner: &indicatif::ProgressBar,
 ) -> Result<()> {
     spinner.set_message(format!("[{}] Removing jails and networking...", host));
 
-    let ls_cmd = format!("ls {}/ | grep '^{}-' || true", JAILS_DIR, config.service);
-
-    if let Ok(ls_out) = remote::run_with_output(host, &ls_cmd) {
-        for jname in ls_out
-            .lines()
-            .map(|s| s.trim())
-            .filter(|s| !s.is_empty())
-        {
-            spinner.set_message(format!("[{}] Cleaning up jail {}...", host, jname));
-
-            let jpath = format!("{}/{}", JAILS_DIR, jname);
-
-            // Get IP before stopping
-            let info_cmd = format!("jls -j {} ip4.addr 2>/dev/null || echo '-'", jname);
-            if let Ok(jip) = remote::run_with_output(host, &info_cmd) {
-                let jip = jip.trim();
-
-                // Stop jail
-                remote::run(host, &format!("{}jail -r {} 2>/dev/null", cmd_prefix, jname)).ok();
-
-                // Remove IP alias
-                if jip != "-" && !jip.is_empty() {
-                    remote::run(
-                        host,
-                        &format!("{}ifconfig lo1 inet {} -alias 2>/dev/null", cmd_prefix, jip),
-                    )
-                    .ok();
-                }
-            }
-
-            // Unmount everything under jpath
-            let mount_check = format!("mount | grep '{}' | awk '{{print $3}}'", jpath);
-            if let Ok(mounts) = remote::run_with_output(host, &mount_check) {
-                for mnt in mounts.lines().rev() {
-                    if !mnt.trim().is_empty() {
-                        remote::run(host, &format!("{}umount -f {}", cmd_prefix, mnt.trim())).ok();
-                    }
-                }
-            }
-
-            // Remove jail dir or ZFS dataset
-            if let Ok(Some(dataset)) = remote::get_zfs_dataset(host, &jpath) {
-                remote::run(host, &format!("{}zfs destroy -r {}", cmd_prefix, dataset)).ok();
-            }
-
-            remote::run(host, &format!("{}chflags -R noschg {}", cmd_prefix, jpath)).ok();
-            remote::run(host, &format!("{}rm -rf {}", cmd_prefix, jpath)).ok();
-        }
+    // Teardown goes through the helper when it is enabled
+    if helper::is_enabled() {
+        helper::ensure_installed(host, config.doas)?;
+    }
+
+    let ls_cmd = format!("ls {}/ 2>/dev/null | grep '^{}-' || true", JAILS_DIR, config.service);
+    let ls_out = remote::query(host, &ls_cmd)?;
+    for jname in ls_out
+        .lines()
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+    {
+        spinner.set_message(format!("[{}] Cleaning up jail {}...", host, jname));
+
+        jail::teardown(host, jname, None, config.doas)
+            .with_context(|| format!("Failed to remove jail {} on {}", jname, host))?;
     }
 
     Ok(())
@@ -113,17 +109,3 @@ fn remove_active_symlink(
     Ok(())
 }
 
-fn remove_proxy_config(
-    config: &Config,
-    host: &str,
-    cmd_prefix: &str,
-    spinner: &indicatif::ProgressBar,
-) -> Result<()> {
-    spinner.set_message(format!("[{}] Removing proxy configuration...", host));
-
-    let caddy_conf = format!("{}/{}.caddy", CADDY_CONF_DIR, config.service);
-    remote::run(host, &format!("{}rm -f {}", cmd_prefix, caddy_conf)).ok();
-    remote::run(host, &format!("{}service caddy reload", cmd_prefix)).ok();
-
-    Ok(())
-}
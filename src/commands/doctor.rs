@@ -0,0 +1,469 @@
+use anyhow::{Result, bail};
+use std::net::Ipv4Addr;
+use std::process::{Command, Stdio};
+
+use crate::config::{Config, ProxyServer, SshTransport, SyncStrategy};
+use crate::constants::*;
+use crate::{os, preflight, proxy, rcd, remote, shell, ui};
+
+use super::host_manifest::{self, HostManifest};
+use super::parallel_hosts;
+
+/// Free space under `BSDEPLOY_BASE` below which a first deploy (base
+/// system and image) may not fit
+const LOW_DISK_KB: u64 = 4 << 20;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Outcome {
+    Pass,
+    Warn,
+    Fail,
+}
+
+/// Result of one check, with how to fix it when it did not pass
+#[derive(Debug, PartialEq)]
+struct Check {
+    name: &'static str,
+    outcome: Outcome,
+    detail: String,
+    hint: Option<String>,
+}
+
+impl Check {
+    fn pass(name: &'static str, detail: impl Into<String>) -> Self {
+        Self { name, outcome: Outcome::Pass, detail: detail.into(), hint: None }
+    }
+
+    fn warn(name: &'static str, detail: impl Into<String>, hint: impl Into<String>) -> Self {
+        Self { name, outcome: Outcome::Warn, detail: detail.into(), hint: Some(hint.into()) }
+    }
+
+    fn fail(name: &'static str, detail: impl Into<String>, hint: impl Into<String>) -> Self {
+        Self { name, outcome: Outcome::Fail, detail: detail.into(), hint: Some(hint.into()) }
+    }
+}
+
+pub fn run(config: &Config) -> Result<()> {
+    ui::print_step(&format!("Checking prerequisites for {} on {} hosts", config.service, config.hosts.len()));
+
+    ui::print_output("");
+    ui::print_output("Local");
+    let mut failed = print_checks(&local_checks(config));
+
+    let spinner = ui::create_spinner(&format!("Checking {} hosts", config.hosts.len()));
+    let script = host_script(config);
+    let results = parallel_hosts(&config.hosts, config.parallelism, |host| {
+        remote::run_with_output(host, &script).map(|output| HostReport::parse(&output))
+    });
+    spinner.finish_and_clear();
+
+    for (host, result) in config.hosts.iter().zip(results) {
+        ui::print_output("");
+        ui::print_output(&format!("Host: {}", host));
+        let checks = match result {
+            Ok(report) => host_checks(config, host, &report),
+            Err(e) => vec![Check::fail(
+                "connection",
+                format!("{:#}", e),
+                remote::hint(&e).unwrap_or_else(|| format!("check that `ssh {}` works", host)),
+            )],
+        };
+        failed += print_checks(&checks);
+    }
+
+    ui::print_output("");
+    if failed > 0 {
+        bail!("{} checks failed", failed);
+    }
+    ui::print_success("All checks passed");
+    Ok(())
+}
+
+/// Print `checks`, returning how many failed.
+fn print_checks(checks: &[Check]) -> usize {
+    for check in checks {
+        let line = format!("  {}: {}", check.name, check.detail);
+        match check.outcome {
+            Outcome::Pass => ui::print_success(&line),
+            Outcome::Warn => ui::print_warning(&line),
+            Outcome::Fail => ui::print_error(&line),
+        }
+        if let Some(hint) = &check.hint {
+            ui::print_hint(&format!("  {}", hint));
+        }
+    }
+    checks.iter().filter(|c| c.outcome == Outcome::Fail).count()
+}
+
+fn local_tool(tool: &str, version_arg: &str) -> bool {
+    Command::new(tool)
+        .arg(version_arg)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok()
+}
+
+fn local_checks(config: &Config) -> Vec<Check> {
+    let mut checks = Vec::new();
+
+    let remote_hosts = config.hosts.iter().any(|h| !remote::is_local(h));
+    let rsync = config.sync.strategy == SyncStrategy::Rsync && config.sync.artifact.is_none();
+    if remote_hosts && config.ssh.transport != SshTransport::Native {
+        checks.push(match local_tool("ssh", "-V") {
+            true => Check::pass("ssh", "found"),
+            false => Check::fail("ssh", "not found", "install OpenSSH, or set ssh.transport: native"),
+        });
+    } else if remote_hosts && rsync {
+        // rsync runs the ssh binary even with the native transport
+        checks.push(match local_tool("ssh", "-V") {
+            true => Check::pass("ssh", "found (for rsync)"),
+            false => Check::fail("ssh", "not found", "install OpenSSH, or set sync.strategy: git"),
+        });
+    }
+    if rsync {
+        checks.push(match local_tool("rsync", "--version") {
+            true => Check::pass("rsync", "found"),
+            false => Check::fail("rsync", "not found", "install rsync, or set sync.strategy: git"),
+        });
+    }
+    if config.metrics.as_ref().is_some_and(|m| m.pushgateway.is_some()) {
+        checks.push(match local_tool("curl", "--version") {
+            true => Check::pass("curl", "found"),
+            false => Check::fail("curl", "not found", "install curl to push metrics.pushgateway"),
+        });
+    }
+
+    let ssl = config.proxy.as_ref().and_then(|p| p.ssl.as_ref());
+    let secrets: Vec<&str> = config
+        .env
+        .secret
+        .iter()
+        .map(String::as_str)
+        .chain(ssl.into_iter().flat_map(|s| [s.certificate_pem.as_str(), s.private_key_pem.as_str()]))
+        .collect();
+    checks.push(secrets_check(&secrets, |name| std::env::var(name).is_ok_and(|v| !v.is_empty())));
+    checks
+}
+
+fn secrets_check(secrets: &[&str], is_set: impl Fn(&str) -> bool) -> Check {
+    let missing: Vec<&str> = secrets.iter().copied().filter(|s| !is_set(s)).collect();
+    if missing.is_empty() {
+        Check::pass("secrets", format!("{} set", secrets.len()))
+    } else {
+        Check::fail(
+            "secrets",
+            format!("not set: {}", missing.join(", ")),
+            "export them in the environment bsdeploy runs in",
+        )
+    }
+}
+
+/// Marker of the lines `host_script` reports on
+const REPORT: &str = "DOCTOR:";
+
+/// Script collecting everything the host checks need, as `DOCTOR:` lines,
+/// followed by the operating system and the preflight problems.
+fn host_script(config: &Config) -> String {
+    let extra_tools: &[&str] = match (&config.sync.strategy, &config.sync.artifact) {
+        (SyncStrategy::Rsync, None) => &["rsync"],
+        _ => &[],
+    };
+    let ports = config.proxy.as_ref().map_or((80, 443), |p| p.listen_ports());
+    let mut script = format!(
+        "d={base}; while [ ! -e \"$d\" ]; do d=$(dirname \"$d\"); done\n\
+         echo \"{r} free $(df -k \"$d\" | awk 'NR==2 {{print $4}}')\"\n\
+         echo \"{r} fs $(df \"$d\" | awk 'NR==2 {{print $1}}') $(kldstat -q -m zfs && echo zfs)\"\n\
+         ifconfig lo1 >/dev/null 2>&1 && echo \"{r} lo1 $(ifconfig lo1 | head -1)\"\n\
+         ifconfig -a inet 2>/dev/null | awk '/^[^ \\t]/ {{i=$1; sub(\":\", \"\", i)}} $1 == \"inet\" {{print \"{r} inet\", i, $2}}'\n\
+         sockstat -46l -p {http},{https} 2>/dev/null | awk 'NR>1 {{print \"{r} listen\", $2, $6}}'\n\
+         for d in {base_dir} {images} {jails} {active}; do test -d $d || echo \"{r} missing $d\"; done\n\
+         l={active}/{service}; if [ -L $l ]; then if [ -e $l/ ]; then echo \"{r} active $(readlink $l)\"; else echo \"{r} dangling $(readlink $l)\"; fi; fi\n\
+         for p in {images}/.partial-*; do [ -e \"$p\" ] && echo \"{r} partial $p\"; done\n\
+         echo \"{r} rcd $({versions} | tr '\\n' ' ')\"\n\
+         echo \"{r} manifest $(cat {manifest} 2>/dev/null | tr -d '\\n')\"\n",
+        r = REPORT,
+        versions = rcd::versions_command(config),
+        manifest = HOST_MANIFEST_PATH,
+        base = BSDEPLOY_BASE,
+        base_dir = BASE_DIR,
+        images = IMAGES_DIR,
+        jails = JAILS_DIR,
+        active = ACTIVE_DIR,
+        service = shell::escape(&config.service),
+        http = ports.0,
+        https = ports.1,
+    );
+    script.push_str(&preflight::script(config.doas, extra_tools));
+    script.push_str("true\n");
+    script
+}
+
+/// What `host_script` found on a host
+#[derive(Debug, Default, PartialEq)]
+struct HostReport {
+    os: Option<os::HostOs>,
+    free_kb: Option<u64>,
+    /// Filesystem of `BSDEPLOY_BASE` (a dataset name on ZFS)
+    filesystem: String,
+    zfs_loaded: bool,
+    /// First line of `ifconfig lo1`, if it exists
+    lo1: Option<String>,
+    /// `(interface, address)` of every IPv4 address
+    addresses: Vec<(String, String)>,
+    /// `(command, local address)` of the sockets listening on the proxy ports
+    listeners: Vec<(String, String)>,
+    missing_dirs: Vec<String>,
+    active: Option<String>,
+    dangling: Option<String>,
+    partial_images: Vec<String>,
+    /// Installed rc.d script versions, see `rcd::versions_command`
+    rcd_versions: Vec<String>,
+    /// What setup recorded, see `host_manifest`
+    manifest: Option<HostManifest>,
+    problems: Vec<String>,
+}
+
+impl HostReport {
+    fn parse(output: &str) -> Self {
+        let mut report = Self {
+            os: os::HostOs::from_output(output),
+            problems: preflight::parse_problems(output),
+            ..Self::default()
+        };
+        for line in output.lines().filter_map(|l| l.strip_prefix(REPORT)) {
+            let mut fields = line.split_whitespace();
+            let (Some(kind), rest) = (fields.next(), fields.collect::<Vec<_>>()) else {
+                continue;
+            };
+            let field = |i: usize| rest.get(i).map(|s| s.to_string()).unwrap_or_default();
+            match kind {
+                "free" => report.free_kb = rest.first().and_then(|kb| kb.parse().ok()),
+                "fs" => (report.filesystem, report.zfs_loaded) = (field(0), field(1) == "zfs"),
+                "lo1" => report.lo1 = Some(rest.join(" ")),
+                "inet" => report.addresses.push((field(0), field(1))),
+                "listen" => report.listeners.push((field(0), field(1))),
+                "missing" => report.missing_dirs.push(field(0)),
+                "active" => report.active = Some(field(0)),
+                "dangling" => report.dangling = Some(field(0)),
+                "partial" => report.partial_images.push(field(0)),
+                "rcd" => report.rcd_versions = rest.iter().map(|v| v.to_string()).collect(),
+                "manifest" => report.manifest = HostManifest::parse(&rest.join(" ")),
+                _ => {}
+            }
+        }
+        report
+    }
+}
+
+/// Whether `ip` lies in the CIDR `range`.
+fn in_range(ip: &str, range: &str) -> bool {
+    let (Ok(ip), Some((net, bits))) = (ip.parse::<Ipv4Addr>(), range.split_once('/')) else {
+        return false;
+    };
+    let (Ok(net), Ok(bits)) = (net.parse::<Ipv4Addr>(), bits.parse::<u32>()) else {
+        return false;
+    };
+    let mask = u32::MAX.checked_shl(32 - bits.min(32)).unwrap_or(0);
+    u32::from(ip) & mask == u32::from(net) & mask
+}
+
+fn host_checks(config: &Config, host: &str, report: &HostReport) -> Vec<Check> {
+    let mut checks = Vec::new();
+
+    let supported = "bsdeploy manages FreeBSD and HardenedBSD 13, 14 and 15 hosts";
+    checks.push(match &report.os {
+        Some(os) => match os.check() {
+            Ok(()) => Check::pass("os", os.describe()),
+            Err(e) => Check::fail("os", e.to_string(), supported),
+        },
+        None => Check::fail("os", "could not tell the operating system", supported),
+    });
+
+    checks.push(match report.problems.is_empty() {
+        true => Check::pass("prerequisites", "privilege escalation, tools and paths"),
+        false => Check::fail(
+            "prerequisites",
+            report.problems.join("; "),
+            "fix the listed problems; `bsdeploy setup` installs the missing packages",
+        ),
+    });
+
+    let zfs = !report.filesystem.is_empty() && !report.filesystem.starts_with('/');
+    checks.push(match (zfs, report.zfs_loaded) {
+        (true, _) => Check::pass("zfs", format!("{} on dataset {}", BSDEPLOY_BASE, report.filesystem)),
+        (false, true) => Check::warn(
+            "zfs",
+            format!("{} is not on a ZFS dataset", BSDEPLOY_BASE),
+            format!("create a dataset mounted at {} to clone images instead of copying them", BSDEPLOY_BASE),
+        ),
+        (false, false) => Check::warn("zfs", "not available", "jails and images are copied instead of cloned"),
+    });
+
+    checks.push(match report.free_kb {
+        Some(kb) if kb < LOW_DISK_KB => Check::warn(
+            "disk",
+            format!("{} MiB free under {}", kb >> 10, BSDEPLOY_BASE),
+            "a new base system and image need about 3.5 GiB; free up space on the host",
+        ),
+        Some(kb) => Check::pass("disk", format!("{} MiB free under {}", kb >> 10, BSDEPLOY_BASE)),
+        None => Check::warn("disk", "could not read the free space", format!("check `df -k {}`", BSDEPLOY_BASE)),
+    });
+
+    let range = config.jail_ip_range(host);
+    let conflicts: Vec<String> = report
+        .addresses
+        .iter()
+        .filter(|(iface, ip)| iface != "lo1" && in_range(ip, range))
+        .map(|(iface, ip)| format!("{} on {}", ip, iface))
+        .collect();
+    checks.push(match &report.lo1 {
+        Some(flags) if !flags.contains("LOOPBACK") => Check::fail(
+            "lo1",
+            "exists but is not a loopback interface",
+            "rename the interface; bsdeploy puts the jail addresses on lo1",
+        ),
+        _ if !conflicts.is_empty() => Check::fail(
+            "lo1",
+            format!("jail network {} overlaps {}", range, conflicts.join(", ")),
+            "choose another jail.ip_range (or jail.host_ip_ranges for this host)",
+        ),
+        Some(_) => Check::pass("lo1", format!("jail network {}", range)),
+        None => Check::pass("lo1", format!("not created yet, jail network {}", range)),
+    });
+
+    if let Some(proxy_config) = &config.proxy
+        && proxy::hosts(config).iter().any(|h| h == host)
+    {
+        let server = match proxy_config.server {
+            ProxyServer::Caddy => "caddy",
+            ProxyServer::Relayd => "relayd",
+        };
+        let mut taken: Vec<String> = report
+            .listeners
+            .iter()
+            .filter(|(command, _)| command != server)
+            .map(|(command, address)| format!("{} by {}", address, command))
+            .collect();
+        taken.dedup();
+        checks.push(match taken.is_empty() {
+            true => {
+                let (http, https) = proxy_config.listen_ports();
+                Check::pass("ports", format!("{} and {} free for {}", http, https, server))
+            }
+            false => Check::fail(
+                "ports",
+                format!("in use: {}", taken.join(", ")),
+                format!("stop the other server, or disable it with `sysrc <name>_enable=NO`, so {} can bind", server),
+            ),
+        });
+    }
+
+    checks.push(if let Some(target) = &report.dangling {
+        Check::fail(
+            "layout",
+            format!("{}/{} points to missing {}", ACTIVE_DIR, config.service, target),
+            "redeploy with `bsdeploy deploy`, or remove the link",
+        )
+    } else if !report.missing_dirs.is_empty() {
+        Check::warn("layout", format!("missing {}", report.missing_dirs.join(", ")), "run `bsdeploy setup`")
+    } else if !report.partial_images.is_empty() {
+        Check::warn(
+            "layout",
+            format!("interrupted image builds: {}", report.partial_images.join(", ")),
+            "the next build of the same image removes them",
+        )
+    } else {
+        match &report.active {
+            Some(jail) => Check::pass("layout", format!("active jail {}", jail)),
+            None => Check::pass("layout", "not deployed yet"),
+        }
+    });
+
+    if let Some(host_version) = report.rcd_versions.first() {
+        checks.push(match rcd::outdated(&report.rcd_versions.join("\n")) {
+            Some(old) => Check::warn(
+                "rc.d",
+                format!("{} boot scripts, bsdeploy has version {}", rcd::describe_version(&old), rcd::RCD_VERSION),
+                "run `bsdeploy setup` or deploy to update them",
+            ),
+            None if host_version == "missing" => Check::warn("rc.d", "boot scripts not installed", "run `bsdeploy setup`"),
+            None => Check::pass("rc.d", format!("boot scripts version {}", rcd::RCD_VERSION)),
+        });
+    }
+
+    checks.push(match &report.manifest {
+        Some(manifest) => match manifest.skew() {
+            Some(skew) if skew.fatal => Check::fail("manifest", skew.detail, skew.hint),
+            Some(skew) => Check::warn("manifest", skew.detail, skew.hint),
+            None => Check::pass(
+                "manifest",
+                format!("set up by bsdeploy {} (layout version {})", host_manifest::VERSION, LAYOUT_VERSION),
+            ),
+        },
+        None => Check::warn("manifest", "not recorded", "run `bsdeploy setup` to record what the host is set up with"),
+    });
+
+    checks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_host_report() {
+        let output = "OS: FreeBSD 14.2-RELEASE-p1 amd64 amd64 -\n\
+                      DOCTOR: free 8388608\n\
+                      DOCTOR: fs zroot/bsdeploy zfs\n\
+                      DOCTOR: lo1 lo1: flags=8049<UP,LOOPBACK,RUNNING,MULTICAST> metric 0 mtu 16384\n\
+                      DOCTOR: inet vtnet0 192.0.2.10\n\
+                      DOCTOR: inet vtnet1 10.0.0.1\n\
+                      DOCTOR: inet lo1 10.0.0.5\n\
+                      DOCTOR: listen nginx *:80\n\
+                      DOCTOR: listen caddy *:443\n\
+                      DOCTOR: dangling /usr/local/bsdeploy/jails/app-20260101\n\
+                      DOCTOR: rcd 0 missing \n\
+                      PREFLIGHT: required command not found: jail\n";
+        let report = HostReport::parse(output);
+        assert_eq!(report.os.as_ref().map(|os| os.release.as_str()), Some("14.2-RELEASE-p1"));
+        assert_eq!(report.free_kb, Some(8 << 20));
+        assert!(report.zfs_loaded);
+        assert_eq!(report.problems, ["required command not found: jail"]);
+
+        let config = Config::from_str("service: app\nhosts: [web1]\nproxy: { hostname: app.example.com, port: 3000 }\n").unwrap();
+        let checks = host_checks(&config, "web1", &report);
+        let outcome = |name: &str| checks.iter().find(|c| c.name == name).map(|c| (c.outcome, c.detail.as_str()));
+        assert_eq!(outcome("os"), Some((Outcome::Pass, "FreeBSD 14.2-RELEASE-p1 (amd64)")));
+        assert_eq!(outcome("prerequisites").unwrap().0, Outcome::Fail);
+        assert_eq!(outcome("zfs").unwrap().0, Outcome::Pass);
+        assert_eq!(outcome("lo1"), Some((Outcome::Fail, "jail network 10.0.0.0/24 overlaps 10.0.0.1 on vtnet1")));
+        assert_eq!(outcome("ports"), Some((Outcome::Fail, "in use: *:80 by nginx")));
+        assert_eq!(outcome("layout").unwrap().0, Outcome::Fail);
+        assert_eq!(outcome("rc.d").unwrap(), (Outcome::Warn, format!("unversioned boot scripts, bsdeploy has version {}", rcd::RCD_VERSION).as_str()));
+        assert_eq!(outcome("manifest"), Some((Outcome::Warn, "not recorded")));
+
+        let report = HostReport::parse(&format!(
+            "DOCTOR: manifest {{  \"bsdeploy_version\": \"0.0.1\",  \"layout_version\": {},  \"rcd_version\": \"3\",  \"set_up_at\": \"\",  \"packages\": {{}}}}\n",
+            LAYOUT_VERSION
+        ));
+        let checks = host_checks(&config, "web1", &report);
+        let manifest = checks.iter().find(|c| c.name == "manifest").unwrap();
+        assert_eq!(manifest.outcome, Outcome::Warn);
+        assert_eq!(manifest.detail, format!("set up by bsdeploy 0.0.1, this is {}", host_manifest::VERSION));
+    }
+
+    #[test]
+    fn test_in_range() {
+        assert!(in_range("10.0.0.7", "10.0.0.0/24"));
+        assert!(!in_range("10.0.1.7", "10.0.0.0/24"));
+        assert!(in_range("10.9.9.9", "0.0.0.0/0"));
+        assert!(!in_range("fe80::1", "10.0.0.0/24"));
+    }
+
+    #[test]
+    fn test_secrets_check() {
+        assert_eq!(secrets_check(&["A", "B"], |s| s == "A").detail, "not set: B");
+        assert_eq!(secrets_check(&[], |_| false).outcome, Outcome::Pass);
+    }
+}
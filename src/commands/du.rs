@@ -0,0 +1,281 @@
+use anyhow::Result;
+use std::collections::HashSet;
+
+use crate::config::Config;
+use crate::constants::*;
+use crate::{jail, remote, shell, ui};
+
+use super::parallel_hosts;
+use super::status::format_size;
+
+pub fn run(config: &Config) -> Result<()> {
+    ui::print_step(&format!("Storage of {} hosts", config.hosts.len()));
+
+    let script = du_script(config);
+    let results = parallel_hosts(&config.hosts, config.parallelism, |host| {
+        let usage = Usage::parse(&remote::run_with_output_timeout(host, &script, remote::Timeout::Command)?);
+        // Bases are also referenced through the origins of image datasets
+        let bases = jail::referenced_base_versions(host)?;
+        Ok((usage, bases))
+    });
+
+    for (host, result) in config.hosts.iter().zip(results) {
+        let (usage, bases) = result?;
+        ui::print_output("");
+        ui::print_output(&format!("Host: {}", host));
+        ui::print_output(&"─".repeat(60));
+        print_usage(config, &usage, &bases);
+    }
+    ui::print_output("");
+    Ok(())
+}
+
+/// Report `<kind> <path> <bytes>` for every directory, the image each jail
+/// was created from, running and active jails and the free space.
+fn du_script(config: &Config) -> String {
+    let p = shell::escalation_prefix(config.doas);
+    let data: Vec<String> = config
+        .data_directories
+        .iter()
+        .map(|d| d.get_paths().0)
+        .filter(|path| !path.is_empty())
+        .map(|path| format!("[ -d {p} ] && usage data {p}\n", p = shell::escape(&path)))
+        .collect();
+    format!(
+        "usage() {{ z=$(zfs list -Hp -o mountpoint,used \"$2\" 2>/dev/null | tail -n 1); set -- \"$1\" \"$2\" $z; \
+         if [ \"$3\" = \"$2\" ]; then echo \"$1 $2 $4\"; \
+         else k=$({p}du -sk \"$2\" 2>/dev/null | cut -f1); echo \"$1 $2 $(( ${{k:-0}} * 1024 ))\"; fi; }}\n\
+         for d in {jails}/*; do [ -d \"$d\" ] && usage jail \"$d\"; done\n\
+         for d in {images}/* {images}/.partial-*; do [ -d \"$d\" ] && [ \"$d\" != {layers} ] && usage image \"$d\"; done\n\
+         [ -d {layers} ] && usage layers {layers}\n\
+         for d in {bases}/*; do [ -d \"$d\" ] && usage base \"$d\"; done\n\
+         {data}\
+         for d in {jails}/{service}-*/var/log; do [ -d \"$d\" ] && usage logs \"$d\"; done\n\
+         for f in {caddy_logs}/{service}.log*; do [ -f \"$f\" ] && usage logs \"$f\"; done\n\
+         [ -d {staging}/{service} ] && usage staging {staging}/{service}\n\
+         for f in {jails}/*/.bsdeploy.json; do [ -f \"$f\" ] && echo \"uses ${{f%/.bsdeploy.json}} \
+         $(sed -n 's/.*\"image_path\": *\"\\([^\"]*\\)\".*/\\1/p' \"$f\")\"; done\n\
+         jls -N name 2>/dev/null | sed 's/^/running /'\n\
+         for l in {active}/*; do [ -L \"$l\" ] && echo \"active $(readlink \"$l\")\"; done\n\
+         df -k {root} 2>/dev/null | awk 'NR==2 {{print \"free\", $4}}'\n\
+         true\n",
+        p = p,
+        jails = JAILS_DIR,
+        images = IMAGES_DIR,
+        layers = IMAGE_LAYERS_DIR,
+        bases = BASE_DIR,
+        data = data.concat(),
+        service = config.service,
+        caddy_logs = CADDY_LOG_DIR,
+        staging = STAGING_DIR,
+        active = ACTIVE_DIR,
+        root = BSDEPLOY_BASE,
+    )
+}
+
+#[derive(Debug, PartialEq)]
+struct Item {
+    path: String,
+    used: u64,
+}
+
+impl Item {
+    fn name(&self) -> &str {
+        self.path.rsplit('/').next().unwrap_or(&self.path)
+    }
+}
+
+/// What `du_script` found on a host
+#[derive(Debug, Default, PartialEq)]
+struct Usage {
+    jails: Vec<Item>,
+    images: Vec<Item>,
+    layers: Option<u64>,
+    bases: Vec<Item>,
+    data: Vec<Item>,
+    logs: Vec<Item>,
+    staging: Option<u64>,
+    /// `(jail path, image path)` from the metadata of every jail
+    image_users: Vec<(String, String)>,
+    running: Vec<String>,
+    /// Targets of the active symlinks of all services
+    active: Vec<String>,
+    free: Option<u64>,
+}
+
+fn total(items: &[Item]) -> u64 {
+    items.iter().map(|i| i.used).sum()
+}
+
+impl Usage {
+    fn parse(output: &str) -> Self {
+        let mut usage = Self::default();
+        for line in output.lines() {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields.as_slice() {
+                ["uses", jail, image] => usage.image_users.push((jail.to_string(), image.to_string())),
+                ["running", name] => usage.running.push(name.to_string()),
+                ["active", target] => usage.active.push(target.to_string()),
+                ["free", kb] => usage.free = kb.parse::<u64>().ok().map(|kb| kb * 1024),
+                [kind, path, used] => {
+                    let Ok(used) = used.parse() else { continue };
+                    let item = Item { path: path.to_string(), used };
+                    match *kind {
+                        "jail" => usage.jails.push(item),
+                        "image" => usage.images.push(item),
+                        "layers" => usage.layers = Some(used),
+                        "base" => usage.bases.push(item),
+                        "data" => usage.data.push(item),
+                        "logs" => usage.logs.push(item),
+                        "staging" => usage.staging = Some(used),
+                        _ => {}
+                    }
+                }
+                _ => {}
+            }
+        }
+        usage
+    }
+
+    /// Jails created from `image`.
+    fn users(&self, image: &Item) -> Vec<&str> {
+        self.image_users
+            .iter()
+            .filter(|(_, path)| *path == image.path)
+            .filter_map(|(jail, _)| jail.rsplit('/').next())
+            .collect()
+    }
+
+    /// Images no jail was created from.
+    fn unreferenced_images(&self) -> Vec<&Item> {
+        self.images
+            .iter()
+            .filter(|i| !i.name().starts_with(".partial-") && self.users(i).is_empty())
+            .collect()
+    }
+
+    /// Stopped jails of `service` other than the active one: the rollback
+    /// copies a cleanup would remove.
+    fn old_jails(&self, service: &str) -> Vec<&Item> {
+        self.jails
+            .iter()
+            .filter(|j| j.name().starts_with(&format!("{}-", service)))
+            .filter(|j| !self.active.contains(&j.path) && !self.running.iter().any(|r| r == j.name()))
+            .collect()
+    }
+
+    /// Images left by interrupted builds.
+    fn partial_images(&self) -> Vec<&Item> {
+        self.images.iter().filter(|i| i.name().starts_with(".partial-")).collect()
+    }
+}
+
+fn print_usage(config: &Config, usage: &Usage, referenced_bases: &HashSet<String>) {
+    let service_jails: Vec<&Item> =
+        usage.jails.iter().filter(|j| j.name().starts_with(&format!("{}-", config.service))).collect();
+    ui::print_output(&format!(
+        "  Jails:   {:>8}  {} of {}, {} in total ({})",
+        format_size(service_jails.iter().map(|j| j.used).sum()),
+        service_jails.len(),
+        config.service,
+        usage.jails.len(),
+        format_size(total(&usage.jails))
+    ));
+    for jail in &service_jails {
+        let state = if usage.active.contains(&jail.path) {
+            "active"
+        } else if usage.running.iter().any(|r| r == jail.name()) {
+            "running"
+        } else {
+            "stopped"
+        };
+        ui::print_output(&format!("    {:<32} {:>8}  {}", jail.name(), format_size(jail.used), state));
+    }
+
+    let layers = usage.layers.map(|l| format!(", layers {}", format_size(l))).unwrap_or_default();
+    ui::print_output(&format!("  Images:  {:>8}  {} images{}", format_size(total(&usage.images)), usage.images.len(), layers));
+    for image in &usage.images {
+        let users = usage.users(image);
+        let note = if image.name().starts_with(".partial-") {
+            "interrupted build".to_string()
+        } else if users.is_empty() {
+            "unreferenced".to_string()
+        } else {
+            format!("used by {}", users.join(", "))
+        };
+        ui::print_output(&format!("    {:<32} {:>8}  {}", image.name(), format_size(image.used), note));
+    }
+
+    ui::print_output(&format!("  Bases:   {:>8}  {} bases", format_size(total(&usage.bases)), usage.bases.len()));
+    for base in &usage.bases {
+        let note = if referenced_bases.contains(base.name()) { "in use" } else { "unused" };
+        ui::print_output(&format!("    {:<32} {:>8}  {}", base.name(), format_size(base.used), note));
+    }
+
+    for (label, items) in [("Data:", &usage.data), ("Logs:", &usage.logs)] {
+        if items.is_empty() {
+            continue;
+        }
+        ui::print_output(&format!("  {:<8} {:>8}", label, format_size(total(items))));
+        for item in items {
+            ui::print_output(&format!("    {:<32} {:>8}", item.path, format_size(item.used)));
+        }
+    }
+    if let Some(staging) = usage.staging {
+        ui::print_output(&format!("  Staging: {:>8}", format_size(staging)));
+    }
+    if let Some(free) = usage.free {
+        ui::print_output(&format!("  Free:    {:>8}", format_size(free)));
+    }
+
+    let unused_bases: Vec<&Item> = usage.bases.iter().filter(|b| !referenced_bases.contains(b.name())).collect();
+    let reclaimable = [
+        ("Unreferenced images", usage.unreferenced_images(), "no jail was created from them"),
+        ("Interrupted image builds", usage.partial_images(), "removed by the next build of the same image"),
+        ("Stopped jails", usage.old_jails(&config.service), "kept for rollback"),
+        ("Unused bases", unused_bases, "`bsdeploy base prune` removes them"),
+    ];
+    if reclaimable.iter().all(|(_, items, _)| items.is_empty()) {
+        return;
+    }
+    ui::print_output("");
+    ui::print_output("  Reclaimable:");
+    for (label, items, note) in reclaimable {
+        if items.is_empty() {
+            continue;
+        }
+        let size: u64 = items.iter().map(|i| i.used).sum();
+        ui::print_output(&format!("    {:<26} {:>8} in {}  ({})", label, format_size(size), items.len(), note));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_usage() {
+        let output = "jail /usr/local/bsdeploy/jails/app-20260101-120000 1048576\n\
+                      jail /usr/local/bsdeploy/jails/app-20260102-120000 2097152\n\
+                      jail /usr/local/bsdeploy/jails/other-20260101-120000 4096\n\
+                      image /usr/local/bsdeploy/images/aaaaaaaaaaaa 500\n\
+                      image /usr/local/bsdeploy/images/bbbbbbbbbbbb 700\n\
+                      image /usr/local/bsdeploy/images/.partial-cccccccccccc 100\n\
+                      layers /usr/local/bsdeploy/images/layers 9000\n\
+                      base /usr/local/bsdeploy/base/14.2-RELEASE 300\n\
+                      uses /usr/local/bsdeploy/jails/app-20260102-120000 /usr/local/bsdeploy/images/aaaaaaaaaaaa\n\
+                      uses /usr/local/bsdeploy/jails/other-20260101-120000 /usr/local/bsdeploy/images/aaaaaaaaaaaa\n\
+                      running app-20260102-120000\n\
+                      active /usr/local/bsdeploy/jails/app-20260102-120000\n\
+                      free 1024\n";
+        let usage = Usage::parse(output);
+        assert_eq!(usage.layers, Some(9000));
+        assert_eq!(usage.free, Some(1 << 20));
+        assert_eq!(usage.users(&usage.images[0]), ["app-20260102-120000", "other-20260101-120000"]);
+
+        let names = |items: Vec<&Item>| items.iter().map(|i| i.name().to_string()).collect::<Vec<_>>();
+        assert_eq!(names(usage.unreferenced_images()), ["bbbbbbbbbbbb"]);
+        assert_eq!(names(usage.partial_images()), [".partial-cccccccccccc"]);
+        assert_eq!(names(usage.old_jails("app")), ["app-20260101-120000"]);
+    }
+}
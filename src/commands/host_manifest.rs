@@ -0,0 +1,208 @@
+//! Manifest of what `setup` provisioned a host with: the bsdeploy
+//! version, the directory layout, the rc.d scripts and the versions of
+//! the packages bsdeploy relies on. Deploy and doctor compare it with the
+//! running bsdeploy to catch version skew, e.g. a host set up by a newer
+//! release than the one deploying to it.
+
+use anyhow::{Result, bail};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::config::Config;
+use crate::constants::*;
+use crate::{rcd, remote, shell, ui};
+
+use super::parallel_hosts;
+use super::setup::default_packages;
+
+pub(super) const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// What `HOST_MANIFEST_PATH` holds
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub(super) struct HostManifest {
+    pub bsdeploy_version: String,
+    pub layout_version: u32,
+    pub rcd_version: String,
+    pub set_up_at: String,
+    /// Installed versions of the packages setup installed, by name
+    pub packages: BTreeMap<String, String>,
+    /// Layout version each service was last set up with
+    #[serde(default)]
+    pub services: BTreeMap<String, u32>,
+}
+
+/// A difference between the manifest and the running bsdeploy
+#[derive(Debug, PartialEq)]
+pub(super) struct Skew {
+    /// The host cannot be deployed to until it is resolved
+    pub fatal: bool,
+    pub detail: String,
+    pub hint: &'static str,
+}
+
+impl HostManifest {
+    pub fn parse(json: &str) -> Option<Self> {
+        serde_json::from_str(json).ok()
+    }
+
+    /// A different layout is fatal, a different bsdeploy release is not.
+    pub fn skew(&self) -> Option<Skew> {
+        let newer = version_key(&self.bsdeploy_version) > version_key(VERSION);
+        let hint = match newer {
+            true => "upgrade bsdeploy to the release the host was set up with",
+            false => "run `bsdeploy setup` to update the host",
+        };
+        if self.layout_version != LAYOUT_VERSION {
+            return Some(Skew {
+                fatal: true,
+                detail: format!(
+                    "set up by bsdeploy {} with layout version {}, bsdeploy {} uses version {}",
+                    self.bsdeploy_version, self.layout_version, VERSION, LAYOUT_VERSION
+                ),
+                hint: match self.layout_version > LAYOUT_VERSION {
+                    true => "upgrade bsdeploy to the release the host was set up with",
+                    false => "run `bsdeploy setup --upgrade` to migrate the host",
+                },
+            });
+        }
+        (self.bsdeploy_version != VERSION).then(|| Skew {
+            fatal: false,
+            detail: format!("set up by bsdeploy {}, this is {}", self.bsdeploy_version, VERSION),
+            hint,
+        })
+    }
+}
+
+/// `0.10.2` sorts after `0.9.1`; anything after a `-` is ignored.
+fn version_key(version: &str) -> Vec<u64> {
+    let release = version.split('-').next().unwrap_or_default();
+    release.split('.').map(|part| part.parse().unwrap_or(0)).collect()
+}
+
+/// Prints `<name> <version>` of every installed package among `names`.
+fn packages_command(names: &[String]) -> String {
+    let names: Vec<String> = names.iter().map(|n| shell::escape(n)).collect();
+    format!("for p in {}; do pkg query '%n %v' \"$p\" 2>/dev/null; done; true", names.join(" "))
+}
+
+pub(super) fn read(host: &str) -> Result<Option<HostManifest>> {
+    let json = remote::query(host, &format!("cat {} 2>/dev/null || true", HOST_MANIFEST_PATH))?;
+    Ok(HostManifest::parse(&json))
+}
+
+/// Record what setup just provisioned `host` with. Packages and services
+/// another service's setup recorded stay in the manifest, packages while
+/// they are installed.
+pub(super) fn write(config: &Config, host: &str) -> Result<()> {
+    let mut names: Vec<String> = default_packages(config).into_iter().map(str::to_string).collect();
+    names.extend(config.packages.iter().cloned());
+    let mut services = BTreeMap::new();
+    if let Some(previous) = read(host)? {
+        names.extend(previous.packages.into_keys());
+        services = previous.services;
+    }
+    services.insert(config.service.clone(), LAYOUT_VERSION);
+    names.sort();
+    names.dedup();
+
+    let output = remote::run_with_output(host, &packages_command(&names))?;
+    let manifest = HostManifest {
+        bsdeploy_version: VERSION.to_string(),
+        layout_version: LAYOUT_VERSION,
+        rcd_version: rcd::RCD_VERSION.to_string(),
+        set_up_at: chrono::Local::now().to_rfc3339(),
+        packages: output
+            .lines()
+            .filter_map(|l| l.trim().split_once(' '))
+            .map(|(name, version)| (name.to_string(), version.to_string()))
+            .collect(),
+        services,
+    };
+    remote::write_file(host, &serde_json::to_string_pretty(&manifest)?, HOST_MANIFEST_PATH, config.doas)
+}
+
+/// Compare every host's manifest with this bsdeploy before a deploy:
+/// warn about a different release and fail on a different layout. Hosts
+/// set up before manifests existed are not checked.
+pub(super) fn check(config: &Config) -> Result<()> {
+    let results = parallel_hosts(&config.hosts, config.parallelism, read);
+    let mut fatal = Vec::new();
+    for (host, result) in config.hosts.iter().zip(results) {
+        match result {
+            Ok(Some(manifest)) => {
+                if let Some(skew) = manifest.skew() {
+                    if skew.fatal {
+                        ui::print_error(&format!("{}: {}", host, skew.detail));
+                        ui::print_hint(&format!("  {}", skew.hint));
+                        fatal.push(host.as_str());
+                    } else {
+                        ui::print_warning(&format!("{}: {}; {}", host, skew.detail, skew.hint));
+                    }
+                }
+            }
+            Ok(None) => {}
+            Err(e) => log::debug!("Reading the host manifest failed on {}: {:#}", host, e),
+        }
+    }
+    if !fatal.is_empty() {
+        bail!("The directory layout on {} does not match this bsdeploy", fatal.join(", "));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::remote::mock::{self, MockExecutor};
+
+    fn manifest(version: &str, layout: u32) -> HostManifest {
+        HostManifest {
+            bsdeploy_version: version.to_string(),
+            layout_version: layout,
+            rcd_version: rcd::RCD_VERSION.to_string(),
+            set_up_at: String::new(),
+            packages: BTreeMap::new(),
+            services: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn test_skew() {
+        assert_eq!(manifest(VERSION, LAYOUT_VERSION).skew(), None);
+        let skew = manifest("0.0.1", LAYOUT_VERSION).skew().unwrap();
+        assert!(!skew.fatal);
+        assert_eq!(skew.detail, format!("set up by bsdeploy 0.0.1, this is {}", VERSION));
+        assert_eq!(skew.hint, "run `bsdeploy setup` to update the host");
+        let skew = manifest("99.0.0", LAYOUT_VERSION + 1).skew().unwrap();
+        assert!(skew.fatal);
+        assert!(skew.hint.starts_with("upgrade bsdeploy"));
+        let skew = manifest(VERSION, LAYOUT_VERSION - 1).skew().unwrap();
+        assert!(skew.fatal);
+        assert_eq!(skew.hint, "run `bsdeploy setup --upgrade` to migrate the host");
+        assert!(version_key("0.10.2") > version_key("0.9.1-rc1"));
+        assert!(HostManifest::parse("").is_none());
+    }
+
+    #[test]
+    fn test_write() {
+        let config = Config::from_str("service: app\nhosts: [web1]\npackages: [postgresql17-client]\nproxy: false\n").unwrap();
+        let mock = MockExecutor::new();
+        mock.respond(
+            "cat /usr/local/etc/bsdeploy/host.json",
+            &serde_json::to_string(&HostManifest {
+                packages: BTreeMap::from([("redis".into(), "7.2.5".into())]),
+                services: BTreeMap::from([("worker".into(), 1)]),
+                ..manifest("0.0.1", 1)
+            })
+            .unwrap(),
+        );
+        mock.respond("pkg query", "bash 5.2.37\njq 1.7.1\nredis 7.4.1\n");
+        mock::with_executor(mock.clone(), || write(&config, "web1")).unwrap();
+        assert!(mock.ran("for p in bash git jq postgresql17-client redis rsync; do pkg query '%n %v' \"$p\""));
+        let written = HostManifest::parse(&mock.file("/usr/local/etc/bsdeploy/host.json").unwrap()).unwrap();
+        assert_eq!(written.bsdeploy_version, VERSION);
+        assert_eq!(written.packages.get("redis").map(String::as_str), Some("7.4.1"));
+        assert_eq!(written.packages.len(), 3);
+        assert_eq!(written.services, BTreeMap::from([("app".into(), LAYOUT_VERSION), ("worker".into(), 1)]));
+    }
+}
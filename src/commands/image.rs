@@ -0,0 +1,124 @@
+use anyhow::Result;
+use clap::Subcommand;
+use std::path::{Path, PathBuf};
+
+use crate::config::Config;
+use crate::{image, ui};
+
+#[derive(Subcommand)]
+pub enum ImageAction {
+    /// Export a built image from a host into a local tarball
+    Export {
+        /// Short image hash (directory name under the images directory)
+        hash: String,
+        /// Path of the tarball to write
+        #[arg(short, long)]
+        output: PathBuf,
+        /// Host to export from (defaults to the first configured host)
+        #[arg(long)]
+        host: Option<String>,
+    },
+    /// Import an image tarball into the remote hosts
+    Import {
+        /// Short image hash the tarball was exported as
+        hash: String,
+        /// Path of the tarball to read
+        #[arg(short, long)]
+        input: PathBuf,
+        /// Only import to this host (defaults to all configured hosts)
+        #[arg(long)]
+        host: Option<String>,
+    },
+    /// Show the manifest of a built image
+    Inspect {
+        /// Short image hash
+        hash: String,
+        /// Host to read the image from (defaults to the first configured host)
+        #[arg(long)]
+        host: Option<String>,
+    },
+}
+
+pub fn run(config: &Config, action: ImageAction) -> Result<()> {
+    match action {
+        ImageAction::Export { hash, output, host } => export(config, &hash, &output, host),
+        ImageAction::Import { hash, input, host } => import(config, &hash, &input, host),
+        ImageAction::Inspect { hash, host } => inspect(config, &hash, host),
+    }
+}
+
+/// Resolve the single host an image command works on.
+fn single_host(config: &Config, host: Option<String>) -> Result<String> {
+    match host.or_else(|| config.hosts.first().cloned()) {
+        Some(h) => Ok(h),
+        None => anyhow::bail!("No hosts configured"),
+    }
+}
+
+fn export(config: &Config, hash: &str, output: &Path, host: Option<String>) -> Result<()> {
+    let host = single_host(config, host)?;
+
+    let spinner = ui::create_spinner(&format!("Exporting image {} from {}", hash, host));
+    image::export_image(&host, hash, output, config.doas)?;
+    spinner.finish_with_message(format!("Export complete for {}", host));
+
+    ui::print_success(&format!("Image {} written to {}", hash, output.display()));
+    Ok(())
+}
+
+fn import(config: &Config, hash: &str, input: &Path, host: Option<String>) -> Result<()> {
+    if !input.is_file() {
+        anyhow::bail!("Image tarball not found: {}", input.display());
+    }
+
+    let hosts = match host {
+        Some(h) => vec![h],
+        None => config.hosts.clone(),
+    };
+
+    ui::print_step(&format!("Importing image {} to {} hosts", hash, hosts.len()));
+
+    for host in &hosts {
+        let spinner = ui::create_spinner(&format!("Importing image {} to {}", hash, host));
+
+        let imported = image::import_image(host, hash, input, config.doas)?;
+
+        spinner.finish_with_message(format!("Import complete for {}", host));
+        if imported {
+            ui::print_success(&format!("{} imported image {}", host, hash));
+        } else {
+            ui::print_success(&format!("{} already has image {}", host, hash));
+        }
+    }
+
+    Ok(())
+}
+
+fn inspect(config: &Config, hash: &str, host: Option<String>) -> Result<()> {
+    let host = single_host(config, host)?;
+    let manifest = image::read_manifest(&host, hash)?;
+
+    ui::print_output("");
+    ui::print_output(&format!("Image: {} ({})", manifest.hash, host));
+    ui::print_output(&"─".repeat(60));
+    ui::print_output(&format!("  Base:      {}", manifest.base_version));
+    ui::print_output(&format!("  Built:     {}", manifest.built_at));
+    ui::print_output(&format!("  bsdeploy:  {}", manifest.bsdeploy_version));
+
+    ui::print_output("");
+    ui::print_output("  Runtimes:");
+    if manifest.mise.is_empty() {
+        ui::print_output("    (none)");
+    }
+    for (tool, version) in &manifest.mise {
+        ui::print_output(&format!("    {:<24} {}", tool, version));
+    }
+
+    ui::print_output("");
+    ui::print_output(&format!("  Packages ({}):", manifest.packages.len()));
+    for (name, version) in &manifest.packages {
+        ui::print_output(&format!("    {:<24} {}", name, version));
+    }
+    ui::print_output("");
+    Ok(())
+}
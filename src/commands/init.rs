@@ -17,6 +17,9 @@ hosts:
 # Run commands with doas privilege escalation (optional, default: false)
 doas: true
 
+# Use sudo instead of doas, or none when connecting as root (optional)
+# privilege_escalation: sudo
+
 # User to run the application as (optional)
 # If set, the user will be created inside the jail
 user: myapp
@@ -31,10 +34,14 @@ jail:
 
 # Reverse proxy configuration (optional)
 # Caddy will proxy traffic from hostname to the jail
+# Without a proxy, use `proxy: false` and redirect ports with `bind: { ports: [25] }`
 proxy:
   hostname: myapp.example.com
   port: 3000
   # tls: true  # default: true
+  # server: relayd  # default: caddy
+  # admin_api: true  # reload Caddy via its admin API
+  # redirect_hosts: [www.myapp.example.com]  # 301 to hostname
 
 # System packages to install in the jail (optional)
 packages:
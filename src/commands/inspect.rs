@@ -0,0 +1,377 @@
+use anyhow::{Result, bail};
+use serde::Deserialize;
+
+use crate::config::Config;
+use crate::constants::*;
+use crate::{remote, shell, ui};
+
+use super::parallel_hosts;
+
+/// `jail` on `host`, or on every host that has it.
+pub fn run(config: &Config, jail: &str, host: Option<String>) -> Result<()> {
+    let valid = jail.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if !valid || !jail.starts_with(&format!("{}-", config.service)) {
+        bail!("'{}' is not a jail of {}; `bsdeploy status` lists them", jail, config.service);
+    }
+    let hosts = match host {
+        Some(host) => vec![host],
+        None => config.hosts.clone(),
+    };
+
+    let script = inspect_script(config, jail);
+    let results = parallel_hosts(&hosts, config.parallelism, |host| {
+        remote::run_with_output(host, &script).map(|output| Inspection::parse(&output))
+    });
+
+    let mut found = 0;
+    for (host, result) in hosts.iter().zip(results) {
+        let inspection = result?;
+        if !inspection.exists {
+            continue;
+        }
+        found += 1;
+        print_inspection(config, host, jail, &inspection);
+    }
+    if found == 0 {
+        bail!("Jail {} not found on {}", jail, hosts.join(", "));
+    }
+    Ok(())
+}
+
+/// Everything known about `jail`, in sections headed by `== <name>` lines.
+fn inspect_script(config: &Config, jail: &str) -> String {
+    let p = shell::escalation_prefix(config.doas);
+    let root = format!("{}/{}", JAILS_DIR, jail);
+    let run = format!("{}/{}", RUN_DIR, config.service);
+    format!(
+        "test -d {root} && echo '== exists'\n\
+         echo '== metadata'; cat {root}/.bsdeploy.json 2>/dev/null; echo\n\
+         echo '== active'; readlink {active}/{service} 2>/dev/null\n\
+         echo '== jls'; jls -j {jail} -n -q 2>/dev/null\n\
+         echo '== mounts'; mount -p | awk -v r={root} '$2 == r || index($2, r \"/\") == 1 {{print $1, $2, $3, $4}}'\n\
+         echo '== rctl'; {p}rctl jail:{jail} 2>/dev/null\n\
+         echo '== usage'; {p}rctl -u jail:{jail} 2>/dev/null\n\
+         echo '== lo1'; ifconfig lo1 2>/dev/null | awk '$1 == \"inet\" {{print $2}}'\n\
+         echo '== processes'; {p}jexec {jail} sh -c 'for f in {run}/service.pid {run}/service-*.pid /var/run/service.pid /var/run/service-*.pid; do \
+         [ -f \"$f\" ] || continue; n=${{f##*/}}; \
+         if pkill -0 -F \"$f\" 2>/dev/null; then s=running; else s=stopped; fi; echo \"${{n%.pid}} $(cat \"$f\") $s\"; \
+         done' 2>/dev/null\n\
+         true\n",
+        root = root,
+        active = ACTIVE_DIR,
+        service = config.service,
+        jail = jail,
+        run = run,
+        p = p,
+    )
+}
+
+#[derive(Debug, Default, Deserialize, PartialEq)]
+struct DataDirectory {
+    host_path: String,
+    jail_path: String,
+}
+
+/// The parts of `.bsdeploy.json` the actual state is compared with
+#[derive(Debug, Default, Deserialize, PartialEq)]
+struct Expected {
+    ip: String,
+    #[serde(default)]
+    processes: Vec<String>,
+    #[serde(default)]
+    data_directories: Vec<DataDirectory>,
+}
+
+#[derive(Debug, PartialEq)]
+struct Mount {
+    source: String,
+    target: String,
+    fstype: String,
+    options: String,
+}
+
+#[derive(Debug, PartialEq)]
+struct PidFile {
+    name: String,
+    pid: String,
+    running: bool,
+}
+
+/// What `inspect_script` found
+#[derive(Debug, Default, PartialEq)]
+struct Inspection {
+    exists: bool,
+    metadata: Option<serde_json::Value>,
+    /// Target of the service's active symlink
+    active: Option<String>,
+    /// `jls -n` parameters; empty when the jail is not running
+    params: Vec<(String, String)>,
+    mounts: Vec<Mount>,
+    rctl_rules: Vec<String>,
+    rctl_usage: Vec<String>,
+    lo1: Vec<String>,
+    pid_files: Vec<PidFile>,
+}
+
+/// Split `jls -n -q` output into `key=value` words, honoring double quotes.
+fn split_params(line: &str) -> Vec<(String, String)> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut quoted = false;
+    for c in line.chars() {
+        match c {
+            '"' => quoted = !quoted,
+            c if c.is_whitespace() && !quoted => {
+                if !word.is_empty() {
+                    words.push(std::mem::take(&mut word));
+                }
+            }
+            c => word.push(c),
+        }
+    }
+    if !word.is_empty() {
+        words.push(word);
+    }
+    words
+        .into_iter()
+        .map(|w| match w.split_once('=') {
+            Some((key, value)) => (key.to_string(), value.to_string()),
+            None => (w, String::new()),
+        })
+        .collect()
+}
+
+impl Inspection {
+    fn parse(output: &str) -> Self {
+        let mut inspection = Self::default();
+        for chunk in output.split("== ").filter(|c| !c.trim().is_empty()) {
+            let (section, body) = chunk.split_once('\n').unwrap_or((chunk, ""));
+            let lines = || body.lines().map(str::trim).filter(|l| !l.is_empty());
+            match section.trim() {
+                "exists" => inspection.exists = true,
+                "metadata" => inspection.metadata = serde_json::from_str(body).ok(),
+                "active" => inspection.active = lines().next().map(str::to_string),
+                "jls" => inspection.params = lines().flat_map(split_params).collect(),
+                "mounts" => {
+                    inspection.mounts = lines()
+                        .filter_map(|l| {
+                            let f: Vec<&str> = l.split_whitespace().collect();
+                            let [source, target, fstype, options] = f[..] else {
+                                return None;
+                            };
+                            Some(Mount {
+                                source: source.to_string(),
+                                target: target.to_string(),
+                                fstype: fstype.to_string(),
+                                options: options.to_string(),
+                            })
+                        })
+                        .collect()
+                }
+                "rctl" => inspection.rctl_rules = lines().map(str::to_string).collect(),
+                "usage" => inspection.rctl_usage = lines().map(str::to_string).collect(),
+                "lo1" => inspection.lo1 = lines().map(str::to_string).collect(),
+                "processes" => {
+                    inspection.pid_files = lines()
+                        .filter_map(|l| {
+                            let mut f = l.split_whitespace();
+                            let (name, pid, state) = (f.next()?, f.next()?, f.next()?);
+                            Some(PidFile { name: name.to_string(), pid: pid.to_string(), running: state == "running" })
+                        })
+                        .collect()
+                }
+                _ => {}
+            }
+        }
+        inspection
+    }
+
+    fn param(&self, key: &str) -> Option<&str> {
+        self.params.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str())
+    }
+
+    fn running(&self) -> bool {
+        !self.params.is_empty()
+    }
+}
+
+/// Differences between what the metadata and the active symlink say and
+/// what is running on the host.
+fn mismatches(config: &Config, jail: &str, inspection: &Inspection) -> Vec<String> {
+    let root = format!("{}/{}", JAILS_DIR, jail);
+    let active = inspection.active.as_deref() == Some(root.as_str());
+    let running = inspection.running();
+    let mut found = Vec::new();
+
+    if active && !running {
+        found.push(format!("active jail of {} but not running", config.service));
+    }
+    if running && !active {
+        found.push("running but not the active jail (left over from an earlier deploy?)".to_string());
+    }
+
+    let expected = match &inspection.metadata {
+        Some(metadata) => serde_json::from_value::<Expected>(metadata.clone()).ok(),
+        None => {
+            found.push("no .bsdeploy.json (interrupted deploy?)".to_string());
+            None
+        }
+    };
+    let Some(expected) = expected else {
+        return found;
+    };
+
+    if running {
+        if let Some(ip) = inspection.param("ip4.addr")
+            && ip != expected.ip
+        {
+            found.push(format!("jail address {} differs from the recorded {}", ip, expected.ip));
+        }
+        if !inspection.lo1.contains(&expected.ip) {
+            found.push(format!("address {} is not aliased on lo1", expected.ip));
+        }
+        let devfs = format!("{}/dev", root);
+        if !inspection.mounts.iter().any(|m| m.fstype == "devfs" && m.target == devfs) {
+            found.push(format!("devfs is not mounted on {}", devfs));
+        }
+        if inspection.pid_files.len() < expected.processes.len() {
+            found.push(format!(
+                "{} start commands recorded, {} pid files found",
+                expected.processes.len(),
+                inspection.pid_files.len()
+            ));
+        }
+        for pid_file in inspection.pid_files.iter().filter(|p| !p.running) {
+            found.push(format!("{} is not running (pid {})", pid_file.name, pid_file.pid));
+        }
+    }
+    for dir in &expected.data_directories {
+        let target = format!("{}/{}", root, dir.jail_path.trim_start_matches('/'));
+        if running && !inspection.mounts.iter().any(|m| m.source == dir.host_path && m.target == target) {
+            found.push(format!("data directory {} is not mounted on {}", dir.host_path, target));
+        }
+    }
+    found
+}
+
+fn print_inspection(config: &Config, host: &str, jail: &str, inspection: &Inspection) {
+    ui::print_output("");
+    ui::print_output(&format!("Jail: {} on {}", jail, host));
+    ui::print_output(&"─".repeat(60));
+
+    let active = inspection.active.as_deref() == Some(format!("{}/{}", JAILS_DIR, jail).as_str());
+    let state = match (inspection.running(), active) {
+        (true, true) => "running (active)",
+        (true, false) => "running",
+        (false, true) => "stopped (active)",
+        (false, false) => "stopped",
+    };
+    ui::print_output(&format!("  State: {}", state));
+
+    ui::print_output("");
+    ui::print_output("  Metadata:");
+    match &inspection.metadata {
+        Some(metadata) => {
+            let pretty = serde_json::to_string_pretty(metadata).unwrap_or_default();
+            for line in pretty.lines() {
+                ui::print_output(&format!("    {}", line));
+            }
+        }
+        None => ui::print_output("    -"),
+    }
+
+    let section = |title: &str, lines: Vec<String>| {
+        ui::print_output("");
+        ui::print_output(&format!("  {}:", title));
+        if lines.is_empty() {
+            ui::print_output("    -");
+        }
+        for line in lines {
+            ui::print_output(&format!("    {}", line));
+        }
+    };
+    section("Jail parameters", inspection.params.iter().map(|(k, v)| format!("{}={}", k, v)).collect());
+    section(
+        "Mounts",
+        inspection
+            .mounts
+            .iter()
+            .map(|m| format!("{} on {} ({}, {})", m.source, m.target, m.fstype, m.options))
+            .collect(),
+    );
+    section("rctl rules", inspection.rctl_rules.clone());
+    section("rctl usage", inspection.rctl_usage.clone());
+    section("lo1 addresses", inspection.lo1.clone());
+    section(
+        "Processes",
+        inspection
+            .pid_files
+            .iter()
+            .map(|p| format!("{} pid {} ({})", p.name, p.pid, if p.running { "running" } else { "stopped" }))
+            .collect(),
+    );
+
+    ui::print_output("");
+    let mismatches = mismatches(config, jail, inspection);
+    if mismatches.is_empty() {
+        ui::print_success("State matches the metadata");
+    }
+    for mismatch in mismatches {
+        ui::print_warning(&mismatch);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OUTPUT: &str = "== exists\n\
+        == metadata\n\
+        {\"ip\": \"10.0.0.5\", \"processes\": [\"daemon a\", \"daemon b\"], \"data_directories\": [{\"host_path\": \"/var/db/bsdeploy/app/storage\", \"jail_path\": \"/app/storage\"}]}\n\
+        == active\n\
+        /usr/local/bsdeploy/jails/app-20260101-120000\n\
+        == jls\n\
+        jid=3 name=app-20260101-120000 ip4.addr=10.0.0.5 exec.start=\"/bin/sh /etc/rc\"\n\
+        == mounts\n\
+        devfs /usr/local/bsdeploy/jails/app-20260101-120000/dev devfs rw\n\
+        == rctl\n\
+        jail:app-20260101-120000:memoryuse:deny=1g\n\
+        == usage\n\
+        == lo1\n\
+        10.0.0.5\n\
+        == processes\n\
+        service 812 running\n\
+        service-worker 815 stopped\n";
+
+    #[test]
+    fn test_parse_inspection() {
+        let inspection = Inspection::parse(OUTPUT);
+        assert!(inspection.exists && inspection.running());
+        assert_eq!(inspection.param("exec.start"), Some("/bin/sh /etc/rc"));
+        assert_eq!(inspection.mounts[0].fstype, "devfs");
+        assert_eq!(inspection.rctl_rules, ["jail:app-20260101-120000:memoryuse:deny=1g"]);
+        assert!(inspection.rctl_usage.is_empty());
+        assert_eq!(inspection.pid_files[1], PidFile { name: "service-worker".into(), pid: "815".into(), running: false });
+
+        assert!(!Inspection::parse("== metadata\n\n== jls\n").exists);
+    }
+
+    #[test]
+    fn test_mismatches() {
+        let config = Config::from_str("service: app\nhosts: [a]\n").unwrap();
+        let inspection = Inspection::parse(OUTPUT);
+        assert_eq!(
+            mismatches(&config, "app-20260101-120000", &inspection),
+            [
+                "service-worker is not running (pid 815)",
+                "data directory /var/db/bsdeploy/app/storage is not mounted on /usr/local/bsdeploy/jails/app-20260101-120000/app/storage",
+            ]
+        );
+
+        let stopped = Inspection { params: vec![], metadata: None, ..Inspection::parse(OUTPUT) };
+        assert_eq!(
+            mismatches(&config, "app-20260101-120000", &stopped),
+            ["active jail of app but not running", "no .bsdeploy.json (interrupted deploy?)"]
+        );
+    }
+}
@@ -0,0 +1,244 @@
+use anyhow::{Context, Result, bail};
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, TimeZone};
+
+use crate::config::Config;
+use crate::constants::ACTIVE_DIR;
+use crate::proxy::caddy;
+use crate::{remote, shell, ui};
+
+use super::deploy;
+
+/// Which lines to show: the last `lines` of those within `since..=until`
+/// (Unix seconds) that match `grep`.
+pub struct Filter {
+    pub lines: usize,
+    pub since: Option<i64>,
+    pub until: Option<i64>,
+    pub grep: Option<String>,
+}
+
+impl Filter {
+    /// Parse the `--since`/`--until` arguments relative to now.
+    pub fn new(lines: usize, since: Option<&str>, until: Option<&str>, grep: Option<String>) -> Result<Self> {
+        let now = Local::now();
+        let since = since.map(|s| parse_time(s, now)).transpose()?;
+        let until = until.map(|s| parse_time(s, now)).transpose()?;
+        if let (Some(since), Some(until)) = (since, until)
+            && since > until
+        {
+            bail!("--since is after --until");
+        }
+        Ok(Self { lines, since, until, grep })
+    }
+}
+
+/// Unix time of a point given as an age (`30s`, `15m`, `2h`, `1d`, `1w`) or
+/// as a local date and time (`2025-01-31`, `2025-01-31 14:00`, RFC 3339).
+fn parse_time(value: &str, now: DateTime<Local>) -> Result<i64> {
+    let value = value.trim();
+    if let Some(unit) = value.chars().last()
+        && let Ok(amount) = value[..value.len() - unit.len_utf8()].parse::<i64>()
+    {
+        let seconds = match unit {
+            's' => 1,
+            'm' => 60,
+            'h' => 3600,
+            'd' => 86400,
+            'w' => 7 * 86400,
+            _ => bail!("Unknown unit in '{}': use s, m, h, d or w", value),
+        };
+        return Ok(now.timestamp() - amount * seconds);
+    }
+    if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+        return Ok(time.timestamp());
+    }
+    let naive = ["%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M:%S", "%Y-%m-%d %H:%M", "%Y-%m-%dT%H:%M"]
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(value, format).ok())
+        .or_else(|| NaiveDate::parse_from_str(value, "%Y-%m-%d").ok().and_then(|d| d.and_hms_opt(0, 0, 0)))
+        .with_context(|| format!("Invalid time '{}': use e.g. 1h, 2d or 2025-01-31 14:00", value))?;
+    Local
+        .from_local_datetime(&naive)
+        .earliest()
+        .map(|time| time.timestamp())
+        .with_context(|| format!("'{}' does not exist in the local time zone", value))
+}
+
+/// Keeps the lines stamped within `since..=until`. Understands Caddy's JSON
+/// `"ts"`, ISO 8601 (taken as the host's local time without a zone) and
+/// Common Log Format times; lines without a time (stack traces) belong to
+/// the line before. Stops reading at the first line after `until`.
+const TIME_FILTER: &str = r#"function days(y, m, d) { if (m > 2) m -= 3; else { m += 9; y-- } return 365*y + int(y/4) - int(y/100) + int(y/400) + int((153*m+2)/5) + d - 719469 }
+function zone(z,  s) { if (z == "") z = tz; if (z == "Z") return 0; gsub(":", "", z); s = substr(z, 2, 2)*3600 + substr(z, 4, 2)*60; return substr(z, 1, 1) == "-" ? -s : s }
+{
+  t = ""
+  if (match($0, /"ts":[0-9]+/)) t = substr($0, RSTART+5, RLENGTH-5) + 0
+  else if (match($0, /[0-9][0-9][0-9][0-9]-[0-9][0-9]-[0-9][0-9][T ][0-9][0-9]:[0-9][0-9]:[0-9][0-9]/)) {
+    s = substr($0, RSTART, RLENGTH); rest = substr($0, RSTART+RLENGTH); sub(/^[.,][0-9]+/, "", rest); z = ""
+    if (match(rest, /^(Z|[+-][0-9][0-9]:?[0-9][0-9])/)) z = substr(rest, 1, RLENGTH)
+    t = days(substr(s, 1, 4)+0, substr(s, 6, 2)+0, substr(s, 9, 2)+0)*86400 + substr(s, 12, 2)*3600 + substr(s, 15, 2)*60 + substr(s, 18, 2) - zone(z)
+  } else if (match($0, /\[[0-9][0-9]\/[A-Z][a-z][a-z]\/[0-9][0-9][0-9][0-9]:[0-9][0-9]:[0-9][0-9]:[0-9][0-9] [+-][0-9][0-9][0-9][0-9]\]/)) {
+    s = substr($0, RSTART+1, RLENGTH-2); m = (index("JanFebMarAprMayJunJulAugSepOctNovDec", substr(s, 4, 3)) + 2) / 3
+    t = days(substr(s, 8, 4)+0, m, substr(s, 1, 2)+0)*86400 + substr(s, 13, 2)*3600 + substr(s, 16, 2)*60 + substr(s, 19, 2) - zone(substr(s, 22, 5))
+  }
+  if (t != "") last = t
+  if (last == "" || last < since) next
+  if (until != "" && last > until) exit
+  print
+}"#;
+
+/// Remote command printing the lines of `path` selected by `filter`. The
+/// filtering happens on the host so only the selected lines are transferred.
+fn command(cmd_prefix: &str, path: &str, filter: &Filter) -> String {
+    let path = shell::escape(path);
+    let grep = filter.grep.as_ref().map(|pattern| format!("grep -E -e {}", shell::escape(pattern)));
+    let tail = format!("tail -n {}", filter.lines);
+    if filter.since.is_some() || filter.until.is_some() {
+        let awk = format!(
+            "{}cat {} | awk -v since={} -v until={} -v tz=\"$(date +%z)\" '{}'",
+            cmd_prefix,
+            path,
+            filter.since.unwrap_or(0),
+            filter.until.map(|u| u.to_string()).unwrap_or_default(),
+            TIME_FILTER
+        );
+        match grep {
+            Some(grep) => format!("{} | {} | {}", awk, grep, tail),
+            None => format!("{} | {}", awk, tail),
+        }
+    } else {
+        match grep {
+            Some(grep) => format!("{}{} {} | {}", cmd_prefix, grep, path, tail),
+            None => format!("{}{} {}", cmd_prefix, tail, path),
+        }
+    }
+}
+
+/// Log files of the start commands in the active jail: their output logs,
+/// or their error logs with `stderr`.
+fn service_logs(config: &Config, stderr: bool) -> Result<Vec<String>> {
+    let mut paths = Vec::new();
+    for index in 0..config.start.len() {
+        let (log, error_log) = deploy::log_paths(config, index);
+        let path = if stderr { error_log } else { Some(log) };
+        if let Some(path) = path
+            && !paths.contains(&path)
+        {
+            paths.push(path);
+        }
+    }
+    if paths.is_empty() {
+        if stderr {
+            bail!("stderr of '{}' goes to the service log: set logs.split_stderr or an error_log", config.service);
+        }
+        paths.push(deploy::service_log_path(config, 0));
+    }
+    // The active symlink points at the running jail
+    Ok(paths.iter().map(|path| format!("{}/{}{}", ACTIVE_DIR, config.service, path)).collect())
+}
+
+/// Print the lines selected by `filter` from the service logs (the error
+/// logs with `stderr`), or from the proxy access log with `proxy`, on every
+/// host.
+pub fn run(config: &Config, proxy: bool, stderr: bool, filter: &Filter) -> Result<()> {
+    let paths = if proxy {
+        if config.proxy.as_ref().and_then(|p| p.access_log()).is_none() {
+            bail!("No proxy access log for '{}': enable proxy.access_log", config.service);
+        }
+        vec![caddy::access_log_path(&config.service)]
+    } else {
+        service_logs(config, stderr)?
+    };
+
+    let cmd_prefix = shell::escalation_prefix(config.doas);
+    for host in &config.hosts {
+        for path in &paths {
+            ui::print_step(&format!("{}: {}", host, path));
+            let output = remote::run_with_output(host, &command(cmd_prefix, path, filter))?;
+            if !output.is_empty() {
+                ui::print_output(output.strip_suffix('\n').unwrap_or(&output));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::remote::mock::{self, MockExecutor};
+
+    fn filter(lines: usize) -> Filter {
+        Filter { lines, since: None, until: None, grep: None }
+    }
+
+    #[test]
+    fn test_logs_paths() {
+        let config = Config::from_str(
+            "service: myapp\nhosts: [a]\nuser: app\nproxy:\n  hostname: a.example.com\n  port: 3000\n  access_log: {}\n",
+        )
+        .unwrap();
+        let mock = MockExecutor::new();
+        mock::with_executor(mock.clone(), || {
+            run(&config, false, false, &filter(20))?;
+            run(&config, true, false, &filter(5))
+        })
+        .unwrap();
+        assert!(mock.ran("tail -n 20 /usr/local/bsdeploy/active/myapp/var/log/bsdeploy/myapp/service.log"));
+        assert!(mock.ran("tail -n 5 /var/log/caddy/myapp.log"));
+
+        let config = Config::from_str("service: myapp\nhosts: [a]\n").unwrap();
+        assert!(run(&config, true, false, &filter(5)).is_err());
+        assert!(run(&config, false, true, &filter(5)).is_err());
+    }
+
+    #[test]
+    fn test_service_logs() {
+        let config = Config::from_str(
+            "service: myapp\nhosts: [a]\nuser: app\nlogs: { split_stderr: true }\nstart:\n  - bin/web\n  - bin/jobs\n  - { command: bin/worker, log: /var/log/worker.log }\n",
+        )
+        .unwrap();
+        let active = "/usr/local/bsdeploy/active/myapp";
+        assert_eq!(
+            service_logs(&config, false).unwrap(),
+            [
+                format!("{}/var/log/bsdeploy/myapp/service.log", active),
+                format!("{}/var/log/bsdeploy/myapp/service-1.log", active),
+                format!("{}/var/log/worker.log", active)
+            ]
+        );
+        assert_eq!(
+            service_logs(&config, true).unwrap(),
+            [
+                format!("{}/var/log/bsdeploy/myapp/service.error.log", active),
+                format!("{}/var/log/bsdeploy/myapp/service-1.error.log", active),
+                format!("{}/var/log/worker.error.log", active)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_filter_command() {
+        let grep = Filter { grep: Some("status\":5".to_string()), ..filter(10) };
+        assert_eq!(command("", "/var/log/x.log", &grep), "grep -E -e 'status\":5' /var/log/x.log | tail -n 10");
+
+        let since = Filter { since: Some(1700000000), ..grep };
+        let cmd = command("doas ", "/var/log/x.log", &since);
+        assert!(cmd.starts_with("doas cat /var/log/x.log | awk -v since=1700000000 -v until= -v tz=\"$(date +%z)\" '"));
+        assert!(cmd.ends_with("' | grep -E -e 'status\":5' | tail -n 10"));
+    }
+
+    #[test]
+    fn test_parse_time() {
+        let now = Local.with_ymd_and_hms(2025, 1, 31, 14, 0, 0).unwrap();
+        assert_eq!(parse_time("90s", now).unwrap(), now.timestamp() - 90);
+        assert_eq!(parse_time("2h", now).unwrap(), now.timestamp() - 7200);
+        assert_eq!(parse_time("1w", now).unwrap(), now.timestamp() - 604800);
+        assert_eq!(parse_time("2025-01-31 13:30", now).unwrap(), now.timestamp() - 1800);
+        assert_eq!(parse_time("2025-01-31", now).unwrap(), now.timestamp() - 14 * 3600);
+        assert_eq!(parse_time("2025-01-31T12:00:00Z", now).unwrap(), 1738324800);
+        assert!(parse_time("5y", now).is_err());
+        assert!(parse_time("yesterday", now).is_err());
+        assert!(Filter::new(10, Some("1h"), Some("2h"), None).is_err());
+    }
+}
@@ -1,20 +1,108 @@
+use anyhow::Result;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+mod applied;
+mod base;
+mod certs;
 mod deploy;
 mod destroy;
+mod doctor;
+mod du;
+mod host_manifest;
+mod image;
+mod inspect;
 mod init;
+mod logs;
+mod monitor;
 mod setup;
+mod setup_check;
 mod status;
+mod teardown_host;
+mod upgrade;
 
+pub use base::BaseAction;
+pub use base::run as base;
+pub use certs::CertsAction;
+pub use certs::run as certs;
 pub use deploy::run as deploy;
 pub use destroy::run as destroy;
+pub use doctor::run as doctor;
+pub use du::run as du;
+pub use image::ImageAction;
+pub use image::run as image;
 pub use init::run as init;
+pub use inspect::run as inspect;
+pub use logs::Filter as LogFilter;
+pub use logs::run as logs;
+pub use monitor::run as monitor;
 pub use setup::run as setup;
 pub use status::run as status;
+pub use teardown_host::run as teardown_host;
 
-/// Build a command with optional doas prefix.
+/// Build a command with the privilege escalation prefix when `doas` is set.
 pub fn maybe_doas(cmd: &str, doas: bool) -> String {
-    if doas {
-        format!("doas {}", cmd)
-    } else {
-        cmd.to_string()
+    format!("{}{}", crate::shell::escalation_prefix(doas), cmd)
+}
+
+/// Run `f` for every host with at most `limit` hosts in flight at once.
+/// Results are returned in the same order as `hosts`.
+pub fn parallel_hosts<T, F>(hosts: &[String], limit: usize, f: F) -> Vec<Result<T>>
+where
+    T: Send,
+    F: Fn(&str) -> Result<T> + Sync,
+{
+    let next = AtomicUsize::new(0);
+    let results: Mutex<Vec<Option<Result<T>>>> =
+        Mutex::new((0..hosts.len()).map(|_| None).collect());
+
+    std::thread::scope(|s| {
+        for _ in 0..limit.max(1).min(hosts.len()) {
+            s.spawn(|| {
+                loop {
+                    let i = next.fetch_add(1, Ordering::SeqCst);
+                    if i >= hosts.len() {
+                        break;
+                    }
+                    let result = f(&hosts[i]);
+                    results.lock().unwrap()[i] = Some(result);
+                }
+            });
+        }
+    });
+
+    results
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .map(|r| r.expect("every host is processed exactly once"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parallel_hosts_preserves_order() {
+        let hosts: Vec<String> = (0..10).map(|i| format!("host{}", i)).collect();
+        let results = parallel_hosts(&hosts, 3, |h| Ok(h.to_uppercase()));
+        let values: Vec<String> = results.into_iter().map(|r| r.unwrap()).collect();
+        assert_eq!(values[0], "HOST0");
+        assert_eq!(values[9], "HOST9");
+        assert_eq!(values.len(), 10);
+    }
+
+    #[test]
+    fn test_parallel_hosts_keeps_errors_per_host() {
+        let hosts = vec!["ok".to_string(), "bad".to_string()];
+        let results = parallel_hosts(&hosts, 0, |h| {
+            if h == "bad" {
+                anyhow::bail!("failed on {}", h)
+            }
+            Ok(())
+        });
+        assert!(results[0].is_ok());
+        assert!(results[1].is_err());
     }
 }
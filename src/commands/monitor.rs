@@ -0,0 +1,185 @@
+use anyhow::Result;
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::config::Config;
+use crate::constants::*;
+use crate::exit::Unhealthy;
+use crate::notify::{self, Alert};
+use crate::{remote, ui};
+
+use super::parallel_hosts;
+use super::status::{Probe, probe_command};
+
+/// State of the service on a host, as seen by one check
+#[derive(Debug, Clone, PartialEq)]
+enum State {
+    /// The active jail runs and, with a health check path, answers it
+    Healthy(String),
+    Unhealthy(String),
+    /// No active jail, or it is not running
+    Down(String),
+    /// The host could not be checked
+    Unreachable(String),
+}
+
+impl State {
+    fn name(&self) -> &'static str {
+        match self {
+            State::Healthy(_) => "healthy",
+            State::Unhealthy(_) => "unhealthy",
+            State::Down(_) => "down",
+            State::Unreachable(_) => "unreachable",
+        }
+    }
+
+    fn message(&self) -> &str {
+        match self {
+            State::Healthy(m) | State::Unhealthy(m) | State::Down(m) | State::Unreachable(m) => m,
+        }
+    }
+
+    fn is_healthy(&self) -> bool {
+        matches!(self, State::Healthy(_))
+    }
+}
+
+/// Whether going from `previous` to `current` is worth an alert: any
+/// change of state, and a first check that is not healthy.
+fn changed(previous: Option<&State>, current: &State) -> bool {
+    match previous {
+        Some(previous) => previous.name() != current.name(),
+        None => !current.is_healthy(),
+    }
+}
+
+/// Print the active jail and, when it runs, its IP address.
+fn jail_script(config: &Config) -> String {
+    format!(
+        "a=$(readlink {}/{} 2>/dev/null); a=${{a##*/}}; echo \"active $a\"; \
+         [ -n \"$a\" ] && jls -j \"$a\" ip4.addr 2>/dev/null | sed 's/^/ip /'; true",
+        ACTIVE_DIR, config.service
+    )
+}
+
+/// The active jail and its IP address, if it is running.
+fn parse_jail(output: &str) -> (Option<String>, Option<String>) {
+    let field = |key: &str| {
+        output
+            .lines()
+            .find_map(|l| l.strip_prefix(key))
+            .map(str::trim)
+            .filter(|v| !v.is_empty() && *v != "-")
+            .map(str::to_string)
+    };
+    (field("active "), field("ip "))
+}
+
+fn check_host(config: &Config, host: &str) -> State {
+    let output = match remote::run_with_output(host, &jail_script(config)) {
+        Ok(output) => output,
+        Err(e) => return State::Unreachable(format!("could not check the host: {:#}", e)),
+    };
+    let (jail, ip) = match parse_jail(&output) {
+        (None, _) => return State::Down("no active jail".to_string()),
+        (Some(jail), None) => return State::Down(format!("jail {} is not running", jail)),
+        (Some(jail), Some(ip)) => (jail, ip),
+    };
+    let Some(proxy) = &config.proxy else {
+        return State::Healthy(format!("jail {} is running", jail));
+    };
+    let Some((path, timeout)) = proxy.healthcheck.as_ref().and_then(|c| Some((c.path.as_ref()?, c.timeout))) else {
+        return State::Healthy(format!("jail {} is running", jail));
+    };
+    let backend = format!("{}:{}", ip, proxy.port);
+    match remote::run_with_output(host, &probe_command(&backend, path, timeout)) {
+        Ok(output) => {
+            let probe = Probe::parse(&output);
+            let message = format!("jail {} is {}", jail, probe.describe());
+            if probe.healthy { State::Healthy(message) } else { State::Unhealthy(message) }
+        }
+        Err(e) => State::Unreachable(format!("could not probe jail {}: {:#}", jail, e)),
+    }
+}
+
+fn report(config: &Config, host: &str, state: &State) {
+    let line = format!("{}: {}", host, state.message());
+    if state.is_healthy() {
+        ui::print_success(&line);
+    } else {
+        ui::print_warning(&line);
+    }
+    if let Some(notify) = &config.notify {
+        let alert = Alert { service: &config.service, host, state: state.name(), message: state.message() };
+        notify::send(notify, &alert);
+    }
+}
+
+/// Check the active jail and health endpoint on every host each `interval`
+/// seconds and alert on changes. `once` checks a single time, alerting on
+/// every host that is not healthy, and fails if there is one.
+pub fn run(config: &Config, interval: u64, once: bool) -> Result<()> {
+    // Every line carries the time it was printed
+    ui::set_plain(true);
+    if config.notify.is_none() {
+        ui::print_warning("No notify configured: changes are only printed");
+    }
+    if !once {
+        ui::print_step(&format!(
+            "Monitoring {} on {} hosts every {}s",
+            config.service,
+            config.hosts.len(),
+            interval
+        ));
+    }
+
+    let mut states: HashMap<String, State> = HashMap::new();
+    loop {
+        let results = parallel_hosts(&config.hosts, config.parallelism, |host| Ok(check_host(config, host)));
+        for (host, state) in config.hosts.iter().zip(results) {
+            let state = state?;
+            if changed(states.get(host), &state) {
+                report(config, host, &state);
+            }
+            states.insert(host.clone(), state);
+        }
+        if once {
+            break;
+        }
+        std::thread::sleep(Duration::from_secs(interval));
+    }
+
+    let failing = states.values().filter(|s| !s.is_healthy()).count();
+    if failing > 0 {
+        return Err(Unhealthy(format!("{} of {} hosts are not healthy", failing, config.hosts.len())).into());
+    }
+    ui::print_success(&format!("{} is healthy on {} hosts", config.service, config.hosts.len()));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_jail() {
+        assert_eq!(
+            parse_jail("active app-20260101-120000\nip 10.0.0.5\n"),
+            (Some("app-20260101-120000".to_string()), Some("10.0.0.5".to_string()))
+        );
+        assert_eq!(parse_jail("active app-20260101-120000\n"), (Some("app-20260101-120000".to_string()), None));
+        assert_eq!(parse_jail("active \n"), (None, None));
+    }
+
+    #[test]
+    fn test_changed() {
+        let healthy = State::Healthy("jail app-1 is running".into());
+        let down = State::Down("no active jail".into());
+        assert!(!changed(None, &healthy));
+        assert!(changed(None, &down));
+        assert!(changed(Some(&healthy), &State::Unhealthy("jail app-1 is unhealthy".into())));
+        assert!(changed(Some(&down), &healthy));
+        // A deploy replacing the jail is no change
+        assert!(!changed(Some(&healthy), &State::Healthy("jail app-2 is running".into())));
+    }
+}
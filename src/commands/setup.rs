@@ -1,29 +1,103 @@
-use anyhow::{Context, Result, anyhow};
+use anyhow::{Context, Result, anyhow, bail};
 
-use crate::config::Config;
+use crate::config::{Config, PrivilegeEscalation};
 use crate::constants::*;
-use crate::{caddy, rcd, remote, shell, ui};
+use crate::{doas, exit, helper, housekeeping, pf, proxy, rcd, remote, shell, tuning, ui};
 
 use super::maybe_doas;
 
-pub fn run(config: &Config, force_pf: bool) -> Result<()> {
+pub fn run(
+    config: &Config,
+    force_pf: bool,
+    install_doas: Option<doas::RootAccess>,
+    bootstrap: bool,
+    check: bool,
+    upgrade: bool,
+) -> Result<()> {
+    if check {
+        return super::setup_check::run(config);
+    }
     ui::print_step(&format!("Running setup for {} hosts", config.hosts.len()));
 
     let env_content = build_env_content(config)?;
 
+    if (bootstrap || install_doas.is_some()) && config.escalation() != PrivilegeEscalation::DoasHelper {
+        let option = if bootstrap { "--bootstrap" } else { "--install-doas" };
+        bail!(
+            "{} installs a doas rule that only permits bsdeploy-helper; set privilege_escalation: doas-helper in the configuration",
+            option
+        );
+    }
+    if bootstrap {
+        // Logged in as root; everything after runs as the deploy user
+        for host in &config.hosts {
+            ui::print_step(&format!("Bootstrapping {} as root", host));
+            doas::bootstrap(host)?;
+            ui::print_success(&format!("The deploy user can log in to {} and run bsdeploy-helper through doas", host));
+        }
+    }
+    if let Some(access) = install_doas {
+        // One host at a time: su prompts for the root password
+        for host in &config.hosts {
+            ui::print_step(&format!("Installing doas on {}", host));
+            doas::install(host, access)?;
+            ui::print_success(&format!("doas works on {}", host));
+        }
+    }
+
+    crate::preflight::run(config, &[])?;
+    for host in proxy::hosts(config) {
+        proxy::check_ports(config, &host)?;
+    }
+    super::applied::check(config);
+    let mut migrate = Vec::new();
     for host in &config.hosts {
+        migrate.push(super::upgrade::check_layout(host, &config.service, upgrade)?);
+    }
+
+    for (i, host) in config.hosts.iter().enumerate() {
+        if migrate[i] {
+            if upgrade {
+                ui::print_step(&format!("Migrating {} on {} to layout version {}", config.service, host, LAYOUT_VERSION));
+            }
+            super::upgrade::run(config, host).map_err(|e| exit::partial(e, i, config.hosts.len()))?;
+        }
         let spinner = ui::create_spinner(&format!("Setting up {}", host));
 
-        setup_host(config, host, &env_content, force_pf, &spinner)?;
+        let unchanged =
+            setup_host(config, host, &env_content, force_pf, &spinner).map_err(|e| exit::partial(e, i, config.hosts.len()))?;
 
         spinner.finish_with_message(format!("Setup complete for {}", host));
-        ui::print_success(&format!("{} setup successfully", host));
+        if unchanged.is_empty() {
+            ui::print_success(&format!("{} setup successfully", host));
+        } else {
+            ui::print_success(&format!("{} setup successfully (unchanged: {})", host, unchanged.join(", ")));
+        }
+    }
+
+    if let Some(lb) = proxy::load_balancer(config) {
+        let spinner = ui::create_spinner(&format!("Setting up load balancer {}", lb.host));
+        spinner.set_message(format!("[{}] Installing caddy...", lb.host));
+        let state = pkg_state(config, &lb.host, &["caddy".to_string()])?;
+        if state.bootstrap {
+            bootstrap_pkg(config, &lb.host)?;
+        }
+        if !state.missing.is_empty() {
+            remote::run_timeout(&lb.host, &maybe_doas("pkg install -y caddy", config.doas), remote::Timeout::Build)?;
+        }
+        let restarted = proxy::setup_load_balancer(config, lb, &spinner)?;
+        spinner.finish_with_message(format!("Setup complete for {}", lb.host));
+        if restarted {
+            ui::print_success(&format!("Load balancer {} setup successfully", lb.host));
+        } else {
+            ui::print_success(&format!("Load balancer {} setup successfully (unchanged)", lb.host));
+        }
     }
 
     Ok(())
 }
 
-fn build_env_content(config: &Config) -> Result<String> {
+pub(super) fn build_env_content(config: &Config) -> Result<String> {
     let mut env_content = String::new();
 
     for map in &config.env.clear {
@@ -45,37 +119,68 @@ fn build_env_content(config: &Config) -> Result<String> {
     Ok(env_content)
 }
 
+/// Set up one host; returns the steps that found nothing to do.
 fn setup_host(
     config: &Config,
     host: &str,
     env_content: &str,
     force_pf: bool,
     spinner: &indicatif::ProgressBar,
-) -> Result<()> {
-    // 1. Update pkg
-    spinner.set_message(format!("[{}] Updating pkg repositories...", host));
-    remote::run(host, &maybe_doas("pkg update", config.doas))?;
+) -> Result<Vec<&'static str>> {
+    let mut unchanged = Vec::new();
+
+    spinner.set_message(format!("[{}] Checking packages...", host));
+    let defaults: Vec<String> = default_packages(config).into_iter().map(str::to_string).collect();
+    let state = pkg_state(config, host, &defaults.iter().chain(&config.packages).cloned().collect::<Vec<_>>())?;
+
+    // 1. Update pkg, bootstrapping it on fresh installs
+    if state.bootstrap {
+        spinner.set_message(format!("[{}] Bootstrapping pkg...", host));
+        bootstrap_pkg(config, host)?;
+    }
+    if state.fresh {
+        unchanged.push("pkg update");
+    } else {
+        spinner.set_message(format!("[{}] Updating pkg repositories...", host));
+        remote::run(host, &maybe_doas("pkg update", config.doas))?;
+    }
 
     // 2. Install default packages (jq needed for rc.d script JSON parsing)
-    spinner.set_message(format!("[{}] Installing default packages...", host));
-    remote::run(
-        host,
-        &maybe_doas("pkg install -y caddy rsync git bash jq", config.doas),
-    )?;
+    let missing: Vec<&String> = defaults.iter().filter(|p| state.missing.contains(p)).collect();
+    if !missing.is_empty() {
+        spinner.set_message(format!("[{}] Installing default packages...", host));
+        let packages: Vec<String> = missing.iter().map(|p| shell::escape(p)).collect();
+        remote::run_timeout(
+            host,
+            &maybe_doas(&format!("pkg install -y {}", packages.join(" ")), config.doas),
+            remote::Timeout::Build,
+        )?;
+    }
 
     // 3. Create user if needed
     setup_user(config, host, spinner)?;
 
     // 4. Install user packages
-    setup_packages(config, host, spinner)?;
+    setup_packages(config, host, &state, spinner)?;
+    if state.missing.is_empty() {
+        unchanged.push("packages");
+    }
 
     // 5. Setup ZFS if available
     setup_zfs(config, host, spinner)?;
 
-    // 6. Setup directories
-    setup_directories(config, host, spinner)?;
+    // 6. Apply sysctl and loader tuning
+    setup_tuning(config, host, spinner)?;
 
-    // 7. Write env file
+    // 7. Setup directories
+    if !setup_directories(config, host, spinner)? {
+        unchanged.push("directories");
+    }
+
+    // 8. Rotate the logs on the host
+    setup_log_rotation(config, host, spinner)?;
+
+    // 9. Write env file
     let safe_service = shell::escape(&config.service);
     let config_dir = format!("{}/{}", CONFIG_DIR, safe_service);
     spinner.set_message(format!("[{}] Configuring environment...", host));
@@ -95,16 +200,33 @@ fn setup_host(
         &maybe_doas(&format!("chmod 600 {}", env_path), config.doas),
     )?;
 
-    // 8. Setup Caddy
-    setup_caddy(config, host, spinner)?;
+    // 10. Setup the reverse proxy
+    if !proxy::setup(config, host, spinner)? && proxy::package(config).is_some() {
+        unchanged.push("proxy");
+    }
 
-    // 9. Setup PF for jail NAT
+    // 11. Setup PF for jail NAT
     setup_pf(config, host, force_pf, spinner)?;
 
-    // 10. Install rc.d script for boot persistence
+    // 12. Install rc.d script for boot persistence
     setup_rcd(config, host, spinner)?;
 
-    Ok(())
+    // 13. Schedule housekeeping
+    if config.host.housekeeping.is_some() {
+        spinner.set_message(format!("[{}] Installing periodic housekeeping...", host));
+    }
+    housekeeping::apply(config, host)?;
+
+    // 14. Record what the host was set up with
+    spinner.set_message(format!("[{}] Writing the host manifest...", host));
+    super::host_manifest::write(config, host)?;
+
+    Ok(unchanged)
+}
+
+/// Packages setup installs on every host, besides the configured ones
+pub(super) fn default_packages(config: &Config) -> Vec<&'static str> {
+    proxy::package(config).into_iter().chain(["rsync", "git", "bash", "jq"]).collect()
 }
 
 fn setup_user(config: &Config, host: &str, spinner: &indicatif::ProgressBar) -> Result<()> {
@@ -130,14 +252,67 @@ fn setup_user(config: &Config, host: &str, spinner: &indicatif::ProgressBar) ->
     Ok(())
 }
 
-fn setup_packages(config: &Config, host: &str, spinner: &indicatif::ProgressBar) -> Result<()> {
-    if !config.packages.is_empty() {
+/// What `setup` finds of pkg on a host
+struct PkgState {
+    /// Only the pkg(7) bootstrap stub is installed
+    bootstrap: bool,
+    /// The catalogue was updated within `host.pkg_update_hours`
+    fresh: bool,
+    /// Requested packages that are not installed
+    missing: Vec<String>,
+}
+
+/// Command printing `fresh` when the pkg catalogue is younger than `hours`
+/// and `missing <name>` for each of `packages` that is not installed, or
+/// `bootstrap` when pkg itself is not installed yet
+fn pkg_state_command(hours: u32, packages: &[String]) -> String {
+    let mut cmd = String::from("if pkg -N >/dev/null 2>&1; then ");
+    if hours > 0 {
+        cmd.push_str(&format!(
+            "find /var/db/pkg -name 'repo-*.sqlite' -mmin -{} 2>/dev/null | grep -q . && echo fresh; ",
+            hours * 60
+        ));
+    }
+    for package in packages {
+        let safe = shell::escape(package);
+        cmd.push_str(&format!("pkg info -e {} || echo missing {}; ", safe, safe));
+    }
+    cmd.push_str("true; else echo bootstrap; fi");
+    cmd
+}
+
+fn parse_pkg_state(output: &str, packages: &[String]) -> PkgState {
+    if output.lines().any(|l| l.trim() == "bootstrap") {
+        return PkgState { bootstrap: true, fresh: false, missing: packages.to_vec() };
+    }
+    PkgState {
+        bootstrap: false,
+        fresh: output.lines().any(|l| l.trim() == "fresh"),
+        missing: output.lines().filter_map(|l| l.trim().strip_prefix("missing ")).map(str::to_string).collect(),
+    }
+}
+
+fn pkg_state(config: &Config, host: &str, packages: &[String]) -> Result<PkgState> {
+    let cmd = pkg_state_command(config.host.pkg_update_hours, packages);
+    Ok(parse_pkg_state(&remote::run_with_output_timeout(host, &cmd, remote::Timeout::Query)?, packages))
+}
+
+/// Install pkg itself, which fresh installs only have the bootstrap stub of
+fn bootstrap_pkg(config: &Config, host: &str) -> Result<()> {
+    let cmd = maybe_doas("env ASSUME_ALWAYS_YES=yes pkg bootstrap -f", config.doas);
+    remote::run_timeout(host, &cmd, remote::Timeout::Build).with_context(|| format!("Failed to bootstrap pkg on {}", host))
+}
+
+fn setup_packages(config: &Config, host: &str, state: &PkgState, spinner: &indicatif::ProgressBar) -> Result<()> {
+    let missing: Vec<&String> = config.packages.iter().filter(|p| state.missing.contains(p)).collect();
+    if !missing.is_empty() {
         spinner.set_message(format!("[{}] Installing user packages...", host));
-        let safe_pkgs: Vec<String> = config.packages.iter().map(|p| shell::escape(p)).collect();
+        let safe_pkgs: Vec<String> = missing.iter().map(|p| shell::escape(p)).collect();
         let pkgs = safe_pkgs.join(" ");
-        remote::run(
+        remote::run_timeout(
             host,
             &maybe_doas(&format!("pkg install -y {}", pkgs), config.doas),
+            remote::Timeout::Build,
         )?;
     }
     Ok(())
@@ -169,7 +344,7 @@ fn setup_zfs(config: &Config, host: &str, spinner: &indicatif::ProgressBar) -> R
                     format!(
                         "{}/{}",
                         BSDEPLOY_BASE,
-                        ds.split('/').last().unwrap_or("unknown")
+                        ds.split('/').next_back().unwrap_or("unknown")
                     )
                 };
 
@@ -183,159 +358,100 @@ fn setup_zfs(config: &Config, host: &str, spinner: &indicatif::ProgressBar) -> R
                 .ok();
             }
         }
+
+        spinner.set_message(format!("[{}] Checking ZFS properties...", host));
+        let applied = tuning::apply_zfs(config, host, &bsdeploy_root_dataset)?;
+        for change in &applied.changes {
+            ui::print_success(&format!("{}: {}", host, change));
+        }
+        if !applied.not_datasets.is_empty() {
+            ui::print_warning(&format!(
+                "{}: host.zfs.data_properties not applied to {}, which are not ZFS datasets",
+                host,
+                applied.not_datasets.join(", ")
+            ));
+        }
     }
     Ok(())
 }
 
-fn setup_directories(config: &Config, host: &str, spinner: &indicatif::ProgressBar) -> Result<()> {
-    spinner.set_message(format!("[{}] Creating directories...", host));
-
-    let safe_service = shell::escape(&config.service);
-    let app_dir = format!("{}/{}/app", APP_DATA_DIR, safe_service);
-    remote::run(
-        host,
-        &maybe_doas(&format!("mkdir -p {}", app_dir), config.doas),
-    )?;
-
-    let config_dir = format!("{}/{}", CONFIG_DIR, safe_service);
-    remote::run(
-        host,
-        &maybe_doas(&format!("mkdir -p {}", config_dir), config.doas),
-    )?;
-
-    for dir in &config.data_directories {
-        let (host_path, _) = dir.get_paths();
-        let safe_path = shell::escape(&host_path);
-        remote::run(
+fn setup_tuning(config: &Config, host: &str, spinner: &indicatif::ProgressBar) -> Result<()> {
+    spinner.set_message(format!("[{}] Applying sysctl and loader settings...", host));
+    let applied = tuning::apply(config, host)?;
+    if !applied.failed.is_empty() {
+        ui::print_warning(&format!(
+            "{}: could not set {} now; read-only tunables belong under host.loader",
             host,
-            &maybe_doas(&format!("mkdir -p {}", safe_path), config.doas),
-        )?;
+            applied.failed.join(", ")
+        ));
     }
-
-    // Create user-specific directories
-    if let Some(user) = &config.user {
-        let safe_user = shell::escape(user);
-        let run_dir = format!("{}/{}", RUN_DIR, safe_service);
-        let log_dir = format!("{}/{}", LOG_DIR, safe_service);
-
-        remote::run(
-            host,
-            &maybe_doas(&format!("mkdir -p {}", run_dir), config.doas),
-        )?;
-        remote::run(
-            host,
-            &maybe_doas(&format!("mkdir -p {}", log_dir), config.doas),
-        )?;
-        remote::run(
-            host,
-            &maybe_doas(
-                &format!("chown {}:{} {}", safe_user, safe_user, run_dir),
-                config.doas,
-            ),
-        )?;
-        remote::run(
-            host,
-            &maybe_doas(
-                &format!("chown {}:{} {}", safe_user, safe_user, log_dir),
-                config.doas,
-            ),
-        )?;
-
-        // Chown app and data directories
-        let app_data_service = format!("{}/{}", APP_DATA_DIR, safe_service);
-        remote::run(
-            host,
-            &maybe_doas(
-                &format!("chown -R {}:{} {}", safe_user, safe_user, app_data_service),
-                config.doas,
-            ),
-        )?;
-
-        for dir in &config.data_directories {
-            let (host_path, _) = dir.get_paths();
-            let safe_path = shell::escape(&host_path);
-            remote::run(
-                host,
-                &maybe_doas(
-                    &format!("chown -R {}:{} {}", safe_user, safe_user, safe_path),
-                    config.doas,
-                ),
-            )?;
-        }
+    if applied.loader_changed {
+        ui::print_warning(&format!("{}: /boot/loader.conf changed; reboot for it to apply", host));
     }
-
     Ok(())
 }
 
-fn setup_caddy(config: &Config, host: &str, spinner: &indicatif::ProgressBar) -> Result<()> {
-    spinner.set_message(format!("[{}] Configuring Caddy...", host));
-
-    remote::run(host, &maybe_doas("sysrc caddy_enable=YES", config.doas))?;
-    remote::run(
+fn setup_log_rotation(config: &Config, host: &str, spinner: &indicatif::ProgressBar) -> Result<()> {
+    spinner.set_message(format!("[{}] Installing host log rotation...", host));
+    remote::ensure_dir(host, NEWSYSLOG_CONF_DIR, config.doas)?;
+    remote::write_file(
         host,
-        &maybe_doas(&format!("mkdir -p {}", CADDY_CONF_DIR), config.doas),
-    )?;
+        &super::deploy::host_newsyslog_entries(config),
+        &super::deploy::host_newsyslog_path(config),
+        config.doas,
+    )
+}
 
-    // Create certs directory if SSL config is present
-    if let Some(proxy) = &config.proxy {
-        if proxy.ssl.is_some() {
-            remote::run(
-                host,
-                &maybe_doas(&format!("mkdir -p {}", CADDY_CERTS_DIR), config.doas),
-            )?;
-        }
+/// Script creating the service's directories and handing them to the
+/// service user, escalating each change; it prints a line for each one it
+/// had to change.
+fn directories_script(config: &Config) -> String {
+    let safe_service = shell::escape(&config.service);
+    let data: Vec<String> = config.data_directories.iter().map(|d| shell::escape(&d.get_paths().0)).collect();
+
+    let mut script = format!(
+        r#"set -e
+dir() {{ [ -d "$1" ] || {{ {p}mkdir -p "$1" && echo "created $1"; }}; }}
+own() {{ [ "$(stat -f %Su:%Sg "$1")" = "$2:$2" ] || {{ {p}chown "$2:$2" "$1" && echo "chowned $1"; }}; }}
+own_tree() {{
+    if {p}find "$1" \( ! -user "$2" -o ! -group "$2" \) -print -quit | grep -q .; then {p}chown -R "$2:$2" "$1"; echo "chowned $1"; fi
+}}
+"#,
+        p = shell::escalation_prefix(config.doas)
+    );
+    script.push_str(&format!("dir {}/{}/app
+", APP_DATA_DIR, safe_service));
+    script.push_str(&format!("dir {}/{}
+", CONFIG_DIR, safe_service));
+    for path in &data {
+        script.push_str(&format!("dir {}
+", path));
     }
 
-    // Check/Create main Caddyfile
-    let check_caddyfile = remote::run(host, &format!("test -f {}", CADDYFILE_PATH));
-
-    if check_caddyfile.is_err() {
-        let default_caddy = "import conf.d/*.caddy\n";
-        remote::write_file(host, default_caddy, CADDYFILE_PATH, config.doas)?;
-    } else {
-        let check_import = remote::run(
-            host,
-            &format!("grep -q 'import conf.d/\\*.caddy' {}", CADDYFILE_PATH),
-        );
-        if check_import.is_err() {
-            ui::print_step(&format!("Appending import to {}", CADDYFILE_PATH));
-            let append_cmd = if config.doas {
-                format!(
-                    "echo 'import conf.d/*.caddy' | doas tee -a {} > /dev/null",
-                    CADDYFILE_PATH
-                )
-            } else {
-                format!(
-                    "echo 'import conf.d/*.caddy' | tee -a {} > /dev/null",
-                    CADDYFILE_PATH
-                )
-            };
-            remote::run(host, &append_cmd)?;
+    if let Some(user) = &config.user {
+        let safe_user = shell::escape(user);
+        for dir in [RUN_DIR, LOG_DIR] {
+            script.push_str(&format!("dir {d}/{s}
+own {d}/{s} {u}
+", d = dir, s = safe_service, u = safe_user));
         }
-    }
-
-    // Proxy config
-    if let Some(proxy) = &config.proxy {
-        // Handle SSL certificates if configured
-        if let Some(ssl) = &proxy.ssl {
-            spinner.set_message(format!("[{}] Writing TLS certificates...", host));
-            caddy::write_ssl_certificates(config, host, ssl)?;
+        script.push_str(&format!("own_tree {}/{} {}
+", APP_DATA_DIR, safe_service, safe_user));
+        for path in &data {
+            script.push_str(&format!("own_tree {} {}
+", path, safe_user));
         }
-
-        let backend = format!(":{}", proxy.port);
-        let proxy_conf_content = caddy::generate_caddyfile(proxy, &config.service, &backend);
-        let proxy_conf_path = format!("{}/{}.caddy", CADDY_CONF_DIR, config.service);
-        remote::write_file(host, &proxy_conf_content, &proxy_conf_path, config.doas)?;
     }
-
-    // Restart caddy
-    remote::run(host, &maybe_doas("service caddy enable", config.doas))?;
-    remote::run(host, &maybe_doas("service caddy restart", config.doas))?;
-
-    Ok(())
+    script
 }
 
-const BSDEPLOY_PF_MARKER: &str = "# PF configuration for bsdeploy jails";
+/// Create the service's directories; returns whether any had to change.
+fn setup_directories(config: &Config, host: &str, spinner: &indicatif::ProgressBar) -> Result<bool> {
+    spinner.set_message(format!("[{}] Creating directories...", host));
+    let cmd = format!("sh -c {}", shell::escape(&directories_script(config)));
+    let output = remote::run_with_output_timeout(host, &cmd, remote::Timeout::Command)?;
+    Ok(!output.trim().is_empty())
+}
 
 fn setup_pf(
     config: &Config,
@@ -346,75 +462,50 @@ fn setup_pf(
     spinner.set_message(format!("[{}] Checking PF configuration...", host));
 
     // Check current state of pf.conf
-    let pf_conf_exists = remote::run(host, "test -s /etc/pf.conf").is_ok();
-    let has_bsdeploy_marker = pf_conf_exists
-        && remote::run(
-            host,
-            &format!("grep -q '{}' /etc/pf.conf", BSDEPLOY_PF_MARKER),
-        )
-        .is_ok();
+    let current = remote::run_with_output_timeout(host, "cat /etc/pf.conf 2>/dev/null || true", remote::Timeout::Query)?;
 
     // Determine action based on state
-    // - No file exists → create from scratch
-    // - File exists with our marker → update (continue)
-    // - File exists without marker → error unless --force-pf (then append)
-    if pf_conf_exists && !has_bsdeploy_marker && !force_pf {
+    // - No file exists → create with the hooks and a permissive ruleset
+    // - File written by bsdeploy → update the hooks
+    // - Hooks already added by the operator → leave the file alone
+    // - Operator's file without hooks → error unless --force-pf (then insert them)
+    let conf = if current.trim().is_empty() {
+        Some(format!("{}\n# Allow all traffic (permissive ruleset)\npass all\n", pf::hooks()))
+    } else if pf::is_managed(&current) || (force_pf && !pf::has_hooks(&current)) {
+        Some(pf::with_hooks(&current))
+    } else if pf::has_hooks(&current) {
+        None
+    } else {
         return Err(anyhow!(
-            "PF is already configured on {} with custom rules. Use --force-pf to append bsdeploy rules.",
-            host
+            "PF is already configured on {} with custom rules. Add these lines to /etc/pf.conf before the filter rules, or use --force-pf to insert them:\n{}",
+            host,
+            pf::hooks()
         ));
-    }
+    };
 
     // Detect the external interface (interface used for default route)
     spinner.set_message(format!("[{}] Detecting external interface...", host));
-    let ext_if = detect_external_interface(host)?;
+    let ext_if = pf::external_interface(host)?;
 
     // Get jail IP range from config
-    let jail_net = config
-        .jail
-        .as_ref()
-        .and_then(|j| j.ip_range.as_deref())
-        .unwrap_or(DEFAULT_IP_RANGE);
-
-    // Generate bsdeploy PF rules
-    let bsdeploy_rules = format!(
-        r#"{}
-# Generated by bsdeploy setup
-
-ext_if = "{}"
-jail_net = "{}"
-
-# NAT for jail network
-nat on $ext_if from $jail_net to any -> ($ext_if)
-"#,
-        BSDEPLOY_PF_MARKER, ext_if, jail_net
-    );
+    let jail_net = config.jail_ip_range(host);
 
-    // Write PF configuration
+    // The NAT rules go into their anchor first, so pf.conf can load them
     spinner.set_message(format!("[{}] Writing PF configuration...", host));
-
-    if !pf_conf_exists {
-        // No existing file - create with our rules + permissive pass all
-        let full_conf = format!("{}\n# Allow all traffic (permissive ruleset)\npass all\n", bsdeploy_rules);
-        remote::write_file(host, &full_conf, "/etc/pf.conf", config.doas)?;
-    } else if has_bsdeploy_marker {
-        // Our marker exists - replace bsdeploy section
-        // Remove old bsdeploy block first
-        let remove_old_cmd = format!(
-            "sed -i '' '/^{}$/,/^# NAT for jail network$/{{/^# NAT for jail network$/!d;}}; /^# NAT for jail network$/d; /^nat on \\$ext_if from \\$jail_net/d; /^ext_if = /d; /^jail_net = /d; /^# Generated by bsdeploy/d; /^$/{{N;/^\\n$/d;}}' /etc/pf.conf",
-            BSDEPLOY_PF_MARKER
+    pf::load_nat(config, host, &ext_if, jail_net)?;
+    if config.host.firewall {
+        spinner.set_message(format!("[{}] Loading the firewall...", host));
+    }
+    pf::apply_firewall(config, host, &ext_if)?;
+
+    if let Some(conf) = conf.filter(|c| *c != current) {
+        // Check the new file before it replaces the old one
+        remote::write_file(host, &conf, "/tmp/bsdeploy_pf.conf", config.doas)?;
+        let install_cmd = format!(
+            "{p}pfctl -nf /tmp/bsdeploy_pf.conf && {p}mv /tmp/bsdeploy_pf.conf /etc/pf.conf",
+            p = shell::escalation_prefix(config.doas)
         );
-        remote::run(host, &maybe_doas(&remove_old_cmd, config.doas))?;
-
-        // Write new rules to temp file, then prepend to existing config
-        remote::write_file(host, &bsdeploy_rules, "/tmp/bsdeploy_pf.conf", config.doas)?;
-        let prepend_cmd = "sh -c 'cat /tmp/bsdeploy_pf.conf /etc/pf.conf > /tmp/pf.conf.new && mv /tmp/pf.conf.new /etc/pf.conf && rm /tmp/bsdeploy_pf.conf'";
-        remote::run(host, &maybe_doas(prepend_cmd, config.doas))?;
-    } else {
-        // Existing file without our marker - prepend our rules (--force-pf was used)
-        remote::write_file(host, &bsdeploy_rules, "/tmp/bsdeploy_pf.conf", config.doas)?;
-        let prepend_cmd = "sh -c 'cat /tmp/bsdeploy_pf.conf /etc/pf.conf > /tmp/pf.conf.new && mv /tmp/pf.conf.new /etc/pf.conf && rm /tmp/bsdeploy_pf.conf'";
-        remote::run(host, &maybe_doas(prepend_cmd, config.doas))?;
+        remote::run(host, &install_cmd).context("The new /etc/pf.conf does not load")?;
     }
 
     // Enable IP forwarding (gateway)
@@ -442,27 +533,68 @@ nat on $ext_if from $jail_net to any -> ($ext_if)
 fn setup_rcd(config: &Config, host: &str, spinner: &indicatif::ProgressBar) -> Result<()> {
     spinner.set_message(format!("[{}] Installing boot persistence script...", host));
 
+    let installed = remote::query(host, &rcd::versions_command(config))?;
+    if let Some(old) = rcd::outdated(&installed) {
+        ui::print_success(&format!(
+            "{}: updating the rc.d scripts ({} → version {})",
+            host,
+            rcd::describe_version(&old),
+            rcd::RCD_VERSION
+        ));
+    }
     rcd::install_rcd_script(host, config.doas)?;
     rcd::enable_service(host, config.doas)?;
+    rcd::install_service_script(config, host)?;
     rcd::ensure_active_dir(host, config.doas)?;
 
+    if config.helper {
+        helper::ensure_installed(host, config.doas)?;
+    }
+
     Ok(())
 }
 
-fn detect_external_interface(host: &str) -> Result<String> {
-    // Get the interface used for the default route
-    let output = remote::run_with_output(
-        host,
-        "route -n get default 2>/dev/null | grep 'interface:' | awk '{print $2}'",
-    )?;
-
-    let iface = output.trim().to_string();
-    if iface.is_empty() {
-        return Err(anyhow!(
-            "Could not detect external interface on {}. No default route found.",
-            host
-        ));
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pkg_state() {
+        let packages = ["jq".to_string(), "py311-pip".to_string()];
+        assert_eq!(
+            pkg_state_command(24, &packages),
+            "if pkg -N >/dev/null 2>&1; then \
+             find /var/db/pkg -name 'repo-*.sqlite' -mmin -1440 2>/dev/null | grep -q . && echo fresh; \
+             pkg info -e jq || echo missing jq; pkg info -e py311-pip || echo missing py311-pip; \
+             true; else echo bootstrap; fi"
+        );
+        assert_eq!(pkg_state_command(0, &[]), "if pkg -N >/dev/null 2>&1; then true; else echo bootstrap; fi");
+
+        let state = parse_pkg_state("fresh\nmissing py311-pip\n", &packages);
+        assert!(state.fresh && !state.bootstrap);
+        assert_eq!(state.missing, ["py311-pip"]);
+        assert!(!parse_pkg_state("", &packages).fresh);
+        let state = parse_pkg_state("bootstrap\n", &packages);
+        assert!(state.bootstrap && !state.fresh);
+        assert_eq!(state.missing, packages);
     }
 
-    Ok(iface)
+    #[test]
+    fn test_directories_script() {
+        let config = Config::from_str("service: app\nhosts: [web1]\nuser: app\ndata_directories: [/var/db/app]\n").unwrap();
+        let script = directories_script(&config);
+        assert!(script.starts_with("set -e\n"));
+        assert!(script.contains("dir /var/db/bsdeploy/app/app\ndir /usr/local/etc/bsdeploy/app\ndir /var/db/app\n"));
+        assert!(script.contains("dir /var/run/bsdeploy/app\nown /var/run/bsdeploy/app app\n"));
+        assert!(script.ends_with("own_tree /var/db/bsdeploy/app app\nown_tree /var/db/app app\n"));
+
+        let config = Config::from_str("service: app\nhosts: [web1]\n").unwrap();
+        assert!(directories_script(&config).ends_with("fi\n}\ndir /var/db/bsdeploy/app/app\ndir /usr/local/etc/bsdeploy/app\n"));
+
+        // The script runs as the SSH user and escalates each change
+        let config = Config::from_str("service: app\nhosts: [web1]\nuser: app\ndoas: true\n").unwrap();
+        let script = directories_script(&config);
+        assert!(script.contains("dir() { [ -d \"$1\" ] || { doas mkdir -p \"$1\" && echo \"created $1\"; }; }\n"));
+        assert!(script.contains("then doas chown -R \"$2:$2\" \"$1\";"));
+    }
 }
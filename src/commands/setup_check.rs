@@ -0,0 +1,297 @@
+//! `setup --check`: compare every host with what `setup` would make of it
+//! and report the differences without changing anything, e.g. for
+//! periodic compliance checks of production hosts in CI.
+
+use anyhow::Result;
+use sha2::{Digest, Sha256};
+
+use crate::config::{Config, Period};
+use crate::constants::*;
+use crate::exit::Drift;
+use crate::{helper, housekeeping, pf, proxy, rcd, remote, shell, tuning, ui};
+
+use super::parallel_hosts;
+use super::deploy::{host_newsyslog_entries, host_newsyslog_path};
+use super::setup::{build_env_content, default_packages};
+
+/// Marker for difference lines in the script output
+const DRIFT: &str = "DRIFT:";
+
+fn sha256(content: &str) -> String {
+    hex::encode(Sha256::digest(content.as_bytes()))
+}
+
+/// Read-only script printing a `DRIFT:` line for everything setup would
+/// change. `env_sha` is the checksum of the env file setup would write.
+fn script(config: &Config, env_sha: Option<&str>) -> String {
+    let p = shell::escalation_prefix(config.doas);
+    let mut s = format!("drift() {{ echo \"{} would $*\"; }}\n", DRIFT);
+    let enabled = |name: &str| {
+        format!("[ \"$(sysrc -qn {name}_enable)\" = YES ] || drift \"enable {name} (sysrc {name}_enable=YES)\"\n", name = name)
+    };
+
+    // Packages
+    let packages: Vec<String> = default_packages(config)
+        .into_iter()
+        .map(str::to_string)
+        .chain(config.packages.iter().map(|p| shell::escape(p)))
+        .collect();
+    s.push_str(&format!(
+        "for p in {}; do pkg info -e \"$p\" || drift \"install package $p\"; done\n",
+        packages.join(" ")
+    ));
+
+    // User
+    if let Some(user) = &config.user {
+        s.push_str(&format!("id {u} >/dev/null 2>&1 || drift \"create user {u}\"\n", u = shell::escape(user)));
+    }
+
+    // ZFS datasets and their properties, when / is on ZFS
+    s.push_str(tuning::ZFS_CHECK);
+    s.push_str(&format!(
+        "d=$(df / | tail -n 1 | awk '{{print $1}}')\n\
+         case \"$d\" in ''|/*) ;; *)\n\
+         for ds in bsdeploy bsdeploy/base bsdeploy/images bsdeploy/jails; do \
+         zfs list -H -o name \"${{d%%/*}}/$ds\" >/dev/null 2>&1 || drift \"create dataset ${{d%%/*}}/$ds\"; done\n\
+         root=\"${{d%%/*}}/bsdeploy\"\n{};;\n\
+         esac\n",
+        tuning::zfs_script(config)
+    ));
+
+    // Directories and their owner
+    let service = &config.service;
+    let mut dirs = vec![format!("{}/{}/app", APP_DATA_DIR, service), format!("{}/{}", CONFIG_DIR, service)];
+    let data_dirs: Vec<String> = config.data_directories.iter().map(|d| d.get_paths().0).collect();
+    dirs.extend(data_dirs.iter().cloned());
+    let mut owned = Vec::new();
+    if config.user.is_some() {
+        let run_log = [format!("{}/{}", RUN_DIR, service), format!("{}/{}", LOG_DIR, service)];
+        dirs.extend(run_log.iter().cloned());
+        owned.extend(run_log);
+        owned.push(format!("{}/{}", APP_DATA_DIR, service));
+        owned.extend(data_dirs);
+    }
+    for dir in &dirs {
+        s.push_str(&format!("[ -d {d} ] || drift \"create directory {d}\"\n", d = shell::escape(dir)));
+    }
+    if let Some(user) = &config.user {
+        for dir in &owned {
+            s.push_str(&format!(
+                "o=$(stat -f %Su {d} 2>/dev/null) && [ \"$o\" != {u} ] && drift \"chown {d} to {u} (owned by $o)\"\n",
+                d = shell::escape(dir),
+                u = shell::escape(user)
+            ));
+        }
+    }
+
+    // Env file
+    let env_path = format!("{}/{}/env", CONFIG_DIR, service);
+    s.push_str(&format!(
+        "if [ -f {f} ]; then m=$(stat -f %Lp {f}); [ \"$m\" = 600 ] || drift \"chmod 600 {f} (mode $m)\"\n",
+        f = env_path
+    ));
+    if let Some(user) = &config.user {
+        s.push_str(&format!(
+            "o=$(stat -f %Su {f}); [ \"$o\" = {u} ] || drift \"chown {f} to {u} (owned by $o)\"\n",
+            f = env_path,
+            u = shell::escape(user)
+        ));
+    }
+    if let Some(sha) = env_sha {
+        s.push_str(&format!(
+            "[ \"$({p}sha256 -q {f} </dev/null 2>/dev/null)\" = {sha} ] || drift \"update {f}\"\n",
+            p = p,
+            f = env_path,
+            sha = sha
+        ));
+    }
+    s.push_str(&format!("else drift \"write {}\"; fi\n", env_path));
+
+    // Host log rotation
+    s.push_str(&format!(
+        "[ \"$(sha256 -q {f} 2>/dev/null)\" = {sha} ] || drift \"install {f}\"\n",
+        f = host_newsyslog_path(config),
+        sha = sha256(&host_newsyslog_entries(config))
+    ));
+
+    // Reverse proxy
+    if let Some(package) = proxy::package(config) {
+        s.push_str(&enabled(package));
+    }
+
+    // PF
+    for rule in pf::HOOK_RULES {
+        s.push_str(&format!(
+            "grep -qxF {r} /etc/pf.conf 2>/dev/null || drift \"add '{rule}' to /etc/pf.conf\"\n",
+            r = shell::escape(rule),
+            rule = rule.replace('"', "\\\"")
+        ));
+    }
+    s.push_str(&format!("[ -f {f} ] || drift \"write the NAT rules to {f}\"\n", f = pf::nat_rules_path()));
+    if config.host.firewall {
+        s.push_str(&format!(
+            "[ -f {f} ] && {p}pfctl -a {a} -s rules </dev/null 2>/dev/null | grep -q . || drift \"load the firewall into {a}\"\n",
+            f = pf::firewall_rules_path(),
+            p = p,
+            a = pf::FIREWALL_ANCHOR
+        ));
+    } else {
+        s.push_str(&format!("[ -f {f} ] && drift \"remove the firewall in {f}\"\n", f = pf::firewall_rules_path()));
+    }
+    s.push_str(&enabled("pf"));
+    s.push_str(&enabled("gateway"));
+    s.push_str("[ \"$(sysctl -n net.inet.ip.forwarding)\" = 1 ] || drift \"enable IP forwarding (sysctl net.inet.ip.forwarding=1)\"\n");
+
+    // Host tuning
+    for (name, value) in &config.host.sysctls {
+        s.push_str(&format!(
+            "v=$(sysctl -n {n} 2>/dev/null); [ \"$v\" = {v} ] || drift \"set {n}={value} (is $v)\"\n",
+            n = name,
+            v = shell::escape(value),
+            value = value
+        ));
+    }
+    for (name, value) in &config.host.loader {
+        s.push_str(&format!(
+            "v=$(sysrc -qn -f /boot/loader.conf {n}); [ \"$v\" = {v} ] || drift \"set {n}={value} in /boot/loader.conf\"\n",
+            n = name,
+            v = shell::escape(value),
+            value = value
+        ));
+    }
+
+    // Boot persistence
+    for (path, content) in [
+        (rcd::RCD_PATH.to_string(), rcd::host_script()),
+        (rcd::service_script_path(service), rcd::service_script(config)),
+    ] {
+        s.push_str(&format!(
+            "[ \"$(sha256 -q {f} 2>/dev/null)\" = {sha} ] || drift \"install {f}\"\n",
+            f = path,
+            sha = sha256(&content)
+        ));
+    }
+    s.push_str(&enabled("bsdeploy"));
+    // The operator may disable the service at boot on purpose
+    s.push_str(&format!(
+        "[ -n \"$(sysrc -qn {rc}_enable)\" ] || drift \"enable {rc} (sysrc {rc}_enable=YES)\"\n",
+        rc = rcd::rc_name(service)
+    ));
+    s.push_str(&format!("[ -d {d} ] || drift \"create directory {d}\"\n", d = ACTIVE_DIR));
+    for period in Period::ALL {
+        let path = housekeeping::script_path(period);
+        if config.host.housekeeping == Some(period) {
+            s.push_str(&format!(
+                "[ \"$(sha256 -q {f} 2>/dev/null)\" = {sha} ] || drift \"install {f}\"\n",
+                f = path,
+                sha = sha256(&housekeeping::script(period))
+            ));
+        } else {
+            s.push_str(&format!("[ -f {f} ] && drift \"remove {f}\"\n", f = path));
+        }
+    }
+    s.push_str(&format!(
+        "[ \"$(jq -r '\"\\(.bsdeploy_version) \\(.layout_version)\"' {f} 2>/dev/null)\" = \"{v} {l}\" ] || drift \"record {f}\"\n",
+        f = HOST_MANIFEST_PATH,
+        v = super::host_manifest::VERSION,
+        l = LAYOUT_VERSION
+    ));
+    if config.helper || config.host.housekeeping.is_some() {
+        s.push_str(&format!(
+            "[ \"$({h} version 2>/dev/null)\" = {v} ] || drift \"install {h} version {v}\"\n",
+            h = HELPER_PATH,
+            v = helper::HELPER_VERSION
+        ));
+    }
+    s
+}
+
+fn parse(output: &str) -> Vec<String> {
+    output
+        .lines()
+        .filter_map(|line| line.strip_prefix(DRIFT))
+        .map(|d| d.trim().to_string())
+        .collect()
+}
+
+/// Report what setup would change on each host. Fails with `Drift` if
+/// anything differs.
+pub fn run(config: &Config) -> Result<()> {
+    let env_sha = match build_env_content(config) {
+        Ok(content) => Some(sha256(&content)),
+        Err(e) => {
+            ui::print_warning(&format!("Not comparing the env file: {:#}", e));
+            None
+        }
+    };
+    let script = script(config, env_sha.as_deref());
+
+    let spinner = ui::create_spinner(&format!("Checking {} hosts", config.hosts.len()));
+    let results = parallel_hosts(&config.hosts, config.parallelism, |host| {
+        remote::run_with_output(host, &script).map(|out| parse(&out))
+    });
+    spinner.finish_and_clear();
+
+    let mut changes = 0;
+    let mut drifted = 0;
+    let mut failure = None;
+    for (host, result) in config.hosts.iter().zip(results) {
+        match result {
+            Ok(found) if found.is_empty() => ui::print_success(&format!("{} matches the configuration", host)),
+            Ok(found) => {
+                for change in &found {
+                    ui::print_warning(&format!("{}: {}", host, change));
+                }
+                changes += found.len();
+                drifted += 1;
+            }
+            Err(e) => {
+                ui::print_error(&format!("{}: could not check: {:#}", host, e));
+                failure.get_or_insert(e);
+            }
+        }
+    }
+    // Differences from the last deploy, e.g. hand-edited proxy sites
+    super::applied::check(config);
+
+    if let Some(e) = failure {
+        return Err(e);
+    }
+    if changes > 0 {
+        return Err(Drift(format!("setup would make {} changes on {} of {} hosts", changes, drifted, config.hosts.len())).into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_script() {
+        let config = Config::from_str(
+            "service: app\nhosts: [a]\nuser: app\npackages: [postgresql16-client]\n\
+             proxy: { hostname: app.example.com, port: 3000 }\nhost: { sysctls: { kern.ipc.somaxconn: 1024 } }\n",
+        )
+        .unwrap();
+        let script = script(&config, Some("abc"));
+        assert!(script.contains(&format!(
+            "[ \"$(jq -r '\"\\(.bsdeploy_version) \\(.layout_version)\"' /usr/local/etc/bsdeploy/host.json 2>/dev/null)\" = \"{} {}\" ] || drift",
+            crate::commands::host_manifest::VERSION,
+            LAYOUT_VERSION
+        )));
+        assert!(script.contains("for p in caddy rsync git bash jq postgresql16-client; do pkg info -e \"$p\" || drift \"install package $p\"; done\n"));
+        assert!(script.contains("id app >/dev/null 2>&1 || drift \"create user app\"\n"));
+        assert!(script.contains("o=$(stat -f %Su /var/log/bsdeploy/app 2>/dev/null) && [ \"$o\" != app ] && drift \"chown /var/log/bsdeploy/app to app (owned by $o)\"\n"));
+        assert!(script.contains("[ \"$(sha256 -q /usr/local/etc/bsdeploy/app/env </dev/null 2>/dev/null)\" = abc ] || drift \"update /usr/local/etc/bsdeploy/app/env\"\n"));
+        assert!(script.contains("[ \"$(sysrc -qn caddy_enable)\" = YES ] || drift \"enable caddy (sysrc caddy_enable=YES)\"\n"));
+        assert!(script.contains("grep -qxF 'anchor \"bsdeploy/*\"' /etc/pf.conf 2>/dev/null || drift \"add 'anchor \\\"bsdeploy/*\\\"' to /etc/pf.conf\"\n"));
+        assert!(script.contains("v=$(sysctl -n kern.ipc.somaxconn 2>/dev/null); [ \"$v\" = 1024 ] || drift \"set kern.ipc.somaxconn=1024 (is $v)\"\n"));
+        assert!(!script.contains("bsdeploy-helper"));
+
+        assert_eq!(
+            parse("DRIFT: would enable caddy (sysrc caddy_enable=YES)\nnoise\nDRIFT: would install package jq\n"),
+            ["would enable caddy (sysrc caddy_enable=YES)", "would install package jq"]
+        );
+    }
+}
@@ -1,8 +1,9 @@
 use anyhow::Result;
+use serde::Deserialize;
 
 use crate::config::Config;
 use crate::constants::*;
-use crate::{remote, ui};
+use crate::{pf, proxy, remote, shell, ui};
 
 pub fn run(config: &Config) -> Result<()> {
     ui::print_step(&format!(
@@ -12,7 +13,7 @@ pub fn run(config: &Config) -> Result<()> {
     ));
 
     for host in &config.hosts {
-        println!();
+        ui::print_output("");
         show_host_status(config, host)?;
     }
 
@@ -20,24 +21,36 @@ pub fn run(config: &Config) -> Result<()> {
 }
 
 fn show_host_status(config: &Config, host: &str) -> Result<()> {
-    println!("Host: {}", host);
-    println!("{}", "─".repeat(60));
+    ui::print_output(&format!("Host: {}", host));
+    ui::print_output(&"─".repeat(60));
 
     // Get list of jails for this service
     let ls_cmd = format!(
         "ls -1t {}/ 2>/dev/null | grep '^{}-' || true",
         JAILS_DIR, config.service
     );
-    let jails_output = remote::run_with_output(host, &ls_cmd)?;
+    let jails_output = remote::query(host, &ls_cmd)?;
     let jails: Vec<&str> = jails_output
         .lines()
         .map(|s| s.trim())
         .filter(|s| !s.is_empty())
         .collect();
 
+    // Disk usage is informational; hosts without `du`/`zfs` access just skip it
+    let usage = remote::run_with_output(host, &usage_script(config))
+        .map(|out| DiskUsage::parse(&out))
+        .ok();
+
+    let deployed = remote::run_with_output(host, &metadata_script(config))
+        .map(|out| parse_metadata(&out))
+        .unwrap_or_default();
+
     if jails.is_empty() {
-        println!("  No jails found for service '{}'", config.service);
-        println!();
+        ui::print_output(&format!("  No jails found for service '{}'", config.service));
+        if let Some(usage) = &usage {
+            print_disk_usage(usage, &deployed);
+        }
+        ui::print_output("");
         return Ok(());
     }
 
@@ -46,15 +59,15 @@ fn show_host_status(config: &Config, host: &str) -> Result<()> {
         "jls -N name 2>/dev/null | grep '^{}-' || true",
         config.service
     );
-    let running_output = remote::run_with_output(host, &running_cmd)?;
+    let running_output = remote::query(host, &running_cmd)?;
     let running_jails: Vec<&str> = running_output
         .lines()
         .map(|s| s.trim())
         .filter(|s| !s.is_empty())
         .collect();
 
-    println!("  Jails ({} total, {} running):", jails.len(), running_jails.len());
-    println!();
+    ui::print_output(&format!("  Jails ({} total, {} running):", jails.len(), running_jails.len()));
+    ui::print_output("");
 
     for (i, jail_name) in jails.iter().enumerate() {
         let is_running = running_jails.contains(jail_name);
@@ -64,7 +77,7 @@ fn show_host_status(config: &Config, host: &str) -> Result<()> {
         // Get IP if running
         let ip = if is_running {
             let ip_cmd = format!("jls -j {} ip4.addr 2>/dev/null || echo '-'", jail_name);
-            remote::run_with_output(host, &ip_cmd)
+            remote::query(host, &ip_cmd)
                 .map(|s| s.trim().to_string())
                 .unwrap_or_else(|_| "-".to_string())
         } else {
@@ -76,38 +89,431 @@ fn show_host_status(config: &Config, host: &str) -> Result<()> {
 
         let marker = if i == 0 && is_running { " (current)" } else { "" };
 
-        println!(
-            "  {} {:<40} {:>8}  IP: {:<15}  Created: {}{}",
-            status_icon, jail_name, status_text, ip, created, marker
-        );
+        let size = usage
+            .as_ref()
+            .and_then(|u| u.jails.iter().find(|e| e.name == *jail_name))
+            .map(Entry::describe)
+            .unwrap_or_else(|| "-".to_string());
+
+        ui::print_output(&format!(
+            "  {} {:<40} {:>8}  IP: {:<15}  Created: {}  Size: {}{}",
+            status_icon, jail_name, status_text, ip, created, size, marker
+        ));
+
+        if let Some((_, deployed)) = deployed.iter().find(|(name, _)| name == jail_name) {
+            ui::print_output(&format!("      {}", deployed.describe()));
+        }
+
+        if is_running
+            && let Ok(output) = remote::run_with_output(host, &processes_command(config, jail_name))
+            && let Some(processes) = describe_processes(&output)
+        {
+            ui::print_output(&format!("      Processes: {}", processes));
+        }
+
+        if is_running
+            && let Ok(output) = remote::run_with_output(host, &resources_command(config, jail_name))
+        {
+            ui::print_output(&format!("      {}", Resources::parse(&output).describe()));
+        }
+    }
+
+    if let Some(usage) = &usage {
+        print_disk_usage(usage, &deployed);
     }
 
     // Show proxy info if configured
     if let Some(proxy) = &config.proxy {
-        println!();
-        let caddy_conf = format!("{}/{}.caddy", CADDY_CONF_DIR, config.service);
-        let cat_cmd = format!("cat {} 2>/dev/null || echo 'not configured'", caddy_conf);
-        if let Ok(conf) = remote::run_with_output(host, &cat_cmd) {
-            let conf = conf.trim();
-            if conf != "not configured" {
-                // Extract backend from reverse_proxy line
-                if let Some(line) = conf.lines().find(|l| l.contains("reverse_proxy")) {
-                    let backend = line
-                        .trim()
-                        .strip_prefix("reverse_proxy ")
-                        .unwrap_or("-");
-                    println!("  Proxy: {} → {}", proxy.hostname, backend);
+        ui::print_output("");
+        match proxy::current_backend(config, host) {
+            Ok(Some(backend)) => {
+                ui::print_output(&format!("  Proxy: {} → {}", proxy.hostname, backend));
+                if let Some(check) = &proxy.healthcheck
+                    && let Some(path) = &check.path
+                {
+                    let probe = remote::run_with_output(host, &probe_command(&backend, path, check.timeout))
+                        .map(|out| Probe::parse(&out));
+                    match probe {
+                        Ok(probe) => ui::print_output(&format!("  Health: {}", probe.describe())),
+                        Err(e) => ui::print_output(&format!("  Health: probe failed: {:#}", e)),
+                    }
                 }
-            } else {
-                println!("  Proxy: not configured");
             }
+            Ok(None) => ui::print_output("  Proxy: not configured"),
+            Err(_) => {}
+        }
+    } else if config.bind.is_some() {
+        ui::print_output("");
+        match pf::current_target(config, host) {
+            Ok(Some(ip)) => ui::print_output(&format!("  Ports: redirected to {}", ip)),
+            Ok(None) => ui::print_output("  Ports: not redirected"),
+            Err(_) => {}
         }
     }
 
-    println!();
+    ui::print_output("");
     Ok(())
 }
 
+#[derive(Debug, PartialEq)]
+struct Process {
+    pid: u32,
+    pcpu: f64,
+    rss_kb: u64,
+    command: String,
+}
+
+/// CPU and memory use of a running jail
+#[derive(Debug, Default, PartialEq)]
+struct Resources {
+    processes: Vec<Process>,
+    /// `memoryuse` from rctl, when RACCT is enabled on the host
+    memoryuse: Option<u64>,
+}
+
+impl Resources {
+    /// Parse `ps -o pid=,pcpu=,rss=,comm=` lines and the prefixed `rctl -u` ones.
+    fn parse(output: &str) -> Self {
+        let mut resources = Self::default();
+        for line in output.lines() {
+            if let Some(usage) = line.strip_prefix("rctl ") {
+                if let Some(bytes) = usage.trim().strip_prefix("memoryuse=") {
+                    resources.memoryuse = bytes.parse().ok();
+                }
+                continue;
+            }
+            let mut fields = line.split_whitespace();
+            let (Some(pid), Some(pcpu), Some(rss)) = (fields.next(), fields.next(), fields.next()) else {
+                continue;
+            };
+            let (Ok(pid), Ok(pcpu), Ok(rss_kb)) = (pid.parse(), pcpu.parse(), rss.parse()) else {
+                continue;
+            };
+            let command = fields.collect::<Vec<_>>().join(" ");
+            resources.processes.push(Process { pid, pcpu, rss_kb, command });
+        }
+        resources
+    }
+
+    fn describe(&self) -> String {
+        let cpu = self.processes.iter().fold(0.0, |cpu, p| cpu + p.pcpu);
+        let memory = self
+            .memoryuse
+            .unwrap_or_else(|| self.processes.iter().map(|p| p.rss_kb * 1024).sum());
+        let mut line = format!(
+            "CPU: {:.1}%  Mem: {}  Procs: {}",
+            cpu,
+            format_size(memory),
+            self.processes.len()
+        );
+        let top = self
+            .processes
+            .iter()
+            .max_by(|a, b| a.pcpu.total_cmp(&b.pcpu).then(a.rss_kb.cmp(&b.rss_kb)));
+        if let Some(top) = top {
+            line.push_str(&format!(
+                "  Top: {} (pid {}, {:.1}%, {})",
+                top.command,
+                top.pid,
+                top.pcpu,
+                format_size(top.rss_kb * 1024)
+            ));
+        }
+        line
+    }
+}
+
+/// Report whether the process of every pid file of the start commands in
+/// `jail_name` is alive, as `<name> running|stopped` lines.
+/// `<name> <state> [<starts> <last start>]` for every start command, after
+/// a `now <epoch>` line.
+fn processes_command(config: &Config, jail_name: &str) -> String {
+    format!(
+        "{}jexec {} sh -c 'echo \"now $(date +%s)\"; \
+         for f in {run}/service.pid {run}/service-*.pid /var/run/service.pid /var/run/service-*.pid; do \
+         [ -f \"$f\" ] || continue; n=${{f##*/}}; s=$(cat \"${{f%.pid}}.starts\" 2>/dev/null); \
+         if pkill -0 -F \"$f\" 2>/dev/null; then echo \"${{n%.pid}} running $s\"; else echo \"${{n%.pid}} stopped $s\"; fi; \
+         done; true'",
+        shell::escalation_prefix(config.doas),
+        jail_name,
+        run = format!("{}/{}", RUN_DIR, config.service)
+    )
+}
+
+/// A process restarted within this many seconds of the last check is
+/// flagged, as daemon(8) may be resurrecting a crashing command.
+const RECENT_RESTART_SECS: u64 = 300;
+
+fn describe_processes(output: &str) -> Option<String> {
+    let mut now = None;
+    let mut processes = Vec::new();
+    for line in output.lines() {
+        match line.split_whitespace().collect::<Vec<_>>().as_slice() {
+            ["now", epoch] => now = epoch.parse::<u64>().ok(),
+            [name, state, rest @ ..] => {
+                let mut details = vec![state.to_string()];
+                if let [starts, last] = rest
+                    && let (Ok(starts), Ok(last)) = (starts.parse::<u64>(), last.parse::<u64>())
+                {
+                    let uptime = now.map(|now| now.saturating_sub(last));
+                    if *state == "running"
+                        && let Some(uptime) = uptime
+                    {
+                        details.push(format!("up {}", format_uptime(uptime)));
+                    }
+                    let restarts = starts.saturating_sub(1);
+                    if restarts > 0 {
+                        details.push(format!("{} restart{}", restarts, if restarts == 1 { "" } else { "s" }));
+                        if uptime.is_some_and(|u| u < RECENT_RESTART_SECS) {
+                            details.push("restarted recently".to_string());
+                        }
+                    }
+                }
+                processes.push(format!("{} ({})", name, details.join(", ")));
+            }
+            _ => {}
+        }
+    }
+    (!processes.is_empty()).then(|| processes.join(", "))
+}
+
+fn format_uptime(secs: u64) -> String {
+    match secs {
+        s if s < 60 => format!("{}s", s),
+        s if s < 3600 => format!("{}m", s / 60),
+        s if s < 86400 => format!("{}h {}m", s / 3600, s % 3600 / 60),
+        s => format!("{}d {}h", s / 86400, s % 86400 / 3600),
+    }
+}
+
+/// Processes of `jail_name`, plus its rctl usage where RACCT is enabled.
+fn resources_command(config: &Config, jail_name: &str) -> String {
+    let p = shell::escalation_prefix(config.doas);
+    format!(
+        "{p}ps -J {jail} -o pid=,pcpu=,rss=,comm= 2>/dev/null; {p}rctl -u jail:{jail} 2>/dev/null | sed 's/^/rctl /'; true",
+        p = p,
+        jail = jail_name
+    )
+}
+
+/// What a jail runs, from the metadata written on deploy
+#[derive(Debug, Default, Deserialize, PartialEq)]
+struct Deployed {
+    revision: Option<String>,
+    image_path: Option<String>,
+}
+
+impl Deployed {
+    fn image(&self) -> Option<&str> {
+        self.image_path.as_deref().and_then(|p| p.rsplit('/').next())
+    }
+
+    fn describe(&self) -> String {
+        let revision = match self.revision.as_deref() {
+            // Full SHA, possibly with a -dirty suffix
+            Some(rev) if rev.len() > 12 && rev.is_char_boundary(12) => {
+                format!("{}{}", &rev[..12], if rev.ends_with("-dirty") { "-dirty" } else { "" })
+            }
+            Some(rev) => rev.to_string(),
+            None => "-".to_string(),
+        };
+        format!("Revision: {}  Image: {}", revision, self.image().unwrap_or("-"))
+    }
+}
+
+/// Print the metadata of the service's jails, each after a `== <jail>` line.
+fn metadata_script(config: &Config) -> String {
+    format!(
+        "for d in {}/{}-*; do [ -f \"$d/.bsdeploy.json\" ] && echo \"== ${{d##*/}}\" && cat \"$d/.bsdeploy.json\"; done; true",
+        JAILS_DIR, config.service
+    )
+}
+
+/// Jail names and their metadata; jails with unreadable metadata are left out.
+fn parse_metadata(output: &str) -> Vec<(String, Deployed)> {
+    let mut jails = Vec::new();
+    for chunk in output.split("== ").filter(|c| !c.trim().is_empty()) {
+        let (name, json) = chunk.split_once('\n').unwrap_or((chunk, ""));
+        if let Ok(deployed) = serde_json::from_str(json) {
+            jails.push((name.trim().to_string(), deployed));
+        }
+    }
+    jails
+}
+
+/// Result of requesting the health check path on the active jail
+#[derive(Debug, PartialEq)]
+pub(super) struct Probe {
+    pub(super) healthy: bool,
+    /// Seconds the request took
+    latency: Option<f64>,
+    /// What fetch reported for a failed request
+    error: Option<String>,
+}
+
+impl Probe {
+    /// Parse the `probe_command` output: the fetch exit code, fetch's
+    /// error message and the `time -p` report.
+    pub(super) fn parse(output: &str) -> Self {
+        let mut probe = Probe { healthy: false, latency: None, error: None };
+        for line in output.lines().map(str::trim).filter(|l| !l.is_empty()) {
+            if let Some(rc) = line.strip_prefix("rc=") {
+                probe.healthy = rc == "0";
+            } else if let Some(real) = line.strip_prefix("real ") {
+                probe.latency = real.trim().parse().ok();
+            } else if !line.starts_with("user ") && !line.starts_with("sys ") && probe.error.is_none() {
+                probe.error = Some(line.to_string());
+            }
+        }
+        if probe.healthy {
+            probe.error = None;
+        }
+        probe
+    }
+
+    pub(super) fn describe(&self) -> String {
+        let latency = self.latency.map(|s| format!(" ({:.0} ms)", s * 1000.0)).unwrap_or_default();
+        match (self.healthy, &self.error) {
+            (true, _) => format!("healthy{}", latency),
+            (false, Some(error)) => format!("unhealthy{}: {}", latency, error),
+            (false, None) => format!("unhealthy{}", latency),
+        }
+    }
+}
+
+/// Request `path` on `backend` from the host, as Caddy's active check does
+/// (fetch fails on anything but a 2xx/3xx response).
+pub(super) fn probe_command(backend: &str, path: &str, timeout: u64) -> String {
+    format!(
+        "out=$( {{ /usr/bin/time -p fetch -q -T {} -o /dev/null {}; }} 2>&1 ); echo \"rc=$?\"; echo \"$out\"",
+        timeout,
+        shell::escape(&format!("http://{}{}", backend, path))
+    )
+}
+
+/// Disk usage of a jail, image or base directory, in bytes. `refer` is only
+/// known on ZFS, where `used` of a clone is just what it added to its origin.
+#[derive(Debug, PartialEq)]
+struct Entry {
+    name: String,
+    used: u64,
+    refer: Option<u64>,
+}
+
+impl Entry {
+    fn describe(&self) -> String {
+        match self.refer {
+            Some(refer) => format!("{} (refer {})", format_size(self.used), format_size(refer)),
+            None => format_size(self.used),
+        }
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct DiskUsage {
+    jails: Vec<Entry>,
+    images: Vec<Entry>,
+    bases: Vec<Entry>,
+    /// Bytes available under BSDEPLOY_BASE
+    free: Option<u64>,
+    /// Name, free bytes and capacity (percent) of the pool holding BSDEPLOY_BASE
+    pool: Option<(String, u64, u64)>,
+}
+
+impl DiskUsage {
+    /// Parse the `usage_script` output, skipping lines it doesn't understand.
+    fn parse(output: &str) -> Self {
+        let mut usage = Self::default();
+        for line in output.lines() {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields.as_slice() {
+                ["pool", name, free, capacity] => {
+                    if let (Ok(free), Ok(capacity)) = (free.parse(), capacity.parse()) {
+                        usage.pool = Some((name.to_string(), free, capacity));
+                    }
+                }
+                [kind, name, used, refer] => {
+                    let Ok(used) = used.parse() else { continue };
+                    let entry = Entry { name: name.to_string(), used, refer: refer.parse().ok() };
+                    match *kind {
+                        "jail" => usage.jails.push(entry),
+                        "image" => usage.images.push(entry),
+                        "base" => usage.bases.push(entry),
+                        _ => {}
+                    }
+                }
+                ["free", kb] => usage.free = kb.parse::<u64>().ok().map(|kb| kb * 1024),
+                _ => {}
+            }
+        }
+        usage
+    }
+}
+
+/// Report the size of the service's jails, all images and base systems (as
+/// `zfs used/refer` when the directory is its own dataset, `du` otherwise),
+/// the free space under BSDEPLOY_BASE and its ZFS pool.
+fn usage_script(config: &Config) -> String {
+    let p = shell::escalation_prefix(config.doas);
+    format!(
+        "usage() {{ z=$(zfs list -Hp -o mountpoint,used,refer \"$2\" 2>/dev/null | tail -n 1); set -- \"$1\" \"$2\" $z; \
+         if [ \"$3\" = \"$2\" ]; then echo \"$1 ${{2##*/}} $4 $5\"; \
+         else k=$({p}du -sk \"$2\" 2>/dev/null | cut -f1); echo \"$1 ${{2##*/}} $(( ${{k:-0}} * 1024 )) -\"; fi; }}; \
+         for d in {jails}/{service}-*; do [ -d \"$d\" ] && usage jail \"$d\"; done; \
+         for d in {images}/*; do [ -d \"$d\" ] && usage image \"$d\"; done; \
+         for d in {bases}/*; do [ -d \"$d\" ] && usage base \"$d\"; done; \
+         df -k {root} 2>/dev/null | awk 'NR==2 {{print \"free\", $4}}'; \
+         ds=$(zfs list -H -o name {root} 2>/dev/null) && zpool list -Hp -o name,free,capacity \"${{ds%%/*}}\" 2>/dev/null | sed 's/^/pool /'; true",
+        p = p,
+        jails = JAILS_DIR,
+        service = config.service,
+        images = IMAGES_DIR,
+        bases = BASE_DIR,
+        root = BSDEPLOY_BASE,
+    )
+}
+
+fn print_disk_usage(usage: &DiskUsage, deployed: &[(String, Deployed)]) {
+    ui::print_output("");
+    let total = |entries: &[Entry]| format_size(entries.iter().map(|e| e.used).sum());
+    ui::print_output("  Disk usage:");
+    ui::print_output(&format!("    Jails:  {} in {} jails", total(&usage.jails), usage.jails.len()));
+    for (label, entries) in [("Images", &usage.images), ("Bases", &usage.bases)] {
+        ui::print_output(&format!("    {}: {} in {} {}", label, total(entries), entries.len(), label.to_lowercase()));
+        for entry in entries {
+            let users: Vec<&str> = deployed
+                .iter()
+                .filter(|(_, d)| label == "Images" && d.image() == Some(entry.name.as_str()))
+                .map(|(name, _)| name.as_str())
+                .collect();
+            let used_by = if users.is_empty() { String::new() } else { format!("  used by {}", users.join(", ")) };
+            ui::print_output(&format!("      {:<24} {}{}", entry.name, entry.describe(), used_by));
+        }
+    }
+    match (&usage.free, &usage.pool) {
+        (Some(free), Some((pool, pool_free, capacity))) => ui::print_output(&format!(
+            "    Free:   {} (pool {}: {} free, {}% used)",
+            format_size(*free),
+            pool,
+            format_size(*pool_free),
+            capacity
+        )),
+        (Some(free), None) => ui::print_output(&format!("    Free:   {}", format_size(*free))),
+        _ => {}
+    }
+}
+
+pub(super) fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "K", "M", "G", "T"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 { format!("{}B", bytes) } else { format!("{:.1}{}", size, UNITS[unit]) }
+}
+
 /// Parse timestamp from jail name format: service-YYYYMMDD-HHMMSS
 fn parse_jail_timestamp(jail_name: &str) -> Option<String> {
     // Find the timestamp part (last two hyphen-separated segments)
@@ -131,3 +537,98 @@ fn parse_jail_timestamp(jail_name: &str) -> Option<String> {
     }
     None
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disk_usage() {
+        let usage = DiskUsage::parse(
+            "jail app-20250101-120000 1048576 2147483648\n\
+             jail app-20250102-120000 524288 -\n\
+             image 0123456789ab 1073741824 1073741824\n\
+             base 14.1-RELEASE 805306368 -\n\
+             free 10485760\n\
+             pool zroot 21474836480 45\n\
+             du: garbage\n",
+        );
+        assert_eq!(usage.jails.len(), 2);
+        assert_eq!(usage.jails[0].describe(), "1.0M (refer 2.0G)");
+        assert_eq!(usage.jails[1].describe(), "512.0K");
+        assert_eq!(usage.images[0].name, "0123456789ab");
+        assert_eq!(usage.bases[0], Entry { name: "14.1-RELEASE".to_string(), used: 805306368, refer: None });
+        assert_eq!(usage.free, Some(10737418240));
+        assert_eq!(usage.pool, Some(("zroot".to_string(), 21474836480, 45)));
+
+        assert_eq!(DiskUsage::parse(""), DiskUsage::default());
+        assert_eq!(format_size(512), "512B");
+    }
+
+    #[test]
+    fn test_parse_metadata() {
+        let jails = parse_metadata(
+            "== app-20250102-120000\n{\n  \"service\": \"app\",\n  \"image_path\": \"/usr/local/bsdeploy/images/0123456789ab\",\n  \
+             \"revision\": \"4f1c2d9e8b7a6f5e4d3c2b1a09f8e7d6c5b4a392-dirty\"\n}\n\
+             == app-20250101-120000\n{\"image_path\": null}\n\
+             == app-broken\nnot json\n",
+        );
+        assert_eq!(jails.len(), 2);
+        assert_eq!(jails[0].0, "app-20250102-120000");
+        assert_eq!(jails[0].1.image(), Some("0123456789ab"));
+        assert_eq!(jails[0].1.describe(), "Revision: 4f1c2d9e8b7a-dirty  Image: 0123456789ab");
+        assert_eq!(jails[1].1.describe(), "Revision: -  Image: -");
+    }
+
+    #[test]
+    fn test_probe() {
+        let probe = Probe::parse("rc=0\nreal 0.01\nuser 0.00\nsys 0.00\n");
+        assert_eq!(probe, Probe { healthy: true, latency: Some(0.01), error: None });
+        assert_eq!(probe.describe(), "healthy (10 ms)");
+
+        let probe = Probe::parse(
+            "rc=1\nfetch: http://10.0.0.5:3000/up: Service Unavailable\nreal 0.25\nuser 0.00\nsys 0.00\n",
+        );
+        assert_eq!(probe.describe(), "unhealthy (250 ms): fetch: http://10.0.0.5:3000/up: Service Unavailable");
+
+        assert!(probe_command("10.0.0.5:3000", "/up", 5).contains("fetch -q -T 5 -o /dev/null http://10.0.0.5:3000/up;"));
+    }
+
+    #[test]
+    fn test_describe_processes() {
+        assert_eq!(
+            describe_processes("service running\nservice-worker stopped\n").as_deref(),
+            Some("service (running), service-worker (stopped)")
+        );
+        assert_eq!(describe_processes(""), None);
+
+        // Start counts and times from the starts files
+        assert_eq!(
+            describe_processes("now 1000000
+service running 1 992800
+service-1 running 4 999970
+service-2 stopped 2 900000
+")
+                .as_deref(),
+            Some("service (running, up 2h 0m), service-1 (running, up 30s, 3 restarts, restarted recently), service-2 (stopped, 1 restart)")
+        );
+    }
+
+    #[test]
+    fn test_resources() {
+        let resources = Resources::parse(
+            " 1201  0.0  2048 daemon\n 1202 12.5 204800 ruby\n 1203  2.5 10240 sh\n\
+             rctl cputime=30\nrctl memoryuse=157286400\n",
+        );
+        assert_eq!(resources.processes.len(), 3);
+        assert_eq!(
+            resources.describe(),
+            "CPU: 15.0%  Mem: 150.0M  Procs: 3  Top: ruby (pid 1202, 12.5%, 200.0M)"
+        );
+
+        // Without RACCT the resident sizes are summed
+        let resources = Resources::parse(" 1201  0.0  2048 daemon\n");
+        assert_eq!(resources.describe(), "CPU: 0.0%  Mem: 2.0M  Procs: 1  Top: daemon (pid 1201, 0.0%, 2.0M)");
+        assert_eq!(Resources::parse("").describe(), "CPU: 0.0%  Mem: 0B  Procs: 0");
+    }
+}
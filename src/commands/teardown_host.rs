@@ -0,0 +1,137 @@
+//! `teardown-host`: remove bsdeploy from hosts altogether, for all
+//! services, e.g. before decommissioning or repurposing a machine. Unlike
+//! `destroy`, which removes one service, this also drops the host-wide
+//! parts `setup` installed. The configured data directories are kept.
+
+use anyhow::{Context, Result, bail};
+
+use crate::config::{Config, PrivilegeEscalation};
+use crate::constants::*;
+use crate::{housekeeping, pf, proxy, rcd, remote, shell, tuning, ui};
+
+/// Directories of bsdeploy removed after everything below them is unmounted
+const DIRS: &[&str] = &[BSDEPLOY_BASE, CONFIG_DIR, RUN_DIR, LOG_DIR, APP_DATA_DIR];
+
+/// Root script removing bsdeploy from a host. The jails are stopped first;
+/// nothing is deleted while anything is still mounted below
+/// `BSDEPLOY_BASE`, as that could be a service's data directory.
+fn script() -> String {
+    let mut s = format!(
+        r#"# Services and jails
+for rc in {rcd}_*; do [ -f "$rc" ] && "$rc" onestop; done
+[ -f {rcd} ] && {rcd} onestop
+jls name path 2>/dev/null | awk '$2 ~ "^{base}/" {{print $1}}' | while read -r j; do jail -r "$j"; done
+ifconfig lo1 destroy 2>/dev/null
+for v in $(sysrc -a | awk -F: '/^bsdeploy/ {{print $1}}'); do sysrc -x "$v"; done
+rm -f {rcd} {rcd}_* {helper} {newsyslog}/bsdeploy-*.conf
+"#,
+        rcd = rcd::RCD_PATH,
+        base = BSDEPLOY_BASE,
+        helper = HELPER_PATH,
+        newsyslog = NEWSYSLOG_CONF_DIR
+    );
+    s.push_str(&housekeeping::remove_script());
+    s.push_str("# Firewall\n");
+    s.push_str(&pf::teardown_script());
+    s.push_str("# Proxy\n");
+    s.push_str(&proxy::teardown_script());
+    s.push_str("# Host tuning\n");
+    s.push_str(&tuning::remove_script());
+    s.push_str(&format!(
+        r#"# Mounts, datasets and directories
+mount -p | awk '$2 ~ "^{base}/" {{print $2}}' | sort -r | while read -r m; do umount -f "$m"; done
+if mount -p | awk '$2 ~ "^{base}/" {{print $2}}' | grep . >&2; then
+    echo "still mounted below {base}; not deleting anything there" >&2
+    exit 1
+fi
+ds=$(zfs list -H -o name,mountpoint 2>/dev/null | awk '$2 == "{base}" {{print $1}}')
+if [ -n "$ds" ]; then zfs destroy -R "$ds" || exit 1; fi
+chflags -R noschg {dirs} 2>/dev/null
+rm -rf {dirs}
+echo removed
+"#,
+        base = BSDEPLOY_BASE,
+        dirs = DIRS.join(" ")
+    ));
+    s
+}
+
+/// Services with an active jail on the host
+fn services(host: &str) -> Result<Vec<String>> {
+    let output = remote::query(host, &format!("ls {} 2>/dev/null || true", ACTIVE_DIR))?;
+    Ok(output.lines().map(str::trim).filter(|l| !l.is_empty()).map(str::to_string).collect())
+}
+
+/// Remove bsdeploy from `host` (or every configured host). Without `yes`
+/// only the services that would go are listed.
+pub fn run(config: &Config, host: Option<&str>, yes: bool) -> Result<()> {
+    let hosts: Vec<String> = match host {
+        Some(host) if !config.hosts.iter().any(|h| h == host) => bail!("{} is not one of the configured hosts", host),
+        Some(host) => vec![host.to_string()],
+        None => config.hosts.clone(),
+    };
+    if config.escalation() == PrivilegeEscalation::DoasHelper {
+        bail!(
+            "teardown-host needs full root, which privilege_escalation: doas-helper does not give; run it as root with privilege_escalation: none and root@ host entries"
+        );
+    }
+
+    if !yes {
+        for host in &hosts {
+            let services = services(host)?;
+            if services.is_empty() {
+                ui::print_warning(&format!("{}: no deployed services", host));
+            } else {
+                ui::print_warning(&format!("{}: would remove {}", host, services.join(", ")));
+            }
+        }
+        bail!("This removes every bsdeploy jail, image and setting from {} hosts; pass --yes to go ahead", hosts.len());
+    }
+
+    let command = format!("{}sh -c {}", shell::escalation_prefix(config.doas), shell::escape(&script()));
+    for host in &hosts {
+        let spinner = ui::create_spinner(&format!("Removing bsdeploy from {}", host));
+        let result = remote::run_with_output_timeout(host, &command, remote::Timeout::Build);
+        spinner.finish_and_clear();
+        result.with_context(|| format!("Failed to remove bsdeploy from {}", host))?;
+        ui::print_success(&format!("{}: bsdeploy removed", host));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::remote::mock::{self, MockExecutor};
+
+    #[test]
+    fn test_script() {
+        let script = script();
+        assert!(script.starts_with("# Services and jails\nfor rc in /usr/local/etc/rc.d/bsdeploy_*; do"));
+        assert!(script.contains("jls name path 2>/dev/null | awk '$2 ~ \"^/usr/local/bsdeploy/\" {print $1}'"));
+        assert!(script.contains("for a in $(pfctl -a bsdeploy -s Anchors 2>/dev/null)"));
+        // Only bsdeploy's sites and global options go, not the whole of conf.d
+        assert!(script.contains("grep -rlF -e '# bsdeploy managed begin' -e '# generated by bsdeploy' /usr/local/etc/caddy/conf.d 2>/dev/null"));
+        assert!(script.contains(
+            "sed -i '' '/^# bsdeploy global options begin$/,/^# bsdeploy global options end$/d' /usr/local/etc/caddy/Caddyfile\n"
+        ));
+        assert!(!script.contains("conf.d/*"));
+        assert!(script.contains("sed -i '' '\\|^include \"/usr/local/etc/relayd.d/bsdeploy.conf\"$|d' /usr/local/etc/relayd.conf"));
+        // Nothing is deleted before the mounts are gone
+        let guard = script.find("exit 1").unwrap();
+        assert!(guard < script.find("zfs destroy -R").unwrap());
+        assert!(script.ends_with(
+            "rm -rf /usr/local/bsdeploy /usr/local/etc/bsdeploy /var/run/bsdeploy /var/log/bsdeploy /var/db/bsdeploy\necho removed\n"
+        ));
+    }
+
+    #[test]
+    fn test_needs_yes() {
+        let config = Config::from_str("service: app\nhosts: [web1, web2]\n").unwrap();
+        let mock = MockExecutor::new();
+        mock.respond("ls /usr/local/bsdeploy/active", "app\nother\n");
+        assert!(mock::with_executor(mock.clone(), || run(&config, Some("web2"), false)).is_err());
+        assert_eq!(mock.commands(), ["ls /usr/local/bsdeploy/active 2>/dev/null || true"]);
+        assert!(run(&config, Some("db1"), true).is_err());
+    }
+}
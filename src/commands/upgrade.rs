@@ -0,0 +1,303 @@
+//! `setup --upgrade`: bring what older bsdeploy releases left on a host
+//! to the current layout (`LAYOUT_VERSION`) in place, instead of leaving
+//! old deployments half-compatible. The host manifest records the layout
+//! per service. Plain `setup` refuses a service recorded with an older
+//! layout, and migrates one that was never recorded, which is how hosts
+//! from before manifests are picked up. The migrations only repair what is
+//! missing, so they run again for every service set up with `--upgrade`.
+
+use anyhow::{Result, bail};
+
+use crate::config::Config;
+use crate::constants::*;
+use crate::{jail, remote, ui};
+
+use super::deploy::JailMetadata;
+use super::host_manifest;
+
+/// The layout version the manifest of `host` records for `service`,
+/// `None` when the service was never recorded there.
+pub(super) fn layout(host: &str, service: &str) -> Result<Option<u32>> {
+    Ok(host_manifest::read(host)?.and_then(|manifest| manifest.services.get(service).copied()))
+}
+
+/// Whether setup migrates `service` on `host`: when `upgrade` is set, or
+/// the service was never recorded. Fails when it is recorded with another
+/// layout that `upgrade` does not migrate.
+pub(super) fn check_layout(host: &str, service: &str, upgrade: bool) -> Result<bool> {
+    match layout(host, service)? {
+        Some(version) if version > LAYOUT_VERSION => bail!(
+            "{} on {} was set up by a newer bsdeploy (layout version {}, this one uses {}); upgrade bsdeploy",
+            service,
+            host,
+            version,
+            LAYOUT_VERSION
+        ),
+        Some(version) if version < LAYOUT_VERSION && !upgrade => bail!(
+            "{} on {} has layout version {} from an older bsdeploy; run `bsdeploy setup --upgrade` to migrate it to version {}",
+            service,
+            host,
+            version,
+            LAYOUT_VERSION
+        ),
+        Some(_) => Ok(upgrade),
+        None => Ok(true),
+    }
+}
+
+/// Where the service's active link points
+#[derive(Debug, PartialEq)]
+enum Link {
+    Current(String),
+    Dangling(String),
+    Missing,
+}
+
+/// A jail of the service, as the probe found it
+#[derive(Debug, PartialEq)]
+struct FoundJail {
+    name: String,
+    metadata: bool,
+    /// Address of the running jail
+    ip: Option<String>,
+    zfs: bool,
+    base_version: Option<String>,
+    image_path: Option<String>,
+}
+
+#[derive(Debug, PartialEq)]
+struct Probe {
+    link: Link,
+    jails: Vec<FoundJail>,
+}
+
+/// Read-only script describing the service's active link and jails. For
+/// jails without metadata it reports what the running jail reveals: its
+/// address, and the base system and image it was created from.
+fn probe_script(config: &Config) -> String {
+    format!(
+        r#"s={service}
+l={active}/$s
+if [ -L "$l" ]; then
+    if [ -e "$l/" ]; then echo "LINK current $(readlink "$l")"; else echo "LINK dangling $(readlink "$l")"; fi
+else
+    echo "LINK missing"
+fi
+for j in {jails}/$s-*; do
+    [ -d "$j" ] || continue
+    n=${{j##*/}}
+    case "${{n#$s-}}" in [0-9][0-9][0-9][0-9][0-9][0-9][0-9][0-9]-[0-9][0-9][0-9][0-9][0-9][0-9]) ;; *) continue ;; esac
+    if [ -f "$j/.bsdeploy.json" ]; then echo "JAIL $n metadata"; continue; fi
+    ip=$(jls -j "$n" ip4.addr 2>/dev/null)
+    ds=$(zfs list -H -o name,mountpoint 2>/dev/null | awk -v m="$j" '$2 == m {{print $1}}')
+    if [ -n "$ds" ]; then
+        origin=$(zfs get -H -o value origin "$ds")
+        image=-
+        [ "$origin" != - ] && image=$(zfs get -H -o value mountpoint "${{origin%@*}}")
+        base=$(sed -n 's/^USERLAND_VERSION="\(.*\)"$/\1/p' "$j/bin/freebsd-version" 2>/dev/null)
+    else
+        image=$(mount -p | awk -v m="$j/usr/local" '$2 == m {{print $1}}')
+        image=${{image%/usr/local}}
+        base=$(mount -p | awk -v m="$j/bin" '$2 == m {{print $1}}')
+        base=${{base%/bin}}
+        base=${{base##*/}}
+    fi
+    echo "JAIL $n none ${{ip:--}} $([ -n "$ds" ] && echo zfs || echo -) ${{base:--}} ${{image:--}}"
+done
+"#,
+        service = config.service,
+        active = ACTIVE_DIR,
+        jails = JAILS_DIR
+    )
+}
+
+fn parse_probe(output: &str) -> Probe {
+    let field = |value: &str| (value != "-").then(|| value.to_string());
+    let mut probe = Probe { link: Link::Missing, jails: Vec::new() };
+    for line in output.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        match fields.as_slice() {
+            ["LINK", "current", target] => probe.link = Link::Current(target.to_string()),
+            ["LINK", "dangling", target] => probe.link = Link::Dangling(target.to_string()),
+            ["JAIL", name, "metadata"] => probe.jails.push(FoundJail {
+                name: name.to_string(),
+                metadata: true,
+                ip: None,
+                zfs: false,
+                base_version: None,
+                image_path: None,
+            }),
+            ["JAIL", name, "none", ip, zfs, base, image] => probe.jails.push(FoundJail {
+                name: name.to_string(),
+                metadata: false,
+                ip: field(ip),
+                zfs: *zfs == "zfs",
+                // freebsd-version includes the patch level, the base directory does not
+                base_version: field(base).map(|b| match b.rsplit_once("-p") {
+                    Some((release, patch)) if patch.chars().all(|c| c.is_ascii_digit()) => release.to_string(),
+                    _ => b,
+                }),
+                image_path: field(image),
+            }),
+            _ => {}
+        }
+    }
+    probe.jails.sort_by(|a, b| a.name.cmp(&b.name));
+    probe
+}
+
+/// One change the migration makes
+#[derive(Debug, PartialEq)]
+enum Step {
+    /// Write the missing `.bsdeploy.json` of a running jail
+    Metadata(usize),
+    /// Point the active link at this jail
+    Link(String),
+}
+
+/// The steps bringing the service's jails to the current layout, and what
+/// cannot be repaired.
+fn plan(probe: &Probe) -> (Vec<Step>, Vec<String>) {
+    let mut steps = Vec::new();
+    let mut problems = Vec::new();
+    let mut usable = Vec::new();
+    for (i, found) in probe.jails.iter().enumerate() {
+        match (found.metadata, &found.ip, &found.base_version) {
+            (true, _, _) => usable.push(found.name.as_str()),
+            (false, Some(_), Some(_)) => {
+                steps.push(Step::Metadata(i));
+                usable.push(found.name.as_str());
+            }
+            (false, None, _) => problems.push(format!(
+                "{} has no metadata and is not running, so its address is unknown and it cannot start at boot",
+                found.name
+            )),
+            (false, Some(_), None) => problems.push(format!("could not tell the base system of {}", found.name)),
+        }
+    }
+    match (&probe.link, usable.last()) {
+        (Link::Current(_), _) => {}
+        (_, Some(newest)) => steps.push(Step::Link(newest.to_string())),
+        (Link::Dangling(target), None) => {
+            problems.push(format!("the active link points to missing {} and no jail can replace it", target))
+        }
+        (Link::Missing, None) => {}
+    }
+    (steps, problems)
+}
+
+/// Migrate the service's jails on `host` to the current layout. The rest
+/// of setup then installs the current rc.d scripts and records the host
+/// manifest.
+pub(super) fn run(config: &Config, host: &str) -> Result<()> {
+    let probe = parse_probe(&remote::run_with_output(host, &probe_script(config))?);
+    let (steps, problems) = plan(&probe);
+    for step in &steps {
+        match step {
+            Step::Metadata(i) => {
+                let found = &probe.jails[*i];
+                let jail_info = jail::JailInfo {
+                    name: found.name.clone(),
+                    path: format!("{}/{}", JAILS_DIR, found.name),
+                    ip: found.ip.clone().unwrap_or_default(),
+                    zfs: found.zfs,
+                };
+                let base_version = found.base_version.as_deref().unwrap_or_default();
+                let metadata = JailMetadata::new(config, &jail_info, base_version, found.image_path.as_deref(), None);
+                let path = format!("{}/.bsdeploy.json", jail_info.path);
+                remote::write_file(host, &serde_json::to_string_pretty(&metadata)?, &path, config.doas)?;
+                ui::print_success(&format!("{}: wrote the missing metadata of {}", host, found.name));
+            }
+            Step::Link(name) => {
+                let link = format!("{}/{}", ACTIVE_DIR, config.service);
+                remote::ensure_dir(host, ACTIVE_DIR, config.doas)?;
+                remote::ensure_symlink(host, &format!("{}/{}", JAILS_DIR, name), &link, config.doas)?;
+                ui::print_success(&format!("{}: pointed {} at {}", host, link, name));
+            }
+        }
+    }
+    for problem in &problems {
+        ui::print_warning(&format!("{}: {}", host, problem));
+    }
+    if steps.is_empty() && problems.is_empty() {
+        log::debug!("{}: {} needs no migration", host, config.service);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::remote::mock::{self, MockExecutor};
+
+    #[test]
+    fn test_plan() {
+        let probe = parse_probe(
+            "LINK dangling /usr/local/bsdeploy/jails/app-20240101-000000\n\
+             JAIL app-20240301-120000 none - - - -\n\
+             JAIL app-20240201-120000 metadata\n\
+             JAIL app-20240401-120000 none 10.0.0.4 zfs 14.1-RELEASE-p3 /usr/local/bsdeploy/images/abc\n",
+        );
+        assert_eq!(probe.jails[2].base_version.as_deref(), Some("14.1-RELEASE"));
+        assert!(probe.jails[2].zfs && !probe.jails[1].zfs);
+        let (steps, problems) = plan(&probe);
+        assert_eq!(steps, [Step::Metadata(2), Step::Link("app-20240401-120000".to_string())]);
+        assert_eq!(
+            problems,
+            ["app-20240301-120000 has no metadata and is not running, so its address is unknown and it cannot start at boot"]
+        );
+
+        let probe = parse_probe("LINK dangling /usr/local/bsdeploy/jails/app-20240101-000000\nJAIL app-20240301-120000 none - - - -\n");
+        let (steps, problems) = plan(&probe);
+        assert!(steps.is_empty());
+        assert_eq!(
+            problems[1],
+            "the active link points to missing /usr/local/bsdeploy/jails/app-20240101-000000 and no jail can replace it"
+        );
+
+        let (steps, problems) = plan(&parse_probe("LINK current /usr/local/bsdeploy/jails/app-20240201-120000\nJAIL app-20240201-120000 metadata\n"));
+        assert!(steps.is_empty() && problems.is_empty());
+    }
+
+    #[test]
+    fn test_run_writes_metadata() {
+        let config = Config::from_str("service: app\nhosts: [web1]\nstart: [bin/web]\n").unwrap();
+        let mock = MockExecutor::new();
+        mock.respond(
+            "s=app",
+            "LINK current /usr/local/bsdeploy/jails/app-20240401-120000\n\
+             JAIL app-20240401-120000 none 10.0.0.4 zfs 14.1-RELEASE /usr/local/bsdeploy/images/abc\n",
+        );
+        mock::with_executor(mock.clone(), || run(&config, "web1")).unwrap();
+        let json = mock.file("/usr/local/bsdeploy/jails/app-20240401-120000/.bsdeploy.json").unwrap();
+        let metadata: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(metadata["ip"], "10.0.0.4");
+        assert_eq!(metadata["zfs"], true);
+        assert_eq!(metadata["image_path"], "/usr/local/bsdeploy/images/abc");
+        assert_eq!(metadata["start_commands"][0], "bin/web");
+        assert!(!mock.ran("ln -s"));
+    }
+
+    #[test]
+    fn test_check_layout() {
+        let mock = MockExecutor::new();
+        mock.respond(
+            "cat /usr/local/etc/bsdeploy/host.json",
+            &format!(
+                r#"{{"bsdeploy_version": "0.1.0", "layout_version": {l}, "rcd_version": "3", "set_up_at": "", "packages": {{}},
+                   "services": {{"app": {l}, "old": 0, "new": {n}}}}}"#,
+                l = LAYOUT_VERSION,
+                n = LAYOUT_VERSION + 1
+            ),
+        );
+        mock::with_executor(mock, || {
+            assert!(!check_layout("web1", "app", false).unwrap());
+            assert!(check_layout("web1", "app", true).unwrap());
+            // Never recorded, e.g. deployed before manifests: migrated by plain setup
+            assert!(check_layout("web1", "worker", false).unwrap());
+            assert!(check_layout("web1", "old", false).unwrap_err().to_string().contains("setup --upgrade"));
+            assert!(check_layout("web1", "old", true).unwrap());
+            assert!(check_layout("web1", "new", true).is_err());
+        });
+    }
+}
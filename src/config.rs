@@ -1,9 +1,15 @@
 use serde::Deserialize;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use anyhow::{Context, Result};
 
+use crate::constants::{
+    APP_DATA_DIR, DEFAULT_BOOTSTRAP_PACKAGES, DEFAULT_BUILD_TIMEOUT, DEFAULT_COMMAND_TIMEOUT, DEFAULT_IP_RANGE, DEFAULT_MISE_BUILD_ENV,
+    DEFAULT_MISE_BUILD_PACKAGES, DEFAULT_QUERY_TIMEOUT, DEFAULT_SSH_CONNECT_RETRIES, DEFAULT_SSH_CONNECT_TIMEOUT,
+    DEFAULT_SSH_SERVER_ALIVE_COUNT_MAX, DEFAULT_SSH_SERVER_ALIVE_INTERVAL, POUDRIERE_PACKAGES_DIR,
+};
+
 #[derive(Debug, Deserialize)]
 pub struct Config {
     pub service: String,
@@ -17,14 +23,222 @@ pub struct Config {
     #[serde(default)]
     pub before_start: Vec<String>,
     #[serde(default)]
-    pub start: Vec<String>,
+    pub start: Vec<StartCommand>,
+    /// How the output of the `start` commands is written
+    #[serde(default)]
+    pub logs: LogsConfig,
+    /// Forward the jail's syslog to a central collector
+    pub syslog: Option<SyslogConfig>,
+    /// Send deploy metrics to statsd or a Prometheus pushgateway
+    pub metrics: Option<MetricsConfig>,
+    /// Where `monitor` sends its alerts
+    pub notify: Option<NotifyConfig>,
     #[serde(default)]
     pub data_directories: Vec<DataDirectory>,
+    /// Run privileged commands through `privilege_escalation`. Derived from
+    /// `privilege_escalation` when that is set.
     #[serde(default)]
     pub doas: bool,
+    /// Tool for privileged commands; overrides `doas`
+    pub privilege_escalation: Option<PrivilegeEscalation>,
+    /// Reverse proxy in front of the jails; `proxy: false` is the same as
+    /// leaving it out
+    #[serde(default, deserialize_with = "deserialize_proxy")]
     pub proxy: Option<ProxyConfig>,
+    /// Ports forwarded straight to the active jail, for services without a proxy
+    pub bind: Option<BindConfig>,
+    #[serde(default)]
+    pub mise: MiseConfig,
+    #[serde(default)]
+    pub image: ImageConfig,
+    /// Maximum number of hosts worked on concurrently
+    #[serde(default = "default_parallelism")]
+    pub parallelism: usize,
+    #[serde(default)]
+    pub ssh: SshConfig,
+    /// Run multi-step host operations through an uploaded helper script
+    #[serde(default)]
+    pub helper: bool,
+    /// rsync options for uploading the application
+    #[serde(default)]
+    pub sync: SyncConfig,
+    /// Host settings applied by `setup`
+    #[serde(default)]
+    pub host: HostConfig,
+    /// Ordering of the service's rc.d script at boot
+    #[serde(default)]
+    pub boot: BootConfig,
+}
+
+fn deserialize_proxy<'de, D>(deserializer: D) -> std::result::Result<Option<ProxyConfig>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    match serde_yaml::Value::deserialize(deserializer)? {
+        serde_yaml::Value::Null | serde_yaml::Value::Bool(false) => Ok(None),
+        serde_yaml::Value::Bool(true) => Err(serde::de::Error::custom("proxy: true needs the proxy settings instead")),
+        value => ProxyConfig::deserialize(value).map(Some).map_err(serde::de::Error::custom),
+    }
+}
+
+/// Ports the host redirects to the active jail with PF, so the service
+/// is reachable without a proxy (non-HTTP daemons, apps terminating TLS)
+#[derive(Debug, Deserialize, Clone)]
+pub struct BindConfig {
+    /// TCP ports
     #[serde(default)]
-    pub mise: HashMap<String, String>,
+    pub ports: Vec<u16>,
+    #[serde(default)]
+    pub udp_ports: Vec<u16>,
+    /// Interface the ports are redirected on (default: the default route's)
+    pub interface: Option<String>,
+    /// Only redirect traffic to this IPv4 address of the host
+    pub address: Option<String>,
+}
+
+/// How the application is uploaded, and rsync tuning
+#[derive(Debug, Deserialize, Default, Clone)]
+#[serde(default)]
+pub struct SyncConfig {
+    pub strategy: SyncStrategy,
+    /// Pre-built archive extracted into /app instead of uploading the source tree
+    pub artifact: Option<PathBuf>,
+    /// Bandwidth limit passed to `--bwlimit` (e.g. "2m", KiB/s without a suffix)
+    pub bwlimit: Option<String>,
+    /// zlib compression level 0-9; 0 disables compression
+    pub compress_level: Option<u32>,
+    /// Compare file checksums instead of size and modification time
+    pub checksum: bool,
+    /// Additional rsync arguments, appended last
+    pub extra_args: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SyncStrategy {
+    /// rsync the working tree (honoring .gitignore)
+    #[default]
+    Rsync,
+    /// Stream `git archive HEAD`, i.e. only committed files
+    Git,
+}
+
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PrivilegeEscalation {
+    #[default]
+    Doas,
+    Sudo,
+    /// Connect as root; nothing is prefixed
+    None,
+    /// `doas bsdeploy-helper exec`, for a doas rule that only permits the
+    /// helper (`setup --install-doas`)
+    #[serde(rename = "doas-helper")]
+    DoasHelper,
+}
+
+/// How bsdeploy connects to the hosts
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct SshConfig {
+    pub transport: SshTransport,
+    /// How often a failed SSH connection is retried (with exponential backoff)
+    pub connect_retries: u32,
+    /// Bastion host(s) passed to `ssh -J` for every host
+    pub proxy_jump: Option<String>,
+    /// Per-host overrides, keyed by the host as written in `hosts`
+    pub hosts: HashMap<String, SshHostConfig>,
+    pub timeouts: SshTimeouts,
+    /// Seconds allowed to establish a connection (`ConnectTimeout`)
+    pub connect_timeout: u64,
+    /// Seconds between keepalive probes (`ServerAliveInterval`, 0 disables)
+    pub server_alive_interval: u64,
+    /// Unanswered probes before the connection is dropped (`ServerAliveCountMax`)
+    pub server_alive_count_max: u32,
+    /// Forward the local SSH agent (`ssh -A`), also into `before_start` hooks
+    pub forward_agent: bool,
+    /// Local environment variables passed to remote commands (`SendEnv`)
+    pub send_env: Vec<String>,
+    /// Fixed environment variables set for remote commands (`SetEnv`)
+    pub set_env: BTreeMap<String, String>,
+}
+
+/// Timeouts in seconds for each class of remote command
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct SshTimeouts {
+    /// Quick checks and lookups (`test -d`, `zfs list`, `jls`)
+    pub query: u64,
+    /// Everything else
+    pub command: u64,
+    /// Package installs, runtime builds and image transfers
+    pub build: u64,
+}
+
+impl Default for SshTimeouts {
+    fn default() -> Self {
+        Self {
+            query: DEFAULT_QUERY_TIMEOUT,
+            command: DEFAULT_COMMAND_TIMEOUT,
+            build: DEFAULT_BUILD_TIMEOUT,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Default, Clone)]
+#[serde(default)]
+pub struct SshHostConfig {
+    /// Overrides `ssh.proxy_jump` for this host ("none" connects directly)
+    pub proxy_jump: Option<String>,
+}
+
+impl Default for SshConfig {
+    fn default() -> Self {
+        Self {
+            transport: SshTransport::default(),
+            connect_retries: DEFAULT_SSH_CONNECT_RETRIES,
+            proxy_jump: None,
+            hosts: HashMap::new(),
+            timeouts: SshTimeouts::default(),
+            connect_timeout: DEFAULT_SSH_CONNECT_TIMEOUT,
+            server_alive_interval: DEFAULT_SSH_SERVER_ALIVE_INTERVAL,
+            server_alive_count_max: DEFAULT_SSH_SERVER_ALIVE_COUNT_MAX,
+            forward_agent: false,
+            send_env: Vec::new(),
+            set_env: BTreeMap::new(),
+        }
+    }
+}
+
+impl SshConfig {
+    /// The jump host to use for `host`, if any.
+    pub fn proxy_jump_for(&self, host: &str) -> Option<&str> {
+        let jump = self
+            .hosts
+            .get(host)
+            .and_then(|h| h.proxy_jump.as_deref())
+            .or(self.proxy_jump.as_deref())?;
+        (jump != "none").then_some(jump)
+    }
+
+    fn has_proxy_jump(&self) -> bool {
+        self.proxy_jump.is_some() || self.hosts.values().any(|h| h.proxy_jump.is_some())
+    }
+
+    /// Names of all environment variables passed to remote commands.
+    pub fn forwarded_env(&self) -> impl Iterator<Item = &str> {
+        self.send_env.iter().map(String::as_str).chain(self.set_env.keys().map(String::as_str))
+    }
+}
+
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SshTransport {
+    /// Shell out to the local `ssh` binary
+    #[default]
+    Command,
+    /// Built-in SSH client (requires the `native-ssh` feature)
+    Native,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -50,11 +264,305 @@ impl DataDirectory {
     }
 }
 
+/// A `start` entry: the command, or the command with its own log files
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum StartCommand {
+    Command(String),
+    Detailed {
+        command: String,
+        /// Names the command's pid and log files (`service-<name>`)
+        name: Option<String>,
+        /// Output log inside the jail
+        log: Option<String>,
+        /// Error log inside the jail; setting it splits stderr for this command
+        error_log: Option<String>,
+        /// When daemon(8) starts the command again after it exits
+        #[serde(default)]
+        restart: RestartPolicy,
+        /// Seconds daemon(8) waits before restarting the command
+        restart_delay: Option<u32>,
+    },
+}
+
+/// When a start command that exited is started again
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum RestartPolicy {
+    #[default]
+    Never,
+    /// Only after a non-zero exit status
+    OnFailure,
+    Always,
+}
+
+impl StartCommand {
+    pub fn command(&self) -> &str {
+        match self {
+            StartCommand::Command(command) | StartCommand::Detailed { command, .. } => command,
+        }
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        match self {
+            StartCommand::Command(_) => None,
+            StartCommand::Detailed { name, .. } => name.as_deref(),
+        }
+    }
+
+    pub fn log(&self) -> Option<&str> {
+        match self {
+            StartCommand::Command(_) => None,
+            StartCommand::Detailed { log, .. } => log.as_deref(),
+        }
+    }
+
+    pub fn error_log(&self) -> Option<&str> {
+        match self {
+            StartCommand::Command(_) => None,
+            StartCommand::Detailed { error_log, .. } => error_log.as_deref(),
+        }
+    }
+
+    pub fn restart(&self) -> RestartPolicy {
+        match self {
+            StartCommand::Command(_) => RestartPolicy::Never,
+            StartCommand::Detailed { restart, .. } => *restart,
+        }
+    }
+
+    pub fn restart_delay(&self) -> Option<u32> {
+        match self {
+            StartCommand::Command(_) => None,
+            StartCommand::Detailed { restart_delay, .. } => *restart_delay,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Default)]
+pub struct LogsConfig {
+    /// Write stderr to its own file instead of merging it into the log
+    #[serde(default)]
+    pub split_stderr: bool,
+    /// Prefix every line with the local time it was written
+    #[serde(default)]
+    pub timestamps: bool,
+    /// Rotate the logs with the host's newsyslog
+    pub rotate: Option<RotateConfig>,
+}
+
+/// Forwarding by a syslogd running inside each jail
+#[derive(Debug, Deserialize)]
+pub struct SyslogConfig {
+    /// Collector as `host` or `host:port`, reached over UDP
+    pub forward: String,
+    /// syslog.conf selector of the forwarded messages
+    #[serde(default = "default_syslog_selector")]
+    pub selector: String,
+    /// Also send the output of the start commands to syslog
+    #[serde(default)]
+    pub output: bool,
+}
+
+fn default_syslog_selector() -> String {
+    "*.*".to_string()
+}
+
+/// Deploy duration, phase timings, outcome and image cache hits, sent
+/// from the machine running bsdeploy
+#[derive(Debug, Deserialize)]
+pub struct MetricsConfig {
+    /// statsd server as `host:port`, reached over UDP
+    pub statsd: Option<String>,
+    /// Prometheus pushgateway URL
+    pub pushgateway: Option<String>,
+    /// First component of the metric names
+    #[serde(default = "default_metrics_prefix")]
+    pub prefix: String,
+}
+
+fn default_metrics_prefix() -> String {
+    "bsdeploy".to_string()
+}
+
+/// Alerts from `monitor`, posted from the machine running bsdeploy
+#[derive(Debug, Deserialize)]
+pub struct NotifyConfig {
+    /// URL receiving a JSON document per alert
+    pub webhook: Option<String>,
+    /// Slack incoming webhook URL
+    pub slack: Option<String>,
+}
+
+/// Where the service's rc.d script runs in the boot order (rcorder(8))
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct BootConfig {
+    /// rc.d services started before this one, e.g. `postgresql`
+    pub require: Vec<String>,
+    /// rc.d services started after this one
+    pub before: Vec<String>,
+    /// bsdeploy services on the same host started, and waited for, first
+    pub services: Vec<String>,
+    /// Seconds to wait for `services` to become healthy
+    pub wait: u64,
+}
+
+impl Default for BootConfig {
+    fn default() -> Self {
+        Self {
+            require: Vec::new(),
+            before: Vec::new(),
+            services: Vec::new(),
+            wait: 60,
+        }
+    }
+}
+
+/// Host settings applied by `setup`
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct HostConfig {
+    /// sysctl(8) variables, kept in /etc/sysctl.conf and set right away
+    #[serde(deserialize_with = "deserialize_tunables")]
+    pub sysctls: BTreeMap<String, String>,
+    /// loader(8) tunables, kept in /boot/loader.conf; they apply after a reboot
+    #[serde(deserialize_with = "deserialize_tunables")]
+    pub loader: BTreeMap<String, String>,
+    /// Block inbound traffic except SSH, HTTP(S) and `open_ports`
+    pub firewall: bool,
+    /// Further inbound TCP ports `firewall` lets through
+    pub open_ports: Vec<u16>,
+    pub zfs: ZfsConfig,
+    /// `setup` skips `pkg update` when the catalogue is younger than this
+    /// many hours; 0 always updates
+    pub pkg_update_hours: u32,
+    /// periodic(8) run pruning old jails and unused images
+    pub housekeeping: Option<Period>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Period {
+    Daily,
+    Weekly,
+}
+
+impl Period {
+    pub const ALL: [Period; 2] = [Period::Daily, Period::Weekly];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Period::Daily => "daily",
+            Period::Weekly => "weekly",
+        }
+    }
+}
+
+impl Default for HostConfig {
+    fn default() -> Self {
+        Self {
+            sysctls: BTreeMap::new(),
+            loader: BTreeMap::new(),
+            firewall: false,
+            open_ports: Vec::new(),
+            zfs: ZfsConfig::default(),
+            pkg_update_hours: 24,
+            housekeeping: None,
+        }
+    }
+}
+
+/// ZFS properties `setup` keeps on the bsdeploy datasets
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct ZfsConfig {
+    /// Set on `<pool>/bsdeploy` and inherited by the datasets below it
+    #[serde(deserialize_with = "deserialize_tunables")]
+    pub properties: BTreeMap<String, String>,
+    /// Set on the data directories that are datasets of their own
+    #[serde(deserialize_with = "deserialize_tunables")]
+    pub data_properties: BTreeMap<String, String>,
+}
+
+impl Default for ZfsConfig {
+    fn default() -> Self {
+        Self {
+            properties: BTreeMap::from([
+                ("atime".to_string(), "off".to_string()),
+                ("compression".to_string(), "zstd".to_string()),
+            ]),
+            data_properties: BTreeMap::new(),
+        }
+    }
+}
+
+/// Tunable values may be written as YAML numbers or strings.
+fn deserialize_tunables<'de, D>(deserializer: D) -> std::result::Result<BTreeMap<String, String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    BTreeMap::<String, serde_yaml::Value>::deserialize(deserializer)?
+        .into_iter()
+        .map(|(name, value)| match value {
+            serde_yaml::Value::String(s) => Ok((name, s)),
+            serde_yaml::Value::Number(n) => Ok((name, n.to_string())),
+            _ => Err(serde::de::Error::custom(format!("{}: use a number or a string", name))),
+        })
+        .collect()
+}
+
+/// newsyslog(8) settings for the service logs
+#[derive(Debug, Deserialize)]
+pub struct RotateConfig {
+    /// Rotate once a log is larger than this many KiB
+    pub size_kb: Option<u64>,
+    /// newsyslog time specification, e.g. `@T00` (daily at midnight) or `$W0D23`
+    pub when: Option<String>,
+    /// Rotated files kept
+    #[serde(default = "default_rotate_keep")]
+    pub keep: u32,
+    #[serde(default)]
+    pub compress: LogCompression,
+}
+
+fn default_rotate_keep() -> u32 {
+    7
+}
+
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogCompression {
+    None,
+    #[default]
+    Gzip,
+    Bzip2,
+    Xz,
+    Zstd,
+}
+
+impl LogCompression {
+    /// newsyslog flag selecting the compression
+    pub fn flag(self) -> Option<char> {
+        match self {
+            LogCompression::None => None,
+            LogCompression::Gzip => Some('Z'),
+            LogCompression::Bzip2 => Some('J'),
+            LogCompression::Xz => Some('X'),
+            LogCompression::Zstd => Some('Y'),
+        }
+    }
+}
 
 #[derive(Debug, Deserialize, Default)]
 pub struct JailConfig {
     pub base_version: Option<String>,
     pub ip_range: Option<String>,
+    /// Per-host `ip_range` overrides, keyed by host as listed in `hosts`
+    #[serde(default)]
+    pub host_ip_ranges: BTreeMap<String, String>,
+    /// Local base.txz to upload instead of fetching from download.freebsd.org
+    pub base_tarball: Option<PathBuf>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -65,6 +573,326 @@ pub struct ProxyConfig {
     pub tls: bool,
     /// Optional SSL certificate configuration (overrides ACME when present)
     pub ssl: Option<SslConfig>,
+    #[serde(default)]
+    pub server: ProxyServer,
+    /// Load Caddy configuration through its admin API instead of
+    /// `service caddy reload`
+    #[serde(default)]
+    pub admin_api: bool,
+    /// Additional hostnames served by the same site
+    #[serde(default)]
+    pub aliases: Vec<String>,
+    /// Hostnames that permanently redirect to `hostname`
+    #[serde(default)]
+    pub redirect_hosts: Vec<String>,
+    /// Serve only this path on `hostname`, sharing it with other services
+    pub path_prefix: Option<String>,
+    /// Remove `path_prefix` from requests before proxying
+    #[serde(default = "default_true")]
+    pub strip_prefix: bool,
+    /// Extra Caddyfile lines for the site (or route), before `reverse_proxy`
+    #[serde(default)]
+    pub custom_directives: Vec<String>,
+    /// File with more directives, appended to `custom_directives` on load
+    pub custom_directives_file: Option<PathBuf>,
+    /// Per client IP request limit
+    pub rate_limit: Option<RateLimitConfig>,
+    /// Local HTML files served instead of Caddy's empty error responses
+    pub error_pages: Option<ErrorPagesConfig>,
+    /// Obtain certificates with the ACME DNS challenge (needed for wildcards)
+    pub dns_provider: Option<DnsProviderConfig>,
+    /// Require TLS client certificates signed by a CA
+    pub client_auth: Option<ClientAuthConfig>,
+    /// Redirect plain HTTP requests to HTTPS (Caddy's default)
+    #[serde(default = "default_true")]
+    pub https_redirect: bool,
+    /// Client networks (CIDR) still served over plain HTTP; everyone else
+    /// is redirected
+    #[serde(default)]
+    pub plain_http_from: Vec<String>,
+    /// Strict-Transport-Security header on HTTPS responses
+    pub hsts: Option<HstsConfig>,
+    /// Compress responses with Caddy's `encode`
+    pub compression: Option<CompressionConfig>,
+    /// Caddy access log for the site
+    pub access_log: Option<AccessLogConfig>,
+    /// Internal site with Caddy metrics and a status summary for monitoring
+    pub status_endpoint: Option<StatusEndpointConfig>,
+    /// Run Caddy on one host that balances across the jails on all hosts
+    pub load_balancer: Option<LoadBalancerConfig>,
+    /// Timeouts and streaming options for the connections to the backend
+    pub transport: Option<TransportConfig>,
+    /// Stop sending traffic to a backend that fails between deploys
+    pub healthcheck: Option<HealthcheckConfig>,
+    /// ACME account and CA, written to the global options of the host's
+    /// main Caddyfile (shared by all services on the host)
+    pub acme: Option<AcmeConfig>,
+    /// Port Caddy serves plain HTTP on instead of 80 (a global option too)
+    pub http_port: Option<u16>,
+    /// Port Caddy serves HTTPS on instead of 443 (a global option too)
+    pub https_port: Option<u16>,
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct AcmeConfig {
+    /// Account email for expiry notices
+    pub email: Option<String>,
+    /// ACME directory URL of another CA, e.g. an internal step-ca
+    pub ca: Option<String>,
+    /// Local file with the root certificate of `ca`, if it is not publicly trusted
+    pub ca_root: Option<PathBuf>,
+    /// Use the Let's Encrypt staging environment
+    #[serde(default)]
+    pub staging: bool,
+}
+
+/// Durations are in seconds.
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct TransportConfig {
+    pub dial_timeout: Option<u64>,
+    /// Longest wait for the backend's response
+    pub read_timeout: Option<u64>,
+    /// Longest time to send the request to the backend
+    pub write_timeout: Option<u64>,
+    /// Idle time before a kept-alive connection is closed; 0 disables keepalive
+    pub keepalive: Option<u64>,
+    pub keepalive_idle_conns: Option<u32>,
+    /// Milliseconds between response flushes; -1 flushes immediately (SSE)
+    pub flush_interval: Option<i64>,
+    /// Maximum lifetime of WebSocket and other upgraded connections
+    pub stream_timeout: Option<u64>,
+    /// Keep upgraded connections open this long after a reload, so
+    /// deploys do not drop every WebSocket at once
+    pub stream_close_delay: Option<u64>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct LoadBalancerConfig {
+    /// Host running Caddy; it does not need to be one of `hosts`
+    pub host: String,
+    /// Caddy `lb_policy`, e.g. `round_robin`, `least_conn`, `ip_hash`
+    #[serde(default = "default_lb_policy")]
+    pub policy: String,
+}
+
+fn default_lb_policy() -> String {
+    "round_robin".to_string()
+}
+
+/// Health checks Caddy runs against the backends. Durations are in seconds.
+#[derive(Debug, Deserialize, Clone)]
+pub struct HealthcheckConfig {
+    /// Path probed on every backend (active checks); without it only
+    /// failed requests mark a backend unhealthy
+    pub path: Option<String>,
+    #[serde(default = "default_health_interval")]
+    pub interval: u64,
+    #[serde(default = "default_health_timeout")]
+    pub timeout: u64,
+    /// How long a failed request counts against the backend; 0 disables
+    /// passive checks
+    #[serde(default = "default_fail_duration")]
+    pub fail_duration: u64,
+    /// Failed requests within `fail_duration` that mark the backend unhealthy
+    #[serde(default = "default_max_fails")]
+    pub max_fails: u32,
+}
+
+fn default_health_interval() -> u64 {
+    10
+}
+
+fn default_health_timeout() -> u64 {
+    5
+}
+
+fn default_fail_duration() -> u64 {
+    30
+}
+
+fn default_max_fails() -> u32 {
+    1
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct StatusEndpointConfig {
+    /// Address the site listens on; keep it internal
+    #[serde(default = "default_status_bind")]
+    pub bind: String,
+    /// Port, unique per service on a host
+    pub port: u16,
+}
+
+fn default_status_bind() -> String {
+    "127.0.0.1".to_string()
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct AccessLogConfig {
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    #[serde(default)]
+    pub format: AccessLogFormat,
+    /// Rotate the log when it reaches this size
+    #[serde(default = "default_access_log_roll_size_mb")]
+    pub roll_size_mb: u32,
+    /// Rotated files to keep
+    #[serde(default = "default_access_log_roll_keep")]
+    pub roll_keep: u32,
+}
+
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessLogFormat {
+    #[default]
+    Json,
+    /// Common Log Format, via the transform-encoder plugin
+    Common,
+}
+
+fn default_access_log_roll_size_mb() -> u32 {
+    100
+}
+
+fn default_access_log_roll_keep() -> u32 {
+    10
+}
+
+impl ProxyConfig {
+    /// The access log settings, if the log is enabled.
+    pub fn access_log(&self) -> Option<&AccessLogConfig> {
+        self.access_log.as_ref().filter(|log| log.enabled)
+    }
+
+    /// The HTTP and HTTPS ports the proxy listens on.
+    pub fn listen_ports(&self) -> (u16, u16) {
+        (self.http_port.unwrap_or(80), self.https_port.unwrap_or(443))
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct CompressionConfig {
+    #[serde(default = "default_true")]
+    pub zstd: bool,
+    #[serde(default = "default_true")]
+    pub gzip: bool,
+    /// Smallest response in bytes worth compressing (Caddy default: 512)
+    pub minimum_length: Option<u32>,
+    /// Compress only these Content-Types (`text/*` style wildcards allowed)
+    #[serde(default)]
+    pub content_types: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct HstsConfig {
+    /// Seconds browsers remember to use HTTPS only
+    #[serde(default = "default_hsts_max_age")]
+    pub max_age: u64,
+    #[serde(default)]
+    pub include_subdomains: bool,
+    /// Ask for inclusion in browsers' preload lists
+    #[serde(default)]
+    pub preload: bool,
+}
+
+fn default_hsts_max_age() -> u64 {
+    31_536_000
+}
+
+impl HstsConfig {
+    pub fn header_value(&self) -> String {
+        let mut value = format!("max-age={}", self.max_age);
+        if self.include_subdomains {
+            value.push_str("; includeSubDomains");
+        }
+        if self.preload {
+            value.push_str("; preload");
+        }
+        value
+    }
+}
+
+/// Client certificate (mTLS) authentication
+#[derive(Debug, Deserialize, Clone)]
+pub struct ClientAuthConfig {
+    /// Environment variable name containing the CA certificate PEM
+    pub ca_certificate_pem: Option<String>,
+    /// Local file with the CA certificate PEM
+    pub ca_certificate_file: Option<PathBuf>,
+    #[serde(default)]
+    pub mode: ClientAuthMode,
+}
+
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ClientAuthMode {
+    /// Reject clients without a valid certificate
+    #[default]
+    Require,
+    /// Verify certificates that are presented, but allow clients without one
+    Verify,
+}
+
+impl ClientAuthMode {
+    pub fn caddy_mode(self) -> &'static str {
+        match self {
+            ClientAuthMode::Require => "require_and_verify",
+            ClientAuthMode::Verify => "verify_if_given",
+        }
+    }
+}
+
+/// DNS provider for the ACME DNS-01 challenge, as a caddy-dns plugin
+#[derive(Debug, Deserialize, Clone)]
+pub struct DnsProviderConfig {
+    /// Plugin name from github.com/caddy-dns, e.g. `cloudflare`
+    pub name: String,
+    /// Environment variable name containing the provider API token
+    pub api_token: String,
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct ErrorPagesConfig {
+    /// Shown with 502 when the backend is unreachable, e.g. while restarting
+    pub bad_gateway: Option<PathBuf>,
+    /// Shown with 503 while the service is in maintenance mode
+    pub maintenance: Option<PathBuf>,
+}
+
+impl ErrorPagesConfig {
+    /// Configured pages with their status code
+    pub fn pages(&self) -> impl Iterator<Item = (u16, &PathBuf)> {
+        [(502, &self.bad_gateway), (503, &self.maintenance)]
+            .into_iter()
+            .filter_map(|(status, page)| page.as_ref().map(|p| (status, p)))
+    }
+}
+
+/// Requests allowed per client IP, enforced by Caddy's `rate_limit`
+/// module (github.com/mholt/caddy-ratelimit)
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+pub struct RateLimitConfig {
+    /// Requests allowed per `interval`
+    pub requests: u32,
+    /// Window in seconds
+    #[serde(default = "default_rate_limit_interval")]
+    pub interval: u64,
+    /// Requests allowed in any single second, on top of the window limit
+    pub burst: Option<u32>,
+}
+
+fn default_rate_limit_interval() -> u64 {
+    60
+}
+
+/// Reverse proxy running on the hosts
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ProxyServer {
+    #[default]
+    Caddy,
+    /// relayd from packages; TLS needs `proxy.ssl` as it has no ACME client
+    Relayd,
 }
 
 /// SSL certificate configuration using secrets (environment variables)
@@ -76,33 +904,745 @@ pub struct SslConfig {
     pub private_key_pem: String,
 }
 
+/// Runtimes installed via mise, plus the environment used to build them.
+///
+/// ```yaml
+/// mise:
+///   build_env:
+///     CC: clang
+///   ruby:
+///     version: 3.4.7
+///     env:
+///       RUBY_CONFIGURE_OPTS: --with-jemalloc
+///   node: 20.0.0
+/// ```
+#[derive(Debug, Deserialize, Default)]
+pub struct MiseConfig {
+    /// Environment for all runtime builds, merged over the gcc/gmake defaults
+    #[serde(default)]
+    pub build_env: BTreeMap<String, String>,
+    #[serde(flatten)]
+    pub tools: HashMap<String, MiseTool>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum MiseTool {
+    Version(String),
+    Detailed {
+        version: String,
+        /// Extra build environment for this tool (e.g. configure options, mirrors)
+        #[serde(default)]
+        env: BTreeMap<String, String>,
+    },
+}
+
+impl MiseTool {
+    pub fn version(&self) -> &String {
+        match self {
+            MiseTool::Version(v) => v,
+            MiseTool::Detailed { version, .. } => version,
+        }
+    }
+
+    pub fn env(&self) -> Option<&BTreeMap<String, String>> {
+        match self {
+            MiseTool::Version(_) => None,
+            MiseTool::Detailed { env, .. } => Some(env),
+        }
+    }
+}
+
+impl MiseConfig {
+    pub fn is_empty(&self) -> bool {
+        self.tools.is_empty()
+    }
+
+    /// Version configured for a tool
+    #[cfg(test)]
+    pub fn get(&self, tool: &str) -> Option<&String> {
+        self.tools.get(tool).map(|t| t.version())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &MiseTool)> {
+        self.tools.iter()
+    }
+
+    /// Build environment for a tool: defaults, then `build_env`, then the tool's own `env`
+    pub fn build_env_for(&self, tool: &str) -> BTreeMap<String, String> {
+        let mut env: BTreeMap<String, String> = DEFAULT_MISE_BUILD_ENV
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        env.extend(self.build_env.clone());
+        if let Some(tool_env) = self.tools.get(tool).and_then(|t| t.env()) {
+            env.extend(tool_env.clone());
+        }
+        env
+    }
+}
+
+/// Image build and distribution settings
+#[derive(Debug, Deserialize, Default)]
+#[serde(default)]
+pub struct ImageConfig {
+    /// Optional registry host used as a fleet-wide image cache
+    pub registry: Option<RegistryConfig>,
+    /// Packages installed into every image before `packages` (default: git, bash)
+    pub bootstrap_packages: Option<Vec<String>>,
+    /// Build dependencies installed alongside mise (default: gmake, gcc, python3, pkgconf)
+    pub mise_build_packages: Option<Vec<String>>,
+    /// Install packages from a poudriere repository on the host
+    pub poudriere: Option<PoudriereConfig>,
+    /// Network identity of build jails
+    pub build_network: BuildNetwork,
+    /// HTTP(S) proxy used for downloads during image builds
+    pub build_proxy: Option<String>,
+}
+
+/// How build jails reach the network
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum BuildNetwork {
+    /// Share the host's addresses (ip4=inherit)
+    #[default]
+    Inherit,
+    /// Own lo1 alias from the jail ip_range, egress via the PF NAT rules
+    Isolated,
+}
+
+/// Poudriere repository on the deploy host used as an extra package source
+#[derive(Debug, Deserialize, Clone)]
+pub struct PoudriereConfig {
+    /// Poudriere build jail name (e.g. 141amd64)
+    pub jail: String,
+    #[serde(default = "default_ports_tree")]
+    pub ports_tree: String,
+    /// Optional poudriere set name
+    pub set: Option<String>,
+    /// Port origins to build with `poudriere bulk` before the image build
+    #[serde(default)]
+    pub bulk: Vec<String>,
+    /// Repository directory (defaults to poudriere's packages directory for jail/tree/set)
+    pub packages_dir: Option<String>,
+}
+
+impl PoudriereConfig {
+    pub fn packages_dir(&self) -> String {
+        if let Some(dir) = &self.packages_dir {
+            return dir.clone();
+        }
+        match &self.set {
+            Some(set) => format!("{}/{}-{}-{}", POUDRIERE_PACKAGES_DIR, self.jail, self.ports_tree, set),
+            None => format!("{}/{}-{}", POUDRIERE_PACKAGES_DIR, self.jail, self.ports_tree),
+        }
+    }
+}
+
+fn default_ports_tree() -> String {
+    "default".to_string()
+}
+
+impl ImageConfig {
+    pub fn bootstrap_packages(&self) -> Vec<String> {
+        self.bootstrap_packages
+            .clone()
+            .unwrap_or_else(|| DEFAULT_BOOTSTRAP_PACKAGES.iter().map(|s| s.to_string()).collect())
+    }
+
+    pub fn mise_build_packages(&self) -> Vec<String> {
+        self.mise_build_packages
+            .clone()
+            .unwrap_or_else(|| DEFAULT_MISE_BUILD_PACKAGES.iter().map(|s| s.to_string()).collect())
+    }
+}
+
+/// Registry host storing image tarballs, reached over SSH like the deploy hosts
+#[derive(Debug, Deserialize, Clone)]
+pub struct RegistryConfig {
+    pub host: String,
+    /// Directory on the registry host holding `<hash>.txz` files
+    #[serde(default = "default_registry_path")]
+    pub path: String,
+    /// Use privilege escalation when writing to the registry directory
+    #[serde(default)]
+    pub doas: bool,
+}
+
+fn default_registry_path() -> String {
+    "/var/db/bsdeploy-registry".to_string()
+}
+
+fn default_parallelism() -> usize {
+    4
+}
+
 fn default_true() -> bool {
     true
 }
 
-#[derive(Debug, Deserialize, Default)]
-pub struct EnvConfig {
-    #[serde(default)]
-    pub clear: Vec<HashMap<String, String>>,
-    #[serde(default)]
-    pub secret: Vec<String>,
-}
+#[derive(Debug, Deserialize, Default)]
+pub struct EnvConfig {
+    #[serde(default)]
+    pub clear: Vec<HashMap<String, String>>,
+    #[serde(default)]
+    pub secret: Vec<String>,
+}
+
+impl Config {
+    /// Validate that a service name contains only safe characters.
+    /// Allowed: lowercase letters, digits, and hyphens (not at start/end).
+    fn validate_service_name(name: &str) -> Result<()> {
+        if name.is_empty() {
+            anyhow::bail!("Service name cannot be empty");
+        }
+        if !name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-') {
+            anyhow::bail!(
+                "Service name '{}' contains invalid characters. Only lowercase letters, digits, and hyphens are allowed.",
+                name
+            );
+        }
+        if name.starts_with('-') || name.ends_with('-') {
+            anyhow::bail!("Service name '{}' cannot start or end with a hyphen", name);
+        }
+        Ok(())
+    }
+
+    fn is_env_name(name: &str) -> bool {
+        name.chars().next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+    }
+
+    /// Validate that build environment variable names are plain shell identifiers.
+    fn validate_mise_env(mise: &MiseConfig) -> Result<()> {
+        let tool_envs = mise.tools.values().filter_map(|t| t.env());
+        for name in mise.build_env.keys().chain(tool_envs.flat_map(|e| e.keys())) {
+            if !Self::is_env_name(name) {
+                anyhow::bail!("Invalid mise build environment variable name '{}'", name);
+            }
+        }
+        Ok(())
+    }
+
+    fn validate_sync(sync: &SyncConfig) -> Result<()> {
+        if let Some(bwlimit) = &sync.bwlimit
+            && (bwlimit.is_empty() || !bwlimit.chars().all(|c| c.is_ascii_alphanumeric() || c == '.'))
+        {
+            anyhow::bail!("Invalid sync.bwlimit '{}'", bwlimit);
+        }
+        if sync.compress_level.is_some_and(|level| level > 9) {
+            anyhow::bail!("sync.compress_level must be between 0 and 9");
+        }
+        if sync.extra_args.iter().any(|arg| arg.trim().is_empty()) {
+            anyhow::bail!("sync.extra_args must not contain empty arguments");
+        }
+        Ok(())
+    }
+
+    fn validate_start(&self) -> Result<()> {
+        let start = &self.start;
+        for name in start.iter().filter_map(StartCommand::name) {
+            if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+                anyhow::bail!("Invalid start command name '{}'", name);
+            }
+        }
+        let names: Vec<String> = (0..start.len()).map(|i| self.process_name(i)).collect();
+        if let Some(dup) = names.iter().enumerate().find_map(|(i, n)| names[..i].contains(n).then_some(n)) {
+            anyhow::bail!("Two start commands use the pid and log files '{}': give them distinct names", dup);
+        }
+        for (i, s) in start.iter().enumerate() {
+            match s.restart_delay() {
+                Some(_) if s.restart() == RestartPolicy::Never => {
+                    anyhow::bail!("Start command '{}' sets restart_delay without a restart policy", self.process_name(i));
+                }
+                Some(0) => anyhow::bail!("restart_delay of start command '{}' must be greater than zero", self.process_name(i)),
+                _ => {}
+            }
+        }
+        if let Some(rotate) = &self.logs.rotate {
+            if rotate.size_kb.is_none() && rotate.when.is_none() {
+                anyhow::bail!("logs.rotate needs a size_kb or a when");
+            }
+            if rotate.size_kb == Some(0) {
+                anyhow::bail!("logs.rotate.size_kb must be greater than zero");
+            }
+            if let Some(when) = &rotate.when
+                && (when.is_empty() || when.chars().any(char::is_whitespace))
+            {
+                anyhow::bail!("Invalid logs.rotate.when '{}'", when);
+            }
+        }
+        for path in start.iter().flat_map(|s| [s.log(), s.error_log()]).flatten() {
+            // The paths are written into the command line of the process
+            if !path.starts_with('/')
+                || !path.chars().all(|c| c.is_ascii_alphanumeric() || "/._-".contains(c))
+            {
+                anyhow::bail!("Invalid start log path '{}': use an absolute path inside the jail", path);
+            }
+        }
+        Ok(())
+    }
+
+    fn validate_syslog(&self) -> Result<()> {
+        let Some(syslog) = &self.syslog else {
+            return Ok(());
+        };
+        let (host, port) = match syslog.forward.rsplit_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (syslog.forward.as_str(), None),
+        };
+        if host.is_empty()
+            || !host.chars().all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-')
+            || port.is_some_and(|p| p.parse::<u16>().is_err())
+        {
+            anyhow::bail!("Invalid syslog.forward '{}': use host or host:port", syslog.forward);
+        }
+        if syslog.selector.is_empty() || syslog.selector.chars().any(char::is_whitespace) {
+            anyhow::bail!("Invalid syslog.selector '{}'", syslog.selector);
+        }
+        // daemon(8) only sends to syslog what it writes itself
+        if syslog.output
+            && (self.logs.split_stderr || self.logs.timestamps || self.start.iter().any(|s| s.error_log().is_some()))
+        {
+            anyhow::bail!("syslog.output cannot be combined with logs.split_stderr, logs.timestamps or error_log");
+        }
+        Ok(())
+    }
+
+    fn validate_notify(&self) -> Result<()> {
+        let Some(notify) = &self.notify else {
+            return Ok(());
+        };
+        if notify.webhook.is_none() && notify.slack.is_none() {
+            anyhow::bail!("notify needs a webhook or slack URL");
+        }
+        for (key, url) in [("webhook", &notify.webhook), ("slack", &notify.slack)] {
+            if let Some(url) = url
+                && !(url.starts_with("http://") || url.starts_with("https://"))
+            {
+                anyhow::bail!("Invalid notify.{} '{}': use an http:// or https:// URL", key, url);
+            }
+        }
+        Ok(())
+    }
+
+    fn validate_boot(&self) -> Result<()> {
+        let boot = &self.boot;
+        for (key, names) in [("require", &boot.require), ("before", &boot.before)] {
+            for name in names {
+                if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-')) {
+                    anyhow::bail!("Invalid boot.{} entry '{}': use an rc.d service name", key, name);
+                }
+            }
+        }
+        for name in &boot.services {
+            Self::validate_service_name(name).with_context(|| format!("Invalid boot.services entry '{}'", name))?;
+            if *name == self.service {
+                anyhow::bail!("boot.services cannot contain the service itself");
+            }
+        }
+        if boot.wait == 0 {
+            anyhow::bail!("boot.wait must be greater than zero");
+        }
+        Ok(())
+    }
+
+    fn validate_host(host: &HostConfig) -> Result<()> {
+        for (section, tunables) in [("sysctls", &host.sysctls), ("loader", &host.loader)] {
+            for (name, value) in tunables {
+                if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-')) {
+                    anyhow::bail!("Invalid host.{} name '{}'", section, name);
+                }
+                if value.contains(['"', '\n', '\r']) {
+                    anyhow::bail!("Invalid host.{}.{} value: quotes and line breaks are not allowed", section, name);
+                }
+            }
+        }
+        for (section, properties) in [("properties", &host.zfs.properties), ("data_properties", &host.zfs.data_properties)] {
+            for (name, value) in properties {
+                if name.is_empty() || !name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || matches!(c, ':' | '.' | '_' | '-')) {
+                    anyhow::bail!("Invalid host.zfs.{} name '{}'", section, name);
+                }
+                if value.is_empty() || value.chars().any(|c| c.is_whitespace() || c == '"' || c == '\'') {
+                    anyhow::bail!("Invalid host.zfs.{}.{} value '{}'", section, name, value);
+                }
+            }
+        }
+        if !host.open_ports.is_empty() && !host.firewall {
+            anyhow::bail!("host.open_ports needs host.firewall: true");
+        }
+        if host.open_ports.contains(&0) {
+            anyhow::bail!("Invalid host.open_ports entry 0");
+        }
+        Ok(())
+    }
+
+    fn validate_metrics(&self) -> Result<()> {
+        let Some(metrics) = &self.metrics else {
+            return Ok(());
+        };
+        if metrics.statsd.is_none() && metrics.pushgateway.is_none() {
+            anyhow::bail!("metrics needs statsd or pushgateway");
+        }
+        if let Some(statsd) = &metrics.statsd
+            && !statsd.rsplit_once(':').is_some_and(|(host, port)| {
+                !host.is_empty()
+                    && host.chars().all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-')
+                    && port.parse::<u16>().is_ok()
+            })
+        {
+            anyhow::bail!("Invalid metrics.statsd '{}': use host:port", statsd);
+        }
+        if let Some(url) = &metrics.pushgateway
+            && !(url.starts_with("http://") || url.starts_with("https://"))
+        {
+            anyhow::bail!("Invalid metrics.pushgateway '{}': use an http:// or https:// URL", url);
+        }
+        if metrics.prefix.is_empty() || !metrics.prefix.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            anyhow::bail!("Invalid metrics.prefix '{}': use letters, digits and underscores", metrics.prefix);
+        }
+        Ok(())
+    }
+
+    fn validate_proxy(proxy: &ProxyConfig) -> Result<()> {
+        if proxy.server == ProxyServer::Relayd && proxy.tls && proxy.ssl.is_none() {
+            anyhow::bail!("relayd cannot obtain certificates: set proxy.ssl, or proxy.tls: false");
+        }
+        if proxy.server == ProxyServer::Relayd && proxy.admin_api {
+            anyhow::bail!("proxy.admin_api is only supported with Caddy");
+        }
+        if proxy.server == ProxyServer::Relayd && (!proxy.aliases.is_empty() || !proxy.redirect_hosts.is_empty()) {
+            anyhow::bail!("proxy.aliases and proxy.redirect_hosts are only supported with Caddy");
+        }
+        if let Some(prefix) = &proxy.path_prefix {
+            if proxy.server == ProxyServer::Relayd {
+                anyhow::bail!("proxy.path_prefix is only supported with Caddy");
+            }
+            let valid = prefix == "/"
+                || (prefix.starts_with('/')
+                    && !prefix.ends_with('/')
+                    && !prefix.chars().any(|c| c.is_whitespace() || "{}*?\"'".contains(c)));
+            if !valid {
+                anyhow::bail!("Invalid proxy.path_prefix '{}': use a path like /api", prefix);
+            }
+            if proxy.hostname.contains(['*', '/']) {
+                anyhow::bail!("proxy.path_prefix cannot be used with hostname '{}'", proxy.hostname);
+            }
+        }
+        if let Some(dns) = &proxy.dns_provider {
+            if proxy.server == ProxyServer::Relayd {
+                anyhow::bail!("proxy.dns_provider is only supported with Caddy");
+            }
+            if !proxy.tls || proxy.ssl.is_some() {
+                anyhow::bail!("proxy.dns_provider needs tls enabled and no proxy.ssl certificates");
+            }
+            if dns.name.is_empty() || !dns.name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit()) {
+                anyhow::bail!("Invalid proxy.dns_provider.name '{}'", dns.name);
+            }
+        }
+        if let Some(auth) = &proxy.client_auth {
+            if proxy.server == ProxyServer::Relayd {
+                anyhow::bail!("proxy.client_auth is only supported with Caddy");
+            }
+            if !proxy.tls && proxy.ssl.is_none() {
+                anyhow::bail!("proxy.client_auth needs TLS");
+            }
+            if auth.ca_certificate_pem.is_some() == auth.ca_certificate_file.is_some() {
+                anyhow::bail!("proxy.client_auth needs exactly one of ca_certificate_pem and ca_certificate_file");
+            }
+        }
+        let tls = proxy.tls || proxy.ssl.is_some();
+        let http_options = !proxy.https_redirect || !proxy.plain_http_from.is_empty() || proxy.hsts.is_some();
+        if http_options && proxy.server == ProxyServer::Relayd {
+            anyhow::bail!("proxy.https_redirect, proxy.plain_http_from and proxy.hsts are only supported with Caddy");
+        }
+        if http_options && !tls {
+            anyhow::bail!("proxy.https_redirect, proxy.plain_http_from and proxy.hsts need TLS");
+        }
+        if !proxy.https_redirect && proxy.client_auth.is_some() {
+            anyhow::bail!("https_redirect: false would serve the site without client certificates");
+        }
+        if !proxy.plain_http_from.is_empty() && proxy.client_auth.is_some() {
+            anyhow::bail!("proxy.plain_http_from would serve the site without client certificates");
+        }
+        if !proxy.https_redirect && !proxy.plain_http_from.is_empty() {
+            anyhow::bail!("proxy.plain_http_from has no effect with https_redirect: false");
+        }
+        for network in &proxy.plain_http_from {
+            let (ip, prefix) = network.split_once('/').unwrap_or((network, "0"));
+            if ip.parse::<std::net::IpAddr>().is_err() || prefix.parse::<u8>().is_err() {
+                anyhow::bail!("Invalid proxy.plain_http_from network '{}'", network);
+            }
+        }
+        if let Some(log) = proxy.access_log() {
+            if proxy.server == ProxyServer::Relayd {
+                anyhow::bail!("proxy.access_log is only supported with Caddy");
+            }
+            // log belongs to the site, which path routes share
+            if proxy.path_prefix.is_some() {
+                anyhow::bail!("proxy.access_log cannot be combined with proxy.path_prefix");
+            }
+            if log.roll_size_mb == 0 {
+                anyhow::bail!("proxy.access_log.roll_size_mb must be greater than zero");
+            }
+        }
+        if let Some(endpoint) = &proxy.status_endpoint {
+            if proxy.server == ProxyServer::Relayd {
+                anyhow::bail!("proxy.status_endpoint is only supported with Caddy");
+            }
+            if endpoint.bind.parse::<std::net::IpAddr>().is_err() {
+                anyhow::bail!("Invalid proxy.status_endpoint.bind '{}': use an IP address", endpoint.bind);
+            }
+            let (http, https) = proxy.listen_ports();
+            if [0, http, https, proxy.port].contains(&endpoint.port) {
+                anyhow::bail!("proxy.status_endpoint.port {} is not available", endpoint.port);
+            }
+        }
+        if let Some(transport) = &proxy.transport {
+            if proxy.server == ProxyServer::Relayd {
+                anyhow::bail!("proxy.transport is only supported with Caddy");
+            }
+            let timeouts = [
+                ("dial_timeout", transport.dial_timeout),
+                ("read_timeout", transport.read_timeout),
+                ("write_timeout", transport.write_timeout),
+                ("stream_timeout", transport.stream_timeout),
+            ];
+            if let Some((name, _)) = timeouts.iter().find(|(_, value)| *value == Some(0)) {
+                anyhow::bail!("proxy.transport.{} must be greater than zero", name);
+            }
+            if transport.flush_interval.is_some_and(|ms| ms < -1) {
+                anyhow::bail!("proxy.transport.flush_interval must be -1 (flush immediately) or more");
+            }
+        }
+        if let Some(lb) = &proxy.load_balancer {
+            if proxy.server == ProxyServer::Relayd {
+                anyhow::bail!("proxy.load_balancer is only supported with Caddy");
+            }
+            const POLICIES: &[&str] =
+                &["random", "round_robin", "weighted_round_robin", "least_conn", "ip_hash", "client_ip_hash", "uri_hash", "first"];
+            if !POLICIES.contains(&lb.policy.as_str()) {
+                anyhow::bail!("Unknown proxy.load_balancer.policy '{}' (one of: {})", lb.policy, POLICIES.join(", "));
+            }
+            if lb.host.is_empty() || lb.host.starts_with('-') || lb.host.contains(char::is_whitespace) {
+                anyhow::bail!("Invalid proxy.load_balancer.host '{}'", lb.host);
+            }
+        }
+        if let Some(acme) = &proxy.acme {
+            if proxy.server == ProxyServer::Relayd {
+                anyhow::bail!("proxy.acme is only supported with Caddy");
+            }
+            if let Some(email) = &acme.email
+                && (!email.contains('@') || email.contains(char::is_whitespace))
+            {
+                anyhow::bail!("Invalid proxy.acme.email '{}'", email);
+            }
+            if let Some(ca) = &acme.ca {
+                if acme.staging {
+                    anyhow::bail!("proxy.acme.staging and proxy.acme.ca cannot be combined");
+                }
+                if !ca.starts_with("https://") || ca.contains(char::is_whitespace) {
+                    anyhow::bail!("Invalid proxy.acme.ca '{}': use the https:// URL of the ACME directory", ca);
+                }
+            }
+            if acme.ca_root.is_some() && acme.ca.is_none() {
+                anyhow::bail!("proxy.acme.ca_root needs proxy.acme.ca");
+            }
+        }
+        if proxy.http_port.is_some() || proxy.https_port.is_some() {
+            if proxy.server == ProxyServer::Relayd {
+                anyhow::bail!("proxy.http_port and proxy.https_port are only supported with Caddy");
+            }
+            let (http, https) = proxy.listen_ports();
+            if http == 0 || https == 0 || http == https || [http, https].contains(&proxy.port) {
+                anyhow::bail!("proxy.http_port and proxy.https_port must be two different ports other than proxy.port");
+            }
+        }
+        if let Some(check) = &proxy.healthcheck {
+            if proxy.server == ProxyServer::Relayd {
+                anyhow::bail!("proxy.healthcheck is only supported with Caddy");
+            }
+            if let Some(path) = &check.path
+                && (!path.starts_with('/') || path.contains(char::is_whitespace))
+            {
+                anyhow::bail!("Invalid proxy.healthcheck.path '{}'", path);
+            }
+            if check.interval == 0 || check.timeout == 0 || check.max_fails == 0 {
+                anyhow::bail!("proxy.healthcheck interval, timeout and max_fails must be greater than zero");
+            }
+            if check.path.is_none() && check.fail_duration == 0 {
+                anyhow::bail!("proxy.healthcheck needs a path or a fail_duration");
+            }
+        }
+        if let Some(compression) = &proxy.compression {
+            if proxy.server == ProxyServer::Relayd {
+                anyhow::bail!("proxy.compression is only supported with Caddy");
+            }
+            if !compression.zstd && !compression.gzip {
+                anyhow::bail!("proxy.compression needs zstd or gzip enabled");
+            }
+            if let Some(t) = compression.content_types.iter().find(|t| t.is_empty() || t.contains(|c: char| c.is_whitespace() || "{}\"'".contains(c))) {
+                anyhow::bail!("Invalid proxy.compression content type '{}'", t);
+            }
+        }
+        if let Some(hsts) = &proxy.hsts
+            && hsts.preload
+            && (!hsts.include_subdomains || hsts.max_age < default_hsts_max_age())
+        {
+            anyhow::bail!("proxy.hsts.preload needs include_subdomains and a max_age of at least one year");
+        }
+        let wildcard = std::iter::once(&proxy.hostname).chain(&proxy.aliases).chain(&proxy.redirect_hosts).find(|h| h.starts_with('*'));
+        if let Some(hostname) = wildcard
+            && proxy.tls
+            && proxy.ssl.is_none()
+            && proxy.dns_provider.is_none()
+        {
+            anyhow::bail!("Wildcard hostname '{}' needs proxy.dns_provider (or proxy.ssl) to get a certificate", hostname);
+        }
+        if proxy.error_pages.is_some() {
+            if proxy.server == ProxyServer::Relayd {
+                anyhow::bail!("proxy.error_pages are only supported with Caddy");
+            }
+            // handle_errors belongs to the site, which path routes share
+            if proxy.path_prefix.is_some() {
+                anyhow::bail!("proxy.error_pages cannot be combined with proxy.path_prefix");
+            }
+        }
+        if let Some(limit) = &proxy.rate_limit {
+            if proxy.server == ProxyServer::Relayd {
+                anyhow::bail!("proxy.rate_limit is only supported with Caddy");
+            }
+            if limit.requests == 0 || limit.interval == 0 || limit.burst == Some(0) {
+                anyhow::bail!("proxy.rate_limit values must be greater than zero");
+            }
+        }
+        if !proxy.custom_directives.is_empty() {
+            if proxy.server == ProxyServer::Relayd {
+                anyhow::bail!("proxy.custom_directives are only supported with Caddy");
+            }
+            // Unbalanced braces would end the site block early
+            let mut depth = 0i32;
+            for c in proxy.custom_directives.iter().flat_map(|l| l.chars()) {
+                depth += match c {
+                    '{' => 1,
+                    '}' => -1,
+                    _ => 0,
+                };
+                if depth < 0 {
+                    break;
+                }
+            }
+            if depth != 0 {
+                anyhow::bail!("proxy.custom_directives have unbalanced braces");
+            }
+        }
+        let mut seen = HashSet::from([proxy.hostname.as_str()]);
+        for host in proxy.aliases.iter().chain(&proxy.redirect_hosts) {
+            if host.is_empty() || host.chars().any(|c| c.is_whitespace() || "{},\"'".contains(c)) {
+                anyhow::bail!("Invalid proxy hostname '{}'", host);
+            }
+            if !seen.insert(host) {
+                anyhow::bail!("Hostname '{}' is listed more than once in proxy", host);
+            }
+        }
+        Ok(())
+    }
+
+    fn validate_bind(bind: &BindConfig, has_proxy: bool) -> Result<()> {
+        if has_proxy {
+            anyhow::bail!("bind is for services without a proxy; remove the proxy section or set proxy: false");
+        }
+        if bind.ports.is_empty() && bind.udp_ports.is_empty() {
+            anyhow::bail!("bind needs at least one port in ports or udp_ports");
+        }
+        for ports in [&bind.ports, &bind.udp_ports] {
+            let mut seen = HashSet::new();
+            if let Some(port) = ports.iter().find(|p| **p == 0 || !seen.insert(**p)) {
+                anyhow::bail!("Invalid or duplicate bind port {}", port);
+            }
+        }
+        if let Some(interface) = &bind.interface
+            && (interface.is_empty() || !interface.chars().all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '_'))
+        {
+            anyhow::bail!("Invalid bind.interface '{}'", interface);
+        }
+        if let Some(address) = &bind.address
+            && address.parse::<std::net::Ipv4Addr>().is_err()
+        {
+            anyhow::bail!("Invalid bind.address '{}': use an IPv4 address", address);
+        }
+        Ok(())
+    }
+
+    fn validate_jail_ranges(&self) -> Result<()> {
+        if let Some(jail) = &self.jail
+            && let Some(host) = jail.host_ip_ranges.keys().find(|h| !self.hosts.contains(h))
+        {
+            anyhow::bail!("jail.host_ip_ranges has an entry for '{}', which is not in hosts", host);
+        }
+        // The balancer routes to every host's jails, so the networks must not overlap
+        if self.proxy.as_ref().is_some_and(|p| p.load_balancer.is_some()) {
+            let mut seen = HashMap::new();
+            for host in &self.hosts {
+                if let Some(other) = seen.insert(self.jail_ip_range(host), host) {
+                    anyhow::bail!(
+                        "proxy.load_balancer needs a distinct jail network per host, but {} and {} both use {} (set jail.host_ip_ranges)",
+                        other,
+                        host,
+                        self.jail_ip_range(host)
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
 
-impl Config {
-    /// Validate that a service name contains only safe characters.
-    /// Allowed: lowercase letters, digits, and hyphens (not at start/end).
-    fn validate_service_name(name: &str) -> Result<()> {
-        if name.is_empty() {
-            anyhow::bail!("Service name cannot be empty");
+    /// The helper only writes below bsdeploy's own directories
+    fn validate_doas_helper(&self) -> Result<()> {
+        for dir in &self.data_directories {
+            let (host_path, _) = dir.get_paths();
+            if !host_path.starts_with(&format!("{}/", APP_DATA_DIR)) {
+                anyhow::bail!(
+                    "With privilege_escalation: doas-helper, data directories must be below {}: {}",
+                    APP_DATA_DIR,
+                    host_path
+                );
+            }
         }
-        if !name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-') {
-            anyhow::bail!(
-                "Service name '{}' contains invalid characters. Only lowercase letters, digits, and hyphens are allowed.",
-                name
-            );
+        if !self.host.loader.is_empty() {
+            anyhow::bail!("host.loader needs full root; it is not available with privilege_escalation: doas-helper");
         }
-        if name.starts_with('-') || name.ends_with('-') {
-            anyhow::bail!("Service name '{}' cannot start or end with a hyphen", name);
+        Ok(())
+    }
+
+    fn validate_ssh(ssh: &SshConfig) -> Result<()> {
+        let jumps = ssh.proxy_jump.iter().chain(ssh.hosts.values().filter_map(|h| h.proxy_jump.as_ref()));
+        for jump in jumps {
+            if jump.is_empty() || jump.starts_with('-') || jump.chars().any(char::is_whitespace) {
+                anyhow::bail!("Invalid ssh.proxy_jump '{}'", jump);
+            }
+        }
+        if ssh.transport == SshTransport::Native && ssh.has_proxy_jump() {
+            anyhow::bail!("ssh.proxy_jump is not supported with the native SSH transport");
+        }
+        let t = &ssh.timeouts;
+        if t.query == 0 || t.command == 0 || t.build == 0 {
+            anyhow::bail!("ssh.timeouts must be greater than zero");
+        }
+        if ssh.connect_timeout == 0 {
+            anyhow::bail!("ssh.connect_timeout must be greater than zero");
+        }
+        if ssh.server_alive_interval > 0 && ssh.server_alive_count_max == 0 {
+            anyhow::bail!("ssh.server_alive_count_max must be greater than zero");
+        }
+        if let Some(name) = ssh.forwarded_env().find(|name| !Self::is_env_name(name)) {
+            anyhow::bail!("Invalid ssh environment variable name '{}'", name);
+        }
+        if let Some((name, _)) = ssh.set_env.iter().find(|(_, v)| v.contains(['"', '\'', '\n'])) {
+            anyhow::bail!("ssh.set_env value for '{}' must not contain quotes or newlines", name);
+        }
+        if ssh.transport == SshTransport::Native && ssh.forward_agent {
+            anyhow::bail!("ssh.forward_agent is not supported with the native SSH transport");
         }
         Ok(())
     }
@@ -114,18 +1654,75 @@ impl Config {
         // Check for deprecated 'strategy' field
         let value: serde_yaml::Value = serde_yaml::from_str(&content)
             .with_context(|| "Failed to parse YAML config")?;
-        if let Some(mapping) = value.as_mapping() {
-            if mapping.contains_key(&serde_yaml::Value::String("strategy".to_string())) {
-                anyhow::bail!("The 'strategy' field is no longer supported. Remove it from your config - jail deployment is now the only mode.");
-            }
+        if let Some(mapping) = value.as_mapping()
+            && mapping.contains_key(serde_yaml::Value::String("strategy".to_string()))
+        {
+            anyhow::bail!("The 'strategy' field is no longer supported. Remove it from your config - jail deployment is now the only mode.");
         }
 
         let config: Config = serde_yaml::from_str(&content)
             .with_context(|| "Failed to parse YAML config")?;
 
-        Self::validate_service_name(&config.service)?;
+        config.finish()
+    }
+
+    /// Validate the parsed config and resolve derived settings.
+    fn finish(mut self) -> Result<Self> {
+        Self::validate_service_name(&self.service)?;
+        Self::validate_mise_env(&self.mise)?;
+        Self::validate_ssh(&self.ssh)?;
+        Self::validate_sync(&self.sync)?;
+        self.validate_start()?;
+        self.validate_syslog()?;
+        self.validate_metrics()?;
+        self.validate_notify()?;
+        Self::validate_host(&self.host)?;
+        self.validate_boot()?;
+        if let Some(proxy) = &mut self.proxy {
+            if let Some(file) = &proxy.custom_directives_file {
+                let snippet = fs::read_to_string(file)
+                    .with_context(|| format!("Failed to read proxy.custom_directives_file: {}", file.display()))?;
+                proxy.custom_directives.extend(snippet.lines().map(str::to_string));
+            }
+            Self::validate_proxy(proxy)?;
+        }
+        self.validate_jail_ranges()?;
+        if let Some(bind) = &self.bind {
+            Self::validate_bind(bind, self.proxy.is_some())?;
+        }
+
+        if let Some(tool) = self.privilege_escalation {
+            self.doas = tool != PrivilegeEscalation::None;
+        }
+        if self.privilege_escalation == Some(PrivilegeEscalation::DoasHelper) {
+            self.validate_doas_helper()?;
+            self.helper = true;
+        }
+        Ok(self)
+    }
+
+    /// Base name of the pid and log files of the `index`th start command:
+    /// `service` for the first, `service-<name>` or `service-<index>` for
+    /// the others.
+    pub fn process_name(&self, index: usize) -> String {
+        match self.start.get(index).and_then(StartCommand::name) {
+            Some(name) => format!("service-{}", name),
+            None if index == 0 => "service".to_string(),
+            None => format!("service-{}", index),
+        }
+    }
+
+    /// Jail network on `host`.
+    pub fn jail_ip_range(&self, host: &str) -> &str {
+        let jail = self.jail.as_ref();
+        jail.and_then(|j| j.host_ip_ranges.get(host))
+            .or_else(|| jail.and_then(|j| j.ip_range.as_ref()))
+            .map_or(DEFAULT_IP_RANGE, String::as_str)
+    }
 
-        Ok(config)
+    /// The tool used for privileged commands when `doas` is set.
+    pub fn escalation(&self) -> PrivilegeEscalation {
+        self.privilege_escalation.unwrap_or_default()
     }
 
     /// Parse config from a YAML string (for testing)
@@ -133,17 +1730,15 @@ impl Config {
     pub fn from_str(content: &str) -> Result<Self> {
         let value: serde_yaml::Value = serde_yaml::from_str(content)
             .with_context(|| "Failed to parse YAML config")?;
-        if let Some(mapping) = value.as_mapping() {
-            if mapping.contains_key(&serde_yaml::Value::String("strategy".to_string())) {
-                anyhow::bail!("The 'strategy' field is no longer supported. Remove it from your config - jail deployment is now the only mode.");
-            }
+        if let Some(mapping) = value.as_mapping()
+            && mapping.contains_key(serde_yaml::Value::String("strategy".to_string()))
+        {
+            anyhow::bail!("The 'strategy' field is no longer supported. Remove it from your config - jail deployment is now the only mode.");
         }
         let config: Config = serde_yaml::from_str(content)
             .with_context(|| "Failed to parse YAML config")?;
 
-        Self::validate_service_name(&config.service)?;
-
-        Ok(config)
+        config.finish()
     }
 }
 
@@ -213,6 +1808,7 @@ proxy:
         assert!(config.start.is_empty());
         assert!(config.data_directories.is_empty());
         assert!(config.proxy.is_none());
+        assert_eq!(config.parallelism, 4);
     }
 
     #[test]
@@ -236,7 +1832,7 @@ proxy:
         assert_eq!(config.env.secret, vec!["SECRET_KEY_BASE"]);
 
         assert_eq!(config.before_start.len(), 2);
-        assert_eq!(config.start, vec!["bin/rails server"]);
+        assert_eq!(config.start, vec![StartCommand::Command("bin/rails server".to_string())]);
 
         assert_eq!(config.data_directories.len(), 2);
 
@@ -372,6 +1968,22 @@ jail: {}
         let jail = config.jail.unwrap();
         assert!(jail.base_version.is_none());
         assert!(jail.ip_range.is_none());
+        assert!(jail.base_tarball.is_none());
+    }
+
+    #[test]
+    fn test_jail_base_tarball() {
+        let config_yaml = r#"
+service: myapp
+hosts:
+  - example.com
+jail:
+  base_version: "14.1-RELEASE"
+  base_tarball: dist/base.txz
+"#;
+        let config = Config::from_str(config_yaml).unwrap();
+        let jail = config.jail.unwrap();
+        assert_eq!(jail.base_tarball, Some(PathBuf::from("dist/base.txz")));
     }
 
     #[test]
@@ -431,6 +2043,548 @@ proxy:
         // Note: ssl being present means TLS is enabled with manual certs
     }
 
+    #[test]
+    fn test_image_registry_not_set_by_default() {
+        let config = Config::from_str(minimal_config()).unwrap();
+        assert!(config.image.registry.is_none());
+    }
+
+    #[test]
+    fn test_image_registry() {
+        let config_yaml = r#"
+service: myapp
+hosts:
+  - example.com
+image:
+  registry:
+    host: registry.example.com
+"#;
+        let config = Config::from_str(config_yaml).unwrap();
+        let registry = config.image.registry.unwrap();
+        assert_eq!(registry.host, "registry.example.com");
+        assert_eq!(registry.path, "/var/db/bsdeploy-registry");
+        assert!(!registry.doas);
+    }
+
+    #[test]
+    fn test_image_bootstrap_packages_default() {
+        let config = Config::from_str(minimal_config()).unwrap();
+        assert_eq!(config.image.bootstrap_packages(), vec!["git", "bash"]);
+        assert_eq!(
+            config.image.mise_build_packages(),
+            vec!["gmake", "gcc", "python3", "pkgconf"]
+        );
+    }
+
+    #[test]
+    fn test_image_bootstrap_packages_override() {
+        let config_yaml = r#"
+service: myapp
+hosts:
+  - example.com
+image:
+  bootstrap_packages: [bash]
+  mise_build_packages: []
+"#;
+        let config = Config::from_str(config_yaml).unwrap();
+        assert_eq!(config.image.bootstrap_packages(), vec!["bash"]);
+        assert!(config.image.mise_build_packages().is_empty());
+    }
+
+    #[test]
+    fn test_mise_detailed_tool_and_build_env() {
+        let config_yaml = r#"
+service: myapp
+hosts:
+  - example.com
+mise:
+  build_env:
+    CC: clang
+    MAKEFLAGS: -j4
+  ruby:
+    version: 3.4.7
+    env:
+      RUBY_CONFIGURE_OPTS: --with-jemalloc
+  node: 20.0.0
+"#;
+        let config = Config::from_str(config_yaml).unwrap();
+        assert_eq!(config.mise.tools.len(), 2);
+        assert_eq!(config.mise.get("ruby"), Some(&"3.4.7".to_string()));
+        assert_eq!(config.mise.get("node"), Some(&"20.0.0".to_string()));
+
+        let ruby_env = config.mise.build_env_for("ruby");
+        assert_eq!(ruby_env.get("CC").map(String::as_str), Some("clang"));
+        assert_eq!(ruby_env.get("CXX").map(String::as_str), Some("g++"));
+        assert_eq!(ruby_env.get("MAKEFLAGS").map(String::as_str), Some("-j4"));
+        assert_eq!(
+            ruby_env.get("RUBY_CONFIGURE_OPTS").map(String::as_str),
+            Some("--with-jemalloc")
+        );
+
+        let node_env = config.mise.build_env_for("node");
+        assert!(!node_env.contains_key("RUBY_CONFIGURE_OPTS"));
+    }
+
+    #[test]
+    fn test_mise_build_env_only_is_empty() {
+        let config_yaml = r#"
+service: myapp
+hosts:
+  - example.com
+mise:
+  build_env:
+    CC: clang
+"#;
+        let config = Config::from_str(config_yaml).unwrap();
+        assert!(config.mise.is_empty());
+    }
+
+    #[test]
+    fn test_mise_invalid_build_env_name() {
+        let config_yaml = r#"
+service: myapp
+hosts:
+  - example.com
+mise:
+  build_env:
+    "CC; rm -rf /": gcc
+"#;
+        let result = Config::from_str(config_yaml);
+        assert!(result.is_err());
+        assert!(result.unwrap_err().to_string().contains("Invalid mise build environment"));
+    }
+
+    #[test]
+    fn test_image_poudriere() {
+        let config_yaml = r#"
+service: myapp
+hosts:
+  - example.com
+image:
+  poudriere:
+    jail: 141amd64
+    set: custom
+    bulk:
+      - www/nginx
+"#;
+        let config = Config::from_str(config_yaml).unwrap();
+        let poudriere = config.image.poudriere.unwrap();
+        assert_eq!(poudriere.ports_tree, "default");
+        assert_eq!(poudriere.bulk, vec!["www/nginx"]);
+        assert_eq!(
+            poudriere.packages_dir(),
+            "/usr/local/poudriere/data/packages/141amd64-default-custom"
+        );
+    }
+
+    #[test]
+    fn test_image_build_network() {
+        let config = Config::from_str("service: myapp\nhosts: [a]\n").unwrap();
+        assert_eq!(config.image.build_network, BuildNetwork::Inherit);
+        assert!(config.image.build_proxy.is_none());
+
+        let config_yaml = r#"
+service: myapp
+hosts:
+  - example.com
+image:
+  build_network: isolated
+  build_proxy: http://proxy.internal:3128
+"#;
+        let config = Config::from_str(config_yaml).unwrap();
+        assert_eq!(config.image.build_network, BuildNetwork::Isolated);
+        assert_eq!(config.image.build_proxy.as_deref(), Some("http://proxy.internal:3128"));
+
+        assert!(Config::from_str("service: myapp\nhosts: [a]\nimage:\n  build_network: vnet\n").is_err());
+    }
+
+    #[test]
+    fn test_ssh_transport() {
+        let config = Config::from_str("service: myapp\nhosts: [a]\n").unwrap();
+        assert_eq!(config.ssh.transport, SshTransport::Command);
+
+        let config = Config::from_str("service: myapp\nhosts: [a]\nssh:\n  transport: native\n").unwrap();
+        assert_eq!(config.ssh.transport, SshTransport::Native);
+        assert_eq!(config.ssh.connect_retries, 3);
+
+        let config = Config::from_str("service: myapp\nhosts: [a]\nssh:\n  connect_retries: 0\n").unwrap();
+        assert_eq!(config.ssh.connect_retries, 0);
+    }
+
+    #[test]
+    fn test_ssh_proxy_jump() {
+        let config_yaml = r#"
+service: myapp
+hosts:
+  - app1.internal
+  - app2.internal
+  - public.example.com
+ssh:
+  proxy_jump: deploy@bastion.example.com
+  hosts:
+    app2.internal:
+      proxy_jump: bastion2.example.com
+    public.example.com:
+      proxy_jump: none
+"#;
+        let config = Config::from_str(config_yaml).unwrap();
+        assert_eq!(config.ssh.proxy_jump_for("app1.internal"), Some("deploy@bastion.example.com"));
+        assert_eq!(config.ssh.proxy_jump_for("app2.internal"), Some("bastion2.example.com"));
+        assert_eq!(config.ssh.proxy_jump_for("public.example.com"), None);
+
+        assert!(Config::from_str("service: myapp\nhosts: [a]\nssh:\n  proxy_jump: -oProxyCommand=x\n").is_err());
+        assert!(Config::from_str("service: myapp\nhosts: [a]\nssh:\n  transport: native\n  proxy_jump: b\n").is_err());
+    }
+
+    #[test]
+    fn test_sync_options() {
+        let config = Config::from_str("service: myapp\nhosts: [a]\n").unwrap();
+        assert!(config.sync.bwlimit.is_none());
+        assert!(!config.sync.checksum);
+
+        let config_yaml = r#"
+service: myapp
+hosts: [a]
+sync:
+  bwlimit: 2m
+  compress_level: 0
+  checksum: true
+  extra_args: ["--partial"]
+"#;
+        let config = Config::from_str(config_yaml).unwrap();
+        assert_eq!(config.sync.bwlimit.as_deref(), Some("2m"));
+        assert_eq!(config.sync.compress_level, Some(0));
+        assert!(config.sync.checksum);
+        assert_eq!(config.sync.extra_args, ["--partial"]);
+        assert_eq!(config.sync.strategy, SyncStrategy::Rsync);
+
+        let config = Config::from_str("service: myapp\nhosts: [a]\nsync:\n  strategy: git\n").unwrap();
+        assert_eq!(config.sync.strategy, SyncStrategy::Git);
+
+        assert!(Config::from_str("service: myapp\nhosts: [a]\nsync:\n  compress_level: 12\n").is_err());
+        assert!(Config::from_str("service: myapp\nhosts: [a]\nsync:\n  bwlimit: '1m; rm'\n").is_err());
+    }
+
+    #[test]
+    fn test_privilege_escalation() {
+        let config = Config::from_str("service: myapp\nhosts: [a]\ndoas: true\n").unwrap();
+        assert!(config.doas);
+        assert_eq!(config.escalation(), PrivilegeEscalation::Doas);
+
+        let config = Config::from_str("service: myapp\nhosts: [a]\nprivilege_escalation: sudo\n").unwrap();
+        assert!(config.doas);
+        assert_eq!(config.escalation(), PrivilegeEscalation::Sudo);
+
+        let config = Config::from_str("service: myapp\nhosts: [a]\ndoas: true\nprivilege_escalation: none\n").unwrap();
+        assert!(!config.doas);
+
+        let base = "service: myapp\nhosts: [a]\nprivilege_escalation: doas-helper\n";
+        let config = Config::from_str(base).unwrap();
+        assert!(config.doas && config.helper);
+        assert_eq!(config.escalation(), PrivilegeEscalation::DoasHelper);
+        assert!(Config::from_str(&format!("{}data_directories: ['/var/db/bsdeploy/myapp/uploads']\n", base)).is_ok());
+        assert!(Config::from_str(&format!("{}data_directories: ['/var/data']\n", base)).is_err());
+        assert!(Config::from_str(&format!("{}host: {{ loader: {{ kern.racct.enable: 1 }} }}\n", base)).is_err());
+    }
+
+    #[test]
+    fn test_ssh_timeouts() {
+        let config = Config::from_str("service: myapp\nhosts: [a]\n").unwrap();
+        assert_eq!(config.ssh.timeouts.query, 60);
+        assert_eq!(config.ssh.timeouts.build, 3600);
+
+        let config = Config::from_str("service: myapp\nhosts: [a]\nssh:\n  timeouts:\n    build: 10800\n").unwrap();
+        assert_eq!(config.ssh.timeouts.build, 10800);
+        assert_eq!(config.ssh.timeouts.command, 900);
+
+        assert!(Config::from_str("service: myapp\nhosts: [a]\nssh:\n  timeouts:\n    query: 0\n").is_err());
+    }
+
+    #[test]
+    fn test_proxy_server() {
+        let base = "service: myapp\nhosts: [a]\nproxy:\n  hostname: a.example.com\n  port: 3000\n";
+        let config = Config::from_str(base).unwrap();
+        assert_eq!(config.proxy.unwrap().server, ProxyServer::Caddy);
+
+        let config = Config::from_str(&format!("{}  server: relayd\n  tls: false\n", base)).unwrap();
+        assert_eq!(config.proxy.unwrap().server, ProxyServer::Relayd);
+
+        // relayd has no ACME client
+        assert!(Config::from_str(&format!("{}  server: relayd\n", base)).is_err());
+        assert!(Config::from_str(&format!("{}  server: relayd\n  tls: false\n  admin_api: true\n", base)).is_err());
+        assert!(Config::from_str(&format!("{}  server: relayd\n  tls: false\n  redirect_hosts: [b.example.com]\n", base)).is_err());
+
+        assert!(Config::from_str(&format!("{}  redirect_hosts: [www.example.com]\n", base)).is_ok());
+        assert!(Config::from_str(&format!("{}  redirect_hosts: [a.example.com]\n", base)).is_err());
+        assert!(Config::from_str(&format!("{}  aliases: [b.example.com]\n  redirect_hosts: [b.example.com]\n", base)).is_err());
+        assert!(Config::from_str(&format!("{}  aliases: ['b {{']\n", base)).is_err());
+
+        for prefix in ["/api", "/", "/v1/admin"] {
+            assert!(Config::from_str(&format!("{}  path_prefix: {}\n", base, prefix)).is_ok(), "{}", prefix);
+        }
+        assert!(Config::from_str(&format!("{}  custom_directives: ['header {{', '}}']\n", base)).is_ok());
+        assert!(Config::from_str(&format!("{}  custom_directives: ['}}', 'evil {{']\n", base)).is_err());
+        assert!(Config::from_str(&format!("{}  custom_directives: ['header {{']\n", base)).is_err());
+
+        assert!(Config::from_str(&format!("{}  path_prefix: /api\n  error_pages: {{ bad_gateway: 502.html }}\n", base)).is_err());
+
+        let dns = "  dns_provider: { name: cloudflare, api_token: CF_API_TOKEN }\n";
+        assert!(Config::from_str(&format!("{}  aliases: ['*.a.example.com']\n", base)).is_err());
+        assert!(Config::from_str(&format!("{}  aliases: ['*.a.example.com']\n{}", base, dns)).is_ok());
+        assert!(Config::from_str(&format!("{}{}  tls: false\n", base, dns)).is_err());
+        assert!(Config::from_str(&format!("{}  dns_provider: {{ name: ../x, api_token: T }}\n", base)).is_err());
+
+        assert!(Config::from_str(&format!("{}  client_auth: {{ ca_certificate_file: ca.pem }}\n", base)).is_ok());
+        assert!(Config::from_str(&format!("{}  client_auth: {{ mode: require }}\n", base)).is_err());
+        assert!(Config::from_str(&format!("{}  tls: false\n  client_auth: {{ ca_certificate_pem: CA }}\n", base)).is_err());
+
+        assert!(Config::from_str(&format!("{}  plain_http_from: [10.0.0.0/8, 'fd00::1']\n", base)).is_ok());
+        assert!(Config::from_str(&format!("{}  plain_http_from: [internal]\n", base)).is_err());
+        let mtls = "  client_auth: { ca_certificate_file: ca.pem }\n";
+        assert!(Config::from_str(&format!("{}  plain_http_from: [10.0.0.0/8]\n{}", base, mtls)).is_err());
+        assert!(Config::from_str(&format!("{}  tls: false\n  https_redirect: false\n", base)).is_err());
+        assert!(Config::from_str(&format!("{}  hsts: {{ preload: true }}\n", base)).is_err());
+        let hsts = Config::from_str(&format!("{}  hsts: {{ include_subdomains: true, preload: true }}\n", base)).unwrap();
+        assert_eq!(
+            hsts.proxy.unwrap().hsts.unwrap().header_value(),
+            "max-age=31536000; includeSubDomains; preload"
+        );
+
+        assert!(Config::from_str(&format!("{}  compression: {{ zstd: false, gzip: false }}\n", base)).is_err());
+
+        assert!(Config::from_str(&format!("{}  status_endpoint: {{ port: 3000 }}\n", base)).is_err());
+        assert!(Config::from_str(&format!("{}  status_endpoint: {{ port: 9180, bind: localhost }}\n", base)).is_err());
+
+        for prefix in ["api", "/api/", "/api/*", "'/a b'"] {
+            assert!(Config::from_str(&format!("{}  path_prefix: {}\n", base, prefix)).is_err(), "{}", prefix);
+        }
+    }
+
+    #[test]
+    fn test_bind() {
+        let base = "service: mail\nhosts: [a]\n";
+        let config = Config::from_str(&format!("{}proxy: false\nbind:\n  ports: [25, 587]\n", base)).unwrap();
+        assert!(config.proxy.is_none());
+        assert_eq!(config.bind.unwrap().ports, [25, 587]);
+
+        assert!(Config::from_str(&format!("{}proxy: true\n", base)).is_err());
+        assert!(Config::from_str(&format!("{}bind: {{}}\n", base)).is_err());
+        assert!(Config::from_str(&format!("{}bind: {{ ports: [25, 25] }}\n", base)).is_err());
+        assert!(Config::from_str(&format!("{}bind: {{ udp_ports: [53], address: '::1' }}\n", base)).is_err());
+        assert!(
+            Config::from_str(&format!("{}proxy:\n  hostname: a.example.com\n  port: 3000\nbind: {{ ports: [25] }}\n", base))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_start_logs() {
+        let config = Config::from_str(
+            "service: app\nhosts: [a]\nlogs: { split_stderr: true }\nstart:\n  - bin/web\n  - command: bin/worker\n    error_log: /var/log/worker.err\n",
+        )
+        .unwrap();
+        assert!(config.logs.split_stderr && !config.logs.timestamps);
+        assert_eq!(config.start[0].command(), "bin/web");
+        assert_eq!(config.start[1].command(), "bin/worker");
+        assert_eq!(config.start[1].log(), None);
+        assert_eq!(config.start[1].error_log(), Some("/var/log/worker.err"));
+
+        let start = |path: &str| Config::from_str(&format!("service: app\nhosts: [a]\nstart:\n  - {{ command: x, log: '{}' }}\n", path));
+        assert!(start("worker.log").is_err());
+        assert!(start("/var/log/$HOME.log").is_err());
+
+        let config = Config::from_str(
+            "service: app\nhosts: [a]\nstart:\n  - bin/web\n  - bin/jobs\n  - { command: bin/worker, name: worker }\n",
+        )
+        .unwrap();
+        let names: Vec<String> = (0..3).map(|i| config.process_name(i)).collect();
+        assert_eq!(names, ["service", "service-1", "service-worker"]);
+        let names = |a: &str, b: &str| {
+            Config::from_str(&format!("service: app\nhosts: [a]\nstart:\n  - {}\n  - {}\n", a, b))
+        };
+        assert!(names("{ command: x, name: web }", "{ command: y, name: web }").is_err());
+
+        let config = Config::from_str(
+            "service: app\nhosts: [a]\nstart:\n  - bin/web\n  - { command: bin/worker, restart: on-failure, restart_delay: 5 }\n",
+        )
+        .unwrap();
+        assert_eq!(config.start[0].restart(), RestartPolicy::Never);
+        assert_eq!((config.start[1].restart(), config.start[1].restart_delay()), (RestartPolicy::OnFailure, Some(5)));
+        assert!(names("bin/web", "{ command: x, restart_delay: 5 }").is_err());
+        assert!(names("bin/web", "{ command: x, restart: always, restart_delay: 0 }").is_err());
+        assert!(names("bin/web", "{ command: x, restart: sometimes }").is_err());
+
+        let rotate = |yaml: &str| Config::from_str(&format!("service: app\nhosts: [a]\nlogs:\n  rotate: {}\n", yaml));
+        let config = rotate("{ size_kb: 10240 }").unwrap();
+        let settings = config.logs.rotate.unwrap();
+        assert_eq!((settings.keep, settings.compress), (7, LogCompression::Gzip));
+        assert!(rotate("{ when: '@T00', compress: zstd }").is_ok());
+        assert!(rotate("{ keep: 3 }").is_err());
+        assert!(rotate("{ when: '@T00 Z' }").is_err());
+        assert!(Config::from_str("service: app\nhosts: [a]\nstart: [x, y, { command: z, name: '1' }]\n").is_err());
+        assert!(names("x", "{ command: y, name: a/b }").is_err());
+    }
+
+    #[test]
+    fn test_syslog() {
+        let base = "service: app\nhosts: [a]\n";
+        let config = Config::from_str(&format!("{}syslog: {{ forward: 'logs.internal:5514' }}\n", base)).unwrap();
+        let syslog = config.syslog.unwrap();
+        assert_eq!((syslog.selector.as_str(), syslog.output), ("*.*", false));
+
+        assert!(Config::from_str(&format!("{}syslog: {{ forward: 'logs.internal:syslog' }}\n", base)).is_err());
+        assert!(Config::from_str(&format!("{}syslog: {{ forward: 'tcp://logs.internal' }}\n", base)).is_err());
+        assert!(Config::from_str(&format!("{}syslog: {{ forward: logs, selector: '*.* ' }}\n", base)).is_err());
+        assert!(
+            Config::from_str(&format!("{}logs: {{ timestamps: true }}\nsyslog: {{ forward: logs, output: true }}\n", base))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_metrics() {
+        let base = "service: app\nhosts: [a]\n";
+        let config = Config::from_str(&format!("{}metrics: {{ statsd: 'stats.internal:8125' }}\n", base)).unwrap();
+        assert_eq!(config.metrics.unwrap().prefix, "bsdeploy");
+        Config::from_str(&format!("{}metrics: {{ pushgateway: 'http://pushgw:9091', prefix: deploys }}\n", base)).unwrap();
+
+        assert!(Config::from_str(&format!("{}metrics: {{}}\n", base)).is_err());
+        assert!(Config::from_str(&format!("{}metrics: {{ statsd: stats.internal }}\n", base)).is_err());
+        assert!(Config::from_str(&format!("{}metrics: {{ pushgateway: 'pushgw:9091' }}\n", base)).is_err());
+        assert!(Config::from_str(&format!("{}metrics: {{ statsd: 'stats:8125', prefix: 'a.b' }}\n", base)).is_err());
+    }
+
+    #[test]
+    fn test_notify() {
+        let base = "service: app\nhosts: [a]\n";
+        let config = Config::from_str(&format!("{}notify: {{ slack: 'https://hooks.slack.com/services/T/B/x' }}\n", base)).unwrap();
+        assert!(config.notify.unwrap().webhook.is_none());
+        assert!(Config::from_str(&format!("{}notify: {{}}\n", base)).is_err());
+        assert!(Config::from_str(&format!("{}notify: {{ webhook: alerts.internal/hook }}\n", base)).is_err());
+    }
+
+    #[test]
+    fn test_host_tunables() {
+        let base = "service: app\nhosts: [a]\n";
+        let config = Config::from_str(&format!(
+            "{}host:\n  sysctls:\n    kern.ipc.somaxconn: 1024\n    net.inet.ip.portrange.first: '10000'\n  loader:\n    kern.racct.enable: 1\n",
+            base
+        ))
+        .unwrap();
+        assert_eq!(config.host.sysctls["kern.ipc.somaxconn"], "1024");
+        assert_eq!(config.host.sysctls["net.inet.ip.portrange.first"], "10000");
+        assert_eq!(config.host.loader["kern.racct.enable"], "1");
+        assert!(Config::from_str(&format!("{}host: {{ sysctls: {{ kern.ipc.somaxconn: [1] }} }}\n", base)).is_err());
+        assert!(Config::from_str(&format!("{}host: {{ sysctls: {{ 'kern ipc': 1 }} }}\n", base)).is_err());
+        assert!(Config::from_str(&format!("{}host: {{ loader: {{ hw.x: 'a\"b' }} }}\n", base)).is_err());
+
+        let config = Config::from_str(&format!("{}host: {{ firewall: true, open_ports: [9100] }}\n", base)).unwrap();
+        assert!(config.host.firewall);
+        assert!(Config::from_str(&format!("{}host: {{ open_ports: [9100] }}\n", base)).is_err());
+        assert_eq!(config.host.pkg_update_hours, 24);
+        let config = Config::from_str(&format!("{}host: {{ pkg_update_hours: 0 }}\n", base)).unwrap();
+        assert_eq!(config.host.pkg_update_hours, 0);
+        let config = Config::from_str(&format!("{}host: {{ housekeeping: weekly }}\n", base)).unwrap();
+        assert_eq!(config.host.housekeeping, Some(Period::Weekly));
+        assert!(Config::from_str(&format!("{}host: {{ housekeeping: hourly }}\n", base)).is_err());
+
+        assert_eq!(config.host.zfs.properties["compression"], "zstd");
+        let config = Config::from_str(&format!("{}host: {{ zfs: {{ properties: {{}}, data_properties: {{ recordsize: 16K }} }} }}\n", base)).unwrap();
+        assert!(config.host.zfs.properties.is_empty());
+        assert_eq!(config.host.zfs.data_properties["recordsize"], "16K");
+        assert!(Config::from_str(&format!("{}host: {{ zfs: {{ properties: {{ atime: 'off now' }} }} }}\n", base)).is_err());
+    }
+
+    #[test]
+    fn test_acme() {
+        let base = "service: myapp\nhosts: [a]\nproxy:\n  hostname: a.example.com\n  port: 3000\n";
+        let config = Config::from_str(&format!("{}  acme: {{ email: ops@example.com, staging: true }}\n", base)).unwrap();
+        assert!(config.proxy.unwrap().acme.unwrap().staging);
+
+        assert!(Config::from_str(&format!("{}  acme: {{ email: ops }}\n", base)).is_err());
+        assert!(Config::from_str(&format!("{}  acme: {{ ca: 'http://ca.internal/acme' }}\n", base)).is_err());
+        assert!(Config::from_str(&format!("{}  acme: {{ ca: 'https://ca.internal/acme', staging: true }}\n", base)).is_err());
+        assert!(Config::from_str(&format!("{}  acme: {{ ca_root: root.crt }}\n", base)).is_err());
+    }
+
+    #[test]
+    fn test_listen_ports() {
+        let base = "service: myapp\nhosts: [a]\nproxy:\n  hostname: a.example.com\n  port: 3000\n";
+        let config = Config::from_str(base).unwrap();
+        assert_eq!(config.proxy.unwrap().listen_ports(), (80, 443));
+        let config = Config::from_str(&format!("{}  https_port: 8443\n", base)).unwrap();
+        assert_eq!(config.proxy.unwrap().listen_ports(), (80, 8443));
+
+        assert!(Config::from_str(&format!("{}  http_port: 443\n", base)).is_err());
+        assert!(Config::from_str(&format!("{}  http_port: 3000\n", base)).is_err());
+        assert!(Config::from_str(&format!("{}  server: relayd\n  http_port: 8080\n", base)).is_err());
+    }
+
+    #[test]
+    fn test_transport() {
+        let base = "service: myapp\nhosts: [a]\nproxy:\n  hostname: a.example.com\n  port: 3000\n";
+        let config = Config::from_str(&format!("{}  transport: {{ read_timeout: 3600, flush_interval: -1 }}\n", base)).unwrap();
+        let transport = config.proxy.unwrap().transport.unwrap();
+        assert_eq!((transport.read_timeout, transport.flush_interval), (Some(3600), Some(-1)));
+
+        assert!(Config::from_str(&format!("{}  transport: {{ dial_timeout: 0 }}\n", base)).is_err());
+        assert!(Config::from_str(&format!("{}  transport: {{ flush_interval: -2 }}\n", base)).is_err());
+        assert!(
+            Config::from_str(&format!("{}  server: relayd\n  tls: false\n  transport: {{ read_timeout: 60 }}\n", base))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_healthcheck() {
+        let base = "service: myapp\nhosts: [a]\nproxy:\n  hostname: a.example.com\n  port: 3000\n";
+        let config = Config::from_str(&format!("{}  healthcheck: {{ path: /up }}\n", base)).unwrap();
+        let check = config.proxy.unwrap().healthcheck.unwrap();
+        assert_eq!((check.interval, check.timeout, check.fail_duration, check.max_fails), (10, 5, 30, 1));
+
+        assert!(Config::from_str(&format!("{}  healthcheck: {{}}\n", base)).is_ok());
+        assert!(Config::from_str(&format!("{}  healthcheck: {{ fail_duration: 0 }}\n", base)).is_err());
+        assert!(Config::from_str(&format!("{}  healthcheck: {{ path: up }}\n", base)).is_err());
+        assert!(Config::from_str(&format!("{}  healthcheck: {{ path: /up, interval: 0 }}\n", base)).is_err());
+    }
+
+    #[test]
+    fn test_load_balancer() {
+        let base = "service: myapp\nhosts: [web1, web2]\nproxy:\n  hostname: a.example.com\n  port: 3000\n  load_balancer: { host: lb1 }\n";
+        let ranges = "jail:\n  host_ip_ranges:\n    web1: 10.0.1.0/24\n    web2: 10.0.2.0/24\n";
+        let config = Config::from_str(&format!("{}{}", base, ranges)).unwrap();
+        let lb = config.proxy.as_ref().unwrap().load_balancer.as_ref().unwrap();
+        assert_eq!(lb.policy, "round_robin");
+        assert_eq!(config.jail_ip_range("web2"), "10.0.2.0/24");
+        assert_eq!(config.jail_ip_range("other"), DEFAULT_IP_RANGE);
+
+        // Both hosts would use the default range
+        assert!(Config::from_str(base).is_err());
+        assert!(Config::from_str(&format!("{}{}    web3: 10.0.3.0/24\n", base, ranges)).is_err());
+        let with = |lb: &str| Config::from_str(&format!("{}{}", base.replace("{ host: lb1 }", lb), ranges));
+        assert!(with("{ host: lb1, policy: least_conn }").is_ok());
+        assert!(with("{ host: lb1, policy: fastest }").is_err());
+        assert!(with("{ host: lb1 }\n  server: relayd\n  tls: false").is_err());
+    }
+
+    #[test]
+    fn test_ssh_forwarded_env() {
+        let config = Config::from_str(
+            "service: myapp\nhosts: [a]\nssh:\n  forward_agent: true\n  send_env: [GITHUB_TOKEN]\n  set_env:\n    BUNDLE_WITHOUT: development\n",
+        )
+        .unwrap();
+        assert!(config.ssh.forward_agent);
+        assert_eq!(config.ssh.forwarded_env().collect::<Vec<_>>(), ["GITHUB_TOKEN", "BUNDLE_WITHOUT"]);
+
+        assert!(Config::from_str("service: myapp\nhosts: [a]\nssh:\n  send_env: [GIT-TOKEN]\n").is_err());
+        assert!(Config::from_str("service: myapp\nhosts: [a]\nssh:\n  set_env:\n    A: \"x'y\"\n").is_err());
+
+        // The native transport sets the environment on its channels but cannot forward the agent
+        let native = "service: myapp\nhosts: [a]\nssh:\n  transport: native\n";
+        assert!(Config::from_str(&format!("{}  send_env: [GITHUB_TOKEN]\n", native)).is_ok());
+        assert!(Config::from_str(&format!("{}  forward_agent: true\n", native)).is_err());
+    }
+
     #[test]
     fn test_service_name_valid() {
         let config_yaml = r#"
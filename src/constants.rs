@@ -7,9 +7,22 @@ pub const BASE_DIR: &str = "/usr/local/bsdeploy/base";
 /// Directory for storing built images (base + packages + mise)
 pub const IMAGES_DIR: &str = "/usr/local/bsdeploy/images";
 
+/// Directory for shared image layers (base + packages) that images are cloned from
+pub const IMAGE_LAYERS_DIR: &str = "/usr/local/bsdeploy/images/layers";
+
+/// Manifest written into the root of every built image
+pub const IMAGE_MANIFEST_FILE: &str = ".bsdeploy-image.json";
+
+/// Remote helper script installed when `helper: true`
+pub const HELPER_PATH: &str = "/usr/local/libexec/bsdeploy-helper";
+
 /// Directory for storing jail instances
 pub const JAILS_DIR: &str = "/usr/local/bsdeploy/jails";
 
+/// Per-service copy of the application, uploaded to all hosts concurrently
+/// before the jails are created
+pub const STAGING_DIR: &str = "/usr/local/bsdeploy/staging";
+
 /// Directory for active jail symlinks (for boot persistence)
 pub const ACTIVE_DIR: &str = "/usr/local/bsdeploy/active";
 
@@ -31,12 +44,22 @@ pub const APP_DATA_DIR: &str = "/var/db/bsdeploy";
 /// Service configuration directory on host
 pub const CONFIG_DIR: &str = "/usr/local/etc/bsdeploy";
 
+/// What `setup` provisioned the host with, see `commands::host_manifest`
+pub const HOST_MANIFEST_PATH: &str = "/usr/local/etc/bsdeploy/host.json";
+
+/// Version of the directory layout above; bumped whenever bsdeploy moves
+/// or reinterprets what it keeps on hosts, so older hosts are set up again
+pub const LAYOUT_VERSION: u32 = 1;
+
 /// Runtime directory for PID files
 pub const RUN_DIR: &str = "/var/run/bsdeploy";
 
 /// Log directory for service logs
 pub const LOG_DIR: &str = "/var/log/bsdeploy";
 
+/// newsyslog(8) entries of the services' log rotation
+pub const NEWSYSLOG_CONF_DIR: &str = "/usr/local/etc/newsyslog.conf.d";
+
 /// Caddy configuration directory
 pub const CADDY_CONF_DIR: &str = "/usr/local/etc/caddy/conf.d";
 
@@ -46,8 +69,60 @@ pub const CADDYFILE_PATH: &str = "/usr/local/etc/caddy/Caddyfile";
 /// Directory for TLS certificates on remote host
 pub const CADDY_CERTS_DIR: &str = "/usr/local/etc/caddy/certs";
 
+/// Caddy access logs, one file per service
+pub const CADDY_LOG_DIR: &str = "/var/log/caddy";
+
+/// Status summaries served by the internal status sites
+pub const CADDY_STATUS_DIR: &str = "/usr/local/etc/caddy/status";
+
+/// Custom error pages, one directory per service
+pub const CADDY_PAGES_DIR: &str = "/usr/local/etc/caddy/pages";
+
+/// Caddy admin API endpoint (Caddy's default)
+pub const CADDY_ADMIN_ADDRESS: &str = "localhost:2019";
+
+/// Main relayd configuration file
+pub const RELAYD_CONF_PATH: &str = "/usr/local/etc/relayd.conf";
+
+/// relayd configuration generated from all services, included by the main file
+pub const RELAYD_BSDEPLOY_CONF: &str = "/usr/local/etc/relayd.d/bsdeploy.conf";
+
+/// Where relayd looks up `tls keypair` certificates and keys
+pub const RELAYD_CERT_DIR: &str = "/etc/ssl";
+pub const RELAYD_KEY_DIR: &str = "/etc/ssl/private";
+
 /// Default ZFS pool name
 pub const DEFAULT_ZFS_POOL: &str = "zroot";
 
 /// Number of old jails to keep for rollback
 pub const JAILS_TO_KEEP: usize = 3;
+
+/// Packages installed into every image unless `image.bootstrap_packages` is set
+pub const DEFAULT_BOOTSTRAP_PACKAGES: &[&str] = &["git", "bash"];
+
+/// Build dependencies installed with mise unless `image.mise_build_packages` is set
+pub const DEFAULT_MISE_BUILD_PACKAGES: &[&str] = &["gmake", "gcc", "python3", "pkgconf"];
+
+/// Default environment for building mise runtimes (FreeBSD base has no gcc/gmake aliases)
+pub const DEFAULT_MISE_BUILD_ENV: &[(&str, &str)] = &[("CC", "gcc"), ("CXX", "g++"), ("MAKE", "gmake")];
+
+/// Poudriere's default package repository root
+pub const POUDRIERE_PACKAGES_DIR: &str = "/usr/local/poudriere/data/packages";
+
+/// Mountpoint of the poudriere repository inside image build jails
+pub const BUILD_POUDRIERE_MOUNT: &str = "/mnt/poudriere";
+
+/// Default number of retries for SSH connection failures
+pub const DEFAULT_SSH_CONNECT_RETRIES: u32 = 3;
+
+/// Default seconds to establish an SSH connection
+pub const DEFAULT_SSH_CONNECT_TIMEOUT: u64 = 30;
+
+/// Default keepalive: probe every 15s, give up after 4 missed replies
+pub const DEFAULT_SSH_SERVER_ALIVE_INTERVAL: u64 = 15;
+pub const DEFAULT_SSH_SERVER_ALIVE_COUNT_MAX: u32 = 4;
+
+/// Default timeouts (seconds) for quick queries, regular commands and builds
+pub const DEFAULT_QUERY_TIMEOUT: u64 = 60;
+pub const DEFAULT_COMMAND_TIMEOUT: u64 = 900;
+pub const DEFAULT_BUILD_TIMEOUT: u64 = 3600;
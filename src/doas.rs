@@ -0,0 +1,218 @@
+//! Installing doas on hosts that lack it (`setup --install-doas`): the
+//! package, the helper script owned by root, and a doas.conf rule letting
+//! the SSH user run only that helper as root without a password
+//! (`privilege_escalation: doas-helper`). This has to run as root once,
+//! either by logging in as root or through `su`.
+//!
+//! `setup --bootstrap` goes one step further on fresh hosts: logged in as
+//! root, it also creates the deploy user and installs the operator's SSH
+//! keys for it.
+
+use anyhow::{Context, Result, anyhow, bail};
+use std::path::Path;
+
+use crate::constants::HELPER_PATH;
+use crate::{helper, remote};
+
+const DOAS_CONF: &str = "/usr/local/etc/doas.conf";
+
+/// Script left in the SSH user's home for `su` to run
+const SU_SCRIPT: &str = ".bsdeploy-doas.sh";
+
+/// How to get root on a host that has no doas yet
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RootAccess {
+    /// Log in as root over SSH
+    Root,
+    /// Log in as the configured user and `su` to root (prompts for the root password)
+    Su,
+}
+
+/// The doas.conf block for `user`, between markers so it can be replaced.
+/// It only permits the helper, whose `exec` decides what runs as root.
+fn rules(user: &str) -> String {
+    format!(
+        "# BEGIN bsdeploy {user}\npermit nopass {user} as root cmd {helper}\n# END bsdeploy {user}\n",
+        user = user,
+        helper = HELPER_PATH
+    )
+}
+
+/// Root script installing doas and the helper, and replacing the user's
+/// block in doas.conf after checking the new file parses.
+fn install_script(user: &str) -> String {
+    format!(
+        r#"set -e
+command -v doas >/dev/null 2>&1 || ASSUME_ALWAYS_YES=yes pkg install -y doas
+tmp=$(mktemp)
+helper=$(mktemp)
+trap 'rm -f "$tmp" "$helper"' EXIT
+cat > "$helper" <<'BSDEPLOY_HELPER'
+{script}BSDEPLOY_HELPER
+mkdir -p {libexec}
+install -m 0755 -o root -g wheel "$helper" {helper}
+[ -f {conf} ] && sed '/^# BEGIN bsdeploy {user}$/,/^# END bsdeploy {user}$/d' {conf} > "$tmp"
+cat >> "$tmp" <<'EOF'
+{rules}EOF
+doas -C "$tmp"
+install -m 0600 -o root -g wheel "$tmp" {conf}
+"#,
+        conf = DOAS_CONF,
+        user = user,
+        script = helper::restricted_script(),
+        libexec = HELPER_PATH.rsplit_once('/').map_or("/", |(dir, _)| dir),
+        helper = HELPER_PATH,
+        rules = rules(user)
+    )
+}
+
+/// Checks that `user` can run the helper through doas without a password
+fn check_command() -> String {
+    format!("doas -n {} version </dev/null", HELPER_PATH)
+}
+
+/// Whether `user` is safe to put into doas.conf and shell scripts
+fn is_valid_user(user: &str) -> bool {
+    !user.is_empty() && user.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+}
+
+/// `root@` the host name of a `[user@]host` entry
+fn root_target(host: &str) -> String {
+    format!("root@{}", host.rsplit('@').next().unwrap_or(host))
+}
+
+/// Public keys in `ssh_dir` the deploy user is given, in the order ssh
+/// tries the default identities.
+fn public_keys(ssh_dir: &Path) -> Vec<String> {
+    ["id_ed25519.pub", "id_ecdsa.pub", "id_rsa.pub"]
+        .iter()
+        .filter_map(|name| std::fs::read_to_string(ssh_dir.join(name)).ok())
+        .map(|key| key.trim().to_string())
+        .filter(|key| key.starts_with("ssh-") || key.starts_with("ecdsa-"))
+        .collect()
+}
+
+/// Root script creating `user` with a login shell and adding `keys` to its
+/// authorized_keys, followed by `install_script`.
+fn bootstrap_script(user: &str, keys: &[String]) -> String {
+    format!(
+        r#"set -e
+id {user} >/dev/null 2>&1 || pw useradd -n {user} -m -s /bin/sh -c 'bsdeploy deploy user'
+home=$(pw usershow -n {user} | cut -d: -f9)
+install -d -m 0700 -o {user} -g $(id -gn {user}) "$home/.ssh"
+touch "$home/.ssh/authorized_keys"
+while IFS= read -r key; do
+    grep -qxF "$key" "$home/.ssh/authorized_keys" || echo "$key" >> "$home/.ssh/authorized_keys"
+done <<'KEYS'
+{keys}
+KEYS
+chown {user} "$home/.ssh/authorized_keys"
+chmod 0600 "$home/.ssh/authorized_keys"
+{install}"#,
+        user = user,
+        keys = keys.join("\n"),
+        install = install_script(user)
+    )
+}
+
+/// Prepare a fresh host as root: create the deploy user of the `host`
+/// entry (or the local user name, as ssh would), install the local SSH
+/// public keys for it, and install doas with the helper and its rule.
+/// Then check that the deploy user can log in and run the helper.
+pub fn bootstrap(host: &str) -> Result<()> {
+    let user = match host.split_once('@') {
+        Some((user, _)) => user.to_string(),
+        None => std::env::var("USER").map_err(|_| anyhow!("USER is not set; name the deploy user in the host entry, e.g. deploy@{}", host))?,
+    };
+    if user == "root" {
+        bail!("--bootstrap creates an unprivileged deploy user; name it in the host entry, e.g. deploy@{}", host);
+    }
+    if !is_valid_user(&user) {
+        bail!("Invalid deploy user name for {}: {}", host, user);
+    }
+    let home = std::env::var("HOME").map_err(|_| anyhow!("HOME is not set"))?;
+    let keys = public_keys(&Path::new(&home).join(".ssh"));
+    if keys.is_empty() {
+        bail!("No SSH public key found in ~/.ssh (id_ed25519.pub, id_ecdsa.pub or id_rsa.pub) to install for {}", user);
+    }
+
+    let root = root_target(host);
+    remote::run_with_input(&root, "sh -s", &bootstrap_script(&user, &keys))
+        .with_context(|| format!("Failed to bootstrap {} as {}", user, root))?;
+    remote::run(host, &check_command())
+        .with_context(|| format!("{} cannot log in with the installed keys, or doas asks for a password, on {}", user, host))
+}
+
+/// Install doas and the helper on `host` and let the SSH user run the
+/// helper as root, then check that it works without a password.
+pub fn install(host: &str, access: RootAccess) -> Result<()> {
+    let user = remote::query(host, "id -un")?.trim().to_string();
+    if user == "root" {
+        bail!("{} is reached as root, which needs no doas; set privilege_escalation: none instead", host);
+    }
+    if !is_valid_user(&user) {
+        bail!("Unexpected user name on {}: {}", host, user);
+    }
+    let script = install_script(&user);
+
+    match access {
+        RootAccess::Root => {
+            let root = root_target(host);
+            remote::run_with_input(&root, "sh -s", &script)
+                .with_context(|| format!("Failed to install doas as {}", root))?;
+        }
+        RootAccess::Su => {
+            remote::run_with_input(host, &format!("umask 077; cat > {}", SU_SCRIPT), &script)?;
+            let result = remote::run_interactive(host, &format!("su root -c 'sh {}'", SU_SCRIPT));
+            remote::run(host, &format!("rm -f {}", SU_SCRIPT)).ok();
+            result.with_context(|| format!("Failed to install doas through su on {}", host))?;
+        }
+    }
+
+    remote::run(host, &check_command())
+        .with_context(|| format!("doas still asks {} for a password on {}", user, host))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::remote::mock::{self, MockExecutor};
+
+    #[test]
+    fn test_install() {
+        let script = install_script("deploy");
+        assert!(script.contains("sed '/^# BEGIN bsdeploy deploy$/,/^# END bsdeploy deploy$/d' /usr/local/etc/doas.conf"));
+        assert!(script.contains(
+            "<<'EOF'\n# BEGIN bsdeploy deploy\npermit nopass deploy as root cmd /usr/local/libexec/bsdeploy-helper\n# END bsdeploy deploy\nEOF\ndoas -C \"$tmp\"\n"
+        ));
+        // The helper goes in first, owned by root, in its restricted form
+        assert!(script.contains("RESTRICTED=\"yes\"\n"));
+        assert!(script.contains("BSDEPLOY_HELPER\nmkdir -p /usr/local/libexec\ninstall -m 0755 -o root -g wheel \"$helper\" /usr/local/libexec/bsdeploy-helper\n"));
+
+        let mock = MockExecutor::new();
+        mock.respond("id -un", "deploy\n");
+        mock::with_executor(mock.clone(), || install("deploy@web1", RootAccess::Root)).unwrap();
+        assert_eq!(mock.commands(), ["id -un", "sh -s", "doas -n /usr/local/libexec/bsdeploy-helper version </dev/null"]);
+
+        let mock = MockExecutor::new();
+        mock.respond("id -un", "root\n");
+        assert!(mock::with_executor(mock.clone(), || install("web1", RootAccess::Root)).is_err());
+    }
+
+    #[test]
+    fn test_bootstrap_script() {
+        let keys = ["ssh-ed25519 AAAA op@laptop".to_string(), "ssh-rsa BBBB op@laptop".to_string()];
+        let script = bootstrap_script("deploy", &keys);
+        assert!(script.starts_with("set -e\nid deploy >/dev/null 2>&1 || pw useradd -n deploy -m -s /bin/sh"));
+        assert!(script.contains("done <<'KEYS'\nssh-ed25519 AAAA op@laptop\nssh-rsa BBBB op@laptop\nKEYS\n"));
+        assert!(script.ends_with("install -m 0600 -o root -g wheel \"$tmp\" /usr/local/etc/doas.conf\n"));
+        assert_eq!(root_target("deploy@web1"), "root@web1");
+
+        let dir = std::env::temp_dir().join(format!("bsdeploy-keys-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("id_rsa.pub"), "ssh-rsa BBBB op@laptop\n").unwrap();
+        std::fs::write(dir.join("id_ed25519.pub"), "ssh-ed25519 AAAA op@laptop\n").unwrap();
+        assert_eq!(public_keys(&dir), keys);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,111 @@
+//! Exit codes telling scripts what went wrong. Commands mark errors with
+//! the types below; the remote layer's connection and authentication
+//! errors are recognized as they are.
+
+use std::fmt;
+
+use crate::remote::{self, ErrorKind};
+
+/// Exit code of a failed run. 2 is left to clap for invalid arguments.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitCode {
+    /// Any other failure
+    Failure = 1,
+    /// The configuration file is missing or invalid
+    Config = 3,
+    /// A host could not be reached or rejected the SSH login
+    Connection = 4,
+    /// Some hosts were changed before another one failed
+    PartialHosts = 5,
+    /// A health check failed
+    Unhealthy = 6,
+    /// `setup --check` found hosts that differ from the configuration
+    Drift = 8,
+}
+
+/// The run stopped after changing `succeeded` of `total` hosts.
+#[derive(Debug)]
+pub struct PartialFailure {
+    pub succeeded: usize,
+    pub total: usize,
+}
+
+impl fmt::Display for PartialFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Stopped after {} of {} hosts", self.succeeded, self.total)
+    }
+}
+
+impl std::error::Error for PartialFailure {}
+
+/// Mark the failure of the host at `index` as partial when earlier hosts
+/// were already changed.
+pub fn partial(error: anyhow::Error, index: usize, total: usize) -> anyhow::Error {
+    if index == 0 {
+        return error;
+    }
+    error.context(PartialFailure { succeeded: index, total })
+}
+
+/// A health check failed.
+#[derive(Debug)]
+pub struct Unhealthy(pub String);
+
+impl fmt::Display for Unhealthy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Unhealthy {}
+
+/// Hosts differ from what setup would make of them.
+#[derive(Debug)]
+pub struct Drift(pub String);
+
+impl fmt::Display for Drift {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Drift {}
+
+pub fn for_error(error: &anyhow::Error) -> ExitCode {
+    // Markers may be context, which only downcasting finds
+    if error.downcast_ref::<Unhealthy>().is_some() {
+        ExitCode::Unhealthy
+    } else if error.downcast_ref::<Drift>().is_some() {
+        ExitCode::Drift
+    } else if error.downcast_ref::<PartialFailure>().is_some() {
+        ExitCode::PartialHosts
+    } else if error.chain().any(|e| {
+        e.is::<remote::ConnectionError>()
+            || e.downcast_ref::<remote::CommandError>().is_some_and(|c| c.kind() == Some(ErrorKind::Auth))
+    }) {
+        ExitCode::Connection
+    } else {
+        ExitCode::Failure
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_for_error() {
+        assert_eq!(for_error(&anyhow::anyhow!("boom")), ExitCode::Failure);
+
+        let auth = remote::CommandError::new("web1", "true", "deploy@web1: Permission denied (publickey).");
+        let error = anyhow::Error::new(auth).context("Failed to create the jail");
+        assert_eq!(for_error(&error), ExitCode::Connection);
+        // A later host failing to connect still leaves the earlier ones deployed
+        assert_eq!(for_error(&partial(error, 1, 3)), ExitCode::PartialHosts);
+
+        let error = partial(anyhow::anyhow!("boom"), 0, 3);
+        assert_eq!(for_error(&error), ExitCode::Failure);
+        assert_eq!(for_error(&anyhow::Error::new(Unhealthy("1 of 2 hosts are not healthy".into()))), ExitCode::Unhealthy);
+        assert_eq!(for_error(&anyhow::Error::new(Drift("setup would make 2 changes".into()))), ExitCode::Drift);
+    }
+}
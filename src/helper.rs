@@ -0,0 +1,696 @@
+use anyhow::Result;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::config::PrivilegeEscalation;
+use crate::constants::HELPER_PATH;
+use crate::{remote, shell};
+
+/// Bumped whenever HELPER_SCRIPT changes so hosts get the new version
+pub const HELPER_VERSION: &str = "6";
+
+/// Remote helper implementing multi-step host operations in a single call
+const HELPER_SCRIPT: &str = r#"#!/bin/sh
+#
+# bsdeploy remote helper - installed and updated by bsdeploy.
+# Runs multi-step host operations locally so the controller needs a
+# single SSH round trip per operation.
+
+HELPER_VERSION="6"
+# "yes" in the copy installed by `setup --install-doas`, see exec_allowed
+RESTRICTED="no"
+PATH=/sbin:/bin:/usr/sbin:/usr/bin:/usr/local/sbin:/usr/local/bin
+export PATH
+BASE_DIR="/usr/local/bsdeploy/base"
+JAILS_DIR="/usr/local/bsdeploy/jails"
+IMAGES_DIR="/usr/local/bsdeploy/images"
+ACTIVE_DIR="/usr/local/bsdeploy/active"
+NEWSYSLOG_CONF_DIR="/usr/local/etc/newsyslog.conf.d"
+CADDY_CONF_DIR="/usr/local/etc/caddy/conf.d"
+CADDYFILE="/usr/local/etc/caddy/Caddyfile"
+
+usage()
+{
+    echo "usage: bsdeploy-helper version" >&2
+    echo "       bsdeploy-helper create-jail <name> <base version> <image|-> <subnet prefix> [host_path:jail_path ...]" >&2
+    echo "       bsdeploy-helper teardown-jail <name> [ip]" >&2
+    echo "       bsdeploy-helper switch-proxy <service>  (config on stdin)" >&2
+    echo "       bsdeploy-helper housekeeping <jails to keep>" >&2
+    echo "       bsdeploy-helper exec <command> [args ...]" >&2
+    exit 64
+}
+
+valid_name()
+{
+    case "$1" in
+        ""|*/*|.*) echo "bsdeploy-helper: invalid name '$1'" >&2; exit 65 ;;
+    esac
+}
+
+# ZFS dataset holding a path, if any
+dataset_of()
+{
+    d=$(df "$1" 2>/dev/null | tail -n 1 | awk '{ print $1 }')
+    case "$d" in
+        ""|/*) return 1 ;;
+    esac
+    zfs list -H -o name "$d" 2>/dev/null
+}
+
+# Mount $2 on $3 with nullfs options $1 unless something is mounted there
+mount_at()
+{
+    mount -p | awk -v t="$3" '$2 == t { found = 1 } END { exit !found }' && return 0
+    mkdir -p "$3" && mount_nullfs $1 "$2" "$3"
+}
+
+# Create a jail from an image, or from the bare base with "-": a ZFS
+# clone of the image where possible, else copies of its writable
+# directories with the base and the image's /usr/local mounted read-only.
+# Mounts the data directories, aliases a free address of the subnet on
+# lo1 and prints "<ip> <zfs|copy>".
+create_jail()
+{
+    name="$1"
+    base="$BASE_DIR/$2"
+    image="$3"
+    prefix="$4"
+    shift 4
+    valid_name "$name"
+    valid_name "$2"
+    path="$JAILS_DIR/$name"
+
+    ifconfig lo1 >/dev/null 2>&1 || ifconfig lo1 create || return 1
+    mkdir -p "$path" || return 1
+
+    mode=copy
+    if [ "$image" != "-" ]; then
+        img_ds=$(dataset_of "$image")
+        jails_ds=$(dataset_of "$JAILS_DIR")
+        if [ -n "$img_ds" ] && [ -n "$jails_ds" ] && zfs list -H -o name "$img_ds@base" >/dev/null 2>&1 &&
+            zfs clone -o mountpoint="$path" "$img_ds@base" "$jails_ds/$name"; then
+            mode=zfs
+        else
+            mkdir -p "$path/usr" || return 1
+            for dir in etc var root home; do
+                [ -d "$image/$dir" ] || continue
+                rsync -a --link-dest="$image/$dir" "$image/$dir/" "$path/$dir/" || return 1
+            done
+            mount_at "-o ro" "$image/usr/local" "$path/usr/local" || return 1
+        fi
+    else
+        for dir in etc var root tmp; do
+            cp -a "$base/$dir" "$path/" || return 1
+        done
+        cp /etc/resolv.conf "$path/etc/" || return 1
+        mkdir -p "$path/home" "$path/usr/local" || return 1
+    fi
+
+    if [ "$mode" = copy ]; then
+        for dir in bin lib libexec sbin; do
+            mount_at "-o ro" "$base/$dir" "$path/$dir" || return 1
+        done
+        for dir in bin include lib lib32 libdata libexec sbin share; do
+            [ -d "$base/usr/$dir" ] || continue
+            mount_at "-o ro" "$base/usr/$dir" "$path/usr/$dir" || return 1
+        done
+    fi
+    if ! mount -p | awk -v t="$path/dev" '$2 == t { found = 1 } END { exit !found }'; then
+        mkdir -p "$path/dev" && mount -t devfs devfs "$path/dev" || return 1
+    fi
+    for tmp in tmp var/tmp; do
+        mkdir -p "$path/$tmp" && chmod 1777 "$path/$tmp" || return 1
+    done
+    for mapping in "$@"; do
+        host_path="${mapping%%:*}"
+        if [ "$RESTRICTED" = yes ] && ! managed "$host_path"; then
+            echo "bsdeploy-helper: data directory $host_path is outside the bsdeploy directories" >&2
+            return 1
+        fi
+        mkdir -p "$host_path" || return 1
+        mount_at "" "$host_path" "$path/${mapping#*:}" || return 1
+    done
+
+    used=$(ifconfig lo1 | awk '$1 == "inet" { print $2 }')
+    i=2
+    while [ $i -lt 255 ]; do
+        echo "$used" | grep -qxF "$prefix.$i" || break
+        i=$((i + 1))
+    done
+    if [ $i -eq 255 ]; then
+        echo "bsdeploy-helper: no free address in $prefix.0/24" >&2
+        return 1
+    fi
+    ifconfig lo1 inet "$prefix.$i/32" alias || return 1
+    echo "$prefix.$i $mode"
+}
+
+# Stop a jail, drop its IP alias, unmount everything below it and
+# remove its dataset or directory.
+teardown_jail()
+{
+    name="$1"
+    ip="${2:-}"
+    valid_name "$name"
+    path="$JAILS_DIR/$name"
+
+    if [ -z "$ip" ]; then
+        ip=$(jls -j "$name" ip4.addr 2>/dev/null)
+    fi
+
+    jail -r "$name" 2>/dev/null
+
+    if [ -n "$ip" ] && [ "$ip" != "-" ]; then
+        ifconfig lo1 inet "$ip" -alias 2>/dev/null
+    fi
+
+    mount -p | awk -v p="$path" '$2 == p || index($2, p "/") == 1 { print $2 }' | sort -r | \
+    while read -r mnt; do
+        umount -f "$mnt" 2>/dev/null
+    done
+
+    # Only destroy a dataset mounted exactly at the jail path
+    ds=$(zfs list -H -o name,mountpoint 2>/dev/null | awk -v p="$path" '$2 == p { print $1 }')
+    if [ -n "$ds" ]; then
+        zfs destroy -r "$ds"
+    fi
+
+    if [ -e "$path" ]; then
+        chflags -R noschg "$path" 2>/dev/null
+        rm -rf "$path"
+    fi
+}
+
+# Replace a service's Caddy config with stdin and reload Caddy. The
+# previous config is restored if Caddy rejects the new one.
+switch_proxy()
+{
+    service="$1"
+    valid_name "$service"
+    conf="$CADDY_CONF_DIR/$service.caddy"
+
+    cat > "$conf.new" || exit 1
+    if [ -f "$conf" ]; then cp -p "$conf" "$conf.prev" || exit 1; else rm -f "$conf.prev"; fi
+    mv "$conf.new" "$conf" || exit 1
+
+    if ! caddy validate --config "$CADDYFILE" --adapter caddyfile >&2; then
+        restore_proxy "$conf"
+        exit 1
+    fi
+    if ! service caddy reload; then
+        restore_proxy "$conf"
+        service caddy reload
+        exit 1
+    fi
+}
+
+restore_proxy()
+{
+    echo "bsdeploy-helper: Caddy rejected the new config, restoring $1" >&2
+    if [ -f "$1.prev" ]; then mv "$1.prev" "$1"; else rm -f "$1"; fi
+}
+
+# Remove all but the newest <keep> jails of every service (never the
+# active one), images no jail was created from and packages
+# layers no image is cloned from, then rotate the bsdeploy logs. Images
+# and layers younger than a day may belong to a deploy in progress, as may
+# an image mounted into a jail that has no metadata yet.
+housekeeping()
+{
+    keep="$1"
+    case "$keep" in
+        ""|*[!0-9]*) keep=0 ;;
+    esac
+    if [ "$keep" -lt 1 ]; then
+        echo "bsdeploy-helper: keep at least one jail" >&2
+        exit 65
+    fi
+
+    for service in $(ls "$JAILS_DIR" 2>/dev/null | sed -n 's/-[0-9]\{8\}-[0-9]\{6\}$//p' | sort -u); do
+        active=$(readlink "$ACTIVE_DIR/$service" 2>/dev/null)
+        for name in $(ls "$JAILS_DIR" | grep -x "$service-[0-9]\{8\}-[0-9]\{6\}" | sort -r | tail -n +$((keep + 1))); do
+            [ "$name" = "${active##*/}" ] && continue
+            teardown_jail "$name"
+            echo "removed jail $name"
+        done
+    done
+
+    used=$(cat "$JAILS_DIR"/*/.bsdeploy.json 2>/dev/null | jq -r '.image_path // empty')
+    images_ds=$(zfs list -H -o name,mountpoint 2>/dev/null | awk -v p="$IMAGES_DIR" '$2 == p { print $1 }')
+    for path in $(find "$IMAGES_DIR" -mindepth 1 -maxdepth 1 -type d ! -name layers ! -name '.partial-*' -mmin +1440); do
+        echo "$used" | grep -qxF "$path" && continue
+        mount -p | awk -v p="$path" -v j="$JAILS_DIR/" 'index($2, j) == 1 && ($1 == p || index($1, p "/") == 1) { found = 1 } END { exit !found }' && continue
+        # A clone of the image blocks the destroy, so a jail without
+        # metadata keeps it too
+        if [ -n "$images_ds" ] && zfs list -H -o name "$images_ds/${path##*/}" >/dev/null 2>&1; then
+            zfs destroy -r "$images_ds/${path##*/}" 2>/dev/null || continue
+        fi
+        chflags -R noschg "$path" 2>/dev/null
+        rm -rf "$path"
+        echo "removed image ${path##*/}"
+    done
+    if [ -n "$images_ds" ]; then
+        for path in $(find "$IMAGES_DIR/layers" -mindepth 1 -maxdepth 1 -type d -mmin +1440 2>/dev/null); do
+            zfs destroy -r "$images_ds/layers/${path##*/}" 2>/dev/null && echo "removed packages layer ${path##*/}"
+        done
+    fi
+
+    for conf in "$NEWSYSLOG_CONF_DIR"/bsdeploy-*.conf; do
+        [ -f "$conf" ] && newsyslog -f "$conf"
+    done
+    return 0
+}
+
+# Path with its symlinks resolved, also when it does not exist yet
+resolve()
+{
+    d="$1"
+    rest=
+    while [ ! -e "$d" ] && [ ! -L "$d" ]; do
+        rest="/${d##*/}$rest"
+        d="${d%/*}"
+        [ -n "$d" ] || d=/
+    done
+    d=$(realpath -q "$d") || return 1
+    echo "${d%/}$rest"
+}
+
+# Whether an absolute path lies where bsdeploy keeps its files, once its
+# symlinks are resolved
+managed()
+{
+    case "$1" in
+        */../*|*/..) return 1 ;;
+        /*) ;;
+        *) return 1 ;;
+    esac
+    p=$(resolve "$1") || return 1
+    case "$p" in
+        /usr/local/bsdeploy*|/usr/local/etc/bsdeploy*|/var/db/bsdeploy*|/var/log/bsdeploy*|/var/run/bsdeploy*) ;;
+        /usr/local/etc/caddy|/usr/local/etc/caddy/*|/var/log/caddy|/var/log/caddy/*) ;;
+        /usr/local/etc/relayd.conf|/usr/local/etc/relayd.d|/usr/local/etc/relayd.d/*) ;;
+        /etc/ssl/*.crt|/etc/ssl/private/*.key) ;;
+        /usr/local/etc/rc.d/bsdeploy*|/usr/local/etc/newsyslog.conf.d/bsdeploy*|/usr/local/etc/periodic/*/500.bsdeploy) ;;
+        /etc/pf.conf|/etc/sysctl.conf) ;;
+        /tmp/bsdeploy*|/tmp/ssh-*)
+            # Created by the SSH user, so no hard links to other files
+            [ ! -f "$p" ] || [ "$(stat -f %l "$p")" -eq 1 ]
+            ;;
+        *) return 1 ;;
+    esac
+}
+
+# Files that may be read besides bsdeploy's own
+readable()
+{
+    case "$1" in
+        */../*|*/..) return 1 ;;
+        # rsync's server side is given "." for the sender
+        .|/etc/resolv.conf|/etc/localtime|/usr/local/poudriere/data/packages*) ;;
+        *) managed "$1" ;;
+    esac
+}
+
+any() { :; }
+
+managed_jail()
+{
+    jp=$(jls -j "$1" path 2>/dev/null) && managed "$jp"
+}
+
+# The dataset (or the snapshot's dataset) is mounted in bsdeploy's directories
+managed_dataset()
+{
+    mp=$(zfs get -H -o value mountpoint "${1%%@*}" 2>/dev/null) && managed "$mp"
+}
+
+# Checks the operands of a file command: the last with $3, the others
+# with $2, skipping the first $4 (e.g. chown's owner). The options in $1
+# take a value.
+operands()
+{
+    valued="$1" first="$2" last="$3" skip="$4"
+    shift 4
+    n=0
+    for pass in count check; do
+        i=0
+        value=
+        for a in "$@"; do
+            if [ -n "$value" ]; then value=; continue; fi
+            case " $valued " in *" $a "*) value=1; continue ;; esac
+            case "$a" in -*) continue ;; esac
+            i=$((i + 1))
+            [ $pass = check ] && [ $i -gt "$skip" ] || continue
+            if [ $i -eq $n ]; then $last "$a" || return 1; else $first "$a" || return 1; fi
+        done
+        n=$i
+    done
+}
+
+zfs_allowed()
+{
+    sub="$1"
+    shift
+    for a in "$@"; do
+        case "$a" in mountpoint=*) managed "${a#mountpoint=}" || return 1 ;; esac
+    done
+    eval "ds=\${$#}"
+    case "$sub" in
+        list|get) ;;
+        # Mounted where bsdeploy keeps its files
+        create|clone) printf '%s\n' "$@" | grep -q '^mountpoint=' ;;
+        snapshot|destroy|set|inherit) managed_dataset "$ds" ;;
+        # Only renamed next to itself
+        rename) [ $# -eq 2 ] && managed_dataset "$1" && { [ "${1%/*}" = "${2%/*}" ] || [ "${1%%@*}" = "${2%%@*}" ]; } ;;
+        *) return 1 ;;
+    esac
+}
+
+# Whether "exec" runs a command: the commands bsdeploy runs as root,
+# without shells or interpreters, writing files only in the places
+# bsdeploy manages and starting jails only there, without exec.* hooks.
+# env may only set the proxy and pkg variables bsdeploy passes.
+exec_allowed()
+{
+    cmd="$1"
+    shift
+    case "$cmd" in
+        "$0") ;;
+        true|test|\[|id|ps|jls|du|sha256|stat|rctl|ifconfig|pfctl|sysctl|service|relayd|relayctl) ;;
+        env)
+            while [ $# -gt 0 ]; do
+                case "$1" in
+                    PAGER=*|ASSUME_ALWAYS_YES=*|HTTP_PROXY=*|HTTPS_PROXY=*) shift ;;
+                    *) break ;;
+                esac
+            done
+            [ $# -gt 0 ] && exec_allowed "$@"
+            ;;
+        rm|rmdir|mkdir|touch|tee|umount|mv) operands "" managed managed 0 "$@" ;;
+        chown|chmod|chflags) operands "" managed managed 1 "$@" ;;
+        cat|grep|tail) operands "-e -n" readable readable 0 "$@" ;;
+        cp) operands "" readable managed 0 "$@" ;;
+        # A symlink to anything, placed in bsdeploy's directories
+        ln) case "$1" in -*s*) operands "" any managed 1 "$@" ;; *) return 1 ;; esac ;;
+        install)
+            for a in "$@"; do [ "$a" != -d ] || return 1; done
+            operands "-m -o -g" readable managed 0 "$@"
+            ;;
+        find)
+            for a in "$@"; do
+                case "$a" in -exec*|-ok*|-delete|-fprint*|-fls) return 1 ;; esac
+            done
+            ;;
+        fetch)
+            out=
+            for a in "$@"; do
+                if [ "$out" = next ]; then out="$a"; continue; fi
+                case "$a" in
+                    -o) out=next ;;
+                    -*|http://*|https://*|ftp://*) ;;
+                    *) return 1 ;;
+                esac
+            done
+            managed "$out"
+            ;;
+        tar)
+            next=
+            for a in "$@"; do
+                case "$next" in
+                    path) next=; [ "$a" = - ] || managed "$a" || return 1; continue ;;
+                    skip) next=; continue ;;
+                esac
+                case "$a" in
+                    --use-compress-program*|--files-from*|--exclude-from*) return 1 ;;
+                    --exclude) next=skip ;;
+                    --*) ;;
+                    -*[PITX]*) return 1 ;;
+                    -C) next=path ;;
+                    -*f) next=path ;;
+                    -*) ;;
+                    /*) managed "$a" || return 1 ;;
+                    *..*) return 1 ;;
+                esac
+            done
+            ;;
+        rsync)
+            for a in "$@"; do
+                case "$a" in
+                    --log-file*|--*batch*|--*-from*|--temp-dir*|-T|--partial-dir*|--backup-dir*|--compare-dest*|--copy-dest*|--password-file*) return 1 ;;
+                    --link-dest=*) readable "${a#*=}" || return 1 ;;
+                esac
+            done
+            # The server side gets "." and the destination
+            operands "" readable managed 0 "$@" ;;
+        mount_nullfs)
+            if [ "$1" = -o ] && [ "$2" = ro ]; then shift 2; src=readable; else src=managed; fi
+            [ $# -eq 2 ] && $src "$1" && managed "$2"
+            ;;
+        mount)
+            [ "$*" != -p ] || return 0
+            [ "$1" = -t ] || return 1
+            case "$2" in
+                nullfs) shift 2; exec_allowed mount_nullfs "$@" ;;
+                devfs) [ $# -eq 4 ] && [ "$3" = devfs ] && managed "$4" ;;
+                *) return 1 ;;
+            esac
+            ;;
+        jail)
+            case "$1" in
+                -r) [ $# -eq 2 ] && managed_jail "$2" ;;
+                -c)
+                    for a in "$@"; do
+                        case "$a" in
+                            path=*) managed "${a#path=}" || return 1 ;;
+                            exec.*|command=*|mount*|devfs_ruleset=*) return 1 ;;
+                        esac
+                    done
+                    printf '%s\n' "$@" | grep -q '^path='
+                    ;;
+                *) return 1 ;;
+            esac
+            ;;
+        jexec)
+            while [ $# -gt 0 ]; do
+                case "$1" in
+                    -l) shift ;;
+                    -u|-U|-d) [ $# -ge 2 ] || return 1; shift 2 ;;
+                    -*) return 1 ;;
+                    *) break ;;
+                esac
+            done
+            [ $# -gt 0 ] && managed_jail "$1"
+            ;;
+        zfs) zfs_allowed "$@" ;;
+        pkg)
+            while [ $# -gt 0 ]; do
+                case "$1" in
+                    -j) [ $# -ge 2 ] && managed_jail "$2" || return 1; shift 2 ;;
+                    -c) [ $# -ge 2 ] && managed "$2" || return 1; shift 2 ;;
+                    *) break ;;
+                esac
+            done
+            case "$1" in
+                install|update|bootstrap|query|clean|info|upgrade) ;;
+                *) return 1 ;;
+            esac
+            # Packages from the repositories, not files
+            for a in "$@"; do case "$a" in */*) return 1 ;; esac; done
+            ;;
+        sysrc)
+            for a in "$@"; do
+                case "$a" in
+                    *=*[!A-Za-z0-9_.-]*) return 1 ;;
+                    -x|-n|-q|bsdeploy*|caddy_enable*|relayd_enable*|pf_enable*|gateway_enable*) ;;
+                    *) return 1 ;;
+                esac
+            done
+            ;;
+        caddy)
+            case "$1" in validate|reload|add-package) ;; *) return 1 ;; esac
+            [ "$1" = add-package ] && return 0
+            while [ $# -gt 0 ] && [ "$1" != --config ]; do shift; done
+            [ $# -ge 2 ] && managed "$2"
+            ;;
+        openssl)
+            [ "$1" = x509 ] || return 1
+            for a in "$@"; do [ "$a" != -out ] || return 1; done
+            while [ $# -gt 0 ] && [ "$1" != -in ]; do shift; done
+            [ $# -ge 2 ] && readable "$2"
+            ;;
+        freebsd-update)
+            next=
+            for a in "$@"; do
+                if [ -n "$next" ]; then next=; managed "$a" || return 1; continue; fi
+                case "$a" in
+                    -b|-d) next=1 ;;
+                    -f|-F) return 1 ;;
+                esac
+            done
+            ;;
+        poudriere) [ "$1" = bulk ] ;;
+        *) return 1 ;;
+    esac
+}
+
+[ $# -ge 1 ] || usage
+cmd="$1"
+shift
+
+case "$cmd" in
+    version) echo "$HELPER_VERSION" ;;
+    create-jail) [ $# -ge 4 ] || usage; create_jail "$@" ;;
+    teardown-jail) [ $# -ge 1 ] || usage; teardown_jail "$@" ;;
+    switch-proxy) [ $# -eq 1 ] || usage; switch_proxy "$1" ;;
+    housekeeping) [ $# -eq 1 ] || usage; housekeeping "$1" ;;
+    exec)
+        [ $# -ge 1 ] || usage
+        if ! (exec_allowed "$@"); then
+            echo "bsdeploy-helper: refusing to run as root: $*" >&2
+            exit 77
+        fi
+        exec "$@"
+        ;;
+    *) usage ;;
+esac
+"#;
+
+/// Whether host operations go through the remote helper
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// The helper installed as root by `setup --install-doas`, whose `exec`
+/// is all the doas rule permits: data directories must be below
+/// bsdeploy's directories too.
+pub fn restricted_script() -> String {
+    HELPER_SCRIPT.replace("\nRESTRICTED=\"no\"\n", "\nRESTRICTED=\"yes\"\n")
+}
+
+/// Install the helper on the host unless the current version is already
+/// there. With `privilege_escalation: doas-helper` the helper cannot
+/// replace itself, so an outdated one is an error.
+pub fn ensure_installed(host: &str, doas: bool) -> Result<()> {
+    let installed = remote::query(host, &format!("{} version 2>/dev/null || true", HELPER_PATH))?;
+    if installed.trim() == HELPER_VERSION {
+        return Ok(());
+    }
+    if doas && shell::privilege_escalation() == PrivilegeEscalation::DoasHelper {
+        anyhow::bail!(
+            "{} has bsdeploy-helper version {}, this bsdeploy needs version {}; run `bsdeploy setup --install-doas` to install it",
+            host,
+            if installed.trim().is_empty() { "none" } else { installed.trim() },
+            HELPER_VERSION
+        );
+    }
+
+    let cmd_prefix = shell::escalation_prefix(doas);
+    remote::ensure_dir(host, "/usr/local/libexec", doas)?;
+    remote::write_file(host, HELPER_SCRIPT, HELPER_PATH, doas)?;
+    remote::run(host, &format!("{}chmod 755 {}", cmd_prefix, HELPER_PATH))?;
+    Ok(())
+}
+
+fn helper_command(args: &[&str], doas: bool) -> String {
+    let cmd_prefix = shell::escalation_prefix(doas);
+    let args: Vec<String> = args.iter().map(|a| shell::escape(a)).collect();
+    format!("{}{} {}", cmd_prefix, HELPER_PATH, args.join(" "))
+}
+
+/// Create a jail in one call (see `jail::create`); returns its address and
+/// whether it is a ZFS clone.
+pub fn create_jail(
+    host: &str,
+    jail_name: &str,
+    base_version: &str,
+    image_path: Option<&str>,
+    subnet_prefix: &str,
+    data_dirs: &[String],
+    doas: bool,
+) -> Result<(String, bool)> {
+    let mut args = vec!["create-jail", jail_name, base_version, image_path.unwrap_or("-"), subnet_prefix];
+    args.extend(data_dirs.iter().map(String::as_str));
+    let output = remote::run_with_output_timeout(host, &helper_command(&args, doas), remote::Timeout::Build)?;
+    match output.split_whitespace().collect::<Vec<_>>().as_slice() {
+        [ip, mode] => Ok((ip.to_string(), *mode == "zfs")),
+        _ => anyhow::bail!("Unexpected output from bsdeploy-helper create-jail: {}", output.trim()),
+    }
+}
+
+/// Remove a jail in one call. `ip` is needed when the jail is not running.
+pub fn teardown_jail(host: &str, jail_name: &str, ip: Option<&str>, doas: bool) -> Result<()> {
+    let mut args = vec!["teardown-jail", jail_name];
+    args.extend(ip);
+    remote::run(host, &helper_command(&args, doas))
+}
+
+/// Install a service's Caddy config and reload Caddy in one call.
+pub fn switch_proxy(host: &str, service: &str, caddy_conf: &str, doas: bool) -> Result<()> {
+    remote::run_with_input(host, &helper_command(&["switch-proxy", service], doas), caddy_conf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants::{ACTIVE_DIR, BASE_DIR, IMAGES_DIR, JAILS_DIR, NEWSYSLOG_CONF_DIR};
+    use crate::remote::mock::{self, MockExecutor};
+
+    #[test]
+    fn test_helper_script_version_matches() {
+        assert!(HELPER_SCRIPT.contains(&format!("HELPER_VERSION=\"{}\"", HELPER_VERSION)));
+        assert!(HELPER_SCRIPT.contains(&format!("BASE_DIR=\"{}\"", BASE_DIR)));
+        assert!(HELPER_SCRIPT.contains(&format!("JAILS_DIR=\"{}\"", JAILS_DIR)));
+        assert!(HELPER_SCRIPT.contains(&format!("IMAGES_DIR=\"{}\"", IMAGES_DIR)));
+        assert!(HELPER_SCRIPT.contains(&format!("ACTIVE_DIR=\"{}\"", ACTIVE_DIR)));
+        assert!(HELPER_SCRIPT.contains(&format!("NEWSYSLOG_CONF_DIR=\"{}\"", NEWSYSLOG_CONF_DIR)));
+        assert!(HELPER_SCRIPT.contains(r#"CADDY_CONF_DIR="/usr/local/etc/caddy/conf.d""#));
+        assert!(HELPER_SCRIPT.contains(r#"CADDYFILE="/usr/local/etc/caddy/Caddyfile""#));
+        assert!(HELPER_SCRIPT.contains("\nRESTRICTED=\"no\"\n"));
+        assert!(restricted_script().contains("\nRESTRICTED=\"yes\"\n"));
+    }
+
+    #[test]
+    fn test_helper_command() {
+        assert_eq!(
+            helper_command(&["teardown-jail", "myapp-20240101-120000", "10.0.0.2"], true),
+            "doas /usr/local/libexec/bsdeploy-helper teardown-jail myapp-20240101-120000 10.0.0.2"
+        );
+        assert_eq!(
+            helper_command(&["switch-proxy", "myapp"], false),
+            "/usr/local/libexec/bsdeploy-helper switch-proxy myapp"
+        );
+    }
+
+    #[test]
+    fn test_create_jail() {
+        let mock = MockExecutor::new();
+        mock.respond("bsdeploy-helper create-jail", "10.0.0.3 zfs\n");
+        let data = ["/var/db/bsdeploy/app/storage:/app/storage".to_string()];
+        let created = mock::with_executor(mock.clone(), || {
+            create_jail("host", "app-20240101-120000", "14.1-RELEASE", Some("/usr/local/bsdeploy/images/abc"), "10.0.0", &data, true)
+        });
+        assert_eq!(created.unwrap(), ("10.0.0.3".to_string(), true));
+        assert!(mock.ran(
+            "doas /usr/local/libexec/bsdeploy-helper create-jail app-20240101-120000 14.1-RELEASE /usr/local/bsdeploy/images/abc 10.0.0 /var/db/bsdeploy/app/storage:/app/storage"
+        ));
+    }
+
+    #[test]
+    fn test_ensure_installed_skips_current_version() {
+        let mock = MockExecutor::new();
+        mock.respond("bsdeploy-helper version", &format!("{}\n", HELPER_VERSION));
+        mock::with_executor(mock.clone(), || ensure_installed("host", true)).unwrap();
+        assert!(mock.file(HELPER_PATH).is_none());
+
+        let mock = MockExecutor::new();
+        mock.respond("bsdeploy-helper version", "0\n");
+        mock::with_executor(mock.clone(), || ensure_installed("host", true)).unwrap();
+        assert_eq!(mock.file(HELPER_PATH).as_deref(), Some(HELPER_SCRIPT));
+        assert!(mock.ran("doas chmod 755 /usr/local/libexec/bsdeploy-helper"));
+    }
+}
@@ -0,0 +1,91 @@
+//! Scheduled housekeeping (`host.housekeeping`): a periodic(8) script
+//! that runs `bsdeploy-helper housekeeping`, so long-lived hosts drop old
+//! jails and unused images without anyone running a command. The number
+//! of jails kept per service can be changed in /etc/periodic.conf.
+
+use anyhow::Result;
+
+use crate::config::{Config, Period};
+use crate::constants::{HELPER_PATH, JAILS_TO_KEEP};
+use crate::{helper, remote, shell};
+
+const PERIODIC_DIR: &str = "/usr/local/etc/periodic";
+
+pub fn script_path(period: Period) -> String {
+    format!("{}/{}/500.bsdeploy", PERIODIC_DIR, period.name())
+}
+
+/// periodic(8) script, following the conventions of the base system's:
+/// `<period>_bsdeploy_enable` and `<period>_bsdeploy_keep` in periodic.conf
+pub fn script(period: Period) -> String {
+    format!(
+        r#"#!/bin/sh
+#
+# bsdeploy housekeeping - installed by bsdeploy setup (host.housekeeping).
+# Removes old jails beyond the retention count and unused images, and
+# rotates the bsdeploy logs.
+
+if [ -r /etc/defaults/periodic.conf ]; then
+    . /etc/defaults/periodic.conf
+    source_periodic_confs
+fi
+
+case "${{{period}_bsdeploy_enable:-YES}}" in
+    [Yy][Ee][Ss])
+        echo ""
+        echo "bsdeploy housekeeping:"
+        {helper} housekeeping "${{{period}_bsdeploy_keep:-{keep}}}" || rc=3
+        ;;
+esac
+
+exit ${{rc:-0}}
+"#,
+        period = period.name(),
+        helper = HELPER_PATH,
+        keep = JAILS_TO_KEEP
+    )
+}
+
+/// Root script removing the periodic scripts of every period
+pub fn remove_script() -> String {
+    let paths: Vec<String> = Period::ALL.into_iter().map(script_path).collect();
+    format!("rm -f {}\n", paths.join(" "))
+}
+
+/// Install the periodic script for the configured period, with the helper
+/// it runs, and remove it from the other. Without `host.housekeeping`
+/// both are removed.
+pub fn apply(config: &Config, host: &str) -> Result<()> {
+    let p = shell::escalation_prefix(config.doas);
+    let Some(period) = config.host.housekeeping else {
+        return remote::run(host, &format!("{}{}", p, remove_script()));
+    };
+
+    helper::ensure_installed(host, config.doas)?;
+    let path = script_path(period);
+    remote::ensure_dir(host, &format!("{}/{}", PERIODIC_DIR, period.name()), config.doas)?;
+    remote::write_file(host, &script(period), &path, config.doas)?;
+    remote::run(host, &format!("{}chmod 755 {}", p, path))?;
+    for other in Period::ALL.into_iter().filter(|&other| other != period) {
+        remote::run(host, &format!("{}rm -f {}", p, script_path(other)))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_script() {
+        assert_eq!(script_path(Period::Weekly), "/usr/local/etc/periodic/weekly/500.bsdeploy");
+        let script = script(Period::Daily);
+        assert!(script.contains("case \"${daily_bsdeploy_enable:-YES}\" in"));
+        assert!(script.contains("/usr/local/libexec/bsdeploy-helper housekeeping \"${daily_bsdeploy_keep:-3}\" || rc=3\n"));
+        assert!(script.ends_with("exit ${rc:-0}\n"));
+        assert_eq!(
+            remove_script(),
+            "rm -f /usr/local/etc/periodic/daily/500.bsdeploy /usr/local/etc/periodic/weekly/500.bsdeploy\n"
+        );
+    }
+}
@@ -1,9 +1,15 @@
 use crate::commands::maybe_doas;
 use crate::constants::*;
-use crate::{config, remote, shell};
-use anyhow::Result;
+use crate::config::{BuildNetwork, RegistryConfig};
+use crate::{config, jail, remote, shell, ui};
+use anyhow::{Context, Result};
 use sha2::{Sha256, Digest};
 use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use log::debug;
+use serde::{Deserialize, Serialize};
 use indicatif::ProgressBar;
 
 pub fn get_image_hash(config: &config::Config, base_version: &str) -> String {
@@ -20,11 +26,20 @@ pub fn get_image_hash(config: &config::Config, base_version: &str) -> String {
 
     // Hash Mise (sorted keys)
     let mise_btree: BTreeMap<_, _> = config.mise.iter().collect();
-    for (tool, version) in mise_btree {
+    for (tool, spec) in mise_btree {
         hasher.update(tool.as_bytes());
         hasher.update(b":");
-        hasher.update(version.as_bytes());
+        hasher.update(spec.version().as_bytes());
         hasher.update(b";");
+        // Only hashed when set so plain `tool: version` images keep their existing hash
+        if let Some(env) = spec.env().filter(|e| !e.is_empty()) {
+            for (k, v) in env {
+                hasher.update(format!("env:{}={};", k, v).as_bytes());
+            }
+        }
+    }
+    for (k, v) in &config.mise.build_env {
+        hasher.update(format!("build_env:{}={};", k, v).as_bytes());
     }
 
     if let Some(user) = &config.user {
@@ -32,14 +47,40 @@ pub fn get_image_hash(config: &config::Config, base_version: &str) -> String {
         hasher.update(user.as_bytes());
     }
 
+    // Only hashed when customized so default images keep their existing hash
+    if let Some(pkgs) = &config.image.bootstrap_packages {
+        hasher.update(b"bootstrap:");
+        for pkg in pkgs {
+            hasher.update(pkg.as_bytes());
+            hasher.update(b";");
+        }
+    }
+    if let Some(pkgs) = &config.image.mise_build_packages {
+        hasher.update(b"mise_build:");
+        for pkg in pkgs {
+            hasher.update(pkg.as_bytes());
+            hasher.update(b";");
+        }
+    }
+
+    if let Some(poudriere) = &config.image.poudriere {
+        hasher.update(format!("poudriere:{};{};", poudriere.jail, poudriere.packages_dir()).as_bytes());
+        for origin in &poudriere.bulk {
+            hasher.update(origin.as_bytes());
+            hasher.update(b";");
+        }
+    }
+
     hex::encode(hasher.finalize())
 }
 
-pub fn ensure_image(config: &config::Config, host: &str, base_version: &str, spinner: &ProgressBar) -> Result<String> {
+/// Path of the image for `base_version` on `host`, and whether it had to
+/// be built (neither on the host nor in the registry).
+pub fn ensure_image(config: &config::Config, host: &str, base_version: &str, spinner: &ProgressBar) -> Result<(String, bool)> {
     let hash = get_image_hash(config, base_version);
     let short_hash = &hash[..12];
     let image_path = format!("{}/{}", IMAGES_DIR, short_hash);
-    let cmd_prefix = if config.doas { "doas " } else { "" };
+    let cmd_prefix = shell::escalation_prefix(config.doas);
 
     // Check if valid image exists (by checking ZFS snapshot)
     if let Ok(Some(images_parent_ds)) = remote::get_zfs_dataset(host, IMAGES_DIR) {
@@ -48,7 +89,7 @@ pub fn ensure_image(config: &config::Config, host: &str, base_version: &str, spi
         
         if remote::run(host, &format!("zfs list -H -o name {} 2>/dev/null", snap_name)).is_ok() {
             spinner.set_message(format!("[{}] Using existing image {}", host, short_hash));
-            return Ok(image_path);
+            return Ok((image_path, false));
         }
         
         // If dataset exists but no snapshot, it's a failed build. Cleanup.
@@ -57,140 +98,757 @@ pub fn ensure_image(config: &config::Config, host: &str, base_version: &str, spi
             remote::run(host, &format!("{}zfs destroy -r {}", cmd_prefix, image_ds))?;
         }
     } else {
-        // Non-ZFS fallback check
-        if remote::run(host, &format!("test -d {}/usr/local", image_path)).is_ok() {
-             return Ok(image_path);
+        // Non-ZFS fallback check: the manifest is the last thing a build writes
+        if remote::run_timeout(host, &format!("test -f {}/{}", image_path, IMAGE_MANIFEST_FILE), remote::Timeout::Query).is_ok() {
+             return Ok((image_path, false));
+        }
+    }
+
+    // Try the shared registry before falling back to a local build
+    if let Some(registry) = &config.image.registry {
+        spinner.set_message(format!("[{}] Checking registry {} for image {}...", host, registry.host, short_hash));
+        match pull_from_registry(registry, host, short_hash, config.doas) {
+            Ok(true) => {
+                spinner.set_message(format!("[{}] Pulled image {} from registry", host, short_hash));
+                return Ok((image_path, false));
+            }
+            Ok(false) => {}
+            Err(e) => debug!("Registry pull failed for {}: {}", short_hash, e),
         }
     }
 
-    spinner.set_message(format!("[{}] Building image {} (in-place)...", host, short_hash));
+    // 0. Refresh site-built packages before they are installed into the image
+    if let Some(poudriere) = &config.image.poudriere
+        && !poudriere.bulk.is_empty()
+    {
+        let label = format!("[{}] Image: Building ports with poudriere...", host);
+        spinner.set_message(label.clone());
+        let mut bulk_cmd = format!(
+            "{}poudriere bulk -j {} -p {}",
+            cmd_prefix, shell::escape(&poudriere.jail), shell::escape(&poudriere.ports_tree)
+        );
+        if let Some(set) = &poudriere.set {
+            bulk_cmd.push_str(&format!(" -z {}", shell::escape(set)));
+        }
+        for origin in &poudriere.bulk {
+            bulk_cmd.push_str(&format!(" {}", shell::escape(origin)));
+        }
+        run_build_command(host, &bulk_cmd, spinner, &label)?;
+    }
+
+    spinner.set_message(format!("[{}] Building image {}...", host, short_hash));
+
+    // Build under a scratch name and only promote once complete, so an
+    // interrupted build is never mistaken for a usable image
+    remove_partial_image(host, short_hash, config.doas);
+    let build_path = partial_image_path(short_hash);
 
     // 1. Create Image Dataset & Populate Base
     let base_dir = format!("{}/{}", BASE_DIR, base_version);
+    let images_parent_ds = remote::get_zfs_dataset(host, IMAGES_DIR).ok().flatten();
     let mut zfs_cloned_base = false;
+    let mut packages_installed = false;
 
-    if let Ok(Some(images_parent_ds)) = remote::get_zfs_dataset(host, IMAGES_DIR) {
-         let image_ds = format!("{}/{}", images_parent_ds, short_hash);
-         
-         // Check if Base has @clean snapshot
-         let mut base_snap = String::new();
-         if let Ok(Some(base_ds)) = remote::get_zfs_dataset(host, &base_dir) {
-             let snap = format!("{}@clean", base_ds);
-             if remote::run(host, &format!("zfs list -H -o name {} 2>/dev/null", snap)).is_ok() {
-                 base_snap = snap;
-             }
-         }
+    if let Some(images_parent_ds) = &images_parent_ds {
+         let build_ds = partial_image_dataset(images_parent_ds, short_hash);
 
-         if !base_snap.is_empty() {
-             // THIN IMAGE: Clone from Base
-             spinner.set_message(format!("[{}] Image: Cloning base system (Thin)...", host));
-             remote::run(host, &maybe_doas(&format!("zfs clone -o mountpoint={} {} {}", image_path, base_snap, image_ds), config.doas))?;
+         if let Some(base_snap) = jail::base_snapshot(host, base_version) {
+             // LAYERED IMAGE: Clone from the packages layer so runtime changes skip pkg installs
+             let layer_snap = ensure_packages_layer(config, host, base_version, images_parent_ds, &base_snap, spinner)?;
+             spinner.set_message(format!("[{}] Image: Cloning packages layer...", host));
+             remote::run(host, &maybe_doas(&format!("zfs clone -o mountpoint={} {} {}", build_path, layer_snap, build_ds), config.doas))?;
              zfs_cloned_base = true;
+             packages_installed = true;
          } else {
-             // THICK IMAGE: Create empty + Rsync
-             remote::run(host, &maybe_doas(&format!("zfs create -o mountpoint={} {}", image_path, image_ds), config.doas))?;
+             // THICK IMAGE: Base is not its own dataset - create empty + Rsync
+             remote::run(host, &maybe_doas(&format!("zfs create -o mountpoint={} {}", build_path, build_ds), config.doas))?;
          }
     } else {
-         remote::run(host, &format!("{}mkdir -p {}", cmd_prefix, image_path))?;
+         remote::ensure_dir(host, &build_path, config.doas)?;
+    }
+
+    let res = (|| -> Result<()> {
+        if !zfs_cloned_base {
+            spinner.set_message(format!("[{}] Image: Populating base system with hardlinks (UFS-optimized)...", host));
+            // Use rsync --link-dest for hardlinked copy (handles FreeBSD immutable flags)
+            // This shares disk space with base system until files are modified
+            remote::run(host, &format!("{}rsync -a --link-dest={} {}/ {}/", cmd_prefix, base_dir, base_dir, build_path))?;
+            // Fix var/empty permissions (needs to be created with specific perms)
+            remote::run(host, &format!("{}chmod 555 {}/var/empty", cmd_prefix, build_path))?;
+        }
+
+        // 2. Install Packages & Runtimes (a cloned packages layer only needs the runtimes)
+        if !packages_installed || !config.mise.is_empty() {
+            let build_jail_name = format!("build-{}", short_hash);
+            run_in_build_jail(config, host, &build_path, &build_jail_name, |jail| {
+                if !packages_installed {
+                    install_packages(config, host, jail, spinner)?;
+                }
+                install_runtimes(config, host, jail, spinner)
+            })?;
+        }
+
+        // 3. Record what went into the image before it is frozen
+        spinner.set_message(format!("[{}] Image: Writing manifest...", host));
+        write_manifest(config, host, &build_path, &hash, base_version)
+    })();
+
+    if let Err(e) = res {
+        // If build failed, remove the scratch image so we don't leave broken state
+        remove_partial_image(host, short_hash, config.doas);
+        return Err(e);
+    }
+
+    // 4. Promote to the final name (and snapshot on ZFS)
+    spinner.set_message(format!("[{}] Image: Finalizing image {}...", host, short_hash));
+    promote_image(host, short_hash, images_parent_ds.as_deref(), config.doas)?;
+
+    // 5. Share with the rest of the fleet (best effort - the image is usable either way)
+    if let Some(registry) = &config.image.registry {
+        spinner.set_message(format!("[{}] Pushing image {} to registry {}...", host, short_hash, registry.host));
+        if let Err(e) = push_to_registry(registry, host, short_hash, config.doas) {
+            debug!("Registry push failed for {}: {}", short_hash, e);
+        }
+    }
+
+    Ok((image_path, true))
+}
+
+/// Hash of the packages layer: the base system plus everything installed
+/// with pkg, independent of the mise runtimes stacked on top of it.
+pub fn get_packages_layer_hash(config: &config::Config, base_version: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(b"packages-layer:");
+    hasher.update(base_version.as_bytes());
+
+    let mut pkgs = config.packages.clone();
+    pkgs.sort();
+    for pkg in pkgs {
+        hasher.update(pkg.as_bytes());
+        hasher.update(b";");
+    }
+
+    hasher.update(b"bootstrap:");
+    for pkg in config.image.bootstrap_packages() {
+        hasher.update(pkg.as_bytes());
+        hasher.update(b";");
+    }
+
+    if let Some(user) = &config.user {
+        hasher.update(b"user:");
+        hasher.update(user.as_bytes());
+    }
+
+    if let Some(poudriere) = &config.image.poudriere {
+        hasher.update(format!("poudriere:{};", poudriere.packages_dir()).as_bytes());
+    }
+
+    hex::encode(hasher.finalize())
+}
+
+/// Ensure the packages layer for this config exists as a ZFS snapshot
+/// cloned from the base, building it if needed. Returns the snapshot name.
+fn ensure_packages_layer(
+    config: &config::Config,
+    host: &str,
+    base_version: &str,
+    images_parent_ds: &str,
+    base_snap: &str,
+    spinner: &ProgressBar,
+) -> Result<String> {
+    let layer_hash = get_packages_layer_hash(config, base_version);
+    let short_hash = &layer_hash[..12];
+    let layer_path = format!("{}/{}", IMAGE_LAYERS_DIR, short_hash);
+    let layer_ds = format!("{}/layers/{}", images_parent_ds, short_hash);
+    let layer_snap = format!("{}@packages", layer_ds);
+
+    if remote::run(host, &format!("zfs list -H -o name {} 2>/dev/null", layer_snap)).is_ok() {
+        spinner.set_message(format!("[{}] Image: Using existing packages layer {}", host, short_hash));
+        return Ok(layer_snap);
     }
+    if remote::run(host, &format!("zfs list -H -o name {} 2>/dev/null", layer_ds)).is_ok() {
+        spinner.set_message(format!("[{}] Cleaning up incomplete packages layer...", host));
+        remote::run(host, &maybe_doas(&format!("jail -r layer-{} 2>/dev/null", short_hash), config.doas)).ok();
+        remote::run(host, &maybe_doas(&format!("umount -f {}/dev 2>/dev/null", layer_path), config.doas)).ok();
+        remote::run(host, &maybe_doas(&format!("zfs destroy -r {}", layer_ds), config.doas))?;
+    }
+
+    spinner.set_message(format!("[{}] Image: Building packages layer {}...", host, short_hash));
+    remote::run(host, &maybe_doas(&format!("zfs clone -p -o mountpoint={} {} {}", layer_path, base_snap, layer_ds), config.doas))?;
 
-    if !zfs_cloned_base {
-        spinner.set_message(format!("[{}] Image: Populating base system with hardlinks (UFS-optimized)...", host));
-        // Use rsync --link-dest for hardlinked copy (handles FreeBSD immutable flags)
-        // This shares disk space with base system until files are modified
-        remote::run(host, &format!("{}rsync -a --link-dest={} {}/ {}/", cmd_prefix, base_dir, base_dir, image_path))?;
-        // Fix var/empty permissions (needs to be created with specific perms)
-        remote::run(host, &format!("{}chmod 555 {}/var/empty", cmd_prefix, image_path))?;
+    let build_jail_name = format!("layer-{}", short_hash);
+    let res = run_in_build_jail(config, host, &layer_path, &build_jail_name, |jail| {
+        install_packages(config, host, jail, spinner)
+    });
+    if let Err(e) = res {
+        remote::run(host, &maybe_doas(&format!("zfs destroy -r {}", layer_ds), config.doas)).ok();
+        return Err(e);
     }
 
-    // 2. Setup Build Jail (Directly on image_path)
-    let build_jail_name = format!("build-{}", short_hash);
-    
+    remote::run(host, &maybe_doas(&format!("zfs snapshot {}", layer_snap), config.doas))?;
+    Ok(layer_snap)
+}
+
+/// Start a temporary build jail on `path`, run `build` against it and tear
+/// the jail down again. The caller is responsible for discarding `path` on error.
+fn run_in_build_jail<F>(config: &config::Config, host: &str, path: &str, build_jail_name: &str, build: F) -> Result<()>
+where
+    F: FnOnce(&str) -> Result<()>,
+{
+    let cmd_prefix = shell::escalation_prefix(config.doas);
+
     // Mount devfs
-    remote::run(host, &format!("{}mount -t devfs devfs {}/dev", cmd_prefix, image_path))?;
+    remote::ensure_mount(host, "devfs", "devfs", &format!("{}/dev", path), false, config.doas)?;
     // Copy resolv.conf
-    remote::run(host, &format!("{}cp /etc/resolv.conf {}/etc/", cmd_prefix, image_path))?;
+    remote::run(host, &format!("{}cp /etc/resolv.conf {}/etc/", cmd_prefix, path))?;
+    // Expose the poudriere repository read-only with priority over the official repo
+    let poudriere_mount = format!("{}{}", path, BUILD_POUDRIERE_MOUNT);
+    let poudriere_repo_conf = format!("{}/usr/local/etc/pkg/repos/bsdeploy-poudriere.conf", path);
+    if let Some(poudriere) = &config.image.poudriere {
+        remote::ensure_dir(host, &format!("{}/usr/local/etc/pkg/repos", path), config.doas)?;
+        remote::ensure_mount(host, "nullfs", &poudriere.packages_dir(), &poudriere_mount, true, config.doas)?;
+        let repo_conf = format!(
+            "bsdeploy-poudriere: {{\n  url: \"file://{}\",\n  priority: 100,\n  enabled: yes\n}}\n",
+            BUILD_POUDRIERE_MOUNT
+        );
+        remote::write_file(host, &repo_conf, &poudriere_repo_conf, config.doas)?;
+    }
+
+    // Isolated builds get their own lo1 alias instead of the host's addresses
+    let build_ip = match config.image.build_network {
+        BuildNetwork::Inherit => None,
+        BuildNetwork::Isolated => {
+            let subnet = config.jail_ip_range(host);
+            Some(jail::alias_free_ip(host, subnet, config.doas)?)
+        }
+    };
+    let ip_param = match &build_ip {
+        Some(ip) => format!("ip4.addr={}", ip),
+        None => "ip4=inherit".to_string(),
+    };
 
     // Start Jail
     let start_cmd = format!(
-        "{}jail -c name={} path={} host.hostname={} ip4=inherit allow.raw_sockets=1 persist",
-        cmd_prefix, build_jail_name, image_path, build_jail_name
+        "{}jail -c name={} path={} host.hostname={} {} allow.raw_sockets=1 persist",
+        cmd_prefix, build_jail_name, path, build_jail_name, ip_param
     );
-    
+
     if let Err(e) = remote::run(host, &start_cmd) {
-        remote::run(host, &format!("{}umount {}/dev", cmd_prefix, image_path)).ok();
+        remote::run(host, &format!("{}umount {}/dev", cmd_prefix, path)).ok();
+        if config.image.poudriere.is_some() {
+            remote::run(host, &format!("{}umount {}", cmd_prefix, poudriere_mount)).ok();
+        }
+        if let Some(ip) = &build_ip {
+            remote::run(host, &format!("{}ifconfig lo1 inet {} -alias", cmd_prefix, ip)).ok();
+        }
         return Err(e);
     }
 
-    // 3. Install Packages & Configuration
-    let res = (|| -> Result<()> {
-        spinner.set_message(format!("[{}] Image: Installing packages...", host));
-        remote::run(host, &format!("{}pkg -j {} install -y git bash", cmd_prefix, build_jail_name))?;
-        if !config.packages.is_empty() {
-            let safe_pkgs: Vec<String> = config.packages.iter().map(|p| shell::escape(p)).collect();
-            let pkgs = safe_pkgs.join(" ");
-            remote::run(host, &format!("{}pkg -j {} install -y {}", cmd_prefix, build_jail_name, pkgs))?;
+    let res = build(build_jail_name).and_then(|_| {
+        // Cleanup pkg cache inside jail
+        remote::run(host, &format!("{}pkg -j {} clean -y", cmd_prefix, build_jail_name))
+    });
+
+    // Teardown Jail
+    remote::run(host, &format!("{}jail -r {}", cmd_prefix, build_jail_name))?;
+    if let Some(ip) = &build_ip {
+        remote::run(host, &format!("{}ifconfig lo1 inet {} -alias", cmd_prefix, ip))?;
+    }
+    remote::run(host, &format!("{}umount {}/dev", cmd_prefix, path))?;
+    if config.image.poudriere.is_some() {
+        // Images must not reference a repository that only exists at build time
+        remote::run(host, &format!("{}umount {}", cmd_prefix, poudriere_mount))?;
+        remote::run(host, &format!("{}rm -f {}", cmd_prefix, poudriere_repo_conf))?;
+    }
+
+    res
+}
+
+/// Environment assignments routing build downloads through `image.build_proxy`.
+fn proxy_env(config: &config::Config) -> Vec<String> {
+    match &config.image.build_proxy {
+        Some(proxy) => {
+            let proxy = shell::escape(proxy);
+            vec![format!("HTTP_PROXY={}", proxy), format!("HTTPS_PROXY={}", proxy)]
         }
+        None => Vec::new(),
+    }
+}
 
-        // Create User (with same UID as host user for consistent file ownership)
-        if let Some(user) = &config.user {
+/// Command prefix for running `pkg -j` on the host with the build proxy set.
+fn pkg_prefix(config: &config::Config) -> String {
+    let cmd_prefix = shell::escalation_prefix(config.doas);
+    let env = proxy_env(config);
+    if env.is_empty() {
+        cmd_prefix.to_string()
+    } else {
+        format!("{}env {} ", cmd_prefix, env.join(" "))
+    }
+}
+
+/// Install bootstrap and configured packages and create the service user.
+fn install_packages(config: &config::Config, host: &str, build_jail_name: &str, spinner: &ProgressBar) -> Result<()> {
+    let cmd_prefix = shell::escalation_prefix(config.doas);
+    let pkg_prefix = pkg_prefix(config);
+    let label = format!("[{}] Image: Installing packages...", host);
+    spinner.set_message(label.clone());
+    let bootstrap_pkgs = config.image.bootstrap_packages();
+    if !bootstrap_pkgs.is_empty() {
+        let safe_pkgs: Vec<String> = bootstrap_pkgs.iter().map(|p| shell::escape(p)).collect();
+        run_build_command(host, &format!("{}pkg -j {} install -y {}", pkg_prefix, build_jail_name, safe_pkgs.join(" ")), spinner, &label)?;
+    }
+    if !config.packages.is_empty() {
+        let safe_pkgs: Vec<String> = config.packages.iter().map(|p| shell::escape(p)).collect();
+        let pkgs = safe_pkgs.join(" ");
+        run_build_command(host, &format!("{}pkg -j {} install -y {}", pkg_prefix, build_jail_name, pkgs), spinner, &label)?;
+    }
+
+    // Create User (with same UID as host user for consistent file ownership)
+    if let Some(user) = &config.user {
+        let safe_user = shell::escape(user);
+        let check_user = format!("{}jexec {} id {}", cmd_prefix, build_jail_name, safe_user);
+        if remote::run(host, &check_user).is_err() {
+            // Get UID from host user (created during setup) to ensure consistent ownership
+            let host_uid = remote::query(host, &format!("id -u {}", safe_user))?
+                .trim()
+                .to_string();
+            remote::run(host, &format!(
+                "{}jexec {} pw useradd -n {} -u {} -m -s /usr/local/bin/bash",
+                cmd_prefix, build_jail_name, safe_user, host_uid
+            ))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Install mise with its build dependencies and build the configured runtimes.
+fn install_runtimes(config: &config::Config, host: &str, build_jail_name: &str, spinner: &ProgressBar) -> Result<()> {
+    if config.mise.is_empty() {
+        return Ok(());
+    }
+
+    let cmd_prefix = shell::escalation_prefix(config.doas);
+    let label = format!("[{}] Image: Installing Mise and build dependencies...", host);
+    spinner.set_message(label.clone());
+    let mut mise_pkgs = vec!["mise".to_string()];
+    mise_pkgs.extend(config.image.mise_build_packages().iter().map(|p| shell::escape(p)));
+    run_build_command(host, &format!("{}pkg -j {} install -y {}", pkg_prefix(config), build_jail_name, mise_pkgs.join(" ")), spinner, &label)?;
+    for (tool, spec) in config.mise.iter() {
+        let version = spec.version();
+        let label = format!("[{}] Image: Building {}@{}...", host, tool, version);
+        spinner.set_message(label.clone());
+        let safe_tool = shell::escape(tool);
+        let safe_version = shell::escape(version);
+        let mut exports: Vec<String> = config.mise.build_env_for(tool)
+            .iter()
+            .map(|(k, v)| format!("{}={}", k, shell::escape(v)))
+            .collect();
+        exports.extend(proxy_env(config));
+        let cmd = format!("export {} && mise use --global {}@{}", exports.join(" "), safe_tool, safe_version);
+        let exec_cmd = if let Some(user) = &config.user {
             let safe_user = shell::escape(user);
-            let check_user = format!("{}jexec {} id {}", cmd_prefix, build_jail_name, safe_user);
-            if remote::run(host, &check_user).is_err() {
-                // Get UID from host user (created during setup) to ensure consistent ownership
-                let host_uid = remote::run_with_output(host, &format!("id -u {}", safe_user))?
-                    .trim()
-                    .to_string();
-                remote::run(host, &format!(
-                    "{}jexec {} pw useradd -n {} -u {} -m -s /usr/local/bin/bash",
-                    cmd_prefix, build_jail_name, safe_user, host_uid
-                ))?;
+            format!("{}jexec {} su - {} -c {}", cmd_prefix, build_jail_name, safe_user, shell::escape(&cmd))
+        } else {
+            format!("{}jexec {} bash -c {}", cmd_prefix, build_jail_name, shell::escape(&cmd))
+        };
+        run_build_command(host, &exec_cmd, spinner, &label)?;
+    }
+
+    Ok(())
+}
+
+/// How long a build command runs before its latest output line is shown in the spinner
+const BUILD_OUTPUT_THRESHOLD: Duration = Duration::from_secs(10);
+
+/// Run a long build command while surfacing its output: every line when
+/// verbose, otherwise the latest line in the spinner once the command has
+/// been running longer than `BUILD_OUTPUT_THRESHOLD`.
+fn run_build_command(host: &str, command: &str, spinner: &ProgressBar, label: &str) -> Result<()> {
+    let started = Instant::now();
+    remote::run_streaming(host, command, remote::Timeout::Build, |line| {
+        if ui::is_verbose() {
+            ui::print_remote_line(host, line);
+        } else if started.elapsed() >= BUILD_OUTPUT_THRESHOLD {
+            let tail: String = line.trim().chars().take(60).collect();
+            if !tail.is_empty() {
+                spinner.set_message(format!("{} {}", label, tail));
             }
         }
+    })
+}
 
-        // Install Mise
-        if !config.mise.is_empty() {
-            spinner.set_message(format!("[{}] Image: Installing Mise and build dependencies...", host));
-            remote::run(host, &format!("{}pkg -j {} install -y mise gmake gcc python3 pkgconf", cmd_prefix, build_jail_name))?;
-            for (tool, version) in &config.mise {
-                 spinner.set_message(format!("[{}] Image: Building {}@{}...", host, tool, version));
-                 let safe_tool = shell::escape(tool);
-                 let safe_version = shell::escape(version);
-                 let cmd = format!("export CC=gcc CXX=g++ MAKE=gmake && mise use --global {}@{}", safe_tool, safe_version);
-                 let exec_cmd = if let Some(user) = &config.user {
-                     let safe_user = shell::escape(user);
-                     format!("{}jexec {} su - {} -c \"{}\"", cmd_prefix, build_jail_name, safe_user, cmd.replace("\"", "\\\""))
-                 } else {
-                     format!("{}jexec {} bash -c '{}'", cmd_prefix, build_jail_name, cmd)
-                 };
-                 remote::run(host, &exec_cmd)?;
-            }
+/// Contents of an image, stored as `IMAGE_MANIFEST_FILE` in the image root.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ImageManifest {
+    pub hash: String,
+    pub base_version: String,
+    pub bsdeploy_version: String,
+    pub built_at: String,
+    /// Installed packages (name -> version)
+    pub packages: BTreeMap<String, String>,
+    /// mise tools (tool -> version)
+    pub mise: BTreeMap<String, String>,
+}
+
+/// Parse `pkg query '%n %v'` output into a name -> version map.
+fn parse_package_list(output: &str) -> BTreeMap<String, String> {
+    output
+        .lines()
+        .filter_map(|line| line.trim().split_once(' '))
+        .map(|(name, version)| (name.to_string(), version.trim().to_string()))
+        .collect()
+}
+
+fn write_manifest(config: &config::Config, host: &str, image_path: &str, hash: &str, base_version: &str) -> Result<()> {
+    let pkg_output = remote::run_with_output(host, &maybe_doas(&format!("pkg -c {} query '%n %v'", image_path), config.doas))?;
+    let manifest = ImageManifest {
+        hash: hash.to_string(),
+        base_version: base_version.to_string(),
+        bsdeploy_version: env!("CARGO_PKG_VERSION").to_string(),
+        built_at: chrono::Local::now().to_rfc3339(),
+        packages: parse_package_list(&pkg_output),
+        mise: config.mise.iter().map(|(tool, spec)| (tool.clone(), spec.version().to_string())).collect(),
+    };
+    let json = serde_json::to_string_pretty(&manifest)?;
+    remote::write_file(host, &json, &format!("{}/{}", image_path, IMAGE_MANIFEST_FILE), config.doas)
+}
+
+/// Read the manifest of an image on the host.
+pub fn read_manifest(host: &str, short_hash: &str) -> Result<ImageManifest> {
+    validate_image_hash(short_hash)?;
+
+    if !image_exists(host, short_hash) {
+        anyhow::bail!("Image {} not found on {}", short_hash, host);
+    }
+
+    let manifest_path = format!("{}/{}/{}", IMAGES_DIR, short_hash, IMAGE_MANIFEST_FILE);
+    let json = remote::query(host, &format!("cat {} 2>/dev/null", manifest_path))
+        .with_context(|| format!("Image {} on {} has no manifest (built by an older bsdeploy?)", short_hash, host))?;
+    serde_json::from_str(&json).with_context(|| format!("Invalid manifest for image {} on {}", short_hash, host))
+}
+
+/// Validate a short image hash so it can be used safely in paths and commands.
+pub fn validate_image_hash(hash: &str) -> Result<()> {
+    if hash.is_empty() || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+        anyhow::bail!("Invalid image hash '{}': expected a hexadecimal image id", hash);
+    }
+    Ok(())
+}
+
+/// Check whether a complete image with the given short hash exists on the host.
+pub fn image_exists(host: &str, short_hash: &str) -> bool {
+    let image_path = format!("{}/{}", IMAGES_DIR, short_hash);
+    if let Ok(Some(images_parent_ds)) = remote::get_zfs_dataset(host, IMAGES_DIR) {
+        let snap_name = format!("{}/{}@base", images_parent_ds, short_hash);
+        remote::run(host, &format!("zfs list -H -o name {} 2>/dev/null", snap_name)).is_ok()
+    } else {
+        remote::run_timeout(host, &format!("test -f {}/{}", image_path, IMAGE_MANIFEST_FILE), remote::Timeout::Query).is_ok()
+    }
+}
+
+/// Stream a built image from the host into a local xz-compressed tarball.
+pub fn export_image(host: &str, short_hash: &str, output: &Path, doas: bool) -> Result<()> {
+    validate_image_hash(short_hash)?;
+
+    if !image_exists(host, short_hash) {
+        anyhow::bail!("Image {} not found on {}", short_hash, host);
+    }
+
+    let image_path = format!("{}/{}", IMAGES_DIR, short_hash);
+    let tar_cmd = maybe_doas(&format!("tar -cJf - -C {} .", image_path), doas);
+    remote::run_to_file(host, &tar_cmd, output)
+        .with_context(|| format!("Failed to export image {} from {}", short_hash, host))
+}
+
+/// Unpack a local image tarball on the host, creating the image dataset and
+/// `@base` snapshot on ZFS. Returns false if the image was already present.
+pub fn import_image(host: &str, short_hash: &str, input: &Path, doas: bool) -> Result<bool> {
+    validate_image_hash(short_hash)?;
+
+    if image_exists(host, short_hash) {
+        return Ok(false);
+    }
+
+    let partial_path = partial_image_path(short_hash);
+    let images_parent_ds = remote::get_zfs_dataset(host, IMAGES_DIR).ok().flatten();
+
+    // Clear out leftovers from a previous failed build or import
+    remove_image(host, short_hash, doas);
+    remove_partial_image(host, short_hash, doas);
+
+    if let Some(parent_ds) = &images_parent_ds {
+        let partial_ds = partial_image_dataset(parent_ds, short_hash);
+        remote::run(host, &maybe_doas(&format!("zfs create -o mountpoint={} {}", partial_path, partial_ds), doas))?;
+    } else {
+        remote::ensure_dir(host, &partial_path, doas)?;
+    }
+
+    let tar_cmd = maybe_doas(&format!("tar -xpJf - -C {}", partial_path), doas);
+    if let Err(e) = remote::run_from_file(host, input, &tar_cmd) {
+        remove_partial_image(host, short_hash, doas);
+        return Err(e).with_context(|| format!("Failed to import image {} to {}", short_hash, host));
+    }
+
+    promote_image(host, short_hash, images_parent_ds.as_deref(), doas)?;
+    Ok(true)
+}
+
+/// Scratch path an image is built or imported into before it is promoted.
+fn partial_image_path(short_hash: &str) -> String {
+    format!("{}/.partial-{}", IMAGES_DIR, short_hash)
+}
+
+fn partial_image_dataset(images_parent_ds: &str, short_hash: &str) -> String {
+    format!("{}/partial-{}", images_parent_ds, short_hash)
+}
+
+/// Script moving a completed scratch image to its final name without ZFS,
+/// escalating the changes with `cmd_prefix`. An incomplete image left at
+/// the name (no manifest) is renamed away first, so `mv` does not nest the
+/// scratch image inside it, and removed unless jails still mount it. A
+/// complete one wins over the scratch image. Images renamed away by earlier
+/// promotions are removed first, once nothing mounts them any more.
+fn promote_script(short_hash: &str, cmd_prefix: &str) -> String {
+    format!(
+        r#"set -e
+for s in {images}/.stale-*; do
+    [ -d "$s" ] || continue
+    mount -p | awk -v s="$s" '$1 == s || index($1, s "/") == 1 {{ found = 1 }} END {{ exit !found }}' && continue
+    {c}chflags -R noschg "$s"
+    {c}rm -rf "$s"
+done
+p={partial}
+t={images}/{hash}
+[ -f "$p/{manifest}" ] || {{ echo "image {hash} has no {manifest}" >&2; exit 1; }}
+if [ -f "$t/{manifest}" ]; then
+    {c}chflags -R noschg "$p"
+    {c}rm -rf "$p"
+    exit 0
+fi
+old=
+if [ -e "$t" ]; then
+    busy=$(mount -p | awk -v t="$t" '$1 == t || index($1, t "/") == 1' | wc -l)
+    old={images}/.stale-{hash}-$$
+    {c}mv "$t" "$old"
+fi
+{c}mv "$p" "$t"
+if [ -n "$old" ] && [ "$busy" -eq 0 ]; then
+    {c}chflags -R noschg "$old"
+    {c}rm -rf "$old"
+fi
+"#,
+        partial = partial_image_path(short_hash),
+        images = IMAGES_DIR,
+        hash = short_hash,
+        manifest = IMAGE_MANIFEST_FILE,
+        c = cmd_prefix
+    )
+}
+
+/// Move a completed scratch image to its final name. On ZFS the `@base`
+/// snapshot is taken last and marks the image as complete; otherwise the
+/// manifest does (see `promote_script`).
+fn promote_image(host: &str, short_hash: &str, images_parent_ds: Option<&str>, doas: bool) -> Result<()> {
+    let image_path = format!("{}/{}", IMAGES_DIR, short_hash);
+    if let Some(parent_ds) = images_parent_ds {
+        let image_ds = format!("{}/{}", parent_ds, short_hash);
+        let partial_ds = partial_image_dataset(parent_ds, short_hash);
+        remote::run(host, &maybe_doas(&format!("zfs rename {} {}", partial_ds, image_ds), doas))?;
+        remote::run(host, &maybe_doas(&format!("zfs set mountpoint={} {}", image_path, image_ds), doas))?;
+        remote::run(host, &maybe_doas(&format!("zfs snapshot {}@base", image_ds), doas))?;
+    } else {
+        // rename(2) within the images directory is atomic
+        let script = promote_script(short_hash, shell::escalation_prefix(doas));
+        remote::run(host, &format!("sh -c {}", shell::escape(&script)))?;
+    }
+    Ok(())
+}
+
+/// Best-effort removal of an interrupted build or import of an image.
+fn remove_partial_image(host: &str, short_hash: &str, doas: bool) {
+    let partial_path = partial_image_path(short_hash);
+    if remote::run_timeout(host, &format!("test -e {}", partial_path), remote::Timeout::Query).is_err() {
+        return;
+    }
+
+    // A build killed mid-way may leave its jail running and filesystems mounted
+    remote::run(host, &maybe_doas(&format!("jail -r build-{} 2>/dev/null", short_hash), doas)).ok();
+    remote::run(host, &maybe_doas(&format!("umount -f {}{} 2>/dev/null", partial_path, BUILD_POUDRIERE_MOUNT), doas)).ok();
+    remote::run(host, &maybe_doas(&format!("umount -f {}/dev 2>/dev/null", partial_path), doas)).ok();
+
+    if let Ok(Some(images_parent_ds)) = remote::get_zfs_dataset(host, IMAGES_DIR) {
+        let partial_ds = partial_image_dataset(&images_parent_ds, short_hash);
+        if remote::run(host, &format!("zfs list -H -o name {} 2>/dev/null", partial_ds)).is_ok() {
+            remote::run(host, &maybe_doas(&format!("zfs destroy -r {}", partial_ds), doas)).ok();
         }
+    }
+    if remote::run_timeout(host, &format!("test -e {}", partial_path), remote::Timeout::Query).is_ok() {
+        remote::run(host, &maybe_doas(&format!("chflags -R noschg {} 2>/dev/null", partial_path), doas)).ok();
+        remote::run(host, &maybe_doas(&format!("rm -rf {}", partial_path), doas)).ok();
+    }
+}
 
-        // Cleanup pkg cache inside jail
-        remote::run(host, &format!("{}pkg -j {} clean -y", cmd_prefix, build_jail_name))?;
-        Ok(())
-    })();
+/// Counter for unique tarball names within this process
+static TARBALL_SEQ: AtomicUsize = AtomicUsize::new(0);
 
-    // 4. Teardown Jail
-    remote::run(host, &format!("{}jail -r {}", cmd_prefix, build_jail_name))?;
-    remote::run(host, &format!("{}umount {}/dev", cmd_prefix, image_path))?;
+/// Local scratch path for an image tarball passing through the controller.
+/// Unique per call, as several hosts may move the same image at once.
+fn temp_tarball_path(host: &str, short_hash: &str) -> PathBuf {
+    let host: String = host.chars().map(|c| if c.is_ascii_alphanumeric() || c == '.' || c == '-' { c } else { '_' }).collect();
+    std::env::temp_dir().join(format!(
+        "bsdeploy-image-{}-{}-{}-{}.txz",
+        short_hash,
+        host,
+        std::process::id(),
+        TARBALL_SEQ.fetch_add(1, Ordering::Relaxed)
+    ))
+}
 
-    if let Err(e) = res {
-        // If build failed, destroy the dataset so we don't leave broken state
-        if let Ok(Some(images_parent_ds)) = remote::get_zfs_dataset(host, IMAGES_DIR) {
-             let image_ds = format!("{}/{}", images_parent_ds, short_hash);
-             remote::run(host, &format!("{}zfs destroy -r {}", cmd_prefix, image_ds)).ok();
+/// Pull an image from the registry into the host. Returns false if the
+/// registry does not have the image.
+pub fn pull_from_registry(registry: &RegistryConfig, host: &str, short_hash: &str, doas: bool) -> Result<bool> {
+    validate_image_hash(short_hash)?;
+
+    let remote_tarball = format!("{}/{}.txz", registry.path, short_hash);
+    if remote::run_timeout(&registry.host, &format!("test -f {}", shell::escape(&remote_tarball)), remote::Timeout::Query).is_err() {
+        return Ok(false);
+    }
+
+    let local_tarball = temp_tarball_path(host, short_hash);
+    let result = remote::run_to_file(&registry.host, &format!("cat {}", shell::escape(&remote_tarball)), &local_tarball)
+        .and_then(|_| import_image(host, short_hash, &local_tarball, doas));
+    std::fs::remove_file(&local_tarball).ok();
+
+    result.map(|_| true)
+}
+
+/// Push an image built on the host to the registry, unless it is already there.
+pub fn push_to_registry(registry: &RegistryConfig, host: &str, short_hash: &str, doas: bool) -> Result<()> {
+    validate_image_hash(short_hash)?;
+
+    let safe_dir = shell::escape(&registry.path);
+    let remote_tarball = shell::escape(&format!("{}/{}.txz", registry.path, short_hash));
+    if remote::run_timeout(&registry.host, &format!("test -f {}", remote_tarball), remote::Timeout::Query).is_ok() {
+        return Ok(());
+    }
+
+    let local_tarball = temp_tarball_path(host, short_hash);
+    let result = export_image(host, short_hash, &local_tarball, doas).and_then(|_| {
+        remote::run(&registry.host, &maybe_doas(&format!("mkdir -p {}", safe_dir), registry.doas))?;
+        let dest = format!("{}/{}.txz", registry.path, short_hash);
+        remote::upload_file(&registry.host, &local_tarball, &dest, 0o644, None, registry.doas)
+    });
+    std::fs::remove_file(&local_tarball).ok();
+
+    result.with_context(|| format!("Failed to push image {} to registry {}", short_hash, registry.host))
+}
+
+/// Best-effort removal of an image dataset or directory.
+fn remove_image(host: &str, short_hash: &str, doas: bool) {
+    let image_path = format!("{}/{}", IMAGES_DIR, short_hash);
+    if let Ok(Some(images_parent_ds)) = remote::get_zfs_dataset(host, IMAGES_DIR) {
+        let image_ds = format!("{}/{}", images_parent_ds, short_hash);
+        if remote::run(host, &format!("zfs list -H -o name {} 2>/dev/null", image_ds)).is_ok() {
+            remote::run(host, &maybe_doas(&format!("zfs destroy -r {}", image_ds), doas)).ok();
         }
-        return Err(e);
     }
+    remote::run(host, &maybe_doas(&format!("chflags -R noschg {} 2>/dev/null", image_path), doas)).ok();
+    remote::run(host, &maybe_doas(&format!("rm -rf {}", image_path), doas)).ok();
+}
 
-    // 5. Snapshot
-    if let Ok(Some(dataset)) = remote::get_zfs_dataset(host, &image_path) {
-        spinner.set_message(format!("[{}] Image: Creating ZFS snapshot...", host));
-        let snap_name = format!("{}@base", dataset);
-        remote::run(host, &format!("{}zfs snapshot {}", cmd_prefix, snap_name))?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config_from(yaml: &str) -> config::Config {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn test_image_hash_includes_only_custom_bootstrap_packages() {
+        let implicit = config_from("service: myapp\nhosts: [a]\n");
+        let explicit = config_from("service: myapp\nhosts: [a]\nimage:\n  bootstrap_packages: [bash]\n");
+        assert_ne!(
+            get_image_hash(&implicit, "14.1-RELEASE"),
+            get_image_hash(&explicit, "14.1-RELEASE")
+        );
+        assert_eq!(
+            get_image_hash(&implicit, "14.1-RELEASE"),
+            get_image_hash(&config_from("service: other\nhosts: [b]\n"), "14.1-RELEASE")
+        );
+    }
+
+    #[test]
+    fn test_packages_layer_hash_ignores_mise() {
+        let node20 = config_from("service: myapp\nhosts: [a]\npackages: [curl]\nmise:\n  node: \"20\"\n");
+        let node22 = config_from("service: myapp\nhosts: [a]\npackages: [curl]\nmise:\n  node: \"22\"\n");
+        let more_pkgs = config_from("service: myapp\nhosts: [a]\npackages: [curl, git]\nmise:\n  node: \"22\"\n");
+        assert_ne!(get_image_hash(&node20, "14.1-RELEASE"), get_image_hash(&node22, "14.1-RELEASE"));
+        assert_eq!(
+            get_packages_layer_hash(&node20, "14.1-RELEASE"),
+            get_packages_layer_hash(&node22, "14.1-RELEASE")
+        );
+        assert_ne!(
+            get_packages_layer_hash(&node22, "14.1-RELEASE"),
+            get_packages_layer_hash(&more_pkgs, "14.1-RELEASE")
+        );
+    }
+
+    #[test]
+    fn test_parse_package_list() {
+        let packages = parse_package_list("bash 5.2.26\ngit 2.45.1_1\n\n");
+        assert_eq!(packages.len(), 2);
+        assert_eq!(packages["bash"], "5.2.26");
+        assert_eq!(packages["git"], "2.45.1_1");
+    }
+
+    #[test]
+    fn test_validate_image_hash() {
+        assert!(validate_image_hash("0123456789ab").is_ok());
+        assert!(validate_image_hash("").is_err());
+        assert!(validate_image_hash("../etc").is_err());
+        assert!(validate_image_hash("abc; rm -rf /").is_err());
     }
 
-    Ok(image_path)
+    #[test]
+    fn test_parallel_pulls_use_their_own_tarball() {
+        use crate::remote::mock::{self, MockExecutor};
+        let registry = RegistryConfig { host: "registry".into(), path: "/srv/images".into(), doas: false };
+        let mock = MockExecutor::new();
+        mock.fail("test -f /usr/local/bsdeploy/images/0123456789ab/", "");
+        mock.respond("cat /srv/images/0123456789ab.txz", "tarball");
+        mock::with_executor(mock.clone(), || {
+            std::thread::scope(|s| {
+                let pulls: Vec<_> = ["web1", "deploy@web2"]
+                    .into_iter()
+                    .map(|host| s.spawn(|| pull_from_registry(&registry, host, "0123456789ab", false)))
+                    .collect();
+                for pull in pulls {
+                    assert!(pull.join().unwrap().unwrap());
+                }
+            })
+        });
+        let tarballs: Vec<String> = mock
+            .commands()
+            .iter()
+            .filter_map(|c| c.strip_prefix("tar -xpJf - -C /usr/local/bsdeploy/images/.partial-0123456789ab < "))
+            .map(str::to_string)
+            .collect();
+        assert_eq!(tarballs.len(), 2);
+        assert_ne!(tarballs[0], tarballs[1]);
+        assert!(tarballs.iter().all(|t| !Path::new(t).exists()));
+    }
+
+    #[test]
+    fn test_promote_script() {
+        let script = promote_script("0123456789ab", "doas ");
+        assert!(script.contains("p=/usr/local/bsdeploy/images/.partial-0123456789ab\nt=/usr/local/bsdeploy/images/0123456789ab\n"));
+        assert!(script.contains("[ -f \"$p/.bsdeploy-image.json\" ] || {"));
+        // An incomplete image is moved away before the rename, never renamed into
+        assert!(script.contains("    doas mv \"$t\" \"$old\"\nfi\ndoas mv \"$p\" \"$t\"\n"));
+        // Stale images of earlier promotions go once no jail mounts them
+        assert!(script.starts_with("set -e\nfor s in /usr/local/bsdeploy/images/.stale-*; do\n"));
+        assert!(script.contains("END { exit !found }' && continue\n    doas chflags -R noschg \"$s\"\n    doas rm -rf \"$s\"\ndone\n"));
+    }
 }
@@ -1,25 +1,31 @@
+use crate::batch::Batch;
+use crate::remote::ensure;
 use crate::constants::*;
-use crate::remote;
+use crate::{helper, os, remote, shell};
 use anyhow::{Context, Result, anyhow};
 use chrono::Local;
 use std::collections::HashSet;
+use std::path::Path;
 
-fn find_free_ip(host: &str, subnet: &str, _doas: bool) -> Result<String> {
-    // Default 10.0.0.0/24
-    // We scan 10.0.0.2 to 10.0.0.254
-    // subnet format: "10.0.0.0/24"
-
-    // Parse base
+/// The first three octets of a subnet such as "10.0.0.0/24"
+fn subnet_prefix(subnet: &str) -> Result<String> {
     let base_ip = subnet.split('/').next().unwrap_or(DEFAULT_BASE_IP);
     let parts: Vec<&str> = base_ip.split('.').collect();
     if parts.len() != 4 {
         return Err(anyhow!("Invalid subnet format"));
     }
-    let prefix = format!("{}.{}.{}", parts[0], parts[1], parts[2]);
+    Ok(format!("{}.{}.{}", parts[0], parts[1], parts[2]))
+}
+
+fn find_free_ip(host: &str, subnet: &str, _doas: bool) -> Result<String> {
+    // Default 10.0.0.0/24
+    // We scan 10.0.0.2 to 10.0.0.254
+    // subnet format: "10.0.0.0/24"
+    let prefix = subnet_prefix(subnet)?;
 
     // Get current aliases on lo1
     let cmd = "ifconfig lo1 | grep 'inet ' | awk '{print $2}'";
-    let output = remote::run_with_output(host, cmd)?;
+    let output = remote::query(host, cmd)?;
     // Use HashSet for O(1) lookup instead of O(n) Vec::contains
     let used_ips: HashSet<String> = output.lines().map(|s| s.trim().to_string()).collect();
 
@@ -36,24 +42,46 @@ fn find_free_ip(host: &str, subnet: &str, _doas: bool) -> Result<String> {
     Err(anyhow!("No free IPs found in subnet {}", subnet))
 }
 
-pub fn ensure_base(host: &str, version: &str, doas: bool) -> Result<()> {
+/// Alias a free IP from `subnet` on lo1 for a temporary jail (e.g. an image build).
+pub fn alias_free_ip(host: &str, subnet: &str, doas: bool) -> Result<String> {
+    let cmd_prefix = shell::escalation_prefix(doas);
+    if remote::run(host, "ifconfig lo1 >/dev/null 2>&1").is_err() {
+        remote::run(host, &format!("{}ifconfig lo1 create", cmd_prefix))?;
+    }
+    let ip = find_free_ip(host, subnet, doas)?;
+    remote::ensure_ip_alias(host, "lo1", &ip, doas)?;
+    Ok(ip)
+}
+
+/// The `@clean` snapshot of the base for `version`, if the base lives in its
+/// own ZFS dataset and has been fully installed by `ensure_base`.
+pub fn base_snapshot(host: &str, version: &str) -> Option<String> {
+    let parent_ds = remote::get_zfs_dataset(host, BASE_DIR).ok().flatten()?;
+    // Look the snapshot up by dataset name: get_zfs_dataset on a plain
+    // directory would return the parent dataset instead
+    let snap = format!("{}/{}@clean", parent_ds, version);
+    remote::run(host, &format!("zfs list -H -o name {} 2>/dev/null", snap)).ok()?;
+    Some(snap)
+}
+
+/// Make sure the base system for `version` is installed on the host. When
+/// `base_tarball` is given, that local base.txz is uploaded instead of
+/// fetching it from the release mirrors (for hosts without outbound access).
+pub fn ensure_base(host: &str, version: &str, base_tarball: Option<&Path>, doas: bool) -> Result<()> {
     let base_dir = format!("{}/{}", BASE_DIR, version);
-    let cmd_prefix = if doas { "doas " } else { "" };
+    let cmd_prefix = shell::escalation_prefix(doas);
     
     // Check if base is fully ready (marker or just existence)
     // We check for @clean snapshot if ZFS, or just directory if not.
     let is_zfs = remote::get_zfs_dataset(host, BASE_DIR).ok().flatten().is_some();
     
     if is_zfs {
-        if let Ok(Some(ds)) = remote::get_zfs_dataset(host, &base_dir) {
-            // Dataset exists, check for snapshot
-            if remote::run(host, &format!("zfs list -H -o name {}@clean 2>/dev/null", ds)).is_ok() {
-                return Ok(());
-            }
+        if base_snapshot(host, version).is_some() {
+            return Ok(());
         }
     } else {
         // Legacy check
-        if remote::run(host, &format!("test -d {}/bin", base_dir)).is_ok() {
+        if remote::run_timeout(host, &format!("test -d {}/bin", base_dir), remote::Timeout::Query).is_ok() {
             return Ok(());
         }
     }
@@ -72,34 +100,220 @@ pub fn ensure_base(host: &str, version: &str, doas: bool) -> Result<()> {
     }
 
     // Fetch and extract if empty (checking /bin)
-    if remote::run(host, &format!("test -d {}/bin", base_dir)).is_err() {
-        // We assume 14.1-RELEASE format.
-        // Defaulting to amd64.
-        let url = format!("https://download.freebsd.org/ftp/releases/amd64/{}/base.txz", version);
-        
-        let fetch_cmd = format!(
-            "{}fetch -o - {} | {}tar -xf - -C {}", 
-            cmd_prefix, url, cmd_prefix, base_dir
-        );
+    if remote::run_timeout(host, &format!("test -d {}/bin", base_dir), remote::Timeout::Query).is_err() {
+        let tarball = format!("{}/{}.base.txz", BASE_DIR, version);
+
+        if let Some(local_tarball) = base_tarball {
+            // Offline provisioning: upload the operator-provided tarball
+            remote::upload_file(host, local_tarball, &tarball, 0o644, None, doas)
+                .with_context(|| format!("Failed to upload base tarball {}", local_tarball.display()))?;
+        } else {
+            fetch_verified_base(host, version, &tarball, cmd_prefix)?;
+        }
+
+        let extract_result = remote::run(host, &format!("{}tar -xf {} -C {}", cmd_prefix, tarball, base_dir));
+        remote::run(host, &format!("{}rm -f {}", cmd_prefix, tarball)).ok();
+        extract_result.with_context(|| format!("Failed to extract base system version {}", version))?;
 
-        remote::run(host, &fetch_cmd).with_context(|| format!("Failed to fetch and extract base system version {}", version))?;
-        
         // Copy timezone and resolv.conf for template completeness (though we copy resolv.conf later too)
         remote::run(host, &format!("{}cp /etc/localtime {}/etc/localtime", cmd_prefix, base_dir)).ok();
     }
 
     // Create ZFS Snapshot if applicable
-    if is_zfs {
-        if let Ok(Some(ds)) = remote::get_zfs_dataset(host, &base_dir) {
-             if remote::run(host, &format!("zfs list -H -o name {}@clean 2>/dev/null", ds)).is_err() {
-                 remote::run(host, &format!("{}zfs snapshot {}@clean", cmd_prefix, ds))?;
-             }
+    if is_zfs
+        && let Ok(Some(ds)) = remote::get_zfs_dataset(host, &base_dir)
+        && remote::run(host, &format!("zfs list -H -o name {}@clean 2>/dev/null", ds)).is_err()
+    {
+        remote::run(host, &format!("{}zfs snapshot {}@clean", cmd_prefix, ds))?;
+    }
+
+    Ok(())
+}
+
+/// Download base.txz for `version` to `tarball` on the host and verify it
+/// against the SHA256 listed in the release MANIFEST.
+fn fetch_verified_base(host: &str, version: &str, tarball: &str, cmd_prefix: &str) -> Result<()> {
+    // The host's architecture and flavor decide where the release lives
+    let release_url = os::detect(host)?.release_url(version);
+
+    // Look up the expected checksum in the release MANIFEST
+    let manifest = remote::run_with_output(host, &format!("fetch -q -o - {}/MANIFEST", release_url))
+        .with_context(|| format!("Failed to fetch MANIFEST for base system version {}", version))?;
+    let expected_sha = parse_manifest_sha256(&manifest, "base.txz")
+        .ok_or_else(|| anyhow!("base.txz is not listed in the MANIFEST for {}", version))?;
+
+    // Download to a file (not a pipe) so it can be verified before extraction
+    remote::run_timeout(host, &format!("{}fetch -q -o {} {}/base.txz", cmd_prefix, tarball, release_url), remote::Timeout::Build)
+        .with_context(|| format!("Failed to fetch base system version {}", version))?;
+
+    let actual_sha = remote::run_with_output_timeout(host, &format!("sha256 -q {}", tarball), remote::Timeout::Command)?;
+    if actual_sha.trim() != expected_sha {
+        remote::run(host, &format!("{}rm -f {}", cmd_prefix, tarball)).ok();
+        return Err(anyhow!(
+            "Checksum mismatch for base.txz ({}): expected {}, got {}",
+            version, expected_sha, actual_sha.trim()
+        ));
+    }
+
+    Ok(())
+}
+
+/// Validate a base version string (e.g. `14.1-RELEASE`) before using it in paths.
+pub fn validate_base_version(version: &str) -> Result<()> {
+    if version.is_empty()
+        || version.starts_with('.')
+        || !version.chars().all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-')
+    {
+        return Err(anyhow!("Invalid base version '{}'", version));
+    }
+    Ok(())
+}
+
+/// List the base system versions installed on the host.
+pub fn list_bases(host: &str) -> Result<Vec<String>> {
+    let output = remote::query(
+        host,
+        &format!("find {} -mindepth 1 -maxdepth 1 -type d -exec basename {{}} \\; 2>/dev/null || true", BASE_DIR),
+    )?;
+    let mut versions: Vec<String> = output
+        .lines()
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .collect();
+    versions.sort();
+    Ok(versions)
+}
+
+/// Collect base versions still in use by jails (from their metadata) or,
+/// on ZFS, by images cloned from a base snapshot.
+pub fn referenced_base_versions(host: &str) -> Result<HashSet<String>> {
+    let mut referenced = HashSet::new();
+
+    let jails_cmd = format!(
+        "cat {}/*/.bsdeploy.json 2>/dev/null | jq -r '.base_version // empty' 2>/dev/null || true",
+        JAILS_DIR
+    );
+    for line in remote::query(host, &jails_cmd)?.lines() {
+        let v = line.trim();
+        if !v.is_empty() {
+            referenced.insert(v.to_string());
         }
     }
 
+    if let Ok(Some(images_ds)) = remote::get_zfs_dataset(host, IMAGES_DIR) {
+        // Depth 2 covers images and the packages layers under images/layers
+        let origins_cmd = format!("zfs list -H -r -d 2 -o origin {} 2>/dev/null || true", images_ds);
+        for origin in remote::query(host, &origins_cmd)?.lines() {
+            // Origin looks like pool/bsdeploy/base/14.1-RELEASE@clean (or @clean-<ts> after an update)
+            if let Some((dataset, snap)) = origin.trim().split_once('@')
+                && snap.starts_with("clean")
+                && let Some(version) = dataset.rsplit('/').next()
+            {
+                referenced.insert(version.to_string());
+            }
+        }
+    }
+
+    Ok(referenced)
+}
+
+/// Number of nullfs mounts of the base tree (or a directory in it) into
+/// jails, from the source field of `mount -p`.
+fn base_mounts(host: &str, base_dir: &str) -> Result<usize> {
+    let cmd = format!(
+        "mount -p | awk -v b={} '$1 == b || index($1, b \"/\") == 1' | wc -l",
+        shell::escape(base_dir)
+    );
+    Ok(remote::query(host, &cmd)?.trim().parse().unwrap_or(0))
+}
+
+/// Remove an installed base system (dataset or directory).
+pub fn remove_base(host: &str, version: &str, doas: bool) -> Result<()> {
+    validate_base_version(version)?;
+    let base_dir = format!("{}/{}", BASE_DIR, version);
+    let cmd_prefix = shell::escalation_prefix(doas);
+
+    // Refuse to remove a base that is still nullfs-mounted into a jail
+    if base_mounts(host, &base_dir)? > 0 {
+        return Err(anyhow!("Base {} is still mounted on {}", version, host));
+    }
+
+    if let Ok(Some(ds)) = remote::get_zfs_dataset(host, &base_dir)
+        && ds.ends_with(&format!("/{}", version))
+    {
+        // No -R: fail rather than destroy clones that still depend on this base
+        remote::run(host, &format!("{}zfs destroy -r {}", cmd_prefix, ds))?;
+    }
+
+    remote::run(host, &format!("{}chflags -R noschg {}", cmd_prefix, base_dir)).ok();
+    remote::run(host, &format!("{}rm -rf {}", cmd_prefix, base_dir))?;
     Ok(())
 }
 
+/// Apply freebsd-update patches to an installed base tree. On ZFS the
+/// pre-update state is kept as a snapshot and `@clean` is moved forward so
+/// newly built images pick up the patched base. Jails cloned from it keep
+/// their own copy; jails that mount the base itself would see it change
+/// underneath them, so the update is refused while any does.
+pub fn update_base(host: &str, version: &str, doas: bool) -> Result<()> {
+    validate_base_version(version)?;
+    let base_dir = format!("{}/{}", BASE_DIR, version);
+    let cmd_prefix = shell::escalation_prefix(doas);
+
+    if remote::run_timeout(host, &format!("test -d {}/bin", base_dir), remote::Timeout::Query).is_err() {
+        return Err(anyhow!("Base {} is not installed on {}", version, host));
+    }
+    if base_mounts(host, &base_dir)? > 0 {
+        return Err(anyhow!(
+            "Base {} is mounted into running jails on {}; deploy them onto another base or stop them before updating it",
+            version,
+            host
+        ));
+    }
+
+    let timestamp = Local::now().format("%Y%m%d-%H%M%S");
+    let dataset = remote::get_zfs_dataset(host, &base_dir)
+        .ok()
+        .flatten()
+        .filter(|ds| ds.ends_with(&format!("/{}", version)));
+
+    if let Some(ds) = &dataset {
+        remote::run(host, &format!("{}zfs snapshot {}@pre-update-{}", cmd_prefix, ds, timestamp))?;
+    }
+
+    let update_cmd = format!(
+        "{}env PAGER=cat freebsd-update --not-running-from-cron -b {} -d {}/var/db/freebsd-update --currently-running {} fetch install",
+        cmd_prefix, base_dir, base_dir, version
+    );
+    remote::run(host, &update_cmd)
+        .with_context(|| format!("freebsd-update failed for base {} on {}", version, host))?;
+
+    if let Some(ds) = &dataset {
+        // Existing clones keep referencing the renamed snapshot
+        if remote::run(host, &format!("zfs list -H -o name {}@clean 2>/dev/null", ds)).is_ok() {
+            remote::run(host, &format!("{}zfs rename {}@clean {}@clean-{}", cmd_prefix, ds, ds, timestamp))?;
+        }
+        remote::run(host, &format!("{}zfs snapshot {}@clean", cmd_prefix, ds))?;
+    }
+
+    Ok(())
+}
+
+/// Find the SHA256 checksum of a distribution file in a FreeBSD release MANIFEST.
+/// Lines are tab-separated: `<file> <sha256> <count> <name> <description> <default>`.
+fn parse_manifest_sha256(manifest: &str, file: &str) -> Option<String> {
+    manifest.lines().find_map(|line| {
+        let mut fields = line.split('\t');
+        if fields.next()? == file {
+            let sha = fields.next()?.trim();
+            if sha.len() == 64 && sha.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Some(sha.to_lowercase());
+            }
+        }
+        None
+    })
+}
+
 pub struct JailInfo {
     pub name: String,
     pub path: String,
@@ -112,7 +326,19 @@ pub fn create(host: &str, service: &str, base_version: &str, subnet: &str, image
     let jail_name = format!("{}-{}", service, timestamp);
     let jail_root = format!("{}/{}", JAILS_DIR, jail_name);
     let base_dir = format!("{}/{}", BASE_DIR, base_version);
-    let cmd_prefix = if doas { "doas " } else { "" };
+    let cmd_prefix = shell::escalation_prefix(doas);
+
+    if helper::is_enabled() {
+        let data_dirs: Vec<String> = data_dirs
+            .iter()
+            .map(|d| d.get_paths())
+            .filter(|(host_path, jail_path)| !host_path.is_empty() && !jail_path.is_empty())
+            .map(|(host_path, jail_path)| format!("{}:{}", host_path, jail_path))
+            .collect();
+        let (ip, zfs) =
+            helper::create_jail(host, &jail_name, base_version, image_path, &subnet_prefix(subnet)?, &data_dirs, doas)?;
+        return Ok(JailInfo { name: jail_name, path: jail_root, ip, zfs });
+    }
 
     // 0. Ensure lo1 exists
     // We check if lo1 exists, if not create it
@@ -121,7 +347,7 @@ pub fn create(host: &str, service: &str, base_version: &str, subnet: &str, image
     }
 
     // 1. Create Jail Root
-    remote::run(host, &format!("{}mkdir -p {}", cmd_prefix, jail_root))?;
+    remote::ensure_dir(host, &jail_root, doas)?;
 
     // 2. Setup correct structure (Skeleton)
     
@@ -150,13 +376,13 @@ pub fn create(host: &str, service: &str, base_version: &str, subnet: &str, image
         if !zfs_cloned {
             // Fallback to Copy RW dirs from Image (excluding usr/local)
             // Use hardlinks to save disk space - identical files shared until modified
-            remote::run(host, &format!("{}mkdir -p {}/usr", cmd_prefix, jail_root))?;
+            remote::ensure_dir(host, &format!("{}/usr", jail_root), doas)?;
 
             let rw_dirs = vec!["etc", "var", "root", "home"];
             for dir in rw_dirs {
                 let src_dir = format!("{}/{}", img, dir);
                 // Check if directory exists before copying (some dirs may not exist in image)
-                if remote::run(host, &format!("test -d {}", src_dir)).is_ok() {
+                if remote::run_timeout(host, &format!("test -d {}", src_dir), remote::Timeout::Query).is_ok() {
                     // Use rsync --link-dest for hardlinked copy (handles FreeBSD immutable flags)
                     remote::run(host, &format!("{}rsync -a --link-dest={} {}/ {}/{}/", cmd_prefix, src_dir, src_dir, jail_root, dir))?;
                 }
@@ -175,8 +401,7 @@ pub fn create(host: &str, service: &str, base_version: &str, subnet: &str, image
             // If we cloned, /usr/local is already there and writable.
             // We do NOT need to mount it.
         } else {
-            remote::run(host, &format!("{}mkdir -p {}/usr/local", cmd_prefix, jail_root))?;
-            remote::run(host, &format!("{}mount_nullfs -o ro {}/usr/local {}/usr/local", cmd_prefix, img, jail_root))?;
+            remote::ensure_mount(host, "nullfs", &format!("{}/usr/local", img), &format!("{}/usr/local", jail_root), true, doas)?;
         }
         
     } else {
@@ -186,58 +411,74 @@ pub fn create(host: &str, service: &str, base_version: &str, subnet: &str, image
             remote::run(host, &format!("{}cp -a {}/{} {}/", cmd_prefix, base_dir, dir, jail_root))?;
         }
         remote::run(host, &format!("{}cp /etc/resolv.conf {}/etc/", cmd_prefix, jail_root))?;
-        remote::run(host, &format!("{}mkdir -p {}/home", cmd_prefix, jail_root))?;
-        remote::run(host, &format!("{}mkdir -p {}/usr", cmd_prefix, jail_root))?;
-        remote::run(host, &format!("{}mkdir -p {}/usr/local", cmd_prefix, jail_root))?;
+        remote::ensure_dir(host, &format!("{}/home", jail_root), doas)?;
+        remote::ensure_dir(host, &format!("{}/usr/local", jail_root), doas)?;
     }
 
+    // Mounts and directory setup go out as one script to save round trips
+    let mut batch = Batch::new();
+
     // Dirs to create for mounting
     if !zfs_cloned {
         let root_mounts = vec!["bin", "lib", "libexec", "sbin"];
         for dir in root_mounts {
-             remote::run(host, &format!("{}mkdir -p {}/{}", cmd_prefix, jail_root, dir))?;
-             remote::run(host, &format!("{}mount_nullfs -o ro {}/{} {}/{}", cmd_prefix, base_dir, dir, jail_root, dir))?;
+             batch.add(
+                 format!("mount base /{}", dir),
+                 ensure::mount_command("nullfs", &format!("{}/{}", base_dir, dir), &format!("{}/{}", jail_root, dir), true, doas),
+             );
         }
 
         // Handle /usr mounts (skipping local)
         let usr_mounts = vec!["bin", "include", "lib", "lib32", "libdata", "libexec", "sbin", "share"];
         for dir in usr_mounts {
-             if remote::run(host, &format!("test -d {}/usr/{}", base_dir, dir)).is_ok() {
-                 remote::run(host, &format!("{}mkdir -p {}/usr/{}", cmd_prefix, jail_root, dir))?;
-                 remote::run(host, &format!("{}mount_nullfs -o ro {}/usr/{} {}/usr/{}", cmd_prefix, base_dir, dir, jail_root, dir))?;
-             }
+             batch.add(
+                 format!("mount base /usr/{}", dir),
+                 format!(
+                     "if [ -d {}/usr/{} ]; then {}; fi",
+                     base_dir,
+                     dir,
+                     ensure::mount_command("nullfs", &format!("{}/usr/{}", base_dir, dir), &format!("{}/usr/{}", jail_root, dir), true, doas)
+                 ),
+             );
         }
     }
     
     // Devfs
-    remote::run(host, &format!("{}mkdir -p {}/dev", cmd_prefix, jail_root))?;
-    remote::run(host, &format!("{}mount -t devfs devfs {}/dev", cmd_prefix, jail_root))?;
+    batch.add("mount devfs", ensure::mount_command("devfs", "devfs", &format!("{}/dev", jail_root), false, doas));
 
     // Fix permissions for tmp
-    remote::run(host, &format!("{}mkdir -p {}/tmp", cmd_prefix, jail_root))?;
-    remote::run(host, &format!("{}chmod 1777 {}/tmp", cmd_prefix, jail_root))?;
-    remote::run(host, &format!("{}mkdir -p {}/var/tmp", cmd_prefix, jail_root))?;
-    remote::run(host, &format!("{}chmod 1777 {}/var/tmp", cmd_prefix, jail_root))?;
+    for tmp in ["tmp", "var/tmp"] {
+        let tmp_dir = format!("{}/{}", jail_root, tmp);
+        batch.add(
+            format!("prepare /{}", tmp),
+            format!("{} && {}chmod 1777 {}", ensure::dir_command(&tmp_dir, doas), cmd_prefix, tmp_dir),
+        );
+    }
 
     // Data Directories (Host -> Jail nullfs RW)
     for entry in data_dirs {
         let (host_path, jail_path) = entry.get_paths();
         if host_path.is_empty() || jail_path.is_empty() { continue; }
 
-        // Ensure host dir exists
-        remote::run(host, &format!("{}mkdir -p {}", cmd_prefix, host_path))?;
-        // Ensure jail mountpoint exists (absolute path relative to jail root)
+        // Ensure host dir and jail mountpoint exist (absolute path relative to jail root)
         // Strip leading slash from jail_path if it exists to join with jail_root
         let target_in_jail = format!("{}/{}", jail_root, jail_path.trim_start_matches('/'));
-        remote::run(host, &format!("{}mkdir -p {}", cmd_prefix, target_in_jail))?;
-        // Mount
-        remote::run(host, &format!("{}mount_nullfs {} {}", cmd_prefix, host_path, target_in_jail))?;
+        batch.add(
+            format!("mount data directory {}", host_path),
+            format!(
+                "{} && {}",
+                ensure::dir_command(&host_path, doas),
+                ensure::mount_command("nullfs", &host_path, &target_in_jail, false, doas)
+            ),
+        );
     }
 
+    batch.run(host)?;
+
     // 3. Network Setup
     let ip = find_free_ip(host, subnet, doas)?;
     // Alias the IP on lo1
-    remote::run(host, &format!("{}ifconfig lo1 inet {}/32 alias", cmd_prefix, ip))?;
+    remote::ensure_ip_alias(host, "lo1", &ip, doas)?;
 
     Ok(JailInfo {
         name: jail_name,
@@ -246,3 +487,140 @@ pub fn create(host: &str, service: &str, base_version: &str, subnet: &str, image
         zfs: zfs_cloned,
     })
 }
+
+/// Stop and remove a jail: its IP alias, mounts and dataset or directory.
+/// `ip` is needed when the jail may not be running (e.g. a failed deploy).
+/// Best effort - individual steps that fail are skipped.
+pub fn teardown(host: &str, jail_name: &str, ip: Option<&str>, doas: bool) -> Result<()> {
+    if helper::is_enabled() {
+        return helper::teardown_jail(host, jail_name, ip, doas);
+    }
+
+    let cmd_prefix = shell::escalation_prefix(doas);
+    let jpath = format!("{}/{}", JAILS_DIR, jail_name);
+
+    // Get IP before stopping
+    let ip = match ip {
+        Some(ip) => ip.to_string(),
+        None => remote::query(host, &format!("jls -j {} ip4.addr 2>/dev/null || echo '-'", jail_name))
+            .map(|s| s.trim().to_string())
+            .unwrap_or_default(),
+    };
+
+    // Stop jail
+    remote::run(host, &format!("{}jail -r {} 2>/dev/null", cmd_prefix, jail_name)).ok();
+
+    // Remove IP alias
+    if ip != "-" && !ip.is_empty() {
+        remote::run(host, &format!("{}ifconfig lo1 inet {} -alias 2>/dev/null", cmd_prefix, ip)).ok();
+    }
+
+    // Unmount everything under jpath (deepest first)
+    let mount_check = format!("mount | grep '{}' | awk '{{print $3}}'", jpath);
+    if let Ok(mounts) = remote::query(host, &mount_check) {
+        for mnt in mounts.lines().rev() {
+            if !mnt.trim().is_empty() {
+                remote::run(host, &format!("{}umount -f {}", cmd_prefix, mnt.trim())).ok();
+            }
+        }
+    }
+
+    // Remove the jail's own dataset. Looked up by name: once unmounted, the
+    // dataset holding jpath would be the parent of all jails.
+    if let Ok(Some(jails_ds)) = remote::get_zfs_dataset(host, JAILS_DIR) {
+        let dataset = format!("{}/{}", jails_ds, jail_name);
+        if remote::run(host, &format!("zfs list -H -o name {} 2>/dev/null", dataset)).is_ok() {
+            remote::run(host, &format!("{}zfs destroy -r {}", cmd_prefix, dataset)).ok();
+        }
+    }
+
+    remote::run(host, &format!("{}chflags -R noschg {}", cmd_prefix, jpath)).ok();
+    remote::run(host, &format!("{}rm -rf {}", cmd_prefix, jpath)).ok();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::remote::mock::{self, MockExecutor};
+
+    const MANIFEST: &str = "base-dbg.txz\t1111111111111111111111111111111111111111111111111111111111111111\t100\tbase_dbg\t\"Base system (Debugging)\"\toff\n\
+base.txz\tABCDEF0123456789abcdef0123456789abcdef0123456789abcdef0123456789\t26000\tbase\t\"Base system (MANDATORY)\"\ton\n\
+kernel.txz\t2222222222222222222222222222222222222222222222222222222222222222\t900\tkernel\t\"Kernel (MANDATORY)\"\ton\n";
+
+    #[test]
+    fn test_validate_base_version() {
+        assert!(validate_base_version("14.1-RELEASE").is_ok());
+        assert!(validate_base_version("15.0-CURRENT").is_ok());
+        assert!(validate_base_version("").is_err());
+        assert!(validate_base_version("..").is_err());
+        assert!(validate_base_version("14.1/../../etc").is_err());
+        assert!(validate_base_version("14.1; rm -rf /").is_err());
+    }
+
+    #[test]
+    fn test_parse_manifest_sha256() {
+        assert_eq!(
+            parse_manifest_sha256(MANIFEST, "base.txz").as_deref(),
+            Some("abcdef0123456789abcdef0123456789abcdef0123456789abcdef0123456789")
+        );
+        assert_eq!(
+            parse_manifest_sha256(MANIFEST, "kernel.txz").as_deref(),
+            Some("2222222222222222222222222222222222222222222222222222222222222222")
+        );
+    }
+
+    #[test]
+    fn test_parse_manifest_sha256_missing_file() {
+        assert!(parse_manifest_sha256(MANIFEST, "lib32.txz").is_none());
+        assert!(parse_manifest_sha256("", "base.txz").is_none());
+    }
+
+    #[test]
+    fn test_parse_manifest_sha256_rejects_malformed_checksum() {
+        assert!(parse_manifest_sha256("base.txz\tnot-a-checksum\t1\tbase\n", "base.txz").is_none());
+    }
+
+    #[test]
+    fn test_base_in_use() {
+        let mock = MockExecutor::new();
+        mock.respond("mount -p", "3\n");
+        let err = mock::with_executor(mock.clone(), || remove_base("host", "14.1-RELEASE", true)).unwrap_err();
+        assert!(err.to_string().contains("still mounted"));
+        assert!(mock.ran(
+            "mount -p | awk -v b=/usr/local/bsdeploy/base/14.1-RELEASE '$1 == b || index($1, b \"/\") == 1' | wc -l"
+        ));
+        assert!(!mock.ran("rm -rf"));
+
+        let err = mock::with_executor(mock.clone(), || update_base("host", "14.1-RELEASE", true)).unwrap_err();
+        assert!(err.to_string().contains("mounted into running jails"));
+        assert!(!mock.ran("freebsd-update"));
+    }
+
+    #[test]
+    fn test_teardown_destroys_only_the_jail_dataset() {
+        let mock = MockExecutor::new();
+        mock.respond("df /usr/local/bsdeploy/jails", "zroot/bsdeploy/jails\n")
+            .respond("zfs list -H -o name zroot/bsdeploy/jails", "zroot/bsdeploy/jails\n")
+            .respond(
+                "mount | grep",
+                "/usr/local/bsdeploy/jails/app-1/dev\n/usr/local/bsdeploy/jails/app-1/app\n",
+            );
+
+        mock::with_executor(mock.clone(), || teardown("host", "app-1", Some("10.0.0.5"), true)).unwrap();
+
+        assert!(mock.ran("doas jail -r app-1"));
+        assert!(mock.ran("doas ifconfig lo1 inet 10.0.0.5 -alias"));
+        let umounts: Vec<String> = mock.commands().into_iter().filter(|c| c.contains("umount")).collect();
+        assert_eq!(
+            umounts,
+            [
+                "doas umount -f /usr/local/bsdeploy/jails/app-1/app",
+                "doas umount -f /usr/local/bsdeploy/jails/app-1/dev",
+            ]
+        );
+        assert!(mock.ran("doas zfs destroy -r zroot/bsdeploy/jails/app-1"));
+        assert!(!mock.ran("zfs destroy -r zroot/bsdeploy/jails "));
+        assert!(mock.ran("doas rm -rf /usr/local/bsdeploy/jails/app-1"));
+    }
+}
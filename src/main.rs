@@ -1,12 +1,27 @@
-mod caddy;
+mod batch;
+mod ci;
 mod commands;
 mod config;
 mod constants;
+mod doas;
+mod exit;
+mod helper;
+mod housekeeping;
 mod image;
 mod jail;
+mod metrics;
+mod notify;
+mod os;
+mod pf;
+mod preflight;
+mod proxy;
 mod rcd;
 mod remote;
 mod shell;
+#[cfg(feature = "native-ssh")]
+mod ssh_native;
+mod transcript;
+mod tuning;
 mod ui;
 
 use anyhow::Result;
@@ -20,6 +35,30 @@ struct Cli {
     #[arg(short, long, default_value = "config/bsdeploy.yml")]
     config: PathBuf,
 
+    /// Stream remote command output; repeat (-vv) to also print every remote command
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    /// Only print results: successes, warnings and errors
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Write the session transcript to this file instead of ~/.local/state/bsdeploy/
+    #[arg(long, global = true)]
+    log_file: Option<PathBuf>,
+
+    /// Include command output in the transcript
+    #[arg(long, global = true)]
+    log_output: bool,
+
+    /// Progress output: decorated text, or one JSON event per line for CI and log processors
+    #[arg(long, global = true, value_enum, default_value_t)]
+    format: ui::Format,
+
+    /// Print timestamped step lines instead of spinners (the default when stderr is not a terminal)
+    #[arg(long, global = true)]
+    no_progress: bool,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -33,43 +72,215 @@ enum Commands {
         /// Force reconfiguration of PF even if already configured
         #[arg(long)]
         force_pf: bool,
+        /// Install doas and a rule for the SSH user first, getting root by logging in as root or through su
+        #[arg(long, value_enum)]
+        install_doas: Option<doas::RootAccess>,
+        /// Log in as root once to create the deploy user, install the local SSH public keys and doas for it
+        #[arg(long, conflicts_with = "install_doas")]
+        bootstrap: bool,
+        /// Only report what setup would change on each host, and fail if anything
+        #[arg(long, conflicts_with_all = ["force_pf", "install_doas", "bootstrap"])]
+        check: bool,
+        /// Migrate what older bsdeploy releases left on the hosts to the current layout
+        #[arg(long, conflicts_with = "check")]
+        upgrade: bool,
     },
     /// Deploy the application
-    Deploy,
+    Deploy {
+        /// Upload this local base.txz instead of fetching it on the host
+        #[arg(long)]
+        base_tarball: Option<PathBuf>,
+        /// Deploy this pre-built archive (e.g. app.tar.gz) instead of the source tree
+        #[arg(long)]
+        artifact: Option<PathBuf>,
+        /// Regenerate proxy sites edited by hand, dropping the manual changes
+        #[arg(long)]
+        overwrite: bool,
+    },
     /// Show status of jails and services
     Status,
+    /// Check local and host prerequisites and suggest fixes
+    Doctor,
+    /// Show the storage used by bases, images, jails, data and logs, and what could be reclaimed
+    Du,
+    /// Show everything known about a jail and where it differs from its metadata
+    Inspect {
+        /// Jail name, as listed by `status`
+        jail: String,
+        /// Only look on this host (defaults to all configured hosts)
+        #[arg(long)]
+        host: Option<String>,
+    },
+    /// Show the last lines of the service log on every host
+    Logs {
+        /// Show the proxy access log instead (needs proxy.access_log)
+        #[arg(long)]
+        proxy: bool,
+        /// Show the error logs of the start commands (needs logs.split_stderr or an error_log)
+        #[arg(long, conflicts_with = "proxy")]
+        stderr: bool,
+        /// Number of lines to show
+        #[arg(short = 'n', long, default_value_t = 100)]
+        lines: usize,
+        /// Only lines from this time on: an age (30m, 2h, 1d) or a local time (2025-01-31 14:00)
+        #[arg(long)]
+        since: Option<String>,
+        /// Only lines up to this time, in the same formats as --since
+        #[arg(long)]
+        until: Option<String>,
+        /// Only lines matching this extended regular expression
+        #[arg(long)]
+        grep: Option<String>,
+    },
+    /// Watch the active jail and health endpoint on every host and alert on changes
+    Monitor {
+        /// Seconds between checks
+        #[arg(long, default_value_t = 60, value_parser = clap::value_parser!(u64).range(1..))]
+        interval: u64,
+        /// Check once, alert on every unhealthy host and fail if there is one (for cron)
+        #[arg(long)]
+        once: bool,
+    },
     /// Destroy all resources associated with the service on the remote hosts
-    Destroy,
+    Destroy {
+        /// Also delete the proxy logs and disable the proxy if no other site is left
+        #[arg(long)]
+        purge_proxy: bool,
+    },
+    /// Remove bsdeploy from the hosts altogether: all jails, images, datasets and host settings
+    TeardownHost {
+        /// Only this host instead of every configured one
+        #[arg(long)]
+        host: Option<String>,
+        /// Really remove everything; without it the services that would go are listed
+        #[arg(long)]
+        yes: bool,
+    },
+    /// Manage the manually provided TLS certificates (proxy.ssl)
+    Certs {
+        #[command(subcommand)]
+        action: commands::CertsAction,
+    },
+    /// Manage built jail images
+    Image {
+        #[command(subcommand)]
+        action: commands::ImageAction,
+    },
+    /// Manage installed FreeBSD base systems
+    Base {
+        #[command(subcommand)]
+        action: commands::BaseAction,
+    },
+}
+
+impl Commands {
+    /// Subcommand name, used in transcript file names
+    fn name(&self) -> &'static str {
+        match self {
+            Commands::Init => "init",
+            Commands::Setup { .. } => "setup",
+            Commands::Deploy { .. } => "deploy",
+            Commands::Status => "status",
+            Commands::Doctor => "doctor",
+            Commands::Du => "du",
+            Commands::Inspect { .. } => "inspect",
+            Commands::Logs { .. } => "logs",
+            Commands::Monitor { .. } => "monitor",
+            Commands::Destroy { .. } => "destroy",
+            Commands::TeardownHost { .. } => "teardown-host",
+            Commands::Certs { .. } => "certs",
+            Commands::Image { .. } => "image",
+            Commands::Base { .. } => "base",
+        }
+    }
 }
 
 fn main() -> Result<()> {
     env_logger::init();
     let cli = Cli::parse();
+    ui::set_verbosity(cli.quiet, cli.verbose);
+    ui::set_format(cli.format);
+    ui::set_plain(cli.no_progress);
 
     match cli.command {
         Commands::Init => {
             commands::init(&cli.config)?;
         }
-        Commands::Setup { .. } | Commands::Deploy | Commands::Status | Commands::Destroy => {
+        Commands::Setup { .. }
+        | Commands::Deploy { .. }
+        | Commands::Status
+        | Commands::Doctor
+        | Commands::Du
+        | Commands::Inspect { .. }
+        | Commands::Logs { .. }
+        | Commands::Monitor { .. }
+        | Commands::Destroy { .. }
+        | Commands::TeardownHost { .. }
+        | Commands::Certs { .. }
+        | Commands::Image { .. }
+        | Commands::Base { .. } => {
             let config = match config::Config::load(&cli.config) {
                 Ok(c) => c,
                 Err(e) => {
                     ui::print_error(&format!("Error loading configuration: {}", e));
-                    std::process::exit(1);
+                    std::process::exit(exit::ExitCode::Config as i32);
                 }
             };
 
+            ui::set_context(cli.command.name(), &config.hosts);
             ui::print_step(&format!(
                 "Loaded configuration for service: {}",
                 config.service
             ));
+            remote::configure(&config.ssh)?;
+            shell::set_privilege_escalation(config.escalation());
+            helper::set_enabled(config.helper);
 
-            match cli.command {
-                Commands::Setup { force_pf } => commands::setup(&config, force_pf)?,
-                Commands::Deploy => commands::deploy(&config)?,
-                Commands::Status => commands::status(&config)?,
-                Commands::Destroy => commands::destroy(&config)?,
+            // The transcript is a debugging aid; never fail the run over it
+            if let Err(e) = transcript::start(cli.log_file.as_deref(), cli.log_output, cli.command.name()) {
+                log::warn!("Not writing a transcript: {:#}", e);
+            }
+
+            let result = match cli.command {
+                Commands::Setup { force_pf, install_doas, bootstrap, check, upgrade } => {
+                    commands::setup(&config, force_pf, install_doas, bootstrap, check, upgrade)
+                }
+                Commands::Deploy { base_tarball, artifact, overwrite } => {
+                    commands::deploy(&config, base_tarball.as_deref(), artifact.as_deref(), overwrite)
+                }
+                Commands::Status => commands::status(&config),
+                Commands::Doctor => commands::doctor(&config),
+                Commands::Du => commands::du(&config),
+                Commands::Inspect { jail, host } => commands::inspect(&config, &jail, host),
+                Commands::Logs { proxy, stderr, lines, since, until, grep } => {
+                    commands::LogFilter::new(lines, since.as_deref(), until.as_deref(), grep)
+                        .and_then(|filter| commands::logs(&config, proxy, stderr, &filter))
+                }
+                Commands::Monitor { interval, once } => commands::monitor(&config, interval, once),
+                Commands::Destroy { purge_proxy } => commands::destroy(&config, purge_proxy),
+                Commands::TeardownHost { host, yes } => commands::teardown_host(&config, host.as_deref(), yes),
+                Commands::Certs { action } => commands::certs(&config, action),
+                Commands::Image { action } => commands::image(&config, action),
+                Commands::Base { action } => commands::base(&config, action),
                 Commands::Init => unreachable!(),
+            };
+            if let Err(e) = &result {
+                if let Some(hint) = remote::hint(e) {
+                    ui::print_hint(&hint);
+                }
+                if ui::is_verbose()
+                    && let Some(failure) = e.chain().find_map(|c| c.downcast_ref::<remote::CommandError>())
+                {
+                    ui::print_error(&failure.detail());
+                }
+                if let Some(path) = transcript::path() {
+                    ui::print_error(&format!("Transcript: {}", path.display()));
+                }
+            }
+            if let Err(e) = result {
+                ui::annotate("error", &format!("{:#}", e));
+                eprintln!("Error: {:?}", e);
+                std::process::exit(exit::for_error(&e) as i32);
             }
         }
     }
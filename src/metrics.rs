@@ -0,0 +1,171 @@
+//! Deploy metrics (`metrics`): total duration, time spent per phase,
+//! outcome and image cache hits, sent to statsd and/or a Prometheus
+//! pushgateway once the deploy is over. Sending is best effort and never
+//! fails the deploy.
+
+use anyhow::{Context, Result};
+use std::net::UdpSocket;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::config::MetricsConfig;
+use crate::{notify, ui};
+
+/// Timings and counters collected during one deploy
+pub struct DeployMetrics {
+    started: Instant,
+    /// Time per phase, in the order the phases first ran
+    phases: Mutex<Vec<(&'static str, Duration)>>,
+    image_hits: AtomicUsize,
+    image_misses: AtomicUsize,
+}
+
+impl DeployMetrics {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            phases: Mutex::new(Vec::new()),
+            image_hits: AtomicUsize::new(0),
+            image_misses: AtomicUsize::new(0),
+        }
+    }
+
+    /// Run `f`, adding its duration to `phase`.
+    pub fn phase<T>(&self, phase: &'static str, f: impl FnOnce() -> T) -> T {
+        let started = Instant::now();
+        let result = f();
+        self.add_phase(phase, started.elapsed());
+        result
+    }
+
+    fn add_phase(&self, phase: &'static str, elapsed: Duration) {
+        let mut phases = self.phases.lock().unwrap_or_else(|e| e.into_inner());
+        match phases.iter_mut().find(|(name, _)| *name == phase) {
+            Some((_, total)) => *total += elapsed,
+            None => phases.push((phase, elapsed)),
+        }
+    }
+
+    /// Count an image that was found on the host or in the registry
+    /// (hit), or had to be built (miss).
+    pub fn image(&self, built: bool) {
+        let counter = if built { &self.image_misses } else { &self.image_hits };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn phases(&self) -> Vec<(&'static str, Duration)> {
+        self.phases.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// statsd lines: timers in milliseconds, counters for the outcome and
+    /// the images.
+    fn statsd_payload(&self, prefix: &str, service: &str, success: bool, total: Duration) -> String {
+        let name = format!("{}.{}.deploy", prefix, service);
+        let mut lines = vec![format!("{}.duration:{}|ms", name, total.as_millis())];
+        for (phase, elapsed) in self.phases() {
+            lines.push(format!("{}.phase.{}:{}|ms", name, phase, elapsed.as_millis()));
+        }
+        lines.push(format!("{}.{}:1|c", name, if success { "success" } else { "failure" }));
+        lines.push(format!("{}.image_cache.hit:{}|c", name, self.image_hits.load(Ordering::Relaxed)));
+        lines.push(format!("{}.image_cache.miss:{}|c", name, self.image_misses.load(Ordering::Relaxed)));
+        lines.join("\n")
+    }
+
+    /// Prometheus text exposition of the last deploy, as gauges.
+    fn prometheus_payload(&self, prefix: &str, success: bool, total: Duration, finished: u64) -> String {
+        let gauge = |out: &mut String, name: &str, help: &str, samples: &[(String, String)]| {
+            out.push_str(&format!("# HELP {}_{} {}\n# TYPE {}_{} gauge\n", prefix, name, help, prefix, name));
+            for (labels, value) in samples {
+                out.push_str(&format!("{}_{}{} {}\n", prefix, name, labels, value));
+            }
+        };
+        let secs = |d: Duration| format!("{:.3}", d.as_secs_f64());
+        let mut out = String::new();
+        gauge(&mut out, "deploy_duration_seconds", "Duration of the last deploy", &[(String::new(), secs(total))]);
+        let phases: Vec<(String, String)> = self
+            .phases()
+            .into_iter()
+            .map(|(phase, elapsed)| (format!("{{phase=\"{}\"}}", phase), secs(elapsed)))
+            .collect();
+        gauge(&mut out, "deploy_phase_duration_seconds", "Time spent per phase of the last deploy", &phases);
+        gauge(&mut out, "deploy_success", "Whether the last deploy succeeded", &[(String::new(), (success as u8).to_string())]);
+        gauge(
+            &mut out,
+            "deploy_image_cache_hits",
+            "Images of the last deploy found on the host or in the registry",
+            &[(String::new(), self.image_hits.load(Ordering::Relaxed).to_string())],
+        );
+        gauge(
+            &mut out,
+            "deploy_image_cache_misses",
+            "Images built by the last deploy",
+            &[(String::new(), self.image_misses.load(Ordering::Relaxed).to_string())],
+        );
+        gauge(&mut out, "deploy_finished_timestamp_seconds", "When the last deploy finished", &[(String::new(), finished.to_string())]);
+        out
+    }
+
+    /// Send the metrics of the deploy that just ended; failures are
+    /// reported as warnings.
+    pub fn send(&self, metrics: &MetricsConfig, service: &str, success: bool) {
+        let total = self.started.elapsed();
+        if let Some(statsd) = &metrics.statsd
+            && let Err(e) = send_statsd(statsd, &self.statsd_payload(&metrics.prefix, service, success, total))
+        {
+            ui::print_warning(&format!("Could not send deploy metrics to {}: {:#}", statsd, e));
+        }
+        if let Some(url) = &metrics.pushgateway {
+            let finished = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+            let payload = self.prometheus_payload(&metrics.prefix, success, total, finished);
+            if let Err(e) = push_gateway(url, &metrics.prefix, service, &payload) {
+                ui::print_warning(&format!("Could not push deploy metrics to {}: {:#}", url, e));
+            }
+        }
+    }
+}
+
+fn send_statsd(address: &str, payload: &str) -> Result<()> {
+    let socket = UdpSocket::bind("0.0.0.0:0").context("Failed to open a UDP socket")?;
+    socket.send_to(payload.as_bytes(), address).with_context(|| format!("Failed to send to {}", address))?;
+    Ok(())
+}
+
+/// Replace the service's group on the pushgateway with `payload`.
+fn push_gateway(url: &str, job: &str, service: &str, payload: &str) -> Result<()> {
+    let url = format!("{}/metrics/job/{}/service/{}", url.trim_end_matches('/'), job, service);
+    notify::curl_send("PUT", &url, &[], payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_payloads() {
+        let metrics = DeployMetrics::new();
+        metrics.add_phase("images", Duration::from_millis(1500));
+        metrics.add_phase("switch", Duration::from_millis(200));
+        metrics.add_phase("switch", Duration::from_millis(300));
+        metrics.image(false);
+        metrics.image(true);
+        metrics.image(false);
+
+        assert_eq!(
+            metrics.statsd_payload("bsdeploy", "app", true, Duration::from_secs(3)),
+            "bsdeploy.app.deploy.duration:3000|ms\n\
+             bsdeploy.app.deploy.phase.images:1500|ms\n\
+             bsdeploy.app.deploy.phase.switch:500|ms\n\
+             bsdeploy.app.deploy.success:1|c\n\
+             bsdeploy.app.deploy.image_cache.hit:2|c\n\
+             bsdeploy.app.deploy.image_cache.miss:1|c"
+        );
+
+        let text = metrics.prometheus_payload("bsdeploy", false, Duration::from_secs(3), 1_700_000_000);
+        assert!(text.contains("# TYPE bsdeploy_deploy_duration_seconds gauge\nbsdeploy_deploy_duration_seconds 3.000\n"));
+        assert!(text.contains("bsdeploy_deploy_phase_duration_seconds{phase=\"switch\"} 0.500\n"));
+        assert!(text.contains("bsdeploy_deploy_success 0\n"));
+        assert!(text.contains("bsdeploy_deploy_image_cache_misses 1\n"));
+        assert!(text.contains("bsdeploy_deploy_finished_timestamp_seconds 1700000000\n"));
+    }
+}
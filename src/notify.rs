@@ -0,0 +1,103 @@
+//! Alerts (`notify`): a JSON document posted to a webhook and/or a message
+//! to a Slack incoming webhook, sent with the local curl (`curl_send`,
+//! also used by `metrics`). Sending is best effort; failures are reported
+//! as warnings.
+
+use anyhow::{Context, Result, bail};
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+use crate::config::NotifyConfig;
+use crate::ui;
+
+/// A change of the service's state on one host
+pub struct Alert<'a> {
+    pub service: &'a str,
+    pub host: &'a str,
+    /// Short state name, e.g. `healthy` or `down`
+    pub state: &'a str,
+    pub message: &'a str,
+}
+
+impl Alert<'_> {
+    fn webhook_payload(&self, timestamp: &str) -> String {
+        serde_json::json!({
+            "service": self.service,
+            "host": self.host,
+            "state": self.state,
+            "message": self.message,
+            "timestamp": timestamp,
+        })
+        .to_string()
+    }
+
+    fn slack_payload(&self) -> String {
+        serde_json::json!({ "text": format!("*{}* on {}: {}", self.service, self.host, self.message) }).to_string()
+    }
+}
+
+pub fn send(notify: &NotifyConfig, alert: &Alert) {
+    if let Some(url) = &notify.webhook
+        && let Err(e) = curl_send("POST", url, &[JSON], &alert.webhook_payload(&chrono::Local::now().to_rfc3339()))
+    {
+        ui::print_warning(&format!("Could not send the alert to {}: {:#}", url, e));
+    }
+    if let Some(url) = &notify.slack
+        && let Err(e) = curl_send("POST", url, &[JSON], &alert.slack_payload())
+    {
+        ui::print_warning(&format!("Could not send the alert to Slack: {:#}", e));
+    }
+}
+
+const JSON: &str = "Content-Type: application/json";
+
+fn curl_args(method: &str, url: &str, headers: &[&str]) -> Vec<String> {
+    let mut args: Vec<String> = ["-fsS", "--max-time", "10", "-X", method].iter().map(|a| a.to_string()).collect();
+    for header in headers {
+        args.push("-H".to_string());
+        args.push(header.to_string());
+    }
+    args.extend(["--data-binary".to_string(), "@-".to_string(), url.to_string()]);
+    args
+}
+
+/// Send `payload` to `url` with the local curl, failing with curl's error
+/// on connection problems and HTTP errors.
+pub fn curl_send(method: &str, url: &str, headers: &[&str], payload: &str) -> Result<()> {
+    let mut child = Command::new("curl")
+        .args(curl_args(method, url, headers))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("Failed to run curl")?;
+    child.stdin.take().context("curl has no stdin")?.write_all(payload.as_bytes())?;
+    let output = child.wait_with_output().context("Failed to wait for curl")?;
+    if !output.status.success() {
+        bail!("{}", String::from_utf8_lossy(&output.stderr).trim());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_payloads() {
+        let alert = Alert { service: "app", host: "web1", state: "down", message: "jail app-20260101-120000 is not running" };
+        assert_eq!(
+            alert.webhook_payload("2026-01-01T12:00:00+00:00"),
+            r#"{"host":"web1","message":"jail app-20260101-120000 is not running","service":"app","state":"down","timestamp":"2026-01-01T12:00:00+00:00"}"#
+        );
+        assert_eq!(alert.slack_payload(), r#"{"text":"*app* on web1: jail app-20260101-120000 is not running"}"#);
+    }
+
+    #[test]
+    fn test_curl_args() {
+        assert_eq!(
+            curl_args("POST", "https://hooks.example.com/x", &[JSON]),
+            ["-fsS", "--max-time", "10", "-X", "POST", "-H", "Content-Type: application/json", "--data-binary", "@-", "https://hooks.example.com/x"]
+        );
+    }
+}
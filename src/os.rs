@@ -0,0 +1,162 @@
+//! The operating system of a host: FreeBSD or HardenedBSD, its release
+//! and architecture. bsdeploy supports the releases it is tested on and
+//! refuses others before changing anything, and picks the download
+//! location of base.txz to match.
+
+use anyhow::{Context, Result, bail};
+
+use crate::remote;
+
+/// Major releases bsdeploy supports
+pub const SUPPORTED_MAJORS: [u32; 3] = [13, 14, 15];
+
+/// Jail parameters bsdeploy creates jails with; a kernel without them
+/// cannot run its jails
+pub const JAIL_PARAMS: &[&str] = &["persist", "host.hostname", "ip4.addr", "allow.raw_sockets"];
+
+/// Marker of the line `COMMAND` prints
+const MARKER: &str = "OS:";
+
+/// Shell line printing the host's system, release, machine, processor and
+/// HardenedBSD version (`-` on FreeBSD)
+pub const COMMAND: &str =
+    "echo \"OS: $(uname -s) $(uname -r) $(uname -m) $(uname -p) $(sysctl -n hardening.version 2>/dev/null || echo -)\"\n";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flavor {
+    FreeBsd,
+    HardenedBsd,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HostOs {
+    /// `uname -s`
+    pub system: String,
+    /// `uname -r`, e.g. `14.2-RELEASE-p1`
+    pub release: String,
+    /// `uname -m`, e.g. `amd64` or `arm64`
+    pub machine: String,
+    /// `uname -p`, e.g. `amd64` or `aarch64`
+    pub processor: String,
+    pub flavor: Flavor,
+}
+
+impl HostOs {
+    /// Parse the line `COMMAND` printed in `output`.
+    pub fn from_output(output: &str) -> Option<Self> {
+        let line = output.lines().find_map(|l| l.strip_prefix(MARKER))?;
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let [system, release, machine, processor, hardening] = fields.as_slice() else {
+            return None;
+        };
+        Some(Self {
+            system: system.to_string(),
+            release: release.to_string(),
+            machine: machine.to_string(),
+            processor: processor.to_string(),
+            flavor: if *hardening == "-" { Flavor::FreeBsd } else { Flavor::HardenedBsd },
+        })
+    }
+
+    pub fn major(&self) -> Option<u32> {
+        major(&self.release)
+    }
+
+    /// e.g. `FreeBSD 14.2-RELEASE-p1 (amd64)`
+    pub fn describe(&self) -> String {
+        let name = match self.flavor {
+            _ if self.system != "FreeBSD" => self.system.as_str(),
+            Flavor::FreeBsd => "FreeBSD",
+            Flavor::HardenedBsd => "HardenedBSD",
+        };
+        format!("{} {} ({})", name, self.release, self.machine)
+    }
+
+    /// Fail for systems bsdeploy does not support.
+    pub fn check(&self) -> Result<()> {
+        let majors = SUPPORTED_MAJORS.map(|m| m.to_string());
+        let supported = format!("{} or {}", majors[..majors.len() - 1].join(", "), majors[majors.len() - 1]);
+        if self.system != "FreeBSD" {
+            bail!("{} is not supported; bsdeploy needs FreeBSD or HardenedBSD {}", self.describe(), supported);
+        }
+        match self.major() {
+            Some(major) if SUPPORTED_MAJORS.contains(&major) => Ok(()),
+            _ => bail!("{} is not supported; bsdeploy needs release {}", self.describe(), supported),
+        }
+    }
+
+    /// Directory holding base.txz and its MANIFEST for `version` (e.g.
+    /// `14.1-RELEASE`). FreeBSD keeps STABLE and CURRENT builds under
+    /// snapshots/; HardenedBSD publishes the latest build of each branch.
+    pub fn release_url(&self, version: &str) -> String {
+        let arch = if self.machine == self.processor {
+            self.machine.clone()
+        } else {
+            format!("{}/{}", self.machine, self.processor)
+        };
+        match self.flavor {
+            Flavor::FreeBsd => {
+                let tree = if version.contains("-STABLE") || version.contains("-CURRENT") || version.contains("-PRERELEASE") {
+                    "snapshots"
+                } else {
+                    "releases"
+                };
+                format!("https://download.freebsd.org/ftp/{}/{}/{}", tree, arch, version)
+            }
+            Flavor::HardenedBsd => {
+                let branch = match major(version) {
+                    Some(major) if !version.contains("-CURRENT") => format!("{}-stable", major),
+                    _ => "current".to_string(),
+                };
+                format!("https://installers.hardenedbsd.org/pub/{}/{}/{}/BUILD-LATEST", branch, self.machine, self.processor)
+            }
+        }
+    }
+}
+
+/// Look up the operating system of `host`.
+pub fn detect(host: &str) -> Result<HostOs> {
+    let output = remote::run_with_output_timeout(host, COMMAND, remote::Timeout::Query)?;
+    HostOs::from_output(&output).with_context(|| format!("Could not tell the operating system of {}", host))
+}
+
+fn major(release: &str) -> Option<u32> {
+    release.split(['.', '-']).next()?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_host_os() {
+        let os = HostOs::from_output("noise\nOS: FreeBSD 14.2-RELEASE-p1 amd64 amd64 -\n").unwrap();
+        assert_eq!((os.flavor, os.major()), (Flavor::FreeBsd, Some(14)));
+        assert!(os.check().is_ok());
+        assert_eq!(os.describe(), "FreeBSD 14.2-RELEASE-p1 (amd64)");
+        assert_eq!(os.release_url("14.1-RELEASE"), "https://download.freebsd.org/ftp/releases/amd64/14.1-RELEASE");
+        assert_eq!(os.release_url("15.0-CURRENT"), "https://download.freebsd.org/ftp/snapshots/amd64/15.0-CURRENT");
+
+        let os = HostOs::from_output("OS: FreeBSD 13.4-RELEASE arm64 aarch64 -\n").unwrap();
+        assert_eq!(
+            os.release_url("13.4-RELEASE"),
+            "https://download.freebsd.org/ftp/releases/arm64/aarch64/13.4-RELEASE"
+        );
+
+        let os = HostOs::from_output("OS: FreeBSD 14.1-STABLE amd64 amd64 1300\n").unwrap();
+        assert_eq!(os.describe(), "HardenedBSD 14.1-STABLE (amd64)");
+        assert_eq!(
+            os.release_url("14.1-STABLE"),
+            "https://installers.hardenedbsd.org/pub/14-stable/amd64/amd64/BUILD-LATEST"
+        );
+
+        let os = HostOs::from_output("OS: FreeBSD 12.4-RELEASE amd64 amd64 -\n").unwrap();
+        assert_eq!(
+            os.check().unwrap_err().to_string(),
+            "FreeBSD 12.4-RELEASE (amd64) is not supported; bsdeploy needs release 13, 14 or 15"
+        );
+        let os = HostOs::from_output("OS: Linux 6.1.0-18-amd64 x86_64 unknown -\n").unwrap();
+        assert!(os.check().unwrap_err().to_string().starts_with("Linux 6.1.0-18-amd64 (x86_64) is not supported"));
+        assert!(HostOs::from_output("OS: FreeBSD\n").is_none());
+    }
+}
@@ -0,0 +1,308 @@
+//! PF on the hosts: the external interface, the NAT for the jail network
+//! and the redirects that publish the ports of a service without a proxy
+//! (`bind`). bsdeploy only writes rules into anchors under `bsdeploy/`;
+//! pf.conf gets a marked block of hooks evaluating them, and nothing else.
+//! Each service's redirects live in their own anchor, and are also kept in
+//! the service's config directory for the rc.d script to load at boot.
+
+use anyhow::{Context, Result, anyhow};
+
+use crate::config::{BindConfig, Config};
+use crate::constants::CONFIG_DIR;
+use crate::{remote, shell};
+
+/// Anchor with the NAT of the jail network. Service names cannot contain
+/// `_`, so host-wide anchors never clash with a service's.
+const NAT_ANCHOR: &str = "bsdeploy/_nat";
+
+/// Anchor with the inbound filter of `host.firewall`
+pub const FIREWALL_ANCHOR: &str = "bsdeploy/_firewall";
+
+const HOOKS_BEGIN: &str = "# BEGIN bsdeploy";
+const HOOKS_END: &str = "# END bsdeploy";
+
+/// First line of the section older versions of `setup` wrote to pf.conf
+const LEGACY_MARKER: &str = "# PF configuration for bsdeploy jails";
+
+/// pf.conf rules evaluating the bsdeploy anchors
+pub const HOOK_RULES: [&str; 3] = ["nat-anchor \"bsdeploy/*\"", "rdr-anchor \"bsdeploy/*\"", "anchor \"bsdeploy/*\""];
+
+pub fn nat_rules_path() -> String {
+    format!("{}/pf.nat", CONFIG_DIR)
+}
+
+/// The block `setup` adds to pf.conf. It also loads the NAT rules when
+/// pf.conf is loaded, e.g. at boot.
+pub fn hooks() -> String {
+    format!(
+        "{}\n# Managed by bsdeploy setup; the rules are in anchors below bsdeploy/\n{}\nload anchor \"{}\" from \"{}\"\n{}\n",
+        HOOKS_BEGIN,
+        HOOK_RULES.join("\n"),
+        NAT_ANCHOR,
+        nat_rules_path(),
+        HOOKS_END
+    )
+}
+
+/// Whether pf.conf evaluates the bsdeploy anchors, through the block
+/// `setup` adds or rules the operator wrote.
+pub fn has_hooks(conf: &str) -> bool {
+    HOOK_RULES.iter().all(|rule| conf.lines().any(|l| l.trim() == *rule))
+}
+
+/// Whether pf.conf was written by bsdeploy, so `setup` may update it.
+pub fn is_managed(conf: &str) -> bool {
+    conf.lines().any(|l| l == HOOKS_BEGIN || l == LEGACY_MARKER)
+}
+
+/// `conf` with the hooks block inserted before the first filter rule,
+/// as pf.conf wants translation rules first. A previous block and the
+/// section older versions wrote are replaced.
+pub fn with_hooks(conf: &str) -> String {
+    let legacy = conf.lines().any(|l| l == LEGACY_MARKER);
+    let mut lines = Vec::new();
+    let mut in_block = false;
+    for line in conf.lines() {
+        match line {
+            HOOKS_BEGIN => in_block = true,
+            HOOKS_END => in_block = false,
+            _ if in_block => {}
+            LEGACY_MARKER | "# Generated by bsdeploy setup" | "# NAT for jail network" if legacy => {}
+            "nat on $ext_if from $jail_net to any -> ($ext_if)" | "rdr-anchor \"bsdeploy/*\"" if legacy => {}
+            _ if legacy && (line.starts_with("ext_if = ") || line.starts_with("jail_net = ")) => {}
+            _ => lines.push(line),
+        }
+    }
+    while lines.first().is_some_and(|l| l.trim().is_empty()) {
+        lines.remove(0);
+    }
+
+    let is_filter = |l: &&str| {
+        matches!(l.split_whitespace().next(), Some("pass" | "block" | "anchor" | "antispoof" | "match"))
+    };
+    let at = lines.iter().position(is_filter).unwrap_or(lines.len());
+    let mut out = String::new();
+    for line in &lines[..at] {
+        out.push_str(line);
+        out.push('\n');
+    }
+    out.push_str(&hooks());
+    for line in &lines[at..] {
+        out.push_str(line);
+        out.push('\n');
+    }
+    out
+}
+
+pub fn firewall_rules_path() -> String {
+    format!("{}/pf.firewall", CONFIG_DIR)
+}
+
+/// Inbound filter on `interface` letting through SSH on `ssh_port`, HTTP,
+/// HTTPS, `open_ports` and the ICMP needed for path MTU discovery and
+/// IPv6, and blocking everything else. The rules are `quick`, so a later
+/// `pass all` in pf.conf does not open the host again. Published ports
+/// are redirected by `rdr pass`, which skips the filter.
+fn firewall_rules(interface: &str, ssh_port: u16, open_ports: &[u16]) -> String {
+    let mut ports = vec![ssh_port, 80, 443];
+    ports.extend(open_ports);
+    ports.sort_unstable();
+    ports.dedup();
+    let ports: Vec<String> = ports.iter().map(u16::to_string).collect();
+    format!(
+        "pass in quick on {i} proto tcp from any to ({i}) port {{ {} }} keep state\n\
+         pass in quick on {i} inet proto icmp icmp-type {{ echoreq, unreach }} keep state\n\
+         pass in quick on {i} inet6 proto icmp6 all keep state\n\
+         block in quick on {i} all\n",
+        ports.join(", "),
+        i = interface
+    )
+}
+
+/// Load the firewall of `host.firewall` into its anchor, or flush and
+/// remove it when the firewall is off. The SSH port is the one this
+/// connection came in on, so setup cannot lock itself out.
+pub fn apply_firewall(config: &Config, host: &str, interface: &str) -> Result<()> {
+    let p = shell::escalation_prefix(config.doas);
+    let path = firewall_rules_path();
+    if !config.host.firewall {
+        return remote::run(host, &format!("{p}pfctl -a {} -F rules 2>/dev/null; {p}rm -f {}", FIREWALL_ANCHOR, path, p = p));
+    }
+    let connection = remote::query(host, "echo \"$SSH_CONNECTION\"")?;
+    let ssh_port = connection.split_whitespace().nth(3).and_then(|port| port.parse().ok()).unwrap_or(22);
+    // Caddy's own ports, when moved off 80 and 443
+    let mut open_ports = config.host.open_ports.clone();
+    if let Some(proxy) = &config.proxy {
+        let (http, https) = proxy.listen_ports();
+        open_ports.extend([http, https]);
+    }
+    remote::ensure_dir(host, CONFIG_DIR, config.doas)?;
+    remote::write_file(host, &firewall_rules(interface, ssh_port, &open_ports), &path, config.doas)?;
+    remote::run(host, &format!("{}pfctl -a {} -f {}", p, FIREWALL_ANCHOR, path)).context("Failed to load the firewall rules")
+}
+
+/// Root script flushing every bsdeploy anchor and removing the hooks block
+/// from pf.conf, for `teardown-host`. The rest of pf.conf is kept, and
+/// the new file is checked before it replaces the old one.
+pub fn teardown_script() -> String {
+    format!(
+        r#"for a in $(pfctl -a bsdeploy -s Anchors 2>/dev/null); do pfctl -a "$a" -F all 2>/dev/null; done
+if grep -qx '{begin}' /etc/pf.conf 2>/dev/null; then
+    t=$(mktemp)
+    sed '/^{begin}$/,/^{end}$/d' /etc/pf.conf > "$t"
+    if pfctl -nf "$t"; then install -m 600 "$t" /etc/pf.conf && pfctl -f /etc/pf.conf; else echo "kept /etc/pf.conf: it does not load without the bsdeploy block" >&2; fi
+    rm -f "$t"
+fi
+"#,
+        begin = HOOKS_BEGIN,
+        end = HOOKS_END
+    )
+}
+
+/// Load the NAT of `jail_net` out of `interface` into its anchor.
+pub fn load_nat(config: &Config, host: &str, interface: &str, jail_net: &str) -> Result<()> {
+    let path = nat_rules_path();
+    let rules = format!("nat on {} from {} to any -> ({})\n", interface, jail_net, interface);
+    remote::ensure_dir(host, CONFIG_DIR, config.doas)?;
+    remote::write_file(host, &rules, &path, config.doas)?;
+    remote::run(host, &format!("{}pfctl -a {} -f {}", shell::escalation_prefix(config.doas), NAT_ANCHOR, path))
+        .context("Failed to load the NAT rules for the jail network")
+}
+
+/// Interface of the host's default route.
+pub fn external_interface(host: &str) -> Result<String> {
+    let output = remote::query(
+        host,
+        "route -n get default 2>/dev/null | grep 'interface:' | awk '{print $2}'",
+    )?;
+
+    let iface = output.trim().to_string();
+    if iface.is_empty() {
+        return Err(anyhow!(
+            "Could not detect external interface on {}. No default route found.",
+            host
+        ));
+    }
+
+    Ok(iface)
+}
+
+fn anchor(service: &str) -> String {
+    format!("bsdeploy/{}", service)
+}
+
+pub fn rules_path(service: &str) -> String {
+    format!("{}/{}/pf.rdr", CONFIG_DIR, service)
+}
+
+/// Rules redirecting the bound ports on `interface` to `jail_ip`.
+fn rdr_rules(bind: &BindConfig, interface: &str, jail_ip: &str) -> String {
+    let to = bind.address.clone().unwrap_or_else(|| format!("({})", interface));
+    let mut rules = String::new();
+    for (proto, ports) in [("tcp", &bind.ports), ("udp", &bind.udp_ports)] {
+        if ports.is_empty() {
+            continue;
+        }
+        let ports: Vec<String> = ports.iter().map(u16::to_string).collect();
+        rules.push_str(&format!(
+            "rdr pass on {} inet proto {} from any to {} port {{ {} }} -> {}\n",
+            interface,
+            proto,
+            to,
+            ports.join(", "),
+            jail_ip
+        ));
+    }
+    rules
+}
+
+/// Point the service's redirects at the jail at `jail_ip`. Connections
+/// already established keep going to the previous jail.
+pub fn switch(config: &Config, bind: &BindConfig, host: &str, jail_ip: &str) -> Result<()> {
+    let interface = match &bind.interface {
+        Some(interface) => interface.clone(),
+        None => external_interface(host)?,
+    };
+    let path = rules_path(&config.service);
+    remote::ensure_dir(host, &format!("{}/{}", CONFIG_DIR, config.service), config.doas)?;
+    remote::write_file(host, &rdr_rules(bind, &interface, jail_ip), &path, config.doas)?;
+    remote::run(
+        host,
+        &format!("{}pfctl -a {} -f {}", shell::escalation_prefix(config.doas), anchor(&config.service), path),
+    )
+    .with_context(|| format!("Failed to load the PF redirects for {}", config.service))
+}
+
+/// Drop the service's redirects (best effort).
+pub fn remove(config: &Config, host: &str) {
+    let p = shell::escalation_prefix(config.doas);
+    remote::run(
+        host,
+        &format!("{p}pfctl -a {} -F all 2>/dev/null; {p}rm -f {}", anchor(&config.service), rules_path(&config.service), p = p),
+    )
+    .ok();
+}
+
+/// Jail address the service's ports are redirected to, if any.
+pub fn current_target(config: &Config, host: &str) -> Result<Option<String>> {
+    let rules = remote::query(host, &format!("cat {} 2>/dev/null || true", rules_path(&config.service)))?;
+    Ok(rules
+        .lines()
+        .find_map(|l| l.rsplit_once(" -> "))
+        .map(|(_, ip)| ip.trim().to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_with_hooks() {
+        let conf = with_hooks("");
+        assert!(conf.starts_with("# BEGIN bsdeploy\n"));
+        assert!(has_hooks(&conf) && is_managed(&conf));
+        assert!(conf.contains("load anchor \"bsdeploy/_nat\" from \"/usr/local/etc/bsdeploy/pf.nat\"\n# END bsdeploy\n"));
+        // Running it again replaces the block
+        assert_eq!(with_hooks(&format!("{}pass all\n", conf)), format!("{}pass all\n", conf));
+
+        // Operator rules keep their place, the hooks go before filtering
+        let operator = "set skip on lo0\nnat on em0 from 192.168.0.0/24 to any -> (em0)\nblock in all\npass out all\n";
+        let conf = with_hooks(operator);
+        assert!(!is_managed(operator) && !has_hooks(operator));
+        assert!(conf.starts_with("set skip on lo0\nnat on em0 from 192.168.0.0/24 to any -> (em0)\n# BEGIN bsdeploy\n"));
+        assert!(conf.ends_with("# END bsdeploy\nblock in all\npass out all\n"));
+
+        // The section written by older versions is dropped
+        let legacy = "# PF configuration for bsdeploy jails\n# Generated by bsdeploy setup\n\next_if = \"vtnet0\"\njail_net = \"10.0.0.0/24\"\n\n\
+                      # NAT for jail network\nnat on $ext_if from $jail_net to any -> ($ext_if)\nrdr-anchor \"bsdeploy/*\"\n\npass all\n";
+        assert!(is_managed(legacy));
+        assert_eq!(with_hooks(legacy), format!("{}pass all\n", hooks()));
+    }
+
+    #[test]
+    fn test_firewall_rules() {
+        assert_eq!(
+            firewall_rules("vtnet0", 2222, &[9100, 443]),
+            "pass in quick on vtnet0 proto tcp from any to (vtnet0) port { 80, 443, 2222, 9100 } keep state\n\
+             pass in quick on vtnet0 inet proto icmp icmp-type { echoreq, unreach } keep state\n\
+             pass in quick on vtnet0 inet6 proto icmp6 all keep state\n\
+             block in quick on vtnet0 all\n"
+        );
+    }
+
+    #[test]
+    fn test_rdr_rules() {
+        let config = Config::from_str("service: mail\nhosts: [a]\nbind:\n  ports: [25, 587]\n  udp_ports: [53]\n").unwrap();
+        let bind = config.bind.unwrap();
+        assert_eq!(
+            rdr_rules(&bind, "vtnet0", "10.0.0.5"),
+            "rdr pass on vtnet0 inet proto tcp from any to (vtnet0) port { 25, 587 } -> 10.0.0.5\n\
+             rdr pass on vtnet0 inet proto udp from any to (vtnet0) port { 53 } -> 10.0.0.5\n"
+        );
+        let bind = BindConfig { address: Some("192.0.2.10".to_string()), udp_ports: vec![], ..bind };
+        assert_eq!(
+            rdr_rules(&bind, "vtnet0", "10.0.0.5"),
+            "rdr pass on vtnet0 inet proto tcp from any to 192.0.2.10 port { 25, 587 } -> 10.0.0.5\n"
+        );
+    }
+}
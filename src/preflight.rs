@@ -0,0 +1,309 @@
+//! Preflight checks run on every host before setup or deploy change
+//! anything: privilege escalation, required tools, writable paths and free
+//! disk space. All problems on all hosts are reported together.
+
+use anyhow::{Context, Result, bail};
+use std::fs;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use crate::commands::parallel_hosts;
+use crate::config::Config;
+use crate::constants::{BASE_DIR, BSDEPLOY_BASE, CADDY_CONF_DIR, IMAGES_DIR, JAILS_DIR};
+use crate::{image, os, remote, shell, ui};
+
+/// Base system tools bsdeploy relies on
+const REQUIRED_TOOLS: &[&str] = &[
+    "pkg", "fetch", "tar", "jail", "jexec", "jls", "ifconfig", "mount", "umount", "sysrc",
+    "service",
+];
+
+/// Marker for problem lines in the script output
+const PROBLEM: &str = "PREFLIGHT:";
+
+/// Paths that must be writable (as the escalated user, if any). The nearest
+/// existing ancestor is checked for paths that do not exist yet.
+fn writable_paths() -> [&'static str; 3] {
+    [JAILS_DIR, CADDY_CONF_DIR, "/tmp"]
+}
+
+pub fn script(escalate: bool, extra_tools: &[&str]) -> String {
+    let prefix = shell::escalation_prefix(escalate);
+    let mut script = format!("problem() {{ echo \"{} $*\"; }}\n", PROBLEM);
+    script.push_str(os::COMMAND);
+
+    if escalate {
+        script.push_str(&format!(
+            "{}true </dev/null >/dev/null 2>&1 || problem \"'{}' does not work without a password prompt; add a nopass rule for this user\"\n",
+            prefix,
+            prefix.trim()
+        ));
+    }
+
+    let tools: Vec<&str> = REQUIRED_TOOLS.iter().chain(extra_tools).copied().collect();
+    script.push_str(&format!(
+        "for tool in {}; do command -v \"$tool\" >/dev/null 2>&1 || problem \"required command not found: $tool\"; done\n",
+        tools.join(" ")
+    ));
+
+    script.push_str(&format!(
+        "for param in {}; do sysctl -N security.jail.param.$param >/dev/null 2>&1 || problem \"the kernel does not support the jail parameter $param\"; done\n",
+        os::JAIL_PARAMS.join(" ")
+    ));
+
+    for path in writable_paths() {
+        script.push_str(&format!(
+            "d={}; while [ ! -e \"$d\" ]; do d=$(dirname \"$d\"); done; {}test -w \"$d\" </dev/null 2>/dev/null || problem \"not writable: $d (needed for {})\"\n",
+            shell::escape(path),
+            prefix,
+            path
+        ));
+    }
+    script
+}
+
+pub fn parse_problems(output: &str) -> Vec<String> {
+    output
+        .lines()
+        .filter_map(|line| line.strip_prefix(PROBLEM))
+        .map(|p| p.trim().to_string())
+        .collect()
+}
+
+/// Check all hosts, failing with a summary if any host has problems.
+/// `extra_tools` are checked in addition to the base system tools.
+pub fn run(config: &Config, extra_tools: &[&str]) -> Result<()> {
+    let spinner = ui::create_spinner(&format!("Checking {} hosts", config.hosts.len()));
+    let script = script(config.doas, extra_tools);
+
+    let results = parallel_hosts(&config.hosts, config.parallelism, |host| {
+        remote::run_with_output(host, &script).map(|out| {
+            let mut problems = parse_problems(&out);
+            match os::HostOs::from_output(&out) {
+                Some(os) => problems.extend(os.check().err().map(|e| e.to_string())),
+                None => problems.push("could not tell the operating system".to_string()),
+            }
+            problems
+        })
+    });
+    spinner.finish_and_clear();
+
+    let mut failed = 0;
+    for (host, result) in config.hosts.iter().zip(results) {
+        let problems = match result {
+            Ok(problems) => problems,
+            Err(e) => vec![format!("{:#}", e)],
+        };
+        if !problems.is_empty() {
+            failed += 1;
+            ui::print_error(&format!("{}:", host));
+            for problem in problems {
+                ui::print_error(&format!("  {}", problem));
+            }
+        }
+    }
+
+    if failed > 0 {
+        bail!(
+            "Preflight checks failed on {} of {} hosts",
+            failed,
+            config.hosts.len()
+        );
+    }
+    ui::print_success("Preflight checks passed");
+    Ok(())
+}
+
+/// Rough size of an extracted base system
+const BASE_SIZE_ESTIMATE: u64 = 1 << 30;
+/// Rough size of a new image (packages and runtimes)
+const IMAGE_SIZE_ESTIMATE: u64 = 2 << 30;
+/// Headroom kept on top of the estimate
+const FREE_SPACE_MARGIN: u64 = 512 << 20;
+/// Compressed artifacts are assumed to expand by this factor
+const ARTIFACT_EXPANSION: u64 = 4;
+/// Local directories never uploaded (see `remote::sync`), for trees
+/// outside git
+const UNSYNCED_DIRS: &[&str] = &[".git", "node_modules", "tmp", "log"];
+
+fn dir_size(path: &Path) -> Result<u64> {
+    let mut total = 0;
+    for entry in fs::read_dir(path).with_context(|| format!("Failed to read {}", path.display()))? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            if !UNSYNCED_DIRS.iter().any(|d| entry.file_name() == *d) {
+                total += dir_size(&entry.path())?;
+            }
+        } else if file_type.is_file() {
+            total += entry.metadata()?.len();
+        }
+    }
+    Ok(total)
+}
+
+/// Size of the files git considers part of the working tree (tracked and
+/// untracked but not ignored), which matches what is uploaded.
+fn git_files_size() -> Option<u64> {
+    let output = Command::new("git")
+        .args(["ls-files", "-z", "--cached", "--others", "--exclude-standard"])
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let files = String::from_utf8_lossy(&output.stdout);
+    Some(
+        files
+            .split('\0')
+            .filter_map(|f| fs::symlink_metadata(f).ok())
+            .filter(|m| m.is_file())
+            .map(|m| m.len())
+            .sum(),
+    )
+}
+
+/// Estimated size of the application once unpacked on a host.
+pub fn app_size(artifact: Option<&Path>) -> Result<u64> {
+    match artifact {
+        Some(artifact) => Ok(fs::metadata(artifact)
+            .with_context(|| format!("Failed to read {}", artifact.display()))?
+            .len()
+            * ARTIFACT_EXPANSION),
+        None => match git_files_size() {
+            Some(size) => Ok(size),
+            None => dir_size(Path::new(".")),
+        },
+    }
+}
+
+/// Bytes a deploy needs: the staged and the jail copy of the application,
+/// plus the base system and image if they still have to be created.
+fn required_space(app: u64, has_base: bool, has_image: bool) -> u64 {
+    let base = if has_base { 0 } else { BASE_SIZE_ESTIMATE };
+    let image = if has_image { 0 } else { IMAGE_SIZE_ESTIMATE };
+    app * 2 + base + image + FREE_SPACE_MARGIN
+}
+
+fn space_script(base_version: &str, short_hash: &str) -> String {
+    format!(
+        "d={}; while [ ! -e \"$d\" ]; do d=$(dirname \"$d\"); done; df -k \"$d\" | awk 'NR==2 {{print $4}}'; \
+         test -d {}/{} && echo base; test -d {}/{} && echo image; true",
+        BSDEPLOY_BASE,
+        BASE_DIR,
+        shell::escape(base_version),
+        IMAGES_DIR,
+        short_hash
+    )
+}
+
+/// Parse the free space (in bytes) and existing base/image from `space_script` output.
+fn parse_space(output: &str) -> Result<(u64, bool, bool)> {
+    let mut lines = output.lines().map(str::trim);
+    let kb: u64 = lines
+        .next()
+        .and_then(|l| l.parse().ok())
+        .with_context(|| format!("Unexpected df output: {}", output.trim()))?;
+    let rest: Vec<&str> = lines.collect();
+    Ok((kb * 1024, rest.contains(&"base"), rest.contains(&"image")))
+}
+
+fn format_size(bytes: u64) -> String {
+    format!("{:.1} GiB", bytes as f64 / (1u64 << 30) as f64)
+}
+
+/// Check that every host has room for the deploy before anything is
+/// created. `base_version` resolves the base system version for a host.
+pub fn check_disk_space<F>(config: &Config, app: u64, base_version: F) -> Result<()>
+where
+    F: Fn(&str) -> Result<String> + Sync,
+{
+    let results = parallel_hosts(&config.hosts, config.parallelism, |host| {
+        let version = base_version(host)?;
+        let hash = image::get_image_hash(config, &version);
+        let output = remote::run_with_output(host, &space_script(&version, &hash[..12]))?;
+        let (free, has_base, has_image) = parse_space(&output)?;
+        Ok((free, required_space(app, has_base, has_image)))
+    });
+
+    let mut failed = 0;
+    for (host, result) in config.hosts.iter().zip(results) {
+        match result {
+            Ok((free, needed)) if free < needed => {
+                failed += 1;
+                ui::print_error(&format!(
+                    "{}: not enough disk space under {}: {} free, about {} needed",
+                    host,
+                    BSDEPLOY_BASE,
+                    format_size(free),
+                    format_size(needed)
+                ));
+            }
+            Ok(_) => {}
+            Err(e) => {
+                failed += 1;
+                ui::print_error(&format!("{}: disk space check failed: {:#}", host, e));
+            }
+        }
+    }
+    if failed > 0 {
+        bail!("Not enough disk space on {} of {} hosts", failed, config.hosts.len());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::remote::mock::{self, MockExecutor};
+
+    #[test]
+    fn test_script_checks() {
+        let checks = script(true, &["rsync"]);
+        assert!(checks.contains("doas true </dev/null"));
+        assert!(checks.contains(
+            "for tool in pkg fetch tar jail jexec jls ifconfig mount umount sysrc service rsync; do"
+        ));
+        assert!(checks.contains("doas test -w \"$d\""));
+        assert!(checks.contains("for param in persist host.hostname ip4.addr allow.raw_sockets; do sysctl -N"));
+        assert!(!script(false, &[]).contains("doas"));
+    }
+
+    #[test]
+    fn test_required_space() {
+        let app = 100 << 20;
+        assert_eq!(required_space(app, true, true), 2 * app + FREE_SPACE_MARGIN);
+        assert_eq!(
+            required_space(app, false, false),
+            2 * app + BASE_SIZE_ESTIMATE + IMAGE_SIZE_ESTIMATE + FREE_SPACE_MARGIN
+        );
+    }
+
+    #[test]
+    fn test_parse_space() {
+        assert_eq!(parse_space("2048\nbase\n").unwrap(), (2 << 20, true, false));
+        assert_eq!(parse_space("10\nbase\nimage\n").unwrap(), (10240, true, true));
+        assert!(parse_space("df: /: No such file\n").is_err());
+    }
+
+    #[test]
+    fn test_problems_are_collected() {
+        let mock = MockExecutor::new();
+        mock.respond(
+            "problem()",
+            "PREFLIGHT: required command not found: jail\nnoise\nPREFLIGHT: not writable: /usr/local\n",
+        );
+        let output = mock::with_executor(mock.clone(), || {
+            remote::run_with_output("host", &script(false, &[]))
+        })
+        .unwrap();
+        assert_eq!(
+            parse_problems(&output),
+            [
+                "required command not found: jail",
+                "not writable: /usr/local"
+            ]
+        );
+    }
+}
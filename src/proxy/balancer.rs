@@ -0,0 +1,130 @@
+//! Caddy on one host load-balancing across the jails on all hosts
+//! (`proxy.load_balancer`). Each app host records its active jail on the
+//! balancer and the upstream list is rebuilt from those records, so hosts
+//! can deploy independently. Routing from the balancer to the jail
+//! networks is left to the operator.
+
+use std::sync::Mutex;
+
+use anyhow::{Context, Result};
+use indicatif::ProgressBar;
+
+use crate::config::{Config, LoadBalancerConfig, ProxyConfig};
+use crate::constants::CONFIG_DIR;
+use crate::{remote, shell};
+
+use super::caddy;
+
+/// Serializes record updates and reloads from hosts deploying in parallel
+static BALANCER: Mutex<()> = Mutex::new(());
+
+fn upstreams_path(service: &str) -> String {
+    format!("{}/{}/upstreams", CONFIG_DIR, service)
+}
+
+/// `(host, backend)` records from the upstreams file.
+fn parse_upstreams(records: &str) -> Vec<(String, String)> {
+    records
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            Some((fields.next()?.to_string(), fields.next()?.to_string()))
+        })
+        .collect()
+}
+
+fn format_upstreams(upstreams: &[(String, String)]) -> String {
+    upstreams.iter().map(|(host, backend)| format!("{} {}\n", host, backend)).collect()
+}
+
+/// Backends of the configured hosts, in config order. Records of hosts no
+/// longer in `hosts` are ignored.
+fn backends(config: &Config, upstreams: &[(String, String)]) -> Vec<String> {
+    config
+        .hosts
+        .iter()
+        .filter_map(|host| upstreams.iter().find(|(h, _)| h == host).map(|(_, b)| b.clone()))
+        .collect()
+}
+
+fn read_upstreams(config: &Config, lb: &LoadBalancerConfig) -> Result<Vec<(String, String)>> {
+    let cat_cmd = format!("cat {} 2>/dev/null || true", upstreams_path(&config.service));
+    let records = remote::query(&lb.host, &cat_cmd)
+        .with_context(|| format!("Failed to read the upstreams on load balancer {}", lb.host))?;
+    Ok(parse_upstreams(&records))
+}
+
+fn write_upstreams(config: &Config, lb: &LoadBalancerConfig, upstreams: &[(String, String)]) -> Result<()> {
+    remote::ensure_dir(&lb.host, &format!("{}/{}", CONFIG_DIR, config.service), config.doas)?;
+    remote::write_file(&lb.host, &format_upstreams(upstreams), &upstreams_path(&config.service), config.doas)
+}
+
+/// Set up Caddy on the balancer, with the site for the hosts deployed so far.
+pub fn setup(config: &Config, lb: &LoadBalancerConfig, spinner: &ProgressBar) -> Result<bool> {
+    let _guard = BALANCER.lock().unwrap_or_else(|e| e.into_inner());
+    let backends = backends(config, &read_upstreams(config, lb)?);
+    let backend = (!backends.is_empty()).then(|| backends.join(" "));
+    caddy::setup(config, &lb.host, backend.as_deref(), spinner)
+}
+
+/// Record `backend` as `host`'s upstream and reload the balancer.
+pub fn switch(
+    config: &Config,
+    proxy: &ProxyConfig,
+    lb: &LoadBalancerConfig,
+    host: &str,
+    backend: &str,
+    overwrite: bool,
+    spinner: &ProgressBar,
+) -> Result<()> {
+    let _guard = BALANCER.lock().unwrap_or_else(|e| e.into_inner());
+    let mut upstreams = read_upstreams(config, lb)?;
+    upstreams.retain(|(h, _)| h != host);
+    upstreams.push((host.to_string(), backend.to_string()));
+    write_upstreams(config, lb, &upstreams)?;
+
+    spinner.set_message(format!("[{}] Updating load balancer {}...", host, lb.host));
+    caddy::switch(config, proxy, &lb.host, &backends(config, &upstreams).join(" "), overwrite, spinner)
+}
+
+/// Take `host` out of the balancer, dropping the site with the last
+/// upstream (best effort).
+pub fn remove(config: &Config, proxy: &ProxyConfig, lb: &LoadBalancerConfig, host: &str, purge: bool) {
+    let _guard = BALANCER.lock().unwrap_or_else(|e| e.into_inner());
+    let Ok(mut upstreams) = read_upstreams(config, lb) else {
+        return;
+    };
+    upstreams.retain(|(h, _)| h != host);
+    let backends = backends(config, &upstreams);
+    if backends.is_empty() {
+        let p = shell::escalation_prefix(config.doas);
+        remote::run(&lb.host, &format!("{}rm -f {}", p, upstreams_path(&config.service))).ok();
+        caddy::remove(config, &lb.host, purge);
+        return;
+    }
+    if write_upstreams(config, lb, &upstreams).is_ok() {
+        caddy::switch(config, proxy, &lb.host, &backends.join(" "), false, &ProgressBar::hidden()).ok();
+    }
+}
+
+/// Upstream recorded for `host` on the balancer.
+pub fn current_backend(config: &Config, lb: &LoadBalancerConfig, host: &str) -> Result<Option<String>> {
+    let upstreams = read_upstreams(config, lb)?;
+    Ok(upstreams.into_iter().find(|(h, _)| h == host).map(|(_, backend)| backend))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_upstreams() {
+        let upstreams = parse_upstreams("web2 10.0.2.5:3000\nweb1 10.0.1.7:3000\ngarbage\n");
+        assert_eq!(format_upstreams(&upstreams), "web2 10.0.2.5:3000\nweb1 10.0.1.7:3000\n");
+
+        let config = Config::from_str("service: app\nhosts: [web1, web2]\n").unwrap();
+        assert_eq!(backends(&config, &upstreams), ["10.0.1.7:3000", "10.0.2.5:3000"]);
+        let config = Config::from_str("service: app\nhosts: [web1]\n").unwrap();
+        assert_eq!(backends(&config, &upstreams), ["10.0.1.7:3000"]);
+    }
+}
@@ -0,0 +1,1445 @@
+//! Caddy reverse proxy configuration utilities.
+
+use anyhow::{Context, Result};
+use indicatif::ProgressBar;
+
+use crate::config::{
+    AccessLogConfig, AccessLogFormat, ClientAuthConfig, CompressionConfig, Config, DnsProviderConfig,
+    ErrorPagesConfig, HealthcheckConfig, ProxyConfig, RateLimitConfig, SslConfig, StatusEndpointConfig,
+    TransportConfig,
+};
+use crate::constants::{
+    CADDY_ADMIN_ADDRESS, CADDY_CERTS_DIR, CADDY_CONF_DIR, CADDY_LOG_DIR, CADDY_PAGES_DIR, CADDY_STATUS_DIR,
+    CADDYFILE_PATH,
+};
+use crate::{helper, remote, shell, ui};
+
+/// `custom_directives` indented for a block, one per line.
+fn custom_directives(proxy: &ProxyConfig) -> String {
+    proxy
+        .custom_directives
+        .iter()
+        .map(|line| if line.trim().is_empty() { "\n".to_string() } else { format!("    {}\n", line) })
+        .collect()
+}
+
+/// `encode` directive for the configured compression.
+fn encode(compression: &CompressionConfig) -> String {
+    let formats: Vec<&str> = [("zstd", compression.zstd), ("gzip", compression.gzip)]
+        .into_iter()
+        .filter_map(|(format, enabled)| enabled.then_some(format))
+        .collect();
+    let mut options = String::new();
+    if let Some(length) = compression.minimum_length {
+        options.push_str(&format!("        minimum_length {}\n", length));
+    }
+    if !compression.content_types.is_empty() {
+        options.push_str("        match {\n");
+        for content_type in &compression.content_types {
+            options.push_str(&format!("            header Content-Type {}*\n", content_type.trim_end_matches('*')));
+        }
+        options.push_str("        }\n");
+    }
+    if options.is_empty() {
+        format!("    encode {}\n", formats.join(" "))
+    } else {
+        format!("    encode {} {{\n{}    }}\n", formats.join(" "), options)
+    }
+}
+
+/// `rate_limit` block keyed by client IP: one zone for the window and one
+/// capping bursts per second.
+fn rate_limit(limit: &RateLimitConfig, service: &str) -> String {
+    let zone = |name: &str, events: u32, window: u64| {
+        format!(
+            "        zone {}_{} {{\n            key {{remote_host}}\n            events {}\n            window {}s\n        }}\n",
+            service, name, events, window
+        )
+    };
+    let mut block = String::from("    rate_limit {\n");
+    block.push_str(&zone("window", limit.requests, limit.interval));
+    if let Some(burst) = limit.burst {
+        block.push_str(&zone("burst", burst, 1));
+    }
+    block.push_str("    }\n");
+    block
+}
+
+fn pages_dir(service: &str) -> String {
+    format!("{}/{}", CADDY_PAGES_DIR, service)
+}
+
+/// Answer 503 while `maintenance.on` exists in the service's pages
+/// directory (`touch` it on the host to enable maintenance mode).
+fn maintenance_check(service: &str) -> String {
+    format!(
+        "    @maintenance file {{\n        root {}\n        try_files /maintenance.on\n    }}\n    error @maintenance 503\n",
+        pages_dir(service)
+    )
+}
+
+/// Serve `<status>.html` from the service's pages directory for the
+/// configured error pages.
+fn handle_errors(pages: &ErrorPagesConfig, service: &str) -> String {
+    let statuses: Vec<String> = pages.pages().map(|(status, _)| status.to_string()).collect();
+    let lines = [
+        "handle_errors {".to_string(),
+        format!("    @page expression `{{err.status_code}} in [{}]`", statuses.join(", ")),
+        "    handle @page {".to_string(),
+        format!("        root * {}", pages_dir(service)),
+        "        rewrite * /{err.status_code}.html".to_string(),
+        "        file_server {".to_string(),
+        "            status {err.status_code}".to_string(),
+        "        }".to_string(),
+        "    }".to_string(),
+        "}".to_string(),
+    ];
+    lines.iter().map(|l| format!("    {}\n", l)).collect()
+}
+
+/// Caddy writes access logs as www
+fn ensure_log_dir(config: &Config, host: &str) -> Result<()> {
+    remote::ensure_dir(host, CADDY_LOG_DIR, config.doas)?;
+    remote::run(host, &format!("{}chown www:www {}", shell::escalation_prefix(config.doas), CADDY_LOG_DIR))
+}
+
+/// Upload the configured error pages as `<status>.html`.
+fn write_error_pages(config: &Config, host: &str, pages: &ErrorPagesConfig) -> Result<()> {
+    let dir = pages_dir(&config.service);
+    remote::ensure_dir(host, &dir, config.doas)?;
+    for (status, page) in pages.pages() {
+        let content = std::fs::read(page).with_context(|| format!("Failed to read error page {}", page.display()))?;
+        let dest = format!("{}/{}.html", dir, status);
+        remote::upload_bytes(host, &content, &dest, 0o644, Some("root:wheel"), config.doas)?;
+    }
+    Ok(())
+}
+
+/// Caddy modules outside the standard build the config needs, with the
+/// Go package providing each.
+fn plugins(proxy: &ProxyConfig) -> Vec<(String, String)> {
+    let mut plugins = Vec::new();
+    if proxy.rate_limit.is_some() {
+        plugins.push(("http.handlers.rate_limit".to_string(), "github.com/mholt/caddy-ratelimit".to_string()));
+    }
+    if proxy.access_log().is_some_and(|log| log.format == AccessLogFormat::Common) {
+        plugins.push((
+            "caddy.logging.encoders.transform".to_string(),
+            "github.com/caddyserver/transform-encoder".to_string(),
+        ));
+    }
+    if let Some(dns) = &proxy.dns_provider {
+        plugins.push((format!("dns.providers.{}", dns.name), format!("github.com/caddy-dns/{}", dns.name)));
+    }
+    plugins
+}
+
+/// Install missing plugins with `caddy add-package`, which replaces the
+/// caddy binary with a build from caddyserver.com that includes them.
+fn ensure_plugins(config: &Config, proxy: &ProxyConfig, host: &str, spinner: &ProgressBar) -> Result<()> {
+    let cmd_prefix = shell::escalation_prefix(config.doas);
+    for (module, package) in plugins(proxy) {
+        let installed = remote::run(host, &format!("caddy list-modules | grep -qxF {}", module));
+        if installed.is_ok() {
+            continue;
+        }
+        spinner.set_message(format!("[{}] Adding Caddy plugin {}...", host, package));
+        remote::run_timeout(host, &format!("{}caddy add-package {}", cmd_prefix, package), remote::Timeout::Build)
+            .with_context(|| format!("Failed to add the Caddy plugin {} (needed for {})", package, module))?;
+    }
+    Ok(())
+}
+
+/// Caddy reads the DNS provider token from this file at runtime
+fn dns_token_path(service: &str) -> String {
+    format!("{}/{}.dns-token", CADDY_CERTS_DIR, service)
+}
+
+/// CA that client certificates must be signed by
+fn client_ca_path(service: &str) -> String {
+    format!("{}/{}.client-ca.crt", CADDY_CERTS_DIR, service)
+}
+
+fn write_client_ca(config: &Config, host: &str, auth: &ClientAuthConfig) -> Result<()> {
+    let ca = match (&auth.ca_certificate_pem, &auth.ca_certificate_file) {
+        (Some(var), _) => std::env::var(var)
+            .with_context(|| format!("Missing client CA certificate environment variable: {}", var))?
+            .into_bytes(),
+        (None, Some(file)) => std::fs::read(file)
+            .with_context(|| format!("Failed to read client CA certificate {}", file.display()))?,
+        (None, None) => anyhow::bail!("proxy.client_auth needs a CA certificate"),
+    };
+    remote::ensure_dir(host, CADDY_CERTS_DIR, config.doas)?;
+    remote::upload_bytes(host, &ca, &client_ca_path(&config.service), 0o600, Some("www:www"), config.doas)
+}
+
+fn write_dns_token(config: &Config, host: &str, dns: &DnsProviderConfig) -> Result<()> {
+    let token = std::env::var(&dns.api_token)
+        .with_context(|| format!("Missing DNS provider token environment variable: {}", dns.api_token))?;
+    remote::ensure_dir(host, CADDY_CERTS_DIR, config.doas)?;
+    remote::upload_bytes(host, token.trim().as_bytes(), &dns_token_path(&config.service), 0o600, Some("www:www"), config.doas)
+}
+
+/// Access log file of a service on the host
+pub fn access_log_path(service: &str) -> String {
+    format!("{}/{}.log", CADDY_LOG_DIR, service)
+}
+
+/// `log` directive writing the site's access log to its own rolled file.
+fn access_log(log: &AccessLogConfig, service: &str) -> String {
+    let mut block = String::from("    log {\n");
+    block.push_str(&format!("        output file {} {{\n", access_log_path(service)));
+    block.push_str(&format!("            roll_size {}MiB\n", log.roll_size_mb));
+    block.push_str(&format!("            roll_keep {}\n", log.roll_keep));
+    block.push_str("        }\n");
+    match log.format {
+        AccessLogFormat::Json => block.push_str("        format json\n"),
+        AccessLogFormat::Common => block.push_str("        format transform \"{common_log}\"\n"),
+    }
+    block.push_str("    }\n");
+    block
+}
+
+/// `tls` directive for manual certificates, the DNS challenge and client
+/// certificate authentication, if any of them is configured.
+fn tls_directive(proxy: &ProxyConfig, service: &str) -> Option<String> {
+    let certificates = proxy
+        .ssl
+        .as_ref()
+        .map(|_| format!(" {}/{}.crt {}/{}.key", CADDY_CERTS_DIR, service, CADDY_CERTS_DIR, service))
+        .unwrap_or_default();
+
+    let mut options = String::new();
+    if let Some(dns) = &proxy.dns_provider {
+        options.push_str(&format!("        dns {} {{file.{}}}\n", dns.name, dns_token_path(service)));
+    }
+    if let Some(auth) = &proxy.client_auth {
+        options.push_str("        client_auth {\n");
+        options.push_str(&format!("            mode {}\n", auth.mode.caddy_mode()));
+        options.push_str(&format!("            trusted_ca_cert_file {}\n", client_ca_path(service)));
+        options.push_str("        }\n");
+    }
+
+    match (certificates.is_empty(), options.is_empty()) {
+        (true, true) => None,
+        (_, true) => Some(format!("    tls{}\n", certificates)),
+        _ => Some(format!("    tls{} {{\n{}    }}\n", certificates, options)),
+    }
+}
+
+/// Site block for the proxy's hostname and aliases with `body` as its
+/// content, followed by a redirect block per redirect host.
+fn site_blocks(proxy: &ProxyConfig, service: &str, body: &str) -> String {
+    let tls = proxy.ssl.is_some() || proxy.tls;
+    // Determine hostname format based on TLS mode
+    let address = |hostname: &str| {
+        if tls {
+            hostname.to_string()
+        } else {
+            format!("http://{}", hostname)
+        }
+    };
+    let mut tls_directive = tls_directive(proxy, service).unwrap_or_default();
+    if let Some(hsts) = &proxy.hsts {
+        tls_directive.push_str(&format!("    header Strict-Transport-Security \"{}\"\n", hsts.header_value()));
+    }
+    let log = proxy.access_log().map(|log| access_log(log, service)).unwrap_or_default();
+
+    let hostnames: Vec<&String> = std::iter::once(&proxy.hostname).chain(&proxy.aliases).collect();
+    let addresses: Vec<String> = hostnames.iter().map(|h| address(h)).collect();
+    let mut content = format!("{} {{\n", addresses.join(", "));
+    content.push_str(&tls_directive);
+    content.push_str(&log);
+    content.push_str(body);
+    content.push_str("}\n");
+
+    // An explicit http:// site replaces Caddy's automatic redirect to HTTPS
+    if tls && (!proxy.https_redirect || !proxy.plain_http_from.is_empty()) {
+        let addresses: Vec<String> = hostnames.iter().map(|h| format!("http://{}", h)).collect();
+        content.push_str(&format!("\n{} {{\n", addresses.join(", ")));
+        if !proxy.plain_http_from.is_empty() {
+            content.push_str(&format!("    @external not remote_ip {}\n", proxy.plain_http_from.join(" ")));
+            content.push_str(&format!("    redir @external https://{{host}}{}{{uri}} permanent\n", port_suffix(proxy, true)));
+        }
+        content.push_str(&log);
+        content.push_str(body);
+        content.push_str("}\n");
+    }
+
+    for host in &proxy.redirect_hosts {
+        content.push_str(&format!("\n{} {{\n", address(host)));
+        content.push_str(&tls_directive);
+        content.push_str(&format!(
+            "    redir {}://{}{}{{uri}} permanent\n",
+            if tls { "https" } else { "http" },
+            proxy.hostname,
+            port_suffix(proxy, tls)
+        ));
+        content.push_str("}\n");
+    }
+
+    content
+}
+
+/// `:<port>` for redirect targets when Caddy serves HTTP (or HTTPS) on a
+/// port other than the default.
+fn port_suffix(proxy: &ProxyConfig, https: bool) -> String {
+    match (https, proxy.listen_ports()) {
+        (false, (80, _)) | (true, (_, 443)) => String::new(),
+        (false, (port, _)) | (true, (_, port)) => format!(":{}", port),
+    }
+}
+
+/// Subdirectives of reverse_proxy for the transport options.
+fn transport_options(transport: &TransportConfig) -> Vec<String> {
+    let mut options = Vec::new();
+    match transport.flush_interval {
+        Some(-1) => options.push("flush_interval -1".to_string()),
+        Some(ms) => options.push(format!("flush_interval {}ms", ms)),
+        None => {}
+    }
+    if let Some(secs) = transport.stream_timeout {
+        options.push(format!("stream_timeout {}s", secs));
+    }
+    if let Some(secs) = transport.stream_close_delay {
+        options.push(format!("stream_close_delay {}s", secs));
+    }
+
+    let mut http = Vec::new();
+    for (name, value) in [
+        ("dial_timeout", transport.dial_timeout),
+        ("read_timeout", transport.read_timeout),
+        ("write_timeout", transport.write_timeout),
+    ] {
+        if let Some(secs) = value {
+            http.push(format!("{} {}s", name, secs));
+        }
+    }
+    match transport.keepalive {
+        Some(0) => http.push("keepalive off".to_string()),
+        Some(secs) => http.push(format!("keepalive {}s", secs)),
+        None => {}
+    }
+    if let Some(conns) = transport.keepalive_idle_conns {
+        http.push(format!("keepalive_idle_conns {}", conns));
+    }
+    if !http.is_empty() {
+        options.push("transport http {".to_string());
+        options.extend(http.iter().map(|l| format!("    {}", l)));
+        options.push("}".to_string());
+    }
+    options
+}
+
+/// Subdirectives of reverse_proxy for active (probing `path`) and
+/// passive (watching requests) health checks.
+fn health_options(check: &HealthcheckConfig) -> Vec<String> {
+    let mut options = Vec::new();
+    if let Some(path) = &check.path {
+        options.push(format!("health_uri {}", path));
+        options.push(format!("health_interval {}s", check.interval));
+        options.push(format!("health_timeout {}s", check.timeout));
+    }
+    if check.fail_duration > 0 {
+        options.push(format!("fail_duration {}s", check.fail_duration));
+        options.push(format!("max_fails {}", check.max_fails));
+    }
+    options
+}
+
+/// `reverse_proxy` to `backend`, one or more space separated upstreams.
+/// Behind a load balancer it picks between them.
+fn reverse_proxy(proxy: &ProxyConfig, backend: &str) -> String {
+    let mut options = Vec::new();
+    if let Some(lb) = &proxy.load_balancer {
+        options.push(format!("lb_policy {}", lb.policy));
+    }
+    if let Some(check) = &proxy.healthcheck {
+        options.extend(health_options(check));
+    }
+    if let Some(transport) = &proxy.transport {
+        options.extend(transport_options(transport));
+    }
+    if options.is_empty() {
+        return format!("    reverse_proxy {}\n", backend);
+    }
+    let mut block = format!("    reverse_proxy {} {{\n", backend);
+    for option in options {
+        block.push_str(&format!("        {}\n", option));
+    }
+    block.push_str("    }\n");
+    block
+}
+
+/// Generate Caddyfile content for a proxy configuration. With a
+/// `path_prefix` this is only the service's route, imported by the site
+/// block shared by all services on the hostname (see `shared_site`).
+pub fn generate_caddyfile(proxy: &ProxyConfig, service: &str, backend: &str) -> String {
+    let error_pages = proxy.error_pages.as_ref();
+    let mut handler = String::new();
+    if error_pages.is_some_and(|p| p.maintenance.is_some()) {
+        handler.push_str(&maintenance_check(service));
+    }
+    let reverse_proxy = reverse_proxy(proxy, backend);
+    // rate_limit has no place in Caddy's directive order, so it is
+    // wrapped in a route with the proxy to run first
+    match &proxy.rate_limit {
+        Some(limit) => {
+            let inner = format!("{}{}{}", handler, rate_limit(limit, service), reverse_proxy);
+            let indented: String = inner.lines().map(|l| format!("    {}\n", l)).collect();
+            handler = format!("    route {{\n{}    }}\n", indented);
+        }
+        None => handler.push_str(&reverse_proxy),
+    }
+    let compression = proxy.compression.as_ref().map(encode).unwrap_or_default();
+    let mut body = format!("{}{}{}", compression, custom_directives(proxy), handler);
+    if let Some(pages) = error_pages {
+        body.push_str(&handle_errors(pages, service));
+    }
+    let Some(prefix) = &proxy.path_prefix else {
+        return site_blocks(proxy, service, &body);
+    };
+    if prefix == "/" {
+        return format!("handle {{\n{}}}\n", body);
+    }
+    // handle_path strips the prefix before proxying; handle passes it on
+    let directive = if proxy.strip_prefix { "handle_path" } else { "handle" };
+    format!("redir {p} {p}/ 308\n{d} {p}/* {{\n{b}}}\n", p = prefix, d = directive, b = body)
+}
+
+const MANAGED_BEGIN: &str = "# bsdeploy managed begin";
+/// First line of the generated files without managed sections
+const GENERATED: &str = "# generated by bsdeploy";
+const MANAGED_END: &str = "# bsdeploy managed end";
+
+/// Mark the generated lines of each top-level block as managed. The
+/// closing brace stays outside, so directives added before it are kept.
+fn mark_managed(conf: &str) -> String {
+    let mut marked = String::new();
+    let mut open = false;
+    for line in conf.lines() {
+        if !open && !line.trim().is_empty() {
+            marked.push_str(MANAGED_BEGIN);
+            marked.push('\n');
+            open = true;
+        }
+        if open && line == "}" {
+            marked.push_str(MANAGED_END);
+            marked.push('\n');
+            open = false;
+        }
+        marked.push_str(line);
+        marked.push('\n');
+    }
+    marked
+}
+
+/// Split a marked file into the text outside the managed sections (one
+/// more entry than there are sections) and the sections themselves.
+fn split_managed(conf: &str) -> (Vec<String>, Vec<String>) {
+    let mut outside = vec![String::new()];
+    let mut managed = Vec::new();
+    let mut section: Option<String> = None;
+    for line in conf.lines() {
+        match (line, section.as_mut()) {
+            (MANAGED_BEGIN, None) => section = Some(format!("{}\n", line)),
+            (MANAGED_END, Some(s)) => {
+                s.push_str(line);
+                s.push('\n');
+                managed.extend(section.take());
+                outside.push(String::new());
+            }
+            (_, Some(s)) => {
+                s.push_str(line);
+                s.push('\n');
+            }
+            (_, None) => {
+                let last = outside.last_mut().expect("never empty");
+                last.push_str(line);
+                last.push('\n');
+            }
+        }
+    }
+    (outside, managed)
+}
+
+/// Replace the managed sections of `current` with those of `generated`,
+/// keeping everything else. A file without markers predates them and is
+/// replaced. If the sections no longer line up, hand-edited files are
+/// refused rather than guessed at.
+fn merge_managed(current: &str, generated: &str) -> Result<String> {
+    if !current.contains(MANAGED_BEGIN) {
+        return Ok(generated.to_string());
+    }
+    let (outside, old) = split_managed(current);
+    let (_, new) = split_managed(generated);
+    if old.len() == new.len() {
+        let mut merged = outside[0].clone();
+        for (section, rest) in new.iter().zip(&outside[1..]) {
+            merged.push_str(section);
+            merged.push_str(rest);
+        }
+        return Ok(merged);
+    }
+    let edited = outside.iter().flat_map(|s| s.lines()).any(|l| !l.trim().is_empty() && l.trim() != "}");
+    if edited {
+        anyhow::bail!(
+            "the site was edited by hand and its generated structure changed; \
+             deploy with --overwrite to regenerate it (manual changes are lost)"
+        );
+    }
+    Ok(generated.to_string())
+}
+
+/// The service's site for `backend`, merged into the one on the host.
+fn site_conf(config: &Config, proxy: &ProxyConfig, host: &str, backend: &str, overwrite: bool) -> Result<String> {
+    let generated = mark_managed(&generate_caddyfile(proxy, &config.service, backend));
+    if overwrite {
+        return Ok(generated);
+    }
+    let current = remote::query(host, &format!("cat {} 2>/dev/null || true", conf_path(config)))?;
+    merge_managed(&current, &generated).with_context(|| format!("Cannot update {}", conf_path(config)))
+}
+
+/// Directory holding the routes of the services sharing `hostname`
+fn routes_dir(hostname: &str) -> String {
+    format!("{}/routes/{}", CADDY_CONF_DIR, hostname)
+}
+
+fn shared_site_path(hostname: &str) -> String {
+    format!("{}/{}.site.caddy", CADDY_CONF_DIR, hostname)
+}
+
+/// Site block for a hostname split between services by path. Every service
+/// on the hostname writes the same file, so they must agree on TLS.
+fn shared_site(proxy: &ProxyConfig, service: &str) -> String {
+    format!(
+        "{}\n{}",
+        GENERATED,
+        site_blocks(proxy, service, &format!("    import {}/*.caddy\n", routes_dir(&proxy.hostname)))
+    )
+}
+
+fn write_shared_site(config: &Config, proxy: &ProxyConfig, host: &str) -> Result<()> {
+    remote::ensure_dir(host, &routes_dir(&proxy.hostname), config.doas)?;
+    remote::write_file(host, &shared_site(proxy, &config.service), &shared_site_path(&proxy.hostname), config.doas)
+}
+
+pub fn certificate_path(service: &str) -> String {
+    format!("{}/{}.crt", CADDY_CERTS_DIR, service)
+}
+
+fn key_path(service: &str) -> String {
+    format!("{}/{}.key", CADDY_CERTS_DIR, service)
+}
+
+/// Write SSL certificates from environment variables to remote host.
+pub fn write_ssl_certificates(
+    config: &Config,
+    host: &str,
+    ssl: &SslConfig,
+) -> Result<()> {
+    // Ensure certs directory exists
+    remote::ensure_dir(host, CADDY_CERTS_DIR, config.doas)?;
+
+    // Read certificate from environment variable
+    let cert_content = std::env::var(&ssl.certificate_pem).with_context(|| {
+        format!(
+            "Missing SSL certificate environment variable: {}",
+            ssl.certificate_pem
+        )
+    })?;
+
+    // Read private key from environment variable
+    let key_content = std::env::var(&ssl.private_key_pem).with_context(|| {
+        format!(
+            "Missing SSL private key environment variable: {}",
+            ssl.private_key_pem
+        )
+    })?;
+
+    let cert_path = certificate_path(&config.service);
+    let key_path = key_path(&config.service);
+
+    // Caddy runs as www on FreeBSD; the key must not be readable by others
+    remote::upload_bytes(host, cert_content.as_bytes(), &cert_path, 0o600, Some("www:www"), config.doas)?;
+    remote::upload_bytes(host, key_content.as_bytes(), &key_path, 0o600, Some("www:www"), config.doas)?;
+
+    Ok(())
+}
+
+/// The service's site, or its route when it shares the hostname
+pub fn conf_path(config: &Config) -> String {
+    match &config.proxy {
+        Some(proxy) if proxy.path_prefix.is_some() => {
+            format!("{}/{}.caddy", routes_dir(&proxy.hostname), config.service)
+        }
+        _ => format!("{}/{}.caddy", CADDY_CONF_DIR, config.service),
+    }
+}
+
+fn uses_admin_api(config: &Config) -> bool {
+    config.proxy.as_ref().is_some_and(|p| p.admin_api)
+}
+
+/// Command that makes Caddy pick up the files on disk. With the admin API,
+/// `caddy reload` adapts the Caddyfile and loads it over the API, which
+/// validates the config and keeps the running one if it is rejected. When
+/// the API does not answer it falls back to `service caddy reload`.
+fn reload_command(config: &Config) -> String {
+    let cmd_prefix = shell::escalation_prefix(config.doas);
+    let service_reload = format!("{}service caddy reload", cmd_prefix);
+    if !uses_admin_api(config) {
+        return service_reload;
+    }
+    format!(
+        "if fetch -qo /dev/null http://{addr}/config/ 2>/dev/null; then \
+         {p}caddy reload --config {caddyfile} --adapter caddyfile --address {addr}; \
+         else echo 'Caddy admin API not reachable, reloading the service' >&2; {reload}; fi",
+        addr = CADDY_ADMIN_ADDRESS,
+        p = cmd_prefix,
+        caddyfile = CADDYFILE_PATH,
+        reload = service_reload
+    )
+}
+
+/// Command that reloads Caddy even though the config is unchanged, so it
+/// reads rotated certificate files. Without the admin API only a restart
+/// picks them up.
+fn force_reload_command(config: &Config) -> String {
+    format!(
+        "{p}caddy reload --force --config {caddyfile} --adapter caddyfile --address {addr} || \
+         {{ echo 'Caddy admin API not reachable, restarting the service' >&2; {p}service caddy restart; }}",
+        p = shell::escalation_prefix(config.doas),
+        caddyfile = CADDYFILE_PATH,
+        addr = CADDY_ADMIN_ADDRESS
+    )
+}
+
+/// Upload the certificates again and make Caddy use them.
+pub fn push_certificates(config: &Config, host: &str, ssl: &SslConfig) -> Result<()> {
+    write_ssl_certificates(config, host, ssl)?;
+    remote::run(host, &force_reload_command(config))
+}
+
+/// Command that moves the uploaded `<site>.new` into place. The previous
+/// site is kept as `.prev`; if `caddy validate` rejects the assembled
+/// config, or the reload fails, it is restored (and reloaded) so one bad
+/// site cannot take the others on the host offline.
+fn install_command(config: &Config) -> String {
+    let reload = reload_command(config);
+    format!(
+        "c={conf}; restore() {{ echo \"Caddy rejected the new config, restoring $c\" >&2; \
+         if [ -f \"$c.prev\" ]; then {p}mv \"$c.prev\" \"$c\"; else {p}rm -f \"$c\"; fi; }}; \
+         if [ -f \"$c\" ]; then {p}cp -p \"$c\" \"$c.prev\"; else {p}rm -f \"$c.prev\"; fi && {p}mv \"$c.new\" \"$c\" || exit 1; \
+         if ! {p}caddy validate --config {caddyfile} --adapter caddyfile >&2; then restore; exit 1; fi; \
+         if ! {{ {reload}; }}; then restore; {reload}; exit 1; fi",
+        conf = shell::escape(&conf_path(config)),
+        p = shell::escalation_prefix(config.doas),
+        caddyfile = CADDYFILE_PATH,
+        reload = reload
+    )
+}
+
+/// Write what the generated config refers to besides the certificates:
+/// DNS token, client CA, error pages, log directory and status site.
+fn write_site_files(config: &Config, proxy: &ProxyConfig, host: &str, backend: &str) -> Result<()> {
+    if let Some(dns) = &proxy.dns_provider {
+        write_dns_token(config, host, dns)?;
+    }
+    if let Some(auth) = &proxy.client_auth {
+        write_client_ca(config, host, auth)?;
+    }
+    if let Some(pages) = &proxy.error_pages {
+        write_error_pages(config, host, pages)?;
+    }
+    if proxy.access_log().is_some() {
+        ensure_log_dir(config, host)?;
+    }
+    if let Some(endpoint) = &proxy.status_endpoint {
+        write_status(config, proxy, host, endpoint, backend)?;
+    }
+    Ok(())
+}
+
+fn status_json_path(service: &str) -> String {
+    format!("{}/{}.json", CADDY_STATUS_DIR, service)
+}
+
+fn status_site_path(service: &str) -> String {
+    format!("{}/{}.status.caddy", CADDY_CONF_DIR, service)
+}
+
+/// Internal site serving Caddy's metrics and the service's status summary.
+fn status_site(endpoint: &StatusEndpointConfig, service: &str) -> String {
+    let lines = [
+        GENERATED.to_string(),
+        format!("http://:{} {{", endpoint.port),
+        format!("    bind {}", endpoint.bind),
+        "    handle /metrics {".to_string(),
+        "        metrics".to_string(),
+        "    }".to_string(),
+        "    handle /health {".to_string(),
+        format!("        root * {}", CADDY_STATUS_DIR),
+        format!("        rewrite * /{}.json", service),
+        "        header Content-Type application/json".to_string(),
+        "        file_server".to_string(),
+        "    }".to_string(),
+        "    handle {".to_string(),
+        "        respond 404".to_string(),
+        "    }".to_string(),
+        "}".to_string(),
+    ];
+    lines.iter().map(|l| format!("{}\n", l)).collect()
+}
+
+/// Status summary served at /health, refreshed whenever the site changes.
+fn status_summary(config: &Config, proxy: &ProxyConfig, backend: &str) -> String {
+    serde_json::json!({
+        "service": config.service,
+        "hostname": proxy.hostname,
+        "backend": backend,
+        "updated_at": chrono::Local::now().to_rfc3339(),
+    })
+    .to_string()
+}
+
+fn write_status(config: &Config, proxy: &ProxyConfig, host: &str, endpoint: &StatusEndpointConfig, backend: &str) -> Result<()> {
+    remote::ensure_dir(host, CADDY_STATUS_DIR, config.doas)?;
+    let summary = status_summary(config, proxy, backend);
+    let path = status_json_path(&config.service);
+    remote::upload_bytes(host, summary.as_bytes(), &path, 0o644, Some("root:wheel"), config.doas)?;
+    remote::write_file(host, &status_site(endpoint, &config.service), &status_site_path(&config.service), config.doas)
+}
+
+const GLOBALS_BEGIN: &str = "# bsdeploy global options begin";
+const GLOBALS_END: &str = "# bsdeploy global options end";
+const CONF_IMPORT: &str = "import conf.d/*.caddy";
+const LETSENCRYPT_STAGING: &str = "https://acme-staging-v02.api.letsencrypt.org/directory";
+
+fn acme_ca_root_path() -> String {
+    format!("{}/acme-ca-root.crt", CADDY_CERTS_DIR)
+}
+
+/// Comment after a global option naming the services that set it
+const OWNERS_MARKER: &str = " # bsdeploy: ";
+
+/// The service's global options (name and value) for the listen ports and
+/// ACME settings.
+fn global_options(proxy: &ProxyConfig) -> Vec<(&'static str, String)> {
+    let mut options = Vec::new();
+    if let Some(port) = proxy.http_port {
+        options.push(("http_port", port.to_string()));
+    }
+    if let Some(port) = proxy.https_port {
+        options.push(("https_port", port.to_string()));
+    }
+    if let Some(acme) = &proxy.acme {
+        if let Some(email) = &acme.email {
+            options.push(("email", email.clone()));
+        }
+        let ca = if acme.staging { Some(LETSENCRYPT_STAGING) } else { acme.ca.as_deref() };
+        if let Some(ca) = ca {
+            options.push(("acme_ca", ca.to_string()));
+        }
+        if acme.ca_root.is_some() {
+            options.push(("acme_ca_root", acme_ca_root_path()));
+        }
+    }
+    options
+}
+
+/// An option in the managed global block. Options without owners were
+/// written by hand or by an older bsdeploy.
+#[derive(Debug, PartialEq)]
+struct GlobalOption {
+    name: String,
+    value: String,
+    owners: Vec<String>,
+}
+
+fn parse_global_options(block: &[&str]) -> Vec<GlobalOption> {
+    block
+        .iter()
+        .map(|l| l.trim())
+        .filter(|l| !l.is_empty() && *l != "{" && *l != "}")
+        .map(|line| {
+            let (option, owners) = line.split_once(OWNERS_MARKER).unwrap_or((line, ""));
+            let (name, value) = option.trim().split_once(' ').unwrap_or((option.trim(), ""));
+            GlobalOption {
+                name: name.to_string(),
+                value: value.trim().to_string(),
+                owners: owners.split(',').map(str::trim).filter(|o| !o.is_empty()).map(str::to_string).collect(),
+            }
+        })
+        .collect()
+}
+
+/// Caddy has one set of global options per host, shared by every service.
+/// The service's options replace the ones it set before and keep those of
+/// other services; an option another service set to a different value is
+/// a conflict.
+fn merge_global_options(mut options: Vec<GlobalOption>, service: &str, ours: &[(&str, String)]) -> Result<Vec<GlobalOption>> {
+    options.retain_mut(|o| {
+        let owned = !o.owners.is_empty();
+        o.owners.retain(|s| s != service);
+        !owned || !o.owners.is_empty()
+    });
+    for (name, value) in ours {
+        match options.iter_mut().find(|o| o.name == *name) {
+            Some(o) if o.value == *value => o.owners.push(service.to_string()),
+            Some(o) if !o.owners.is_empty() => anyhow::bail!(
+                "Caddy's global option `{} {}` is already set by {} on this host; Caddy has one {} for all services, so use the same value in each",
+                o.name,
+                o.value,
+                o.owners.join(", "),
+                o.name
+            ),
+            Some(o) => {
+                o.value = value.clone();
+                o.owners = vec![service.to_string()];
+            }
+            None => options.push(GlobalOption { name: name.to_string(), value: value.clone(), owners: vec![service.to_string()] }),
+        }
+    }
+    Ok(options)
+}
+
+/// The main Caddyfile with the conf.d import and the global options of all
+/// services between the managed markers at the top. Everything else is
+/// kept, so re-running setup only replaces the managed block.
+fn main_caddyfile(current: &str, service: &str, ours: &[(&str, String)]) -> Result<String> {
+    let mut managed = false;
+    let mut block: Vec<&str> = Vec::new();
+    let mut rest: Vec<&str> = Vec::new();
+    for line in current.lines() {
+        match line {
+            GLOBALS_BEGIN => managed = true,
+            GLOBALS_END => managed = false,
+            _ if !managed => rest.push(line),
+            _ => block.push(line),
+        }
+    }
+    if !rest.iter().any(|l| l.trim() == CONF_IMPORT) {
+        rest.push(CONF_IMPORT);
+    }
+
+    let options = merge_global_options(parse_global_options(&block), service, ours)?;
+    let mut caddyfile = String::new();
+    if !options.is_empty() {
+        // Caddy allows a single global options block, first in the file
+        if rest.iter().map(|l| l.trim()).find(|l| !l.is_empty() && !l.starts_with('#')) == Some("{") {
+            anyhow::bail!(
+                "{} already has a global options block; move its options into proxy.acme, or drop proxy.acme, proxy.http_port and proxy.https_port",
+                CADDYFILE_PATH
+            );
+        }
+        caddyfile.push_str(GLOBALS_BEGIN);
+        caddyfile.push_str("\n{\n");
+        for o in &options {
+            caddyfile.push_str(&format!("    {} {}", o.name, o.value));
+            if !o.owners.is_empty() {
+                caddyfile.push_str(&format!("{}{}", OWNERS_MARKER, o.owners.join(", ")));
+            }
+            caddyfile.push('\n');
+        }
+        caddyfile.push_str("}\n");
+        caddyfile.push_str(GLOBALS_END);
+        caddyfile.push('\n');
+    }
+    for line in rest {
+        caddyfile.push_str(line);
+        caddyfile.push('\n');
+    }
+    Ok(caddyfile)
+}
+
+/// Checksum over the Caddy binary and everything below its config
+/// directory except the status files, which Caddy serves without a reload
+fn fingerprint_command(config: &Config) -> String {
+    let etc = CADDYFILE_PATH.rsplit_once('/').map_or("/", |(dir, _)| dir);
+    format!(
+        "{p}sha256 -r $({p}find /usr/local/bin/caddy {} -path {} -prune -o -type f -print 2>/dev/null | sort) | sha256 -q",
+        etc,
+        CADDY_STATUS_DIR,
+        p = shell::escalation_prefix(config.doas)
+    )
+}
+
+/// Enable Caddy and write its config. Caddy is only restarted when that
+/// changed anything or it is not running; returns whether it was.
+pub fn setup(config: &Config, host: &str, backend: Option<&str>, spinner: &ProgressBar) -> Result<bool> {
+    spinner.set_message(format!("[{}] Configuring Caddy...", host));
+    let cmd_prefix = shell::escalation_prefix(config.doas);
+    let before = remote::run_with_output_timeout(host, &fingerprint_command(config), remote::Timeout::Query)?;
+
+    remote::run(host, &format!("{}sysrc caddy_enable=YES", cmd_prefix))?;
+    remote::ensure_dir(host, CADDY_CONF_DIR, config.doas)?;
+
+    // Create certs directory if SSL config is present
+    if let Some(proxy) = &config.proxy
+        && proxy.ssl.is_some()
+    {
+        remote::ensure_dir(host, CADDY_CERTS_DIR, config.doas)?;
+    }
+
+    // Main Caddyfile: the conf.d import and the managed global options
+    let acme = config.proxy.as_ref().and_then(|p| p.acme.as_ref());
+    if let Some(root) = acme.and_then(|a| a.ca_root.as_ref()) {
+        let pem = std::fs::read(root).with_context(|| format!("Failed to read ACME CA root {}", root.display()))?;
+        remote::ensure_dir(host, CADDY_CERTS_DIR, config.doas)?;
+        remote::upload_bytes(host, &pem, &acme_ca_root_path(), 0o644, Some("root:wheel"), config.doas)?;
+    }
+    let current = remote::query(host, &format!("cat {} 2>/dev/null || true", CADDYFILE_PATH))?;
+    let options = config.proxy.as_ref().map(global_options).unwrap_or_default();
+    let caddyfile = main_caddyfile(&current, &config.service, &options)?;
+    if caddyfile != current {
+        if !current.is_empty() {
+            ui::print_step(&format!("Updating {}", CADDYFILE_PATH));
+        }
+        remote::write_file(host, &caddyfile, CADDYFILE_PATH, config.doas)?;
+    }
+
+    // Proxy config
+    if let Some(proxy) = &config.proxy {
+        ensure_plugins(config, proxy, host, spinner)?;
+
+        // Handle SSL certificates if configured
+        if let Some(ssl) = &proxy.ssl {
+            spinner.set_message(format!("[{}] Writing TLS certificates...", host));
+            write_ssl_certificates(config, host, ssl)?;
+        }
+        if let Some(backend) = backend {
+            write_site_files(config, proxy, host, backend)?;
+
+            if proxy.path_prefix.is_some() {
+                write_shared_site(config, proxy, host)?;
+            }
+            // The site only points at the host port until the first deploy,
+            // so a hand-edited one is left for deploy to merge into
+            match site_conf(config, proxy, host, backend, false) {
+                Ok(content) => remote::write_file(host, &content, &conf_path(config), config.doas)?,
+                Err(e) => ui::print_warning(&format!("Keeping {}: {:#}", conf_path(config), e)),
+            }
+        }
+    }
+
+    // Restart caddy
+    remote::run(host, &format!("{}service caddy enable", cmd_prefix))?;
+    let after = remote::run_with_output_timeout(host, &fingerprint_command(config), remote::Timeout::Query)?;
+    if after == before && remote::run(host, &format!("{}service caddy status >/dev/null 2>&1", cmd_prefix)).is_ok() {
+        return Ok(false);
+    }
+    remote::run(host, &format!("{}service caddy restart", cmd_prefix))?;
+
+    Ok(true)
+}
+
+/// Route the service's site to `backend` and reload Caddy.
+/// Manual changes outside the managed sections of the site are kept
+/// unless `overwrite` is set.
+pub fn switch(
+    config: &Config,
+    proxy: &ProxyConfig,
+    host: &str,
+    backend: &str,
+    overwrite: bool,
+    spinner: &ProgressBar,
+) -> Result<()> {
+    // Update SSL certificates if configured (they may have been rotated)
+    if let Some(ssl) = &proxy.ssl {
+        spinner.set_message(format!("[{}] Updating TLS certificates...", host));
+        write_ssl_certificates(config, host, ssl)?;
+    }
+    write_site_files(config, proxy, host, backend)?;
+
+    let proxy_conf_content = site_conf(config, proxy, host, backend, overwrite)?;
+
+    // The helper only knows per-service sites reloaded through the service
+    if helper::is_enabled() && !uses_admin_api(config) && proxy.path_prefix.is_none() {
+        helper::switch_proxy(host, &config.service, &proxy_conf_content, config.doas)
+    } else {
+        if proxy.path_prefix.is_some() {
+            write_shared_site(config, proxy, host)?;
+        }
+        let conf = conf_path(config);
+        remote::write_file(host, &proxy_conf_content, &format!("{}.new", conf), config.doas)?;
+        remote::run(host, &install_command(config))
+            .with_context(|| format!("Caddy rejected the new config for {}; the previous one was restored", config.service))
+    }
+}
+
+/// Command that stops and disables Caddy when no site is left and the main
+/// Caddyfile holds nothing but the conf.d import bsdeploy added.
+fn disable_if_unused_command(config: &Config) -> String {
+    format!(
+        "if ! ls {conf}/*.caddy >/dev/null 2>&1 && ! sed '/^{begin}$/,/^{end}$/d' {caddyfile} | grep -qvxF -e '{import}' -e ''; then \
+         {p}service caddy stop >/dev/null 2>&1; {p}sysrc caddy_enable=NO >/dev/null; echo 'Caddy has no sites left and was disabled' >&2; fi",
+        conf = CADDY_CONF_DIR,
+        caddyfile = CADDYFILE_PATH,
+        begin = GLOBALS_BEGIN,
+        end = GLOBALS_END,
+        import = CONF_IMPORT,
+        p = shell::escalation_prefix(config.doas)
+    )
+}
+
+/// Drop the service's site with its certificates, secrets and pages, and
+/// reload Caddy (best effort). `purge` also deletes the access logs and
+/// disables Caddy if this was the last site.
+pub fn remove(config: &Config, host: &str, purge: bool) {
+    let cmd_prefix = shell::escalation_prefix(config.doas);
+    let service = &config.service;
+    let conf = conf_path(config);
+    let files = [
+        conf.clone(),
+        format!("{}.prev", conf),
+        certificate_path(service),
+        key_path(service),
+        dns_token_path(service),
+        client_ca_path(service),
+        status_site_path(service),
+        status_json_path(service),
+    ];
+    remote::run(host, &format!("{}rm -f {}", cmd_prefix, files.join(" "))).ok();
+    remote::run(host, &format!("{}rm -rf {}", cmd_prefix, pages_dir(service))).ok();
+    // Drop the shared site with its last route
+    if let Some(proxy) = &config.proxy
+        && proxy.path_prefix.is_some()
+    {
+        let cleanup = format!(
+            "{p}rmdir {dir} 2>/dev/null && {p}rm -f {site}; true",
+            p = cmd_prefix,
+            dir = routes_dir(&proxy.hostname),
+            site = shared_site_path(&proxy.hostname)
+        );
+        remote::run(host, &cleanup).ok();
+    }
+    remote::run(host, &reload_command(config)).ok();
+
+    if purge {
+        // Rolled files are named <service>-<timestamp>.log
+        let logs = format!("{}rm -f {} {}/{}-*.log*", cmd_prefix, access_log_path(service), CADDY_LOG_DIR, service);
+        remote::run(host, &logs).ok();
+        remote::run(host, &disable_if_unused_command(config)).ok();
+    }
+}
+
+/// Root script removing the sites of all services (the files in conf.d
+/// carrying a bsdeploy marker), their error pages, status summaries and
+/// certificates, and the managed global options, then reloading a running
+/// Caddy. Other files in conf.d and the rest of the main Caddyfile stay.
+pub fn teardown_script() -> String {
+    format!(
+        "grep -rlF -e '{managed}' -e '{generated}' {conf} 2>/dev/null | while read -r f; do rm -f \"$f\" \"$f.prev\"; done\n\
+         find {conf}/routes -depth -type d -empty -delete 2>/dev/null\n\
+         [ -f {caddyfile} ] && sed -i '' '/^{begin}$/,/^{end}$/d' {caddyfile}\n\
+         rm -rf {pages} {status} {certs}\n\
+         if service caddy status >/dev/null 2>&1; then service caddy reload; fi\n",
+        managed = MANAGED_BEGIN,
+        generated = GENERATED,
+        conf = CADDY_CONF_DIR,
+        caddyfile = CADDYFILE_PATH,
+        begin = GLOBALS_BEGIN,
+        end = GLOBALS_END,
+        pages = CADDY_PAGES_DIR,
+        status = CADDY_STATUS_DIR,
+        certs = CADDY_CERTS_DIR
+    )
+}
+
+/// Backend the service's site currently points at, if configured.
+pub fn current_backend(config: &Config, host: &str) -> Result<Option<String>> {
+    let cat_cmd = format!("cat {} 2>/dev/null || true", conf_path(config));
+    let conf = remote::query(host, &cat_cmd)?;
+    Ok(conf
+        .lines()
+        .find_map(|l| l.trim().strip_prefix("reverse_proxy "))
+        .map(|backend| backend.trim_end_matches('{').trim_end().to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::remote::mock::{self, MockExecutor};
+
+    fn proxy(extra: &str) -> ProxyConfig {
+        let yaml = format!("service: myapp\nhosts: [a]\nproxy:\n  hostname: example.com\n  port: 3000\n{}", extra);
+        Config::from_str(&yaml).unwrap().proxy.unwrap()
+    }
+
+    #[test]
+    fn test_generate_caddyfile() {
+        assert_eq!(
+            generate_caddyfile(&proxy(""), "myapp", "10.0.0.5:3000"),
+            "example.com {\n    reverse_proxy 10.0.0.5:3000\n}\n"
+        );
+        assert_eq!(
+            generate_caddyfile(&proxy("  tls: false\n"), "myapp", ":3000"),
+            "http://example.com {\n    reverse_proxy :3000\n}\n"
+        );
+
+        let conf = generate_caddyfile(
+            &proxy("  aliases: [app.example.com]\n  redirect_hosts: [www.example.com]\n"),
+            "myapp",
+            "10.0.0.5:3000",
+        );
+        assert_eq!(
+            conf,
+            "example.com, app.example.com {\n    reverse_proxy 10.0.0.5:3000\n}\n\n\
+             www.example.com {\n    redir https://example.com{uri} permanent\n}\n"
+        );
+
+        let conf = generate_caddyfile(
+            &proxy("  redirect_hosts: [www.example.com]\n  ssl:\n    certificate_pem: C\n    private_key_pem: K\n"),
+            "myapp",
+            ":3000",
+        );
+        assert_eq!(conf.matches("    tls /usr/local/etc/caddy/certs/myapp.crt").count(), 2);
+    }
+
+    #[test]
+    fn test_custom_directives() {
+        let conf = generate_caddyfile(
+            &proxy("  custom_directives:\n    - encode gzip\n    - 'header {'\n    - '    -Server'\n    - '}'\n"),
+            "myapp",
+            ":3000",
+        );
+        assert_eq!(
+            conf,
+            "example.com {\n    encode gzip\n    header {\n        -Server\n    }\n    reverse_proxy :3000\n}\n"
+        );
+        assert_eq!(
+            generate_caddyfile(&proxy("  path_prefix: /\n  custom_directives: ['request_body { max_size 10MB }']\n"), "web", ":3000"),
+            "handle {\n    request_body { max_size 10MB }\n    reverse_proxy :3000\n}\n"
+        );
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let conf = generate_caddyfile(&proxy("  rate_limit:\n    requests: 100\n    burst: 20\n"), "myapp", ":3000");
+        assert!(conf.starts_with("example.com {\n    route {\n        rate_limit {\n            zone myapp_window {\n"));
+        assert!(conf.contains("                key {remote_host}\n                events 100\n                window 60s\n"));
+        assert!(conf.contains("            zone myapp_burst {\n"));
+        assert!(conf.contains("                events 20\n                window 1s\n"));
+        assert!(conf.ends_with("        }\n        reverse_proxy :3000\n    }\n}\n"));
+    }
+
+    #[test]
+    fn test_dns_provider() {
+        let dns = proxy("  dns_provider:\n    name: cloudflare\n    api_token: CF_API_TOKEN\n  aliases: ['*.example.com']\n");
+        assert_eq!(
+            generate_caddyfile(&dns, "myapp", ":3000"),
+            "example.com, *.example.com {\n    tls {\n        \
+             dns cloudflare {file./usr/local/etc/caddy/certs/myapp.dns-token}\n    }\n    reverse_proxy :3000\n}\n"
+        );
+        assert_eq!(
+            plugins(&dns),
+            [("dns.providers.cloudflare".to_string(), "github.com/caddy-dns/cloudflare".to_string())]
+        );
+        assert!(plugins(&proxy("")).is_empty());
+    }
+
+    #[test]
+    fn test_client_auth() {
+        let auth = "  client_auth:\n    ca_certificate_pem: CLIENT_CA\n";
+        assert_eq!(
+            tls_directive(&proxy(auth), "myapp").unwrap(),
+            "    tls {\n        client_auth {\n            mode require_and_verify\n            \
+             trusted_ca_cert_file /usr/local/etc/caddy/certs/myapp.client-ca.crt\n        }\n    }\n"
+        );
+
+        let with_ssl = format!("{}    mode: verify\n  ssl:\n    certificate_pem: C\n    private_key_pem: K\n", auth);
+        let tls = tls_directive(&proxy(&with_ssl), "myapp").unwrap();
+        assert!(tls.starts_with(
+            "    tls /usr/local/etc/caddy/certs/myapp.crt /usr/local/etc/caddy/certs/myapp.key {\n        client_auth {\n"
+        ));
+        assert!(tls.contains("            mode verify_if_given\n"));
+
+        assert!(tls_directive(&proxy(""), "myapp").is_none());
+    }
+
+    #[test]
+    fn test_http_options() {
+        assert_eq!(
+            generate_caddyfile(&proxy("  hsts: { max_age: 600 }\n  https_redirect: false\n"), "myapp", ":3000"),
+            "example.com {\n    header Strict-Transport-Security \"max-age=600\"\n    reverse_proxy :3000\n}\n\n\
+             http://example.com {\n    reverse_proxy :3000\n}\n"
+        );
+
+        let conf = generate_caddyfile(&proxy("  plain_http_from: [10.0.0.0/8, 192.168.0.0/16]\n"), "myapp", ":3000");
+        assert!(conf.ends_with(
+            "http://example.com {\n    @external not remote_ip 10.0.0.0/8 192.168.0.0/16\n    \
+             redir @external https://{host}{uri} permanent\n    reverse_proxy :3000\n}\n"
+        ));
+        assert!(!generate_caddyfile(&proxy(""), "myapp", ":3000").contains("http://"));
+    }
+
+    #[test]
+    fn test_load_balancer() {
+        let lb = "  load_balancer:\n    host: lb1\n    policy: least_conn\n  healthcheck:\n    path: /up\n";
+        assert_eq!(
+            generate_caddyfile(&proxy(lb), "myapp", "10.0.1.5:3000 10.0.2.5:3000"),
+            "example.com {\n    reverse_proxy 10.0.1.5:3000 10.0.2.5:3000 {\n        lb_policy least_conn\n        \
+             health_uri /up\n        health_interval 10s\n        health_timeout 5s\n        fail_duration 30s\n        \
+             max_fails 1\n    }\n}\n"
+        );
+        assert!(
+            generate_caddyfile(&proxy("  load_balancer: { host: lb1 }\n"), "myapp", "10.0.1.5:3000")
+                .contains("    reverse_proxy 10.0.1.5:3000 {\n        lb_policy round_robin\n    }\n")
+        );
+    }
+
+    #[test]
+    fn test_health_options() {
+        let check = proxy("  healthcheck:\n    fail_duration: 0\n    path: /up\n    interval: 30\n").healthcheck.unwrap();
+        assert_eq!(health_options(&check), ["health_uri /up", "health_interval 30s", "health_timeout 5s"]);
+        let check = proxy("  healthcheck:\n    max_fails: 3\n").healthcheck.unwrap();
+        assert_eq!(health_options(&check), ["fail_duration 30s", "max_fails 3"]);
+    }
+
+    #[test]
+    fn test_transport() {
+        let transport = "  transport:\n    read_timeout: 3600\n    keepalive: 0\n    flush_interval: -1\n    stream_close_delay: 300\n";
+        assert_eq!(
+            generate_caddyfile(&proxy(transport), "myapp", ":3000"),
+            "example.com {\n    reverse_proxy :3000 {\n        flush_interval -1\n        stream_close_delay 300s\n        \
+             transport http {\n            read_timeout 3600s\n            keepalive off\n        }\n    }\n}\n"
+        );
+        let options = transport_options(&TransportConfig {
+            flush_interval: Some(250),
+            dial_timeout: Some(5),
+            keepalive: Some(120),
+            ..Default::default()
+        });
+        assert_eq!(
+            options,
+            ["flush_interval 250ms", "transport http {", "    dial_timeout 5s", "    keepalive 120s", "}"]
+        );
+    }
+
+    #[test]
+    fn test_compression() {
+        assert_eq!(
+            generate_caddyfile(&proxy("  compression: {}\n"), "myapp", ":3000"),
+            "example.com {\n    encode zstd gzip\n    reverse_proxy :3000\n}\n"
+        );
+        let compression = proxy("  compression:\n    zstd: false\n    minimum_length: 1024\n    content_types: [text/*, application/json]\n");
+        assert_eq!(
+            encode(compression.compression.as_ref().unwrap()),
+            "    encode gzip {\n        minimum_length 1024\n        match {\n            \
+             header Content-Type text/*\n            header Content-Type application/json*\n        }\n    }\n"
+        );
+    }
+
+    #[test]
+    fn test_access_log() {
+        let conf = generate_caddyfile(&proxy("  access_log:\n    roll_size_mb: 50\n"), "myapp", ":3000");
+        assert_eq!(
+            conf,
+            "example.com {\n    log {\n        output file /var/log/caddy/myapp.log {\n            roll_size 50MiB\n            \
+             roll_keep 10\n        }\n        format json\n    }\n    reverse_proxy :3000\n}\n"
+        );
+
+        let common = proxy("  access_log: { format: common }\n");
+        assert!(generate_caddyfile(&common, "myapp", ":3000").contains("        format transform \"{common_log}\"\n"));
+        assert_eq!(plugins(&common)[0].0, "caddy.logging.encoders.transform");
+        assert!(!generate_caddyfile(&proxy("  access_log: { enabled: false }\n"), "myapp", ":3000").contains("log"));
+    }
+
+    #[test]
+    fn test_status_endpoint() {
+        let yaml = "service: myapp\nhosts: [a]\nproxy:\n  hostname: example.com\n  port: 3000\n  status_endpoint: { port: 9180 }\n";
+        let config = Config::from_str(yaml).unwrap();
+        let proxy = config.proxy.as_ref().unwrap();
+        let site = status_site(proxy.status_endpoint.as_ref().unwrap(), "myapp");
+        assert!(site.starts_with("# generated by bsdeploy\nhttp://:9180 {\n    bind 127.0.0.1\n    handle /metrics {\n        metrics\n    }\n"));
+        assert!(site.contains("        rewrite * /myapp.json\n"));
+
+        let summary: serde_json::Value = serde_json::from_str(&status_summary(&config, proxy, "10.0.0.5:3000")).unwrap();
+        assert_eq!(summary["service"], "myapp");
+        assert_eq!(summary["backend"], "10.0.0.5:3000");
+    }
+
+    #[test]
+    fn test_error_pages() {
+        let conf = generate_caddyfile(
+            &proxy("  error_pages:\n    bad_gateway: 502.html\n    maintenance: 503.html\n"),
+            "myapp",
+            ":3000",
+        );
+        assert!(conf.starts_with(
+            "example.com {\n    @maintenance file {\n        root /usr/local/etc/caddy/pages/myapp\n        \
+             try_files /maintenance.on\n    }\n    error @maintenance 503\n    reverse_proxy :3000\n    handle_errors {\n"
+        ));
+        assert!(conf.contains("        @page expression `{err.status_code} in [502, 503]`\n"));
+        assert!(conf.contains("            root * /usr/local/etc/caddy/pages/myapp\n"));
+
+        let conf = generate_caddyfile(
+            &proxy("  error_pages:\n    maintenance: 503.html\n  rate_limit:\n    requests: 10\n"),
+            "myapp",
+            ":3000",
+        );
+        // Maintenance has to answer before the route proxies the request
+        assert!(conf.contains("    route {\n        @maintenance file {\n"));
+        assert!(conf.contains("in [503]`"));
+    }
+
+    #[test]
+    fn test_path_prefix_routes() {
+        let api = proxy("  path_prefix: /api\n");
+        assert_eq!(
+            generate_caddyfile(&api, "api", "10.0.0.5:3000"),
+            "redir /api /api/ 308\nhandle_path /api/* {\n    reverse_proxy 10.0.0.5:3000\n}\n"
+        );
+        assert!(generate_caddyfile(&proxy("  path_prefix: /api\n  strip_prefix: false\n"), "api", ":3000")
+            .contains("\nhandle /api/* {\n"));
+        assert_eq!(
+            generate_caddyfile(&proxy("  path_prefix: /\n"), "web", ":3000"),
+            "handle {\n    reverse_proxy :3000\n}\n"
+        );
+
+        assert_eq!(
+            shared_site(&api, "api"),
+            "# generated by bsdeploy\nexample.com {\n    import /usr/local/etc/caddy/conf.d/routes/example.com/*.caddy\n}\n"
+        );
+        let config = Config::from_str(
+            "service: api\nhosts: [a]\nproxy:\n  hostname: example.com\n  port: 3000\n  path_prefix: /api\n",
+        )
+        .unwrap();
+        assert_eq!(conf_path(&config), "/usr/local/etc/caddy/conf.d/routes/example.com/api.caddy");
+    }
+
+    #[test]
+    fn test_reload_command() {
+        let base = "service: myapp\nhosts: [a]\ndoas: true\nproxy:\n  hostname: a.example.com\n  port: 3000\n";
+        let config = Config::from_str(base).unwrap();
+        assert_eq!(reload_command(&config), "doas service caddy reload");
+
+        let config = Config::from_str(&format!("{}  admin_api: true\n", base)).unwrap();
+        let cmd = reload_command(&config);
+        assert!(cmd.starts_with("if fetch -qo /dev/null http://localhost:2019/config/"));
+        assert!(cmd.contains(
+            "doas caddy reload --config /usr/local/etc/caddy/Caddyfile --adapter caddyfile --address localhost:2019;"
+        ));
+        assert!(cmd.ends_with("doas service caddy reload; fi"));
+    }
+
+    #[test]
+    fn test_install_command() {
+        let config = Config::from_str("service: myapp\nhosts: [a]\nproxy:\n  hostname: a.example.com\n  port: 3000\n").unwrap();
+        let cmd = install_command(&config);
+        assert!(cmd.starts_with("c=/usr/local/etc/caddy/conf.d/myapp.caddy;"));
+        assert!(cmd.contains("cp -p \"$c\" \"$c.prev\""));
+        assert!(cmd.contains(
+            "if ! caddy validate --config /usr/local/etc/caddy/Caddyfile --adapter caddyfile >&2; then restore; exit 1; fi;"
+        ));
+        assert!(cmd.ends_with("if ! { service caddy reload; }; then restore; service caddy reload; exit 1; fi"));
+    }
+
+    #[test]
+    fn test_managed_sections() {
+        let generated = mark_managed(&generate_caddyfile(&proxy(""), "myapp", "10.0.0.5:3000"));
+        assert_eq!(
+            generated,
+            "# bsdeploy managed begin\nexample.com {\n    reverse_proxy 10.0.0.5:3000\n# bsdeploy managed end\n}\n"
+        );
+        let route = mark_managed(&generate_caddyfile(&proxy("  path_prefix: /api\n"), "myapp", ":3000"));
+        assert!(route.starts_with("# bsdeploy managed begin\nredir /api /api/ 308\nhandle_path /api/* {\n"));
+
+        // Manual additions survive the next deploy
+        let edited = generated.replace("\n}\n", "\n    header X-Frame-Options DENY\n}\n\n:8080 {\n    respond ok\n}\n");
+        let next = mark_managed(&generate_caddyfile(&proxy(""), "myapp", "10.0.0.6:3000"));
+        let merged = merge_managed(&edited, &next).unwrap();
+        assert_eq!(merged, edited.replace("10.0.0.5", "10.0.0.6"));
+
+        // Files from before the markers, and unedited ones, are replaced
+        assert_eq!(merge_managed("example.com {\n    reverse_proxy :3000\n}\n", &next).unwrap(), next);
+        let redirect = mark_managed(&generate_caddyfile(&proxy("  redirect_hosts: [www.example.com]\n"), "myapp", ":3000"));
+        assert_eq!(merge_managed(&generated, &redirect).unwrap(), redirect);
+        // but a changed structure is not merged into a hand-edited file
+        assert!(merge_managed(&edited, &redirect).is_err());
+    }
+
+    #[test]
+    fn test_main_caddyfile() {
+        assert_eq!(main_caddyfile("", "myapp", &[]).unwrap(), "import conf.d/*.caddy\n");
+        let custom = "# local sites\nimport conf.d/*.caddy\nlocalhost:8080 {\n    respond ok\n}\n";
+        assert_eq!(main_caddyfile(custom, "myapp", &[]).unwrap(), custom);
+
+        let globals = global_options(&proxy("  acme:\n    email: ops@example.com\n    staging: true\n"));
+        assert_eq!(
+            globals,
+            [("email", "ops@example.com".to_string()), ("acme_ca", LETSENCRYPT_STAGING.to_string())]
+        );
+        assert!(global_options(&proxy("")).is_empty());
+        let managed = main_caddyfile(custom, "myapp", &globals).unwrap();
+        assert_eq!(
+            managed,
+            format!(
+                "# bsdeploy global options begin\n{{\n    email ops@example.com # bsdeploy: myapp\n    \
+                 acme_ca {} # bsdeploy: myapp\n}}\n# bsdeploy global options end\n{}",
+                LETSENCRYPT_STAGING, custom
+            )
+        );
+        // Re-running replaces the block
+        assert_eq!(main_caddyfile(&managed, "myapp", &globals).unwrap(), managed);
+
+        // Another service without global options keeps them, one with its own adds them
+        assert_eq!(main_caddyfile(&managed, "other", &[]).unwrap(), managed);
+        let email = [("email", "ops@example.com".to_string())];
+        let shared = main_caddyfile(&managed, "other", &email).unwrap();
+        assert!(shared.contains("    email ops@example.com # bsdeploy: myapp, other\n"));
+        assert!(main_caddyfile(&managed, "other", &[("email", "dev@example.com".to_string())]).is_err());
+
+        // Dropping proxy.acme removes what only this service set
+        assert_eq!(main_caddyfile(&managed, "myapp", &[]).unwrap(), custom);
+        let left = main_caddyfile(&shared, "myapp", &[]).unwrap();
+        assert!(left.contains("{\n    email ops@example.com # bsdeploy: other\n}\n"));
+        assert!(!left.contains("acme_ca"));
+
+        // Options from before owners were recorded stay until a service sets them
+        let legacy = "# bsdeploy global options begin\n{\n    email old@example.com\n}\n# bsdeploy global options end\nimport conf.d/*.caddy\n";
+        assert_eq!(main_caddyfile(legacy, "other", &[]).unwrap(), legacy);
+        assert!(main_caddyfile(legacy, "myapp", &globals).unwrap().contains("    email ops@example.com # bsdeploy: myapp\n"));
+
+        assert!(main_caddyfile("{\n    debug\n}\nimport conf.d/*.caddy\n", "myapp", &globals).is_err());
+    }
+
+    #[test]
+    fn test_listen_ports_are_host_wide() {
+        let ports = global_options(&proxy("  http_port: 8080\n  https_port: 8443\n"));
+        assert_eq!(ports, [("http_port", "8080".to_string()), ("https_port", "8443".to_string())]);
+        let caddyfile = main_caddyfile("", "myapp", &ports).unwrap();
+        assert!(caddyfile.contains("{\n    http_port 8080 # bsdeploy: myapp\n    https_port 8443 # bsdeploy: myapp\n}\n"));
+
+        // Setting up another service on the host keeps them
+        let acme = global_options(&proxy("  acme:\n    email: ops@example.com\n"));
+        let both = main_caddyfile(&caddyfile, "other", &acme).unwrap();
+        assert!(both.contains("    http_port 8080 # bsdeploy: myapp\n    https_port 8443 # bsdeploy: myapp\n    email ops@example.com # bsdeploy: other\n"));
+
+        // ... unless it wants other ports
+        let err = main_caddyfile(&both, "other", &global_options(&proxy("  http_port: 8081\n"))).unwrap_err();
+        assert!(err.to_string().starts_with("Caddy's global option `http_port 8080` is already set by myapp on this host"));
+    }
+
+    #[test]
+    fn test_remove() {
+        let config = Config::from_str(
+            "service: myapp\nhosts: [a]\nproxy:\n  hostname: example.com\n  port: 3000\n  ssl:\n    certificate_pem: C\n    private_key_pem: K\n",
+        )
+        .unwrap();
+        let mock = MockExecutor::new();
+        mock::with_executor(mock.clone(), || remove(&config, "a", false));
+        assert!(mock.ran(
+            "rm -f /usr/local/etc/caddy/conf.d/myapp.caddy /usr/local/etc/caddy/conf.d/myapp.caddy.prev \
+             /usr/local/etc/caddy/certs/myapp.crt /usr/local/etc/caddy/certs/myapp.key"
+        ));
+        assert!(mock.ran("rm -rf /usr/local/etc/caddy/pages/myapp"));
+        assert!(!mock.ran("caddy_enable=NO"));
+
+        mock::with_executor(mock.clone(), || remove(&config, "a", true));
+        assert!(mock.ran("rm -f /var/log/caddy/myapp.log /var/log/caddy/myapp-*.log*"));
+        assert!(mock.ran("sysrc caddy_enable=NO"));
+    }
+}
@@ -0,0 +1,228 @@
+//! Reverse proxy in front of the jails: Caddy by default, or relayd
+//! (`proxy.server: relayd`). With `proxy.load_balancer` a single Caddy
+//! host proxies to the jails on all hosts instead.
+
+mod balancer;
+pub mod caddy;
+pub mod relayd;
+
+use anyhow::{Result, bail};
+use indicatif::ProgressBar;
+
+use crate::config::{Config, LoadBalancerConfig, ProxyServer, SslConfig};
+use crate::remote;
+
+fn server(config: &Config) -> ProxyServer {
+    config.proxy.as_ref().map(|p| p.server).unwrap_or_default()
+}
+
+fn server_command(server: ProxyServer) -> &'static str {
+    match server {
+        ProxyServer::Caddy => "caddy",
+        ProxyServer::Relayd => "relayd",
+    }
+}
+
+/// Command listing `<command> <jail id> <address>` for every socket
+/// listening on `ports`
+fn listeners_command(ports: (u16, u16)) -> String {
+    format!(
+        "sockstat -46l -p {},{} 2>/dev/null | awk 'NR > 1 {{print $2, $3, $6}}' | \
+         while read -r cmd pid addr; do echo \"$cmd $(ps -o jid= -p \"$pid\" 2>/dev/null | tr -d ' ') $addr\"; done",
+        ports.0, ports.1
+    )
+}
+
+/// `<address> by <command>` for each listener that is not `server` on the
+/// host itself; a proxy in a jail does not count as ours.
+fn port_conflicts(listeners: &str, server: &str) -> Vec<String> {
+    let mut conflicts: Vec<String> = Vec::new();
+    for line in listeners.lines() {
+        let [command, jid, address] = line.split_whitespace().collect::<Vec<_>>()[..] else {
+            continue;
+        };
+        let conflict = match jid {
+            "0" | "" if command == server => continue,
+            "0" | "" => format!("{} by {}", address, command),
+            jid => format!("{} by {} in jail {}", address, command, jid),
+        };
+        if !conflicts.contains(&conflict) {
+            conflicts.push(conflict);
+        }
+    }
+    conflicts
+}
+
+/// Fail when another server already holds the ports the proxy needs on
+/// `host`, before the proxy is enabled and cannot bind.
+pub fn check_ports(config: &Config, host: &str) -> Result<()> {
+    let Some(proxy) = &config.proxy else {
+        return Ok(());
+    };
+    let ports = proxy.listen_ports();
+    let server = server_command(if proxy.load_balancer.is_some() { ProxyServer::Caddy } else { proxy.server });
+    let listeners = remote::run_with_output_timeout(host, &listeners_command(ports), remote::Timeout::Query)?;
+    let conflicts = port_conflicts(&listeners, server);
+    let Some(first) = conflicts.first() else {
+        return Ok(());
+    };
+    let other = first.split(" by ").nth(1).and_then(|c| c.split_whitespace().next()).unwrap_or("server");
+    let alternative = match server {
+        "caddy" => ", or set proxy.http_port and proxy.https_port to free ports",
+        _ => "",
+    };
+    bail!(
+        "Ports {} and {} on {} are in use ({}), so {} cannot bind. Stop and disable the other server \
+         (`service {o} stop && sysrc {o}_enable=NO`){}",
+        ports.0,
+        ports.1,
+        host,
+        conflicts.join(", "),
+        server,
+        alternative,
+        o = other
+    )
+}
+
+/// Root script removing everything bsdeploy configured in either proxy,
+/// for `teardown-host`
+pub fn teardown_script() -> String {
+    caddy::teardown_script() + &relayd::teardown_script()
+}
+
+pub fn load_balancer(config: &Config) -> Option<&LoadBalancerConfig> {
+    config.proxy.as_ref().and_then(|p| p.load_balancer.as_ref())
+}
+
+/// Package providing the proxy on the app hosts, if they run one.
+pub fn package(config: &Config) -> Option<&'static str> {
+    if config.proxy.is_none() || load_balancer(config).is_some() {
+        return None;
+    }
+    match server(config) {
+        ProxyServer::Caddy => Some("caddy"),
+        ProxyServer::Relayd => Some("relayd"),
+    }
+}
+
+/// Hosts running the proxy: the load balancer, or else every host.
+pub fn hosts(config: &Config) -> Vec<String> {
+    match load_balancer(config) {
+        Some(lb) => vec![lb.host.clone()],
+        None => config.hosts.clone(),
+    }
+}
+
+/// Where the certificate from `proxy.ssl` is installed.
+pub fn certificate_path(config: &Config) -> String {
+    match server(config) {
+        ProxyServer::Caddy => caddy::certificate_path(&config.service),
+        ProxyServer::Relayd => relayd::certificate_path(&config.service),
+    }
+}
+
+/// The file holding the service's site on the app hosts, when they run
+/// Caddy themselves.
+pub fn site_path(config: &Config) -> Option<String> {
+    (package(config) == Some("caddy")).then(|| caddy::conf_path(config))
+}
+
+/// Upload the `proxy.ssl` certificates again and reload the proxy.
+pub fn push_certificates(config: &Config, host: &str, ssl: &SslConfig) -> Result<()> {
+    match server(config) {
+        ProxyServer::Caddy => caddy::push_certificates(config, host, ssl),
+        ProxyServer::Relayd => relayd::push_certificates(config, host, ssl),
+    }
+}
+
+/// Enable the proxy on the host and write the service's initial config;
+/// returns whether the proxy was (re)started. Services without a proxy and
+/// app hosts behind a load balancer have nothing to set up.
+pub fn setup(config: &Config, host: &str, spinner: &ProgressBar) -> Result<bool> {
+    let Some(proxy) = &config.proxy else {
+        return Ok(false);
+    };
+    if proxy.load_balancer.is_some() {
+        return Ok(false);
+    }
+    match proxy.server {
+        ProxyServer::Caddy => caddy::setup(config, host, Some(&format!(":{}", proxy.port)), spinner),
+        ProxyServer::Relayd => relayd::setup(config, host, spinner).map(|()| true),
+    }
+}
+
+/// Set up Caddy on the load balancer host; returns whether it was restarted.
+pub fn setup_load_balancer(config: &Config, lb: &LoadBalancerConfig, spinner: &ProgressBar) -> Result<bool> {
+    balancer::setup(config, lb, spinner)
+}
+
+/// Send the service's traffic to the jail at `jail_ip`. `overwrite`
+/// regenerates a Caddy site that was edited by hand.
+pub fn switch(config: &Config, host: &str, jail_ip: &str, overwrite: bool, spinner: &ProgressBar) -> Result<()> {
+    let Some(proxy) = &config.proxy else {
+        return Ok(());
+    };
+    let backend = format!("{}:{}", jail_ip, proxy.port);
+    if let Some(lb) = &proxy.load_balancer {
+        return balancer::switch(config, proxy, lb, host, &backend, overwrite, spinner);
+    }
+    match proxy.server {
+        ProxyServer::Caddy => caddy::switch(config, proxy, host, &backend, overwrite, spinner),
+        ProxyServer::Relayd => relayd::switch(config, proxy, host, &backend, spinner),
+    }
+}
+
+/// Remove the service from the proxy (best effort). `purge` also deletes
+/// its logs and disables the proxy when no other site is left.
+pub fn remove(config: &Config, host: &str, purge: bool) {
+    if let Some(proxy) = &config.proxy
+        && let Some(lb) = &proxy.load_balancer
+    {
+        return balancer::remove(config, proxy, lb, host, purge);
+    }
+    match server(config) {
+        ProxyServer::Caddy => caddy::remove(config, host, purge),
+        ProxyServer::Relayd => relayd::remove(config, host, purge),
+    }
+}
+
+/// Backend (`ip:port`) the service's traffic currently goes to.
+pub fn current_backend(config: &Config, host: &str) -> Result<Option<String>> {
+    if let Some(lb) = load_balancer(config) {
+        return balancer::current_backend(config, lb, host);
+    }
+    match server(config) {
+        ProxyServer::Caddy => caddy::current_backend(config, host),
+        ProxyServer::Relayd => relayd::current_backend(config, host),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::remote::mock::{self, MockExecutor};
+
+    #[test]
+    fn test_port_conflicts() {
+        let listeners = "caddy 0 *:443\ncaddy 0 *:443\nnginx 0 *:80\nnginx 0 *:80\ncaddy 4 10.0.0.2:443\n";
+        assert_eq!(port_conflicts(listeners, "caddy"), ["*:80 by nginx", "10.0.0.2:443 by caddy in jail 4"]);
+        assert!(port_conflicts("caddy 0 *:80\n", "caddy").is_empty());
+        assert_eq!(port_conflicts("caddy 0 *:80\n", "relayd"), ["*:80 by caddy"]);
+    }
+
+    #[test]
+    fn test_check_ports() {
+        let config = Config::from_str("service: app\nhosts: [web1]\nproxy: { hostname: app.example.com, port: 3000, http_port: 8080 }\n").unwrap();
+        let mock = MockExecutor::new();
+        mock.respond("sockstat -46l -p 8080,443", "nginx 0 *:443\n");
+        let err = mock::with_executor(mock.clone(), || check_ports(&config, "web1")).unwrap_err().to_string();
+        assert_eq!(
+            err,
+            "Ports 8080 and 443 on web1 are in use (*:443 by nginx), so caddy cannot bind. Stop and disable the other \
+             server (`service nginx stop && sysrc nginx_enable=NO`), or set proxy.http_port and proxy.https_port to free ports"
+        );
+
+        let mock = MockExecutor::new();
+        mock::with_executor(mock.clone(), || check_ports(&config, "web1")).unwrap();
+    }
+}
@@ -0,0 +1,288 @@
+//! relayd reverse proxy. relayd cannot share a listening port between
+//! separate relays, so every service on a host goes into one generated
+//! configuration: a relay per port that picks the backend table by Host
+//! header. Each service records its site in its config directory and the
+//! whole file is regenerated from those records.
+
+use anyhow::{Context, Result};
+use indicatif::ProgressBar;
+
+use crate::config::{Config, ProxyConfig, SslConfig};
+use crate::constants::{CONFIG_DIR, RELAYD_BSDEPLOY_CONF, RELAYD_CERT_DIR, RELAYD_CONF_PATH, RELAYD_KEY_DIR};
+use crate::{remote, shell};
+
+/// One service's entry in the generated configuration
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Site {
+    service: String,
+    hostname: String,
+    /// `ip:port` traffic is forwarded to
+    backend: String,
+    tls: bool,
+}
+
+impl Site {
+    fn new(config: &Config, proxy: &ProxyConfig, backend: &str) -> Self {
+        Self {
+            service: config.service.clone(),
+            hostname: proxy.hostname.clone(),
+            backend: backend.to_string(),
+            tls: proxy.ssl.is_some(),
+        }
+    }
+
+    fn to_line(&self) -> String {
+        format!("{} {} {} {}\n", self.service, self.hostname, self.backend, if self.tls { "tls" } else { "plain" })
+    }
+
+    fn parse(line: &str) -> Option<Self> {
+        let mut fields = line.split_whitespace();
+        let site = Self {
+            service: fields.next()?.to_string(),
+            hostname: fields.next()?.to_string(),
+            backend: fields.next()?.to_string(),
+            tls: fields.next()? == "tls",
+        };
+        Some(site)
+    }
+}
+
+fn site_path(service: &str) -> String {
+    format!("{}/{}/relayd.site", CONFIG_DIR, service)
+}
+
+/// relayd configuration for all sites on a host.
+fn generate_conf(sites: &[Site]) -> String {
+    let mut conf = String::from("# Generated by bsdeploy - do not edit\n\n");
+    for site in sites {
+        let (ip, _) = site.backend.rsplit_once(':').unwrap_or((&site.backend, ""));
+        conf.push_str(&format!("table <{}> {{ {} }}\n", site.service, ip));
+    }
+
+    for (name, port, tls) in [("bsdeploy_http", 80, false), ("bsdeploy_https", 443, true)] {
+        let sites: Vec<&Site> = sites.iter().filter(|s| s.tls == tls).collect();
+        if sites.is_empty() {
+            continue;
+        }
+
+        conf.push_str(&format!("\nhttp protocol \"{}\" {{\n", name));
+        conf.push_str("    match request header append \"X-Forwarded-For\" value \"$REMOTE_ADDR\"\n");
+        conf.push_str(&format!(
+            "    match request header set \"X-Forwarded-Proto\" value \"{}\"\n",
+            if tls { "https" } else { "http" }
+        ));
+        for site in &sites {
+            if tls {
+                conf.push_str(&format!("    tls keypair \"{}\"\n", site.service));
+            }
+        }
+        conf.push_str("    block\n");
+        for site in &sites {
+            conf.push_str(&format!(
+                "    pass request header \"Host\" value \"{}\" forward to <{}>\n",
+                site.hostname, site.service
+            ));
+        }
+        conf.push_str("}\n");
+
+        conf.push_str(&format!("\nrelay \"{}\" {{\n", name));
+        conf.push_str(&format!("    listen on 0.0.0.0 port {}{}\n", port, if tls { " tls" } else { "" }));
+        conf.push_str(&format!("    protocol \"{}\"\n", name));
+        for site in &sites {
+            let (_, backend_port) = site.backend.rsplit_once(':').unwrap_or(("", "80"));
+            conf.push_str(&format!("    forward to <{}> port {}\n", site.service, backend_port));
+        }
+        conf.push_str("}\n");
+    }
+    conf
+}
+
+pub fn certificate_path(service: &str) -> String {
+    format!("{}/{}.crt", RELAYD_CERT_DIR, service)
+}
+
+fn key_path(service: &str) -> String {
+    format!("{}/{}.key", RELAYD_KEY_DIR, service)
+}
+
+/// Write the service's certificate where `tls keypair` finds it.
+fn write_keypair(config: &Config, host: &str, ssl: &SslConfig) -> Result<()> {
+    let cert = std::env::var(&ssl.certificate_pem)
+        .with_context(|| format!("Missing SSL certificate environment variable: {}", ssl.certificate_pem))?;
+    let key = std::env::var(&ssl.private_key_pem)
+        .with_context(|| format!("Missing SSL private key environment variable: {}", ssl.private_key_pem))?;
+
+    let cert_path = certificate_path(&config.service);
+    let key_path = key_path(&config.service);
+    remote::ensure_dir(host, RELAYD_KEY_DIR, config.doas)?;
+    remote::upload_bytes(host, cert.as_bytes(), &cert_path, 0o644, Some("root:wheel"), config.doas)?;
+    remote::upload_bytes(host, key.as_bytes(), &key_path, 0o600, Some("root:wheel"), config.doas)?;
+    Ok(())
+}
+
+/// Regenerate the configuration from all recorded sites, check it with
+/// `relayd -n` and reload. A configuration relayd rejects is rolled back.
+fn apply(config: &Config, host: &str) -> Result<()> {
+    let p = shell::escalation_prefix(config.doas);
+    let records = remote::query(host, &format!("cat {}/*/relayd.site 2>/dev/null || true", CONFIG_DIR))?;
+    let sites: Vec<Site> = records.lines().filter_map(Site::parse).collect();
+
+    let conf = RELAYD_BSDEPLOY_CONF;
+    if sites.is_empty() {
+        // relayd refuses to run without relays
+        remote::write_file(host, &generate_conf(&sites), conf, config.doas)?;
+        return remote::run(host, &format!("{}service relayd stop >/dev/null 2>&1; true", p));
+    }
+    remote::write_file(host, &generate_conf(&sites), &format!("{}.new", conf), config.doas)?;
+    remote::run(
+        host,
+        &format!(
+            "if [ -f {c} ]; then {p}cp -p {c} {c}.prev; else {p}rm -f {c}.prev; fi; {p}mv {c}.new {c}; \
+             if ! {p}relayd -n -f {main} >&2; then \
+             if [ -f {c}.prev ]; then {p}mv {c}.prev {c}; else {p}rm -f {c}; fi; exit 1; fi",
+            c = conf,
+            p = p,
+            main = RELAYD_CONF_PATH
+        ),
+    )
+    .context("relayd rejected the generated configuration; the previous one was kept")?;
+
+    remote::run(
+        host,
+        &format!(
+            "if {p}service relayd status >/dev/null 2>&1; then {p}relayctl reload; else {p}service relayd start; fi",
+            p = p
+        ),
+    )
+}
+
+/// Enable relayd, include the generated configuration from the main file
+/// and record the service's site (pointing at the port on the host until
+/// the first deploy).
+pub fn setup(config: &Config, host: &str, spinner: &ProgressBar) -> Result<()> {
+    spinner.set_message(format!("[{}] Configuring relayd...", host));
+    let p = shell::escalation_prefix(config.doas);
+
+    remote::run(host, &format!("{}sysrc relayd_enable=YES", p))?;
+    remote::ensure_dir(host, RELAYD_BSDEPLOY_CONF.rsplit_once('/').map_or("/", |(dir, _)| dir), config.doas)?;
+
+    let include = format!("include \"{}\"", RELAYD_BSDEPLOY_CONF);
+    remote::run(
+        host,
+        &format!(
+            "grep -qxF {inc} {main} 2>/dev/null || echo {inc} | {p}tee -a {main} >/dev/null",
+            inc = shell::escape(&include),
+            main = RELAYD_CONF_PATH,
+            p = p
+        ),
+    )?;
+
+    if let Some(proxy) = &config.proxy {
+        if let Some(ssl) = &proxy.ssl {
+            spinner.set_message(format!("[{}] Writing TLS certificates...", host));
+            write_keypair(config, host, ssl)?;
+        }
+        let site = Site::new(config, proxy, &format!("127.0.0.1:{}", proxy.port));
+        remote::write_file(host, &site.to_line(), &site_path(&config.service), config.doas)?;
+    }
+
+    apply(config, host)
+}
+
+/// Route the service's hostname to `backend` and reload relayd.
+pub fn switch(config: &Config, proxy: &ProxyConfig, host: &str, backend: &str, spinner: &ProgressBar) -> Result<()> {
+    if let Some(ssl) = &proxy.ssl {
+        spinner.set_message(format!("[{}] Updating TLS certificates...", host));
+        write_keypair(config, host, ssl)?;
+    }
+    let site = Site::new(config, proxy, backend);
+    remote::write_file(host, &site.to_line(), &site_path(&config.service), config.doas)?;
+    apply(config, host)
+}
+
+/// Upload the certificates again and reload relayd, which reads the
+/// keypairs on reload.
+pub fn push_certificates(config: &Config, host: &str, ssl: &SslConfig) -> Result<()> {
+    write_keypair(config, host, ssl)?;
+    apply(config, host)
+}
+
+/// Root script dropping the generated configuration and its include from
+/// relayd.conf, for `teardown-host`. relayd is stopped if it cannot run
+/// without them.
+pub fn teardown_script() -> String {
+    format!(
+        "if [ -f {main} ]; then sed -i '' '\\|^include \"{conf}\"$|d' {main}; fi\n\
+         rm -f {conf} {conf}.prev {conf}.new\n\
+         if service relayd status >/dev/null 2>&1; then service relayd reload || service relayd stop; fi\n",
+        main = RELAYD_CONF_PATH,
+        conf = RELAYD_BSDEPLOY_CONF
+    )
+}
+
+/// Drop the service's site and keypair and reload relayd (best effort).
+/// relayd is stopped once no site is left; `purge` also disables it.
+pub fn remove(config: &Config, host: &str, purge: bool) {
+    let p = shell::escalation_prefix(config.doas);
+    let files = [
+        site_path(&config.service),
+        certificate_path(&config.service),
+        key_path(&config.service),
+    ];
+    remote::run(host, &format!("{}rm -f {}", p, files.join(" "))).ok();
+    apply(config, host).ok();
+
+    if purge {
+        let disable = format!(
+            "ls {}/*/relayd.site >/dev/null 2>&1 || {}sysrc relayd_enable=NO >/dev/null",
+            CONFIG_DIR, p
+        );
+        remote::run(host, &disable).ok();
+    }
+}
+
+/// Backend the service's site currently points at, if configured.
+pub fn current_backend(config: &Config, host: &str) -> Result<Option<String>> {
+    let record = remote::query(host, &format!("cat {} 2>/dev/null || true", site_path(&config.service)))?;
+    Ok(record.lines().find_map(Site::parse).map(|site| site.backend))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn site(service: &str, hostname: &str, backend: &str, tls: bool) -> Site {
+        Site {
+            service: service.to_string(),
+            hostname: hostname.to_string(),
+            backend: backend.to_string(),
+            tls,
+        }
+    }
+
+    #[test]
+    fn test_site_roundtrip() {
+        let s = site("myapp", "myapp.example.com", "10.0.0.5:3000", true);
+        assert_eq!(s.to_line(), "myapp myapp.example.com 10.0.0.5:3000 tls\n");
+        assert_eq!(Site::parse(&s.to_line()), Some(s));
+        assert_eq!(Site::parse("garbage"), None);
+    }
+
+    #[test]
+    fn test_generate_conf() {
+        let conf = generate_conf(&[
+            site("web", "web.example.com", "10.0.0.5:3000", false),
+            site("api", "api.example.com", "10.0.0.6:8080", true),
+        ]);
+        assert!(conf.contains("table <web> { 10.0.0.5 }\ntable <api> { 10.0.0.6 }\n"));
+        assert!(conf.contains("    pass request header \"Host\" value \"web.example.com\" forward to <web>\n"));
+        assert!(conf.contains(
+            "relay \"bsdeploy_http\" {\n    listen on 0.0.0.0 port 80\n    protocol \"bsdeploy_http\"\n    forward to <web> port 3000\n}\n"
+        ));
+        assert!(conf.contains("    tls keypair \"api\"\n"));
+        assert!(conf.contains("    listen on 0.0.0.0 port 443 tls\n"));
+        assert!(conf.contains("    forward to <api> port 8080\n"));
+
+        assert!(!generate_conf(&[]).contains("relay"));
+    }
+}
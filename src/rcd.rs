@@ -1,61 +1,62 @@
 use anyhow::Result;
 
+use crate::config::Config;
 use crate::constants::ACTIVE_DIR;
-use crate::remote;
+use crate::{remote, shell};
 
-/// RC.D script for bsdeploy boot persistence
-const RCD_SCRIPT: &str = r#"#!/bin/sh
+/// Bumped whenever the rc.d scripts change, so `setup` and `deploy`
+/// replace older ones on the hosts
+pub const RCD_VERSION: &str = "4";
 
-# PROVIDE: bsdeploy
-# REQUIRE: NETWORKING
-# BEFORE: caddy
-# KEYWORD: shutdown
-
-. /etc/rc.subr
+/// Line of the rc.d scripts carrying `RCD_VERSION`
+const VERSION_MARKER: &str = "# bsdeploy-rcd-version: ";
 
-name="bsdeploy"
-rcvar="bsdeploy_enable"
-start_cmd="${name}_start"
-stop_cmd="${name}_stop"
-status_cmd="${name}_status"
-restart_cmd="${name}_restart"
-extra_commands="status"
-
-ACTIVE_DIR="/usr/local/bsdeploy/active"
+/// Shell functions starting, stopping and showing one service from its
+/// active jail's metadata, shared by the rc.d scripts
+const FUNCTIONS: &str = r#"ACTIVE_DIR="/usr/local/bsdeploy/active"
 JAILS_DIR="/usr/local/bsdeploy/jails"
 BASE_DIR="/usr/local/bsdeploy/base"
 JQ="/usr/local/bin/jq"
 
-bsdeploy_start()
+# Start the active jail of a service, given its link in $ACTIVE_DIR
+bsdeploy_start_service()
 {
-    echo "Starting bsdeploy jails..."
-
-    # Ensure lo1 exists
-    if ! ifconfig lo1 > /dev/null 2>&1; then
-        ifconfig lo1 create
+    local link="$1"
+    [ -L "$link" ] || return 0
+
+    local jail_path=$(readlink -f "$link")
+    [ -d "$jail_path" ] || return 0
+
+    local metadata="$jail_path/.bsdeploy.json"
+    [ -f "$metadata" ] || return 0
+
+    # Parse metadata using jq
+    local jail_name=$($JQ -r '.jail_name' "$metadata")
+    local ip=$($JQ -r '.ip' "$metadata")
+    local service=$($JQ -r '.service' "$metadata")
+    local user=$($JQ -r '.user // empty' "$metadata")
+    local base_version=$($JQ -r '.base_version' "$metadata")
+    local image_path=$($JQ -r '.image_path // empty' "$metadata")
+    local is_zfs=$($JQ -r '.zfs' "$metadata")
+
+    # Started already, e.g. as a dependency of another service
+    if jls -j "$jail_name" > /dev/null 2>&1; then
+        echo "  $service is already running ($jail_name)"
+        return 0
     fi
 
-    # Iterate over active services
-    for link in "$ACTIVE_DIR"/*; do
-        [ -L "$link" ] || continue
-
-        jail_path=$(readlink -f "$link")
-        [ -d "$jail_path" ] || continue
+    # 0. Start the services this one depends on, and wait for them
+    bsdeploy_start_dependencies "$metadata" "$service"
 
-        metadata="$jail_path/.bsdeploy.json"
-        [ -f "$metadata" ] || continue
+    echo "  Starting $service ($jail_name)..."
 
-        # Parse metadata using jq
-        jail_name=$($JQ -r '.jail_name' "$metadata")
-        ip=$($JQ -r '.ip' "$metadata")
-        service=$($JQ -r '.service' "$metadata")
-        user=$($JQ -r '.user // empty' "$metadata")
-        base_version=$($JQ -r '.base_version' "$metadata")
-        image_path=$($JQ -r '.image_path // empty' "$metadata")
-        is_zfs=$($JQ -r '.zfs' "$metadata")
-
-        echo "  Starting $service ($jail_name)..."
+    # The output of the steps below is also kept in the host's log
+    # directory of the service, rotated by its newsyslog entry
+    local boot_log="/var/log/bsdeploy/$service/boot.log"
+    mkdir -p "/var/log/bsdeploy/$service"
+    echo "$(date '+%Y-%m-%dT%H:%M:%S') starting $jail_name" >> "$boot_log"
 
+    {
         # 1. Add IP alias to lo1
         if [ -n "$ip" ]; then
             ifconfig lo1 inet "$ip/32" alias 2>/dev/null
@@ -68,11 +69,74 @@ bsdeploy_start()
         jail -c name="$jail_name" path="$jail_path" host.hostname="$jail_name" \
             ip4.addr="$ip" allow.raw_sockets=1 persist
 
-        # 4. Start application processes
+        # 4. Start syslogd for forwarding, before the processes log
+        if [ "$($JQ -r '.syslogd // false' "$metadata")" = "true" ]; then
+            jexec "$jail_name" /usr/sbin/syslogd -s
+        fi
+
+        # 5. Start application processes
         bsdeploy_start_processes "$metadata" "$jail_name" "$service" "$user"
+
+        # 6. Restore the port redirects of services without a proxy
+        local rdr="/usr/local/etc/bsdeploy/$service/pf.rdr"
+        if [ -f "$rdr" ]; then
+            pfctl -a "bsdeploy/$service" -f "$rdr" 2>/dev/null
+        fi
+    } 2>&1 | tee -a "$boot_log"
+}
+
+# Start the services listed in the metadata's depends_on and wait until
+# they are healthy, as deploys expect them to be. A service that does not
+# come up in time is reported, and the dependent service started anyway.
+bsdeploy_start_dependencies()
+{
+    local metadata="$1"
+    local service="$2"
+    local wait=$($JQ -r '.depends_wait // 60' "$metadata")
+    local dep
+    # The services being started further up, to catch circular dependencies
+    local BSDEPLOY_STARTING="$BSDEPLOY_STARTING $service "
+
+    for dep in $($JQ -r '.depends_on[]?' "$metadata"); do
+        case "$BSDEPLOY_STARTING" in
+            *" $dep "*)
+                echo "  $service and $dep depend on each other"
+                continue
+                ;;
+        esac
+        if [ ! -L "$ACTIVE_DIR/$dep" ]; then
+            echo "  $service depends on $dep, which is not deployed"
+            continue
+        fi
+        bsdeploy_start_service "$ACTIVE_DIR/$dep"
+        if ! bsdeploy_wait_healthy "$ACTIVE_DIR/$dep" "$wait"; then
+            echo "  $dep is not healthy after ${wait}s; starting $service anyway"
+        fi
     done
 }
 
+# Wait up to $2 seconds until the active jail of a service runs and
+# accepts connections on its health_port, if it recorded one
+bsdeploy_wait_healthy()
+{
+    local metadata="$(readlink -f "$1")/.bsdeploy.json"
+    local wait="$2"
+    local jail_name=$($JQ -r '.jail_name' "$metadata")
+    local ip=$($JQ -r '.ip' "$metadata")
+    local port=$($JQ -r '.health_port // empty' "$metadata")
+    local waited=0
+
+    while [ $waited -lt $wait ]; do
+        if jls -j "$jail_name" > /dev/null 2>&1 &&
+            { [ -z "$port" ] || nc -z -w 1 "$ip" "$port" > /dev/null 2>&1; }; then
+            return 0
+        fi
+        sleep 1
+        waited=$((waited + 1))
+    done
+    return 1
+}
+
 bsdeploy_mount_jail()
 {
     local jail_path="$1"
@@ -128,12 +192,22 @@ bsdeploy_start_processes()
     local run_dir="/var/run/bsdeploy/$service"
     local log_dir="/var/log/bsdeploy/$service"
 
+    # Jails deployed with the daemon command lines recorded
+    if $JQ -e '.processes' "$metadata" > /dev/null 2>&1; then
+        $JQ -r '.processes[]' "$metadata" | while IFS= read -r daemon_cmd; do
+            jexec "$jail_name" sh -c "$daemon_cmd"
+        done
+        return
+    fi
+
     local idx=0
     $JQ -r '.start_commands[]' "$metadata" 2>/dev/null | while read start_cmd; do
         [ -z "$start_cmd" ] && continue
 
-        local pid_file="$run_dir/service.pid"
-        local log_file="$log_dir/service.log"
+        local name="service"
+        [ $idx -gt 0 ] && name="service-$idx"
+        local pid_file="$run_dir/$name.pid"
+        local log_file="$log_dir/$name.log"
 
         # Build daemon command
         local daemon_cmd="daemon -f -p $pid_file -o $log_file"
@@ -148,37 +222,104 @@ bsdeploy_start_processes()
     done
 }
 
-bsdeploy_stop()
+# Stop the active jail of a service, given its link in $ACTIVE_DIR
+bsdeploy_stop_service()
 {
-    echo "Stopping bsdeploy jails..."
+    local link="$1"
+    [ -L "$link" ] || return 0
 
-    for link in "$ACTIVE_DIR"/*; do
-        [ -L "$link" ] || continue
+    local jail_path=$(readlink -f "$link")
+    [ -d "$jail_path" ] || return 0
 
-        jail_path=$(readlink -f "$link")
-        [ -d "$jail_path" ] || continue
+    local metadata="$jail_path/.bsdeploy.json"
+    [ -f "$metadata" ] || return 0
 
-        metadata="$jail_path/.bsdeploy.json"
-        [ -f "$metadata" ] || continue
+    local jail_name=$($JQ -r '.jail_name' "$metadata")
+    local ip=$($JQ -r '.ip' "$metadata")
+    local service=$($JQ -r '.service' "$metadata")
 
-        jail_name=$($JQ -r '.jail_name' "$metadata")
-        ip=$($JQ -r '.ip' "$metadata")
-        service=$($JQ -r '.service' "$metadata")
+    echo "  Stopping $service ($jail_name)..."
 
-        echo "  Stopping $service ($jail_name)..."
+    # Stop jail (this also stops all processes inside)
+    jail -r "$jail_name" 2>/dev/null
 
-        # Stop jail (this also stops all processes inside)
-        jail -r "$jail_name" 2>/dev/null
+    # Remove IP alias
+    if [ -n "$ip" ]; then
+        ifconfig lo1 inet "$ip" -alias 2>/dev/null
+    fi
 
-        # Remove IP alias
-        if [ -n "$ip" ]; then
-            ifconfig lo1 inet "$ip" -alias 2>/dev/null
-        fi
+    # Unmount filesystems
+    for mnt in $(mount | grep "$jail_path" | awk '{print $3}' | sort -r); do
+        umount -f "$mnt" 2>/dev/null
+    done
+}
 
-        # Unmount filesystems
-        for mnt in $(mount | grep "$jail_path" | awk '{print $3}' | sort -r); do
-            umount -f "$mnt" 2>/dev/null
-        done
+bsdeploy_status_service()
+{
+    local link="$1"
+    local service=$(basename "$link")
+    local jail_path=$(readlink -f "$link")
+
+    if [ ! -d "$jail_path" ]; then
+        echo "  $service: BROKEN (symlink points to non-existent path)"
+        return
+    fi
+
+    local metadata="$jail_path/.bsdeploy.json"
+    if [ ! -f "$metadata" ]; then
+        echo "  $service: BROKEN (missing metadata)"
+        return
+    fi
+
+    local jail_name=$($JQ -r '.jail_name' "$metadata")
+
+    if jls -j "$jail_name" > /dev/null 2>&1; then
+        local ip=$(jls -j "$jail_name" ip4.addr 2>/dev/null)
+        echo "  $service: RUNNING ($jail_name, IP: $ip)"
+    else
+        echo "  $service: STOPPED ($jail_name)"
+    fi
+}
+"#;
+
+/// Host part of the boot persistence: lo1 and the NAT, and the services
+/// deployed before they got their own rc.d script.
+const HOST_SCRIPT: &str = r#"
+bsdeploy_start()
+{
+    echo "Starting bsdeploy jails..."
+
+    # Ensure lo1 exists
+    if ! ifconfig lo1 > /dev/null 2>&1; then
+        ifconfig lo1 create
+    fi
+
+    # NAT for the jail network, in case pf.conf does not load it
+    if [ -f /usr/local/etc/bsdeploy/pf.nat ]; then
+        pfctl -a "bsdeploy/_nat" -f /usr/local/etc/bsdeploy/pf.nat 2>/dev/null
+    fi
+    # Inbound filter of host.firewall
+    if [ -f /usr/local/etc/bsdeploy/pf.firewall ]; then
+        pfctl -a "bsdeploy/_firewall" -f /usr/local/etc/bsdeploy/pf.firewall 2>/dev/null
+    fi
+
+    for link in "$ACTIVE_DIR"/*; do
+        bsdeploy_has_own_script "$link" || bsdeploy_start_service "$link"
+    done
+}
+
+# Services with their own rc.d script are started and stopped by it
+bsdeploy_has_own_script()
+{
+    [ -x "/usr/local/etc/rc.d/bsdeploy_$(basename "$1" | tr - _)" ]
+}
+
+bsdeploy_stop()
+{
+    echo "Stopping bsdeploy jails..."
+
+    for link in "$ACTIVE_DIR"/*; do
+        bsdeploy_has_own_script "$link" || bsdeploy_stop_service "$link"
     done
 }
 
@@ -193,29 +334,7 @@ bsdeploy_status()
 
     for link in "$ACTIVE_DIR"/*; do
         [ -L "$link" ] || continue
-
-        service=$(basename "$link")
-        jail_path=$(readlink -f "$link")
-
-        if [ ! -d "$jail_path" ]; then
-            echo "  $service: BROKEN (symlink points to non-existent path)"
-            continue
-        fi
-
-        metadata="$jail_path/.bsdeploy.json"
-        if [ ! -f "$metadata" ]; then
-            echo "  $service: BROKEN (missing metadata)"
-            continue
-        fi
-
-        jail_name=$($JQ -r '.jail_name' "$metadata")
-
-        if jls -j "$jail_name" > /dev/null 2>&1; then
-            ip=$(jls -j "$jail_name" ip4.addr 2>/dev/null)
-            echo "  $service: RUNNING ($jail_name, IP: $ip)"
-        else
-            echo "  $service: STOPPED ($jail_name)"
-        fi
+        bsdeploy_status_service "$link"
     done
 }
 
@@ -224,37 +343,198 @@ bsdeploy_restart()
     bsdeploy_stop
     bsdeploy_start
 }
+"#;
+
+/// rc.d script for bsdeploy boot persistence on the host
+pub fn host_script() -> String {
+    format!(
+        r#"#!/bin/sh
+
+# PROVIDE: bsdeploy
+# REQUIRE: NETWORKING
+# BEFORE: caddy relayd
+# KEYWORD: shutdown
+{}{}
+
+. /etc/rc.subr
 
+name="bsdeploy"
+rcvar="bsdeploy_enable"
+start_cmd="${{name}}_start"
+stop_cmd="${{name}}_stop"
+status_cmd="${{name}}_status"
+restart_cmd="${{name}}_restart"
+extra_commands="status"
+
+{}{}
 load_rc_config $name
 run_rc_command "$1"
-"#;
+"#,
+        VERSION_MARKER, RCD_VERSION, FUNCTIONS, HOST_SCRIPT
+    )
+}
+
+/// rc.d name of the service's own script, e.g. `bsdeploy_my_app`
+pub fn rc_name(service: &str) -> String {
+    format!("bsdeploy_{}", service.replace('-', "_"))
+}
+
+pub fn service_script_path(service: &str) -> String {
+    format!("/usr/local/etc/rc.d/{}", rc_name(service))
+}
+
+/// rc.d script starting and stopping only this service, ordered after
+/// the host script, the scripts of `boot.services` and `boot.require`.
+pub fn service_script(config: &Config) -> String {
+    let name = rc_name(&config.service);
+    let require: Vec<String> = std::iter::once("bsdeploy".to_string())
+        .chain(config.boot.services.iter().map(|s| rc_name(s)))
+        .chain(config.boot.require.iter().cloned())
+        .collect();
+    let before: Vec<&str> = ["caddy", "relayd"].into_iter().chain(config.boot.before.iter().map(String::as_str)).collect();
+    format!(
+        r#"#!/bin/sh
+
+# PROVIDE: {name}
+# REQUIRE: {require}
+# BEFORE: {before}
+# KEYWORD: shutdown
+{marker}{version}
+
+. /etc/rc.subr
+
+name="{name}"
+rcvar="{name}_enable"
+start_cmd="{name}_start"
+stop_cmd="{name}_stop"
+status_cmd="{name}_status"
+extra_commands="status"
+
+{functions}
+{name}_start()
+{{
+    bsdeploy_start_service "$ACTIVE_DIR/{service}"
+}}
+
+{name}_stop()
+{{
+    bsdeploy_stop_service "$ACTIVE_DIR/{service}"
+}}
+
+{name}_status()
+{{
+    bsdeploy_status_service "$ACTIVE_DIR/{service}"
+}}
+
+load_rc_config $name
+run_rc_command "$1"
+"#,
+        name = name,
+        require = require.join(" "),
+        before = before.join(" "),
+        functions = FUNCTIONS,
+        service = config.service,
+        marker = VERSION_MARKER,
+        version = RCD_VERSION
+    )
+}
+
+pub const RCD_PATH: &str = "/usr/local/etc/rc.d/bsdeploy";
 
 /// Install the rc.d script on the remote host
 pub fn install_rcd_script(host: &str, doas: bool) -> Result<()> {
-    let rcd_path = "/usr/local/etc/rc.d/bsdeploy";
-
     // Write the rc.d script
-    remote::write_file(host, RCD_SCRIPT, rcd_path, doas)?;
+    remote::write_file(host, &host_script(), RCD_PATH, doas)?;
 
     // Make it executable
-    let cmd_prefix = if doas { "doas " } else { "" };
-    remote::run(host, &format!("{}chmod +x {}", cmd_prefix, rcd_path))?;
+    let cmd_prefix = shell::escalation_prefix(doas);
+    remote::run(host, &format!("{}chmod +x {}", cmd_prefix, RCD_PATH))?;
 
     Ok(())
 }
 
 /// Enable the bsdeploy service to start on boot
 pub fn enable_service(host: &str, doas: bool) -> Result<()> {
-    let cmd_prefix = if doas { "doas " } else { "" };
+    let cmd_prefix = shell::escalation_prefix(doas);
     remote::run(host, &format!("{}sysrc bsdeploy_enable=YES", cmd_prefix))?;
     Ok(())
 }
 
+/// Install the service's own rc.d script, enabling it unless the
+/// operator already set `<rc name>_enable`.
+pub fn install_service_script(config: &Config, host: &str) -> Result<()> {
+    let p = shell::escalation_prefix(config.doas);
+    let path = service_script_path(&config.service);
+    remote::write_file(host, &service_script(config), &path, config.doas)?;
+    remote::run(host, &format!("{}chmod +x {}", p, path))?;
+    remote::run(
+        host,
+        &format!("[ -n \"$(sysrc -qn {rc}_enable)\" ] || {p}sysrc {rc}_enable=YES", rc = rc_name(&config.service), p = p),
+    )
+}
+
+/// Remove the service's rc.d script and its rc.conf settings (best effort).
+pub fn remove_service_script(config: &Config, host: &str) {
+    let p = shell::escalation_prefix(config.doas);
+    remote::run(
+        host,
+        &format!(
+            "{p}rm -f {path}; {p}sysrc -x {rc}_enable 2>/dev/null; true",
+            p = p,
+            path = service_script_path(&config.service),
+            rc = rc_name(&config.service)
+        ),
+    )
+    .ok();
+}
+
+/// Prints the version of the host script and of the service's script,
+/// one per line: `0` for scripts from before versioning, `missing` when
+/// not installed.
+pub fn versions_command(config: &Config) -> String {
+    format!(
+        "for f in {} {}; do if [ -f \"$f\" ]; then v=$(sed -n 's/^{}//p' \"$f\"); echo \"${{v:-0}}\"; else echo missing; fi; done",
+        RCD_PATH,
+        service_script_path(&config.service),
+        VERSION_MARKER
+    )
+}
+
+/// The older of the installed versions, if either script is not the
+/// current one. `None` also when the host has no scripts at all: it has
+/// not been set up.
+pub fn outdated(output: &str) -> Option<String> {
+    let mut versions = output.lines().map(str::trim);
+    let (host, service) = (versions.next().unwrap_or("missing"), versions.next().unwrap_or("missing"));
+    if host == "missing" {
+        return None;
+    }
+    [host, service].into_iter().find(|v| *v != RCD_VERSION).map(str::to_string)
+}
+
+/// How `outdated` versions read in messages
+pub fn describe_version(version: &str) -> String {
+    match version {
+        "missing" => "missing".to_string(),
+        "0" => "unversioned".to_string(),
+        v => format!("version {}", v),
+    }
+}
+
+/// Replace rc.d scripts older than this version of bsdeploy. Returns the
+/// version replaced, if any.
+pub fn ensure_current(config: &Config, host: &str) -> Result<Option<String>> {
+    let Some(old) = outdated(&remote::query(host, &versions_command(config))?) else {
+        return Ok(None);
+    };
+    install_rcd_script(host, config.doas)?;
+    install_service_script(config, host)?;
+    Ok(Some(old))
+}
+
 /// Create the active directory for symlinks
 pub fn ensure_active_dir(host: &str, doas: bool) -> Result<()> {
-    let cmd_prefix = if doas { "doas " } else { "" };
-    remote::run(host, &format!("{}mkdir -p {}", cmd_prefix, ACTIVE_DIR))?;
-    Ok(())
+    remote::ensure_dir(host, ACTIVE_DIR, doas)
 }
 
 #[cfg(test)]
@@ -264,77 +544,128 @@ mod tests {
     #[test]
     fn test_rcd_script_has_required_sections() {
         // Test that the rc.d script has all required FreeBSD rc.d components
-        assert!(RCD_SCRIPT.contains("# PROVIDE: bsdeploy"));
-        assert!(RCD_SCRIPT.contains("# REQUIRE: NETWORKING"));
-        assert!(RCD_SCRIPT.contains("# BEFORE: caddy"));
-        assert!(RCD_SCRIPT.contains(". /etc/rc.subr"));
-        assert!(RCD_SCRIPT.contains("load_rc_config $name"));
-        assert!(RCD_SCRIPT.contains("run_rc_command"));
+        assert!(host_script().contains("# PROVIDE: bsdeploy"));
+        assert!(host_script().contains("# REQUIRE: NETWORKING"));
+        assert!(host_script().contains("# BEFORE: caddy relayd"));
+        assert!(host_script().contains(". /etc/rc.subr"));
+        assert!(host_script().contains("load_rc_config $name"));
+        assert!(host_script().contains("run_rc_command"));
     }
 
     #[test]
     fn test_rcd_script_has_start_stop_status() {
         // Test that start, stop, and status commands are defined
-        assert!(RCD_SCRIPT.contains("bsdeploy_start()"));
-        assert!(RCD_SCRIPT.contains("bsdeploy_stop()"));
-        assert!(RCD_SCRIPT.contains("bsdeploy_status()"));
-        assert!(RCD_SCRIPT.contains("bsdeploy_restart()"));
+        assert!(host_script().contains("bsdeploy_start()"));
+        assert!(host_script().contains("bsdeploy_stop()"));
+        assert!(host_script().contains("bsdeploy_status()"));
+        assert!(host_script().contains("bsdeploy_restart()"));
     }
 
     #[test]
     fn test_rcd_script_uses_correct_paths() {
         // Test that the script uses the correct bsdeploy paths
-        assert!(RCD_SCRIPT.contains(r#"ACTIVE_DIR="/usr/local/bsdeploy/active""#));
-        assert!(RCD_SCRIPT.contains(r#"JAILS_DIR="/usr/local/bsdeploy/jails""#));
-        assert!(RCD_SCRIPT.contains(r#"BASE_DIR="/usr/local/bsdeploy/base""#));
+        assert!(host_script().contains(r#"ACTIVE_DIR="/usr/local/bsdeploy/active""#));
+        assert!(host_script().contains(r#"JAILS_DIR="/usr/local/bsdeploy/jails""#));
+        assert!(host_script().contains(r#"BASE_DIR="/usr/local/bsdeploy/base""#));
+        assert!(host_script().contains(r#"local boot_log="/var/log/bsdeploy/$service/boot.log""#));
     }
 
     #[test]
     fn test_rcd_script_handles_zfs_and_non_zfs() {
         // Test that the script distinguishes between ZFS and non-ZFS jails
-        assert!(RCD_SCRIPT.contains(r#"is_zfs=$($JQ -r '.zfs' "$metadata")"#));
-        assert!(RCD_SCRIPT.contains(r#"if [ "$is_zfs" = "true" ]"#));
+        assert!(host_script().contains(r#"is_zfs=$($JQ -r '.zfs' "$metadata")"#));
+        assert!(host_script().contains(r#"if [ "$is_zfs" = "true" ]"#));
     }
 
     #[test]
     fn test_rcd_script_uses_jq_for_json() {
         // Test that the script uses jq to parse JSON metadata
-        assert!(RCD_SCRIPT.contains("$JQ -r '.jail_name'"));
-        assert!(RCD_SCRIPT.contains("$JQ -r '.ip'"));
-        assert!(RCD_SCRIPT.contains("$JQ -r '.service'"));
-        assert!(RCD_SCRIPT.contains("$JQ -r '.start_commands[]'"));
+        assert!(host_script().contains("$JQ -r '.jail_name'"));
+        assert!(host_script().contains("$JQ -r '.ip'"));
+        assert!(host_script().contains("$JQ -r '.service'"));
+        assert!(host_script().contains("$JQ -r '.start_commands[]'"));
+        assert!(host_script().contains("$JQ -r '.processes[]'"));
+        assert!(host_script().contains("$JQ -r '.syslogd // false'"));
     }
 
     #[test]
     fn test_rcd_script_creates_lo1() {
         // Test that the script creates lo1 interface if needed
-        assert!(RCD_SCRIPT.contains("ifconfig lo1 create"));
+        assert!(host_script().contains("ifconfig lo1 create"));
     }
 
     #[test]
     fn test_rcd_script_mounts_devfs() {
         // Test that the script mounts devfs
-        assert!(RCD_SCRIPT.contains("mount -t devfs devfs"));
+        assert!(host_script().contains("mount -t devfs devfs"));
     }
 
     #[test]
     fn test_rcd_script_starts_jail_correctly() {
         // Test that the jail start command has correct parameters
-        assert!(RCD_SCRIPT.contains("jail -c name="));
-        assert!(RCD_SCRIPT.contains("allow.raw_sockets=1"));
-        assert!(RCD_SCRIPT.contains("persist"));
+        assert!(host_script().contains("jail -c name="));
+        assert!(host_script().contains("allow.raw_sockets=1"));
+        assert!(host_script().contains("persist"));
     }
 
     #[test]
     fn test_rcd_script_stops_jail_correctly() {
         // Test that the script stops jails properly
-        assert!(RCD_SCRIPT.contains("jail -r"));
+        assert!(host_script().contains("jail -r"));
     }
 
     #[test]
     fn test_rcd_script_handles_ip_aliases() {
         // Test that the script manages IP aliases on lo1
-        assert!(RCD_SCRIPT.contains("ifconfig lo1 inet"));
-        assert!(RCD_SCRIPT.contains("-alias"));
+        assert!(host_script().contains("ifconfig lo1 inet"));
+        assert!(host_script().contains("-alias"));
+    }
+
+    #[test]
+    fn test_service_script() {
+        let config = Config::from_str("service: my-app\nhosts: [a]\nboot: { require: [postgresql], before: [nginx] }\n").unwrap();
+        let script = service_script(&config);
+        assert!(script.contains("# PROVIDE: bsdeploy_my_app\n# REQUIRE: bsdeploy postgresql\n# BEFORE: caddy relayd nginx\n"));
+        assert!(script.contains("rcvar=\"bsdeploy_my_app_enable\""));
+        assert!(script.contains("bsdeploy_my_app_start()\n{\n    bsdeploy_start_service \"$ACTIVE_DIR/my-app\"\n}"));
+        assert_eq!(service_script_path("my-app"), "/usr/local/etc/rc.d/bsdeploy_my_app");
+        // The host script leaves such services to their own script
+        assert!(host_script().contains("bsdeploy_has_own_script \"$link\" || bsdeploy_start_service \"$link\""));
+
+        assert!(Config::from_str("service: my-app\nhosts: [a]\nboot: { require: ['postgresql; reboot'] }\n").is_err());
+    }
+
+    #[test]
+    fn test_boot_services() {
+        let config = Config::from_str("service: app\nhosts: [a]\nboot: { services: [db, cache-store], require: [nginx] }\n").unwrap();
+        assert_eq!(config.boot.wait, 60);
+        assert!(service_script(&config).contains("# REQUIRE: bsdeploy bsdeploy_db bsdeploy_cache_store nginx\n"));
+        // Dependencies start first, and only once
+        let script = host_script();
+        let start = script.find("bsdeploy_start_service()").unwrap();
+        let running = script[start..].find("if jls -j \"$jail_name\"").unwrap();
+        let deps = script[start..].find("bsdeploy_start_dependencies \"$metadata\" \"$service\"").unwrap();
+        let jail = script[start..].find("jail -c name=").unwrap();
+        assert!(running < deps && deps < jail);
+        assert!(script.contains("for dep in $($JQ -r '.depends_on[]?' \"$metadata\"); do"));
+        assert!(script.contains("nc -z -w 1 \"$ip\" \"$port\""));
+
+        for bad in ["services: [app]", "services: [Db]", "wait: 0"] {
+            assert!(Config::from_str(&format!("service: app\nhosts: [a]\nboot: {{ {} }}\n", bad)).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_versions() {
+        let config = Config::from_str("service: app\nhosts: [a]\n").unwrap();
+        let marker = format!("# KEYWORD: shutdown\n# bsdeploy-rcd-version: {}\n", RCD_VERSION);
+        assert!(host_script().contains(&marker) && service_script(&config).contains(&marker));
+
+        let current = format!("{v}\n{v}\n", v = RCD_VERSION);
+        assert_eq!(outdated(&current), None);
+        assert_eq!(outdated("missing\nmissing\n"), None);
+        assert_eq!(outdated(&format!("0\n{}\n", RCD_VERSION)).as_deref(), Some("0"));
+        assert_eq!(outdated(&format!("{}\nmissing\n", RCD_VERSION)).as_deref(), Some("missing"));
+        assert_eq!(describe_version("0"), "unversioned");
     }
 }
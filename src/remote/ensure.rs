@@ -0,0 +1,90 @@
+//! Idempotent host operations. Each checks the current state and only
+//! applies the change when needed, in a single round trip, so a retried or
+//! resumed deploy can run them again safely.
+//!
+//! The `*_command` builders return the shell snippet for use in a `Batch`.
+
+use anyhow::Result;
+
+use crate::shell::{self, escalation_prefix};
+
+/// Create `path` (and its parents) unless it is already a directory.
+pub fn dir_command(path: &str, use_doas: bool) -> String {
+    let path = shell::escape(path);
+    format!("[ -d {} ] || {}mkdir -p {}", path, escalation_prefix(use_doas), path)
+}
+
+/// Mount `source` on `target` with `fstype` unless something is already
+/// mounted there, creating the mount point if needed.
+pub fn mount_command(fstype: &str, source: &str, target: &str, read_only: bool, use_doas: bool) -> String {
+    let prefix = escalation_prefix(use_doas);
+    let target_escaped = shell::escape(target);
+    let options = if read_only { "-o ro " } else { "" };
+    format!(
+        "mount -p | awk -v t={t} '$2 == t {{ found = 1 }} END {{ exit !found }}' || {{ {dir} && {p}mount -t {fstype} {options}{source} {t}; }}",
+        t = target_escaped,
+        dir = dir_command(target, use_doas),
+        p = prefix,
+        fstype = fstype,
+        options = options,
+        source = shell::escape(source)
+    )
+}
+
+/// Add `ip` as a /32 alias on `interface` unless it is already assigned.
+pub fn ip_alias_command(interface: &str, ip: &str, use_doas: bool) -> String {
+    format!(
+        "ifconfig {iface} | awk -v ip={ip} '$1 == \"inet\" && $2 == ip {{ found = 1 }} END {{ exit !found }}' || {p}ifconfig {iface} inet {ip}/32 alias",
+        iface = interface,
+        ip = ip,
+        p = escalation_prefix(use_doas)
+    )
+}
+
+/// Point `link` at `target`, replacing whatever symlink is there.
+pub fn symlink_command(target: &str, link: &str, use_doas: bool) -> String {
+    let target = shell::escape(target);
+    let link = shell::escape(link);
+    format!(
+        "[ \"$(readlink {link})\" = {target} ] || {p}ln -sfn {target} {link}",
+        link = link,
+        target = target,
+        p = escalation_prefix(use_doas)
+    )
+}
+
+pub fn ensure_dir(host: &str, path: &str, use_doas: bool) -> Result<()> {
+    super::run(host, &dir_command(path, use_doas))
+}
+
+pub fn ensure_mount(host: &str, fstype: &str, source: &str, target: &str, read_only: bool, use_doas: bool) -> Result<()> {
+    super::run(host, &mount_command(fstype, source, target, read_only, use_doas))
+}
+
+pub fn ensure_ip_alias(host: &str, interface: &str, ip: &str, use_doas: bool) -> Result<()> {
+    super::run(host, &ip_alias_command(interface, ip, use_doas))
+}
+
+pub fn ensure_symlink(host: &str, target: &str, link: &str, use_doas: bool) -> Result<()> {
+    super::run(host, &symlink_command(target, link, use_doas))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_commands() {
+        assert_eq!(dir_command("/a b", false), "[ -d '/a b' ] || mkdir -p '/a b'");
+        assert_eq!(
+            mount_command("nullfs", "/base/bin", "/jail/bin", true, true),
+            "mount -p | awk -v t=/jail/bin '$2 == t { found = 1 } END { exit !found }' || \
+             { [ -d /jail/bin ] || doas mkdir -p /jail/bin && doas mount -t nullfs -o ro /base/bin /jail/bin; }"
+        );
+        assert!(ip_alias_command("lo1", "10.0.0.5", true).ends_with("|| doas ifconfig lo1 inet 10.0.0.5/32 alias"));
+        assert_eq!(
+            symlink_command("/jails/app-2", "/active/app", false),
+            "[ \"$(readlink /active/app)\" = /jails/app-2 ] || ln -sfn /jails/app-2 /active/app"
+        );
+    }
+}
@@ -0,0 +1,228 @@
+//! Remote command failures, sorted into categories that come with a
+//! remediation hint instead of just the raw stderr.
+
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// SSH key or host key rejected
+    Auth,
+    /// doas/sudo refused or wanted a password
+    PrivilegeDenied,
+    /// A package, or a command `setup` installs, does not exist on the host
+    MissingPackage,
+    /// A listener could not bind its port
+    PortInUse,
+    /// The filesystem or pool is full
+    OutOfSpace,
+    /// Any other ZFS failure
+    Zfs,
+}
+
+/// Commands of the packages `setup` installs on every host; only these
+/// are missing packages when the shell cannot find them, other commands
+/// may as well be typos or missing inside a jail
+const SETUP_TOOLS: &[&str] = &["bash", "caddy", "git", "jq", "relayd", "rsync"];
+
+/// Whether `line` is a shell reporting one of `SETUP_TOOLS` as not found,
+/// e.g. `sh: rsync: not found` or `bash: line 1: jq: command not found`.
+fn missing_setup_tool(line: &str) -> bool {
+    let Some(rest) = line.strip_suffix(": not found").or_else(|| line.strip_suffix(": command not found")) else {
+        return false;
+    };
+    let command = rest.rsplit(": ").next().unwrap_or(rest);
+    SETUP_TOOLS.contains(&command.rsplit('/').next().unwrap_or(command))
+}
+
+/// stderr fragments identifying each kind, checked in order
+const PATTERNS: &[(ErrorKind, &[&str])] = &[
+    (
+        ErrorKind::Auth,
+        &[
+            "Permission denied (publickey",
+            "Host key verification failed",
+            "Too many authentication failures",
+            "Authentication failed for",
+        ],
+    ),
+    (
+        ErrorKind::PrivilegeDenied,
+        &[
+            "doas: Operation not permitted",
+            "doas: Authentication failed",
+            "doas: a password is required",
+            "sudo: a password is required",
+            "sudo: a terminal is required",
+            "is not in the sudoers file",
+            "bsdeploy-helper: refusing to run as root",
+        ],
+    ),
+    (ErrorKind::OutOfSpace, &["No space left on device", "out of space"]),
+    (ErrorKind::MissingPackage, &["No packages available to install matching"]),
+    (ErrorKind::PortInUse, &["Address already in use"]),
+    (ErrorKind::Zfs, &["dataset does not exist", "dataset already exists", "dataset is busy", "pool I/O"]),
+];
+
+impl ErrorKind {
+    /// Classify a failure by its stderr, returning the matching line too.
+    pub fn classify(stderr: &str) -> Option<(ErrorKind, &str)> {
+        PATTERNS.iter().find_map(|(kind, fragments)| {
+            stderr
+                .lines()
+                .find(|line| {
+                    fragments.iter().any(|f| line.contains(f))
+                        || (*kind == ErrorKind::MissingPackage && missing_setup_tool(line.trim()))
+                })
+                .map(|line| (*kind, line.trim()))
+        })
+    }
+
+    fn title(self) -> &'static str {
+        match self {
+            ErrorKind::Auth => "SSH authentication failed",
+            ErrorKind::PrivilegeDenied => "Privilege escalation denied",
+            ErrorKind::MissingPackage => "Missing package or command",
+            ErrorKind::PortInUse => "Port already in use",
+            ErrorKind::OutOfSpace => "Out of disk space",
+            ErrorKind::Zfs => "ZFS error",
+        }
+    }
+
+    /// What to do about it. `host` is the host as configured, so it may
+    /// carry the SSH user.
+    pub fn hint(self, host: &str, detail: &str) -> String {
+        let user = host.split_once('@').map_or("<ssh user>", |(user, _)| user);
+        let hostname = host.rsplit('@').next().unwrap_or(host);
+        match self {
+            ErrorKind::Auth if detail.contains("Host key verification") => format!(
+                "Add the host key to known_hosts, e.g. `ssh-keyscan {} >> ~/.ssh/known_hosts` (verify the fingerprint)",
+                hostname
+            ),
+            ErrorKind::Auth => format!(
+                "Check that your key is loaded (`ssh-add -l`) and listed in ~{}/.ssh/authorized_keys on {}",
+                user, hostname
+            ),
+            ErrorKind::PrivilegeDenied if detail.contains("bsdeploy-helper: refusing") => {
+                "privilege_escalation: doas-helper only runs what bsdeploy itself needs as root".to_string()
+            }
+            ErrorKind::PrivilegeDenied if detail.contains("bsdeploy-helper") => {
+                "Run `bsdeploy setup --install-doas` to install the helper and its doas rule".to_string()
+            }
+            ErrorKind::PrivilegeDenied if detail.contains("sudo") => format!(
+                "Allow passwordless sudo: add `{} ALL=(ALL) NOPASSWD: ALL` to /usr/local/etc/sudoers.d/bsdeploy",
+                user
+            ),
+            ErrorKind::PrivilegeDenied => {
+                format!("Allow passwordless doas: add `permit nopass {}` to /usr/local/etc/doas.conf", user)
+            }
+            ErrorKind::MissingPackage => {
+                "Run `bsdeploy setup` to install the host packages, and check package names with `pkg search`"
+                    .to_string()
+            }
+            ErrorKind::PortInUse => {
+                "Find the process holding the port with `sockstat -4l` and stop it, or change the port".to_string()
+            }
+            ErrorKind::OutOfSpace => {
+                "Free space on the host (`df -h`, `zfs list`), e.g. by destroying old jails and unused images"
+                    .to_string()
+            }
+            ErrorKind::Zfs => {
+                "Inspect the datasets with `zfs list -r`; an interrupted run may have left one behind".to_string()
+            }
+        }
+    }
+}
+
+/// A remote command exited unsuccessfully.
+#[derive(Debug)]
+pub struct CommandError {
+    pub host: String,
+    pub command: String,
+    pub stderr: String,
+}
+
+impl CommandError {
+    pub fn new(host: &str, command: &str, stderr: &str) -> Self {
+        Self {
+            host: host.to_string(),
+            command: command.to_string(),
+            stderr: stderr.trim().to_string(),
+        }
+    }
+
+    pub fn kind(&self) -> Option<ErrorKind> {
+        ErrorKind::classify(&self.stderr).map(|(kind, _)| kind)
+    }
+
+    /// The full command and stderr, for transcripts and verbose output.
+    pub fn detail(&self) -> String {
+        format!("Command failed on {}: {}. Error: {}", self.host, self.command, self.stderr)
+    }
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match ErrorKind::classify(&self.stderr) {
+            Some((kind, line)) => write!(f, "{} on {}: {}", kind.title(), self.host, line),
+            None => f.write_str(&self.detail()),
+        }
+    }
+}
+
+impl std::error::Error for CommandError {}
+
+/// Remediation hint for an error, if it falls into a known category.
+/// Errors that are not a `CommandError` are classified by their message.
+pub fn hint(error: &anyhow::Error) -> Option<String> {
+    if let Some(e) = error.chain().find_map(|e| e.downcast_ref::<CommandError>()) {
+        return e.kind().map(|kind| kind.hint(&e.host, &e.stderr));
+    }
+    let message = format!("{:#}", error);
+    let (kind, line) = ErrorKind::classify(&message)?;
+    Some(kind.hint("<host>", line))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify() {
+        let stderr = "deploy@web1: Permission denied (publickey,password).";
+        assert_eq!(ErrorKind::classify(stderr).unwrap().0, ErrorKind::Auth);
+        assert_eq!(ErrorKind::classify("doas: Operation not permitted\n").unwrap().0, ErrorKind::PrivilegeDenied);
+        assert_eq!(
+            ErrorKind::classify("bsdeploy-helper: refusing to run as root: sh -c id\n").unwrap().0,
+            ErrorKind::PrivilegeDenied
+        );
+        assert_eq!(
+            ErrorKind::classify("cannot create 'zroot/jails/app': out of space").unwrap().0,
+            ErrorKind::OutOfSpace
+        );
+        assert_eq!(ErrorKind::classify("sh: rsync: not found").unwrap().0, ErrorKind::MissingPackage);
+        assert_eq!(
+            ErrorKind::classify("bash: line 1: /usr/local/bin/jq: command not found\n").unwrap().0,
+            ErrorKind::MissingPackage
+        );
+        assert!(ErrorKind::classify("sh: rsnyc: not found").is_none());
+        assert!(ErrorKind::classify("bash: bundle: command not found").is_none());
+        assert!(ErrorKind::classify("exit status 1").is_none());
+    }
+
+    #[test]
+    fn test_command_error_display_and_hint() {
+        let e = CommandError::new("deploy@web1", "doas pkg update", "warning\ndoas: Operation not permitted\n");
+        assert_eq!(e.to_string(), "Privilege escalation denied on deploy@web1: doas: Operation not permitted");
+        assert!(e.detail().contains("doas pkg update"));
+
+        let error = anyhow::Error::new(e).context("Failed to update packages");
+        assert_eq!(
+            hint(&error).unwrap(),
+            "Allow passwordless doas: add `permit nopass deploy` to /usr/local/etc/doas.conf"
+        );
+
+        let plain = CommandError::new("web1", "false", "boom");
+        assert_eq!(plain.to_string(), "Command failed on web1: false. Error: boom");
+        assert!(hint(&anyhow::Error::new(plain)).is_none());
+    }
+}
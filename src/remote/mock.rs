@@ -0,0 +1,195 @@
+//! Test double for `RemoteExecutor`: records every command and answers
+//! with scripted output instead of talking to a host.
+
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+
+use super::{CommandError, RemoteExecutor, Timeout};
+use crate::config::SyncConfig;
+
+struct Response {
+    pattern: String,
+    success: bool,
+    output: String,
+}
+
+/// Commands that match no scripted response succeed with empty output.
+#[derive(Default)]
+pub struct MockExecutor {
+    responses: Mutex<Vec<Response>>,
+    commands: Mutex<Vec<String>>,
+    files: Mutex<HashMap<String, String>>,
+}
+
+impl MockExecutor {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    /// Commands containing `pattern` succeed and print `stdout`.
+    pub fn respond(&self, pattern: &str, stdout: &str) -> &Self {
+        self.script(pattern, true, stdout)
+    }
+
+    /// Commands containing `pattern` fail with `stderr`.
+    pub fn fail(&self, pattern: &str, stderr: &str) -> &Self {
+        self.script(pattern, false, stderr)
+    }
+
+    fn script(&self, pattern: &str, success: bool, output: &str) -> &Self {
+        self.responses.lock().unwrap().push(Response {
+            pattern: pattern.to_string(),
+            success,
+            output: output.to_string(),
+        });
+        self
+    }
+
+    /// Every command issued so far, in order. File writes show up as
+    /// `write_file <path>`, uploads as `upload <local> -> <path>`, syncs
+    /// as `rsync <src> -> <dest>` or `git archive HEAD -> <dest>` and
+    /// commands fed from a local file as `<command> < <local>`.
+    pub fn commands(&self) -> Vec<String> {
+        self.commands.lock().unwrap().clone()
+    }
+
+    /// Whether any issued command contains `fragment`.
+    pub fn ran(&self, fragment: &str) -> bool {
+        self.commands().iter().any(|c| c.contains(fragment))
+    }
+
+    /// Content last written to `path` with `write_file` or `upload_file`.
+    pub fn file(&self, path: &str) -> Option<String> {
+        self.files.lock().unwrap().get(path).cloned()
+    }
+
+    /// Record the command and look up its response (first match wins).
+    fn exec(&self, command: &str) -> (bool, String) {
+        self.commands.lock().unwrap().push(command.to_string());
+        let responses = self.responses.lock().unwrap();
+        responses
+            .iter()
+            .find(|r| command.contains(&r.pattern))
+            .map_or((true, String::new()), |r| (r.success, r.output.clone()))
+    }
+}
+
+fn failure(host: &str, command: &str, stderr: &str) -> anyhow::Error {
+    CommandError::new(host, command, stderr).into()
+}
+
+impl RemoteExecutor for MockExecutor {
+    fn run(&self, host: &str, command: &str, _timeout: Timeout) -> Result<()> {
+        match self.exec(command) {
+            (true, _) => Ok(()),
+            (false, stderr) => Err(failure(host, command, &stderr)),
+        }
+    }
+
+    fn run_with_output(&self, host: &str, command: &str, _timeout: Timeout) -> Result<String> {
+        match self.exec(command) {
+            (true, stdout) => Ok(stdout),
+            (false, stderr) => Err(failure(host, command, &stderr)),
+        }
+    }
+
+    fn run_with_input(&self, _host: &str, command: &str, _input: &str, _timeout: Timeout) -> Result<(bool, String)> {
+        match self.exec(command) {
+            (true, _) => Ok((true, String::new())),
+            (false, stderr) => Ok((false, stderr)),
+        }
+    }
+
+    fn write_file(&self, host: &str, content: &str, dest_path: &str, _use_doas: bool) -> Result<()> {
+        let command = format!("write_file {}", dest_path);
+        match self.exec(&command) {
+            (true, _) => {
+                self.files.lock().unwrap().insert(dest_path.to_string(), content.to_string());
+                Ok(())
+            }
+            (false, stderr) => Err(failure(host, &command, &stderr)),
+        }
+    }
+
+    fn run_streaming(&self, host: &str, command: &str, _timeout: Timeout, on_line: &mut dyn FnMut(&str)) -> Result<()> {
+        let (success, output) = self.exec(command);
+        output.lines().for_each(&mut *on_line);
+        if success { Ok(()) } else { Err(failure(host, command, &output)) }
+    }
+
+    fn upload_file(&self, host: &str, local_path: &Path, dest_path: &str, _mode: u32, _owner: Option<&str>, _use_doas: bool) -> Result<()> {
+        let command = format!("upload {} -> {}", local_path.display(), dest_path);
+        match self.exec(&command) {
+            (true, _) => {
+                let content = std::fs::read(local_path).with_context(|| format!("Failed to read {:?}", local_path))?;
+                self.files.lock().unwrap().insert(dest_path.to_string(), String::from_utf8_lossy(&content).into_owned());
+                Ok(())
+            }
+            (false, stderr) => Err(failure(host, &command, &stderr)),
+        }
+    }
+
+    fn sync(&self, host: &str, src: &str, dest: &str, _excludes: &[String], _options: &SyncConfig, _use_doas: bool) -> Result<()> {
+        let command = format!("rsync {} -> {}", src, dest);
+        match self.exec(&command) {
+            (true, _) => Ok(()),
+            (false, stderr) => Err(failure(host, &command, &stderr)),
+        }
+    }
+
+    fn sync_git_archive(&self, host: &str, dest: &str, _excludes: &[String], _use_doas: bool) -> Result<()> {
+        let command = format!("git archive HEAD -> {}", dest);
+        match self.exec(&command) {
+            (true, _) => Ok(()),
+            (false, stderr) => Err(failure(host, &command, &stderr)),
+        }
+    }
+
+    /// Writes the scripted output to `local_path`.
+    fn run_to_file(&self, host: &str, command: &str, local_path: &Path) -> Result<()> {
+        match self.exec(command) {
+            (true, stdout) => std::fs::write(local_path, stdout).with_context(|| format!("Failed to write {:?}", local_path)),
+            (false, stderr) => Err(failure(host, command, &stderr)),
+        }
+    }
+
+    /// Recorded as `<command> < <local_path>`; the file must exist.
+    fn run_from_file(&self, host: &str, local_path: &Path, command: &str) -> Result<()> {
+        std::fs::metadata(local_path).with_context(|| format!("Failed to read {:?}", local_path))?;
+        match self.exec(&format!("{} < {}", command, local_path.display())) {
+            (true, _) => Ok(()),
+            (false, stderr) => Err(failure(host, command, &stderr)),
+        }
+    }
+}
+
+/// The installed executor. It is process-wide so that commands issued from
+/// worker threads (`parallel_hosts`) reach it too.
+static CURRENT: RwLock<Option<Arc<dyn RemoteExecutor>>> = RwLock::new(None);
+
+/// Held while an executor is installed; tests using one run one at a time.
+static INSTALLED: Mutex<()> = Mutex::new(());
+
+pub(super) fn current() -> Option<Arc<dyn RemoteExecutor>> {
+    CURRENT.read().unwrap_or_else(PoisonError::into_inner).clone()
+}
+
+/// Uninstalls the executor even when the test panics.
+struct Uninstall;
+
+impl Drop for Uninstall {
+    fn drop(&mut self) {
+        *CURRENT.write().unwrap_or_else(PoisonError::into_inner) = None;
+    }
+}
+
+/// Route all `remote::*` command execution, on every thread, through
+/// `executor` while `f` runs. Must not be nested.
+pub fn with_executor<T>(executor: Arc<dyn RemoteExecutor>, f: impl FnOnce() -> T) -> T {
+    let _installed = INSTALLED.lock().unwrap_or_else(PoisonError::into_inner);
+    *CURRENT.write().unwrap_or_else(PoisonError::into_inner) = Some(executor);
+    let _uninstall = Uninstall;
+    f()
+}
@@ -0,0 +1,1333 @@
+use std::process::{Command, Stdio};
+use std::sync::RwLock;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::{Duration, Instant};
+use anyhow::{Context, Result, anyhow};
+use log::debug;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use wait_timeout::ChildExt;
+
+use crate::config::{SshConfig, SshTimeouts, SshTransport, SyncConfig};
+use crate::{shell, transcript, ui};
+
+#[cfg(test)]
+pub mod mock;
+mod error;
+pub mod ensure;
+
+pub use ensure::{ensure_dir, ensure_ip_alias, ensure_mount, ensure_symlink};
+pub use error::{CommandError, ErrorKind, hint};
+
+/// SSH settings from the loaded configuration
+static SSH_CONFIG: RwLock<Option<SshConfig>> = RwLock::new(None);
+
+/// How long a remote command may take, configured under `ssh.timeouts`
+#[derive(Debug, Clone, Copy)]
+pub enum Timeout {
+    /// Quick checks and lookups
+    Query,
+    /// Regular commands
+    Command,
+    /// Package installs, runtime builds and image transfers
+    Build,
+}
+
+impl Timeout {
+    fn duration(self) -> Duration {
+        let config = SSH_CONFIG.read().unwrap();
+        let defaults = SshTimeouts::default();
+        let timeouts = config.as_ref().map_or(&defaults, |c| &c.timeouts);
+        Duration::from_secs(match self {
+            Timeout::Query => timeouts.query,
+            Timeout::Command => timeouts.command,
+            Timeout::Build => timeouts.build,
+        })
+    }
+}
+
+/// Apply the `ssh` configuration. The native transport is used for every
+/// remote command and transfer except rsync and `run_interactive`, which
+/// always use the `ssh` binary.
+pub fn configure(ssh: &SshConfig) -> Result<()> {
+    if ssh.transport == SshTransport::Native && !cfg!(feature = "native-ssh") {
+        return Err(anyhow!("ssh.transport 'native' requires bsdeploy to be built with the 'native-ssh' feature"));
+    }
+    #[cfg(feature = "native-ssh")]
+    crate::ssh_native::configure(ssh);
+    *SSH_CONFIG.write().unwrap() = Some(ssh.clone());
+    Ok(())
+}
+
+/// Host entry that runs commands on this machine instead of over SSH.
+/// `localhost` is not special and goes over SSH like any other host.
+pub const LOCAL_HOST: &str = "local";
+
+pub fn is_local(host: &str) -> bool {
+    host == LOCAL_HOST
+}
+
+fn native_ssh(host: &str) -> bool {
+    !is_local(host)
+        && SSH_CONFIG
+            .read()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|c| c.transport == SshTransport::Native)
+}
+
+/// Options shared by every `ssh` and `scp` invocation for the host: the
+/// jump host, connect timeout and keepalives.
+fn connection_options(host: &str) -> Vec<String> {
+    let config = SSH_CONFIG.read().unwrap();
+    let Some(ssh) = config.as_ref() else {
+        return Vec::new();
+    };
+    let mut args = Vec::new();
+    if let Some(jump) = ssh.proxy_jump_for(host) {
+        args.push("-J".to_string());
+        args.push(jump.to_string());
+    }
+    args.extend(keepalive_options(ssh));
+    args
+}
+
+fn keepalive_options(ssh: &SshConfig) -> Vec<String> {
+    let mut options = vec![format!("ConnectTimeout={}", ssh.connect_timeout)];
+    if ssh.server_alive_interval > 0 {
+        options.push(format!("ServerAliveInterval={}", ssh.server_alive_interval));
+        options.push(format!("ServerAliveCountMax={}", ssh.server_alive_count_max));
+    }
+    options.into_iter().flat_map(|o| ["-o".to_string(), o]).collect()
+}
+
+/// `ssh` invocation for the host with the configured connection options.
+/// For `local` the command is run by the local shell instead.
+fn ssh_command(host: &str) -> Command {
+    if is_local(host) {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c");
+        return cmd;
+    }
+    let mut cmd = Command::new("ssh");
+    cmd.args(connection_options(host));
+    if let Some(ssh) = SSH_CONFIG.read().unwrap().as_ref() {
+        cmd.args(env_options(ssh));
+    }
+    cmd.arg(host);
+    cmd
+}
+
+/// `ssh` options for agent forwarding and the configured environment.
+fn env_options(ssh: &SshConfig) -> Vec<String> {
+    let mut args = Vec::new();
+    if ssh.forward_agent {
+        args.push("-A".to_string());
+    }
+    for name in &ssh.send_env {
+        args.push("-o".to_string());
+        args.push(format!("SendEnv={}", name));
+    }
+    for (name, value) in &ssh.set_env {
+        args.push("-o".to_string());
+        args.push(format!("SetEnv={}=\"{}\"", name, value));
+    }
+    args
+}
+
+/// ssh's exit code when it fails itself rather than the remote command
+const SSH_ERROR_EXIT: i32 = 255;
+
+/// stderr fragments from ssh failing before the session is established,
+/// when the remote command cannot have started. Drops later on ("Broken
+/// pipe", "Connection closed by") may come after the command ran, so they
+/// are not retried.
+const CONNECTION_ERRORS: &[&str] = &[
+    // Followed by the reason: timed out, refused, reset, no route, ...
+    "ssh: connect to host",
+    "Could not resolve hostname",
+    "kex_exchange_identification",
+    "Connection timed out during banner exchange",
+];
+
+/// Delay before the first retry; doubled after every attempt
+const RETRY_BASE_DELAY: Duration = Duration::from_secs(1);
+
+/// ssh could not reach the host; the remote command may not have run at all.
+#[derive(Debug)]
+pub struct ConnectionError {
+    host: String,
+    stderr: String,
+}
+
+impl std::fmt::Display for ConnectionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Connection to {} failed: {}", self.host, self.stderr.trim())
+    }
+}
+
+impl std::error::Error for ConnectionError {}
+
+impl ConnectionError {
+    pub fn new(host: &str, reason: &str) -> Self {
+        ConnectionError { host: host.to_string(), stderr: reason.to_string() }
+    }
+}
+
+fn is_connection_failure(exit_code: Option<i32>, stderr: &str) -> bool {
+    exit_code == Some(SSH_ERROR_EXIT) && CONNECTION_ERRORS.iter().any(|e| stderr.contains(e))
+}
+
+fn check_connection(host: &str, exit_code: Option<i32>, stderr: &str) -> Result<()> {
+    if is_connection_failure(exit_code, stderr) {
+        return Err(ConnectionError::new(host, stderr).into());
+    }
+    Ok(())
+}
+
+/// Run `attempt`, retrying connection failures up to `ssh.connect_retries`
+/// times with exponential backoff. Command failures are returned as is.
+fn with_retry<T>(host: &str, mut attempt: impl FnMut() -> Result<T>) -> Result<T> {
+    let retries = SSH_CONFIG
+        .read()
+        .unwrap()
+        .as_ref()
+        .map_or(0, |c| c.connect_retries);
+    let mut delay = RETRY_BASE_DELAY;
+    let mut tries = 0;
+    loop {
+        match attempt() {
+            Err(e) if tries < retries && e.is::<ConnectionError>() => {
+                tries += 1;
+                debug!("{} - retrying in {:?} ({}/{})", e, delay, tries, retries);
+                std::thread::sleep(delay);
+                delay *= 2;
+            }
+            Err(e) if tries > 0 && e.is::<ConnectionError>() => {
+                return Err(e).with_context(|| format!("Giving up on {} after {} retries", host, tries));
+            }
+            result => return result,
+        }
+    }
+}
+
+#[cfg(feature = "native-ssh")]
+fn run_native(host: &str, command: &str, stdin: Option<&[u8]>, timeout: Timeout) -> Result<String> {
+    let output = crate::ssh_native::exec(host, command, stdin, timeout.duration())?;
+    if !output.success {
+        debug!("Stderr: {}", output.stderr);
+        return Err(CommandError::new(host, command, &output.stderr).into());
+    }
+    Ok(output.stdout)
+}
+
+#[cfg(not(feature = "native-ssh"))]
+fn run_native(_host: &str, _command: &str, _stdin: Option<&[u8]>, _timeout: Timeout) -> Result<String> {
+    unreachable!("native SSH transport selected without the native-ssh feature")
+}
+
+/// Which of a command's output streams a chunk came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(not(feature = "native-ssh"), allow(dead_code))]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+/// Run `command` over the native transport with `stdin` as its input,
+/// passing its output to `on_output` as it arrives. Returns whether the
+/// command succeeded and its stderr.
+#[cfg(feature = "native-ssh")]
+fn run_native_io(
+    host: &str,
+    command: &str,
+    stdin: &mut dyn Read,
+    timeout: Duration,
+    on_output: &mut dyn FnMut(Stream, &[u8]) -> Result<()>,
+) -> Result<(bool, String)> {
+    let mut stderr = Vec::new();
+    let status = crate::ssh_native::exec_io(host, command, stdin, timeout, &mut |stream, data| {
+        if stream == Stream::Stderr {
+            stderr.extend_from_slice(data);
+        }
+        on_output(stream, data)
+    })?;
+    Ok((status == 0, String::from_utf8_lossy(&stderr).into_owned()))
+}
+
+#[cfg(not(feature = "native-ssh"))]
+fn run_native_io(
+    _host: &str,
+    _command: &str,
+    _stdin: &mut dyn Read,
+    _timeout: Duration,
+    _on_output: &mut dyn FnMut(Stream, &[u8]) -> Result<()>,
+) -> Result<(bool, String)> {
+    unreachable!("native SSH transport selected without the native-ssh feature")
+}
+
+/// Executes commands on hosts. `SshExecutor` is the real backend; tests
+/// swap in `mock::MockExecutor` via `mock::with_executor`.
+pub trait RemoteExecutor: Send + Sync {
+    fn run(&self, host: &str, command: &str, timeout: Timeout) -> Result<()>;
+    fn run_with_output(&self, host: &str, command: &str, timeout: Timeout) -> Result<String>;
+    /// Returns whether the command succeeded and its stderr.
+    fn run_with_input(&self, host: &str, command: &str, input: &str, timeout: Timeout) -> Result<(bool, String)>;
+    fn write_file(&self, host: &str, content: &str, dest_path: &str, use_doas: bool) -> Result<()>;
+    fn run_streaming(&self, host: &str, command: &str, timeout: Timeout, on_line: &mut dyn FnMut(&str)) -> Result<()>;
+    fn upload_file(&self, host: &str, local_path: &Path, dest_path: &str, mode: u32, owner: Option<&str>, use_doas: bool) -> Result<()>;
+    fn sync(&self, host: &str, src: &str, dest: &str, excludes: &[String], options: &SyncConfig, use_doas: bool) -> Result<()>;
+    fn sync_git_archive(&self, host: &str, dest: &str, excludes: &[String], use_doas: bool) -> Result<()>;
+    fn run_to_file(&self, host: &str, command: &str, local_path: &Path) -> Result<()>;
+    fn run_from_file(&self, host: &str, local_path: &Path, command: &str) -> Result<()>;
+}
+
+/// Runs commands over SSH (or locally for `local`), retrying
+/// connection failures.
+pub struct SshExecutor;
+
+impl RemoteExecutor for SshExecutor {
+    fn run(&self, host: &str, command: &str, timeout: Timeout) -> Result<()> {
+        with_retry(host, || run_once(host, command, timeout))
+    }
+
+    fn run_with_output(&self, host: &str, command: &str, timeout: Timeout) -> Result<String> {
+        with_retry(host, || run_with_output_once(host, command, timeout))
+    }
+
+    fn run_with_input(&self, host: &str, command: &str, input: &str, timeout: Timeout) -> Result<(bool, String)> {
+        with_retry(host, || run_with_input_once(host, command, input, timeout))
+    }
+
+    fn write_file(&self, host: &str, content: &str, dest_path: &str, use_doas: bool) -> Result<()> {
+        with_retry(host, || write_file_once(host, content, dest_path, use_doas))
+    }
+
+    fn run_streaming(&self, host: &str, command: &str, timeout: Timeout, on_line: &mut dyn FnMut(&str)) -> Result<()> {
+        run_streaming_once(host, command, timeout, on_line)
+    }
+
+    fn upload_file(&self, host: &str, local_path: &Path, dest_path: &str, mode: u32, owner: Option<&str>, use_doas: bool) -> Result<()> {
+        upload_file_untraced(host, local_path, dest_path, mode, owner, use_doas)
+    }
+
+    fn sync(&self, host: &str, src: &str, dest: &str, excludes: &[String], options: &SyncConfig, use_doas: bool) -> Result<()> {
+        sync_untraced(host, src, dest, excludes, options, use_doas)
+    }
+
+    fn sync_git_archive(&self, host: &str, dest: &str, excludes: &[String], use_doas: bool) -> Result<()> {
+        sync_git_archive_untraced(host, dest, excludes, use_doas)
+    }
+
+    fn run_to_file(&self, host: &str, command: &str, local_path: &Path) -> Result<()> {
+        run_to_file_untraced(host, command, local_path)
+    }
+
+    fn run_from_file(&self, host: &str, local_path: &Path, command: &str) -> Result<()> {
+        run_from_file_untraced(host, local_path, command)
+    }
+}
+
+/// Call `f` with the executor in use.
+fn with_executor<T>(f: impl FnOnce(&dyn RemoteExecutor) -> T) -> T {
+    #[cfg(test)]
+    if let Some(executor) = mock::current() {
+        return f(executor.as_ref());
+    }
+    f(&SshExecutor)
+}
+
+/// Run `f`, recording the command with its duration and outcome in the
+/// session transcript. `output` picks what to record on success.
+fn traced<T>(host: &str, command: &str, f: impl FnOnce() -> Result<T>, output: fn(&T) -> Option<&str>) -> Result<T> {
+    if ui::shows_commands() {
+        ui::print_remote_line(host, &format!("$ {}", command));
+    }
+    if !transcript::is_active() {
+        return f();
+    }
+    let started = Instant::now();
+    let result = f();
+    match &result {
+        Ok(value) => transcript::record(host, command, started.elapsed(), None, output(value)),
+        Err(e) => {
+            let error = match e.downcast_ref::<CommandError>() {
+                Some(failure) => failure.detail(),
+                None => format!("{:#}", e),
+            };
+            transcript::record(host, command, started.elapsed(), Some(&error), None)
+        }
+    }
+    result
+}
+
+pub fn run(host: &str, command: &str) -> Result<()> {
+    run_timeout(host, command, Timeout::Command)
+}
+
+/// Like `run`, with the given timeout tier.
+pub fn run_timeout(host: &str, command: &str, timeout: Timeout) -> Result<()> {
+    traced(host, command, || with_executor(|e| e.run(host, command, timeout)), |_| None)
+}
+
+fn run_once(host: &str, command: &str, timeout: Timeout) -> Result<()> {
+    debug!("SSH [{}] Executing: {}", host, command);
+
+    if native_ssh(host) {
+        return run_native(host, command, None, timeout).map(|_| ());
+    }
+
+    if ui::is_verbose() {
+        return run_streaming_once(host, command, timeout, &mut |line| ui::print_remote_line(host, line));
+    }
+
+    let mut child = ssh_command(host)
+        .arg(command)
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Failed to execute ssh command on {}", host))?;
+
+    // Drain stderr in background to prevent pipe buffer deadlock
+    let stderr_handle = child.stderr.take();
+    let stderr_thread = std::thread::spawn(move || {
+        let mut stderr = String::new();
+        if let Some(mut err) = stderr_handle {
+            err.read_to_string(&mut stderr).ok();
+        }
+        stderr
+    });
+
+    let status = match child.wait_timeout(timeout.duration())
+        .with_context(|| format!("Failed to wait for ssh command on {}", host))?
+    {
+        Some(status) => status,
+        None => {
+            // Timeout - kill the process
+            child.kill().ok();
+            child.wait().ok();
+            return Err(anyhow!("SSH command timed out after {:?} on {}: {}", timeout.duration(), host, command));
+        }
+    };
+
+    if !status.success() {
+        let stderr = stderr_thread.join().unwrap_or_default();
+        debug!("Stderr: {}", stderr);
+        check_connection(host, status.code(), &stderr)?;
+        return Err(CommandError::new(host, command, &stderr).into());
+    }
+    Ok(())
+}
+
+/// Run a command and return its stdout. Uses the `command` timeout; see
+/// `query` for quick lookups and `run_with_output_timeout` for others.
+pub fn run_with_output(host: &str, command: &str) -> Result<String> {
+    run_with_output_timeout(host, command, Timeout::Command)
+}
+
+/// Run a quick read-only lookup (`cat`, `ls`, `jls`, `zfs list`, ...) and
+/// return its stdout. Uses the `query` timeout.
+pub fn query(host: &str, command: &str) -> Result<String> {
+    run_with_output_timeout(host, command, Timeout::Query)
+}
+
+pub fn run_with_output_timeout(host: &str, command: &str, timeout: Timeout) -> Result<String> {
+    traced(
+        host,
+        command,
+        || with_executor(|e| e.run_with_output(host, command, timeout)),
+        |stdout| Some(stdout),
+    )
+}
+
+fn run_with_output_once(host: &str, command: &str, timeout: Timeout) -> Result<String> {
+    debug!("SSH [{}] Executing (output): {}", host, command);
+
+    if native_ssh(host) {
+        return run_native(host, command, None, timeout);
+    }
+
+    let mut child = ssh_command(host)
+        .arg(command)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Failed to execute ssh command on {}", host))?;
+
+    // Drain stdout and stderr in background threads to prevent pipe buffer deadlock
+    let stdout_handle = child.stdout.take();
+    let stdout_thread = std::thread::spawn(move || {
+        let mut stdout = String::new();
+        if let Some(mut out) = stdout_handle {
+            out.read_to_string(&mut stdout).ok();
+        }
+        stdout
+    });
+
+    let stderr_handle = child.stderr.take();
+    let stderr_thread = std::thread::spawn(move || {
+        let mut stderr = String::new();
+        if let Some(mut err) = stderr_handle {
+            err.read_to_string(&mut stderr).ok();
+        }
+        stderr
+    });
+
+    let status = match child.wait_timeout(timeout.duration())
+        .with_context(|| format!("Failed to wait for ssh command on {}", host))?
+    {
+        Some(status) => status,
+        None => {
+            child.kill().ok();
+            child.wait().ok();
+            return Err(anyhow!("SSH command timed out after {:?} on {}: {}", timeout.duration(), host, command));
+        }
+    };
+
+    let stdout = stdout_thread.join().unwrap_or_default();
+
+    if !status.success() {
+        let stderr = stderr_thread.join().unwrap_or_default();
+        check_connection(host, status.code(), &stderr)?;
+        return Err(CommandError::new(host, command, &stderr).into());
+    }
+
+    Ok(stdout)
+}
+
+/// Run a shell script on the host via `sh -s`. Returns whether the script
+/// succeeded along with its stderr; only SSH-level problems are errors.
+/// The script is read from stdin as it runs, so commands in it that read
+/// stdin need `</dev/null` (see `Batch`).
+pub fn run_script(host: &str, script: &str) -> Result<(bool, String)> {
+    debug!("SSH [{}] Executing script", host);
+    traced(
+        host,
+        script,
+        || with_executor(|e| e.run_with_input(host, "sh -s", script, Timeout::Command)),
+        |(_, stderr)| Some(stderr),
+    )
+}
+
+/// Run `command` on the host with `input` on its stdin.
+pub fn run_with_input(host: &str, command: &str, input: &str) -> Result<()> {
+    debug!("SSH [{}] Executing (input): {}", host, command);
+    traced(
+        host,
+        command,
+        || {
+            let (success, stderr) = with_executor(|e| e.run_with_input(host, command, input, Timeout::Command))?;
+            if !success {
+                return Err(CommandError::new(host, command, &stderr).into());
+            }
+            Ok(())
+        },
+        |_| None,
+    )
+}
+
+fn run_with_input_once(host: &str, command: &str, input: &str, timeout: Timeout) -> Result<(bool, String)> {
+    if native_ssh(host) {
+        return run_native_io(host, command, &mut input.as_bytes(), timeout.duration(), &mut |_, _| Ok(()));
+    }
+
+    let mut child = ssh_command(host)
+        .arg(command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Failed to execute ssh command on {}", host))?;
+
+    // Drain stderr in background to prevent pipe buffer deadlock
+    let stderr_handle = child.stderr.take();
+    let stderr_thread = std::thread::spawn(move || {
+        let mut stderr = String::new();
+        if let Some(mut err) = stderr_handle {
+            err.read_to_string(&mut stderr).ok();
+        }
+        stderr
+    });
+
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(input.as_bytes())
+            .with_context(|| "Failed to write input to ssh stdin")?;
+    }
+
+    let status = match child.wait_timeout(timeout.duration())
+        .with_context(|| format!("Failed to wait for ssh command on {}", host))?
+    {
+        Some(status) => status,
+        None => {
+            child.kill().ok();
+            child.wait().ok();
+            return Err(anyhow!("SSH command timed out after {:?} on {}: {}", timeout.duration(), host, command));
+        }
+    };
+
+    let stderr = stderr_thread.join().unwrap_or_default();
+    if !status.success() {
+        check_connection(host, status.code(), &stderr)?;
+    }
+    Ok((status.success(), stderr))
+}
+
+/// Run `command` on the host with a terminal attached, so it can prompt
+/// for a password. Always uses the `ssh` binary.
+pub fn run_interactive(host: &str, command: &str) -> Result<()> {
+    debug!("SSH [{}] Executing (interactive): {}", host, command);
+    let mut cmd = if is_local(host) {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c");
+        cmd
+    } else {
+        let mut cmd = Command::new("ssh");
+        cmd.args(connection_options(host)).arg("-t").arg(host);
+        cmd
+    };
+    // Keep the JSON event stream on stdout clean
+    if ui::is_json() {
+        cmd.stdout(std::io::stderr());
+    }
+    let status = cmd
+        .arg(command)
+        .status()
+        .with_context(|| format!("Failed to execute ssh command on {}", host))?;
+    if !status.success() {
+        return Err(anyhow!("Command failed on {} ({}): {}", host, status, command));
+    }
+    Ok(())
+}
+
+pub fn get_os_release(host: &str) -> Result<String> {
+    let output = query(host, "uname -r")?;
+    Ok(output.trim().to_string())
+}
+
+pub fn write_file(host: &str, content: &str, dest_path: &str, use_doas: bool) -> Result<()> {
+    traced(
+        host,
+        &format!("write {}", dest_path),
+        || with_executor(|e| e.write_file(host, content, dest_path, use_doas)),
+        |_| None,
+    )
+}
+
+fn write_file_once(host: &str, content: &str, dest_path: &str, use_doas: bool) -> Result<()> {
+    debug!("SSH [{}] Writing file: {}", host, dest_path);
+
+    let safe_path = shell::escape(dest_path);
+    let remote_cmd = if use_doas {
+        format!("{}tee {} > /dev/null", shell::escalation_prefix(true), safe_path)
+    } else {
+        format!("cat > {}", safe_path)
+    };
+
+    if native_ssh(host) {
+        return run_native(host, &remote_cmd, Some(content.as_bytes()), Timeout::Command)
+            .map(|_| ())
+            .with_context(|| format!("Failed to write file {} on {}", dest_path, host));
+    }
+
+    let mut child = ssh_command(host)
+        .arg(remote_cmd)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null()) // Suppress stdout
+        .stderr(Stdio::piped()) // Capture stderr
+        .spawn()
+        .with_context(|| format!("Failed to spawn ssh for file writing on {}", host))?;
+
+    // Drain stderr in background to prevent pipe buffer deadlock
+    let stderr_handle = child.stderr.take();
+    let stderr_thread = std::thread::spawn(move || {
+        let mut stderr = String::new();
+        if let Some(mut err) = stderr_handle {
+            err.read_to_string(&mut stderr).ok();
+        }
+        stderr
+    });
+
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(content.as_bytes())
+            .with_context(|| "Failed to write content to ssh stdin")?;
+    }
+
+    let status = match child.wait_timeout(Timeout::Command.duration())
+        .with_context(|| "Failed to wait for ssh process")?
+    {
+        Some(status) => status,
+        None => {
+            child.kill().ok();
+            child.wait().ok();
+            return Err(anyhow!("SSH write_file timed out after {:?} on {}: {}", Timeout::Command.duration(), host, dest_path));
+        }
+    };
+
+    if !status.success() {
+        let stderr = stderr_thread.join().unwrap_or_default();
+        check_connection(host, status.code(), &stderr)?;
+        return Err(anyhow!("Failed to write file {} on {}: {}", dest_path, host, stderr.trim()));
+    }
+    Ok(())
+}
+
+/// Counter for unique temp file names within this process
+static UPLOAD_SEQ: AtomicUsize = AtomicUsize::new(0);
+
+fn upload_temp_name() -> String {
+    format!("bsdeploy-upload-{}-{}", std::process::id(), UPLOAD_SEQ.fetch_add(1, Ordering::Relaxed))
+}
+
+/// Name of the uploaded file inside the upload's temp directory
+const UPLOAD_FILE: &str = "upload";
+
+/// Command that moves an uploaded file from its temp directory into place
+/// with the given mode and owner (`user` or `user:group`), removing the
+/// temp directory either way.
+fn install_command(tmp_dir: &str, dest_path: &str, mode: u32, owner: Option<&str>, use_doas: bool) -> String {
+    let cmd_prefix = shell::escalation_prefix(use_doas);
+    let mut install = format!("{}install -m {:04o}", cmd_prefix, mode);
+    if let Some(owner) = owner {
+        let (user, group) = owner.split_once(':').unwrap_or((owner, ""));
+        install.push_str(&format!(" -o {}", shell::escape(user)));
+        if !group.is_empty() {
+            install.push_str(&format!(" -g {}", shell::escape(group)));
+        }
+    }
+    format!(
+        "{} {} {}; rc=$?; rm -rf {}; exit $rc",
+        install,
+        shell::escape(&format!("{}/{}", tmp_dir, UPLOAD_FILE)),
+        shell::escape(dest_path),
+        shell::escape(tmp_dir)
+    )
+}
+
+/// Upload a local file byte for byte with scp. The file is copied into a
+/// private `mktemp -d` directory on the host first and then installed at
+/// `dest_path` with `mode` and `owner`, so a dropped transfer never leaves a
+/// truncated file in place.
+pub fn upload_file(host: &str, local_path: &Path, dest_path: &str, mode: u32, owner: Option<&str>, use_doas: bool) -> Result<()> {
+    traced(
+        host,
+        &format!("upload {} -> {}", local_path.display(), dest_path),
+        || with_executor(|e| e.upload_file(host, local_path, dest_path, mode, owner, use_doas)),
+        |_| None,
+    )
+}
+
+fn upload_file_untraced(host: &str, local_path: &Path, dest_path: &str, mode: u32, owner: Option<&str>, use_doas: bool) -> Result<()> {
+    debug!("SCP [{}] Uploading {:?} to {}", host, local_path, dest_path);
+
+    // mktemp -d creates the directory with mode 0700 under a random name
+    let tmp_dir = run_with_output(host, "mktemp -d -t bsdeploy-upload")?.trim().to_string();
+    if tmp_dir.is_empty() {
+        return Err(anyhow!("mktemp -d returned no directory on {}", host));
+    }
+    let tmp_path = format!("{}/{}", tmp_dir, UPLOAD_FILE);
+    let cleanup = || {
+        run(host, &format!("rm -rf {}", shell::escape(&tmp_dir))).ok();
+    };
+    if is_local(host) {
+        if let Err(e) = std::fs::copy(local_path, &tmp_path) {
+            cleanup();
+            return Err(e).with_context(|| format!("Failed to copy {:?} to {}", local_path, tmp_path));
+        }
+        return run(host, &install_command(&tmp_dir, dest_path, mode, owner, use_doas))
+            .with_context(|| format!("Failed to install {}", dest_path));
+    }
+    if native_ssh(host) {
+        let copied = File::open(local_path)
+            .with_context(|| format!("Failed to open {:?}", local_path))
+            .and_then(|mut file| {
+                let command = format!("cat > {}", shell::escape(&tmp_path));
+                run_native_io(host, &command, &mut file, Timeout::Build.duration(), &mut |_, _| Ok(()))
+            });
+        match copied {
+            Ok((true, _)) => {}
+            Ok((false, stderr)) => {
+                cleanup();
+                return Err(anyhow!("Failed to upload {:?} to {}: {}", local_path, host, stderr.trim()));
+            }
+            Err(e) => {
+                cleanup();
+                return Err(e).with_context(|| format!("Failed to upload {:?} to {}", local_path, host));
+            }
+        }
+        return run(host, &install_command(&tmp_dir, dest_path, mode, owner, use_doas))
+            .with_context(|| format!("Failed to install {} on {}", dest_path, host));
+    }
+
+    let mut cmd = Command::new("scp");
+    cmd.arg("-q").arg("-B").args(connection_options(host));
+    let child = cmd
+        .arg(local_path)
+        .arg(format!("{}:{}", host, tmp_path))
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn();
+    let mut child = match child {
+        Ok(child) => child,
+        Err(e) => {
+            cleanup();
+            return Err(e).with_context(|| format!("Failed to execute scp to {}", host));
+        }
+    };
+
+    // Drain stderr in background to prevent pipe buffer deadlock
+    let stderr_handle = child.stderr.take();
+    let stderr_thread = std::thread::spawn(move || {
+        let mut stderr = String::new();
+        if let Some(mut err) = stderr_handle {
+            err.read_to_string(&mut stderr).ok();
+        }
+        stderr
+    });
+
+    let timeout = Timeout::Build.duration();
+    let status = match child.wait_timeout(timeout)
+        .with_context(|| format!("Failed to wait for scp to {}", host))?
+    {
+        Some(status) => status,
+        None => {
+            child.kill().ok();
+            child.wait().ok();
+            cleanup();
+            return Err(anyhow!("scp timed out after {:?} uploading {:?} to {}", timeout, local_path, host));
+        }
+    };
+
+    if !status.success() {
+        let stderr = stderr_thread.join().unwrap_or_default();
+        cleanup();
+        return Err(anyhow!("Failed to upload {:?} to {}: {}", local_path, host, stderr.trim()));
+    }
+
+    run(host, &install_command(&tmp_dir, dest_path, mode, owner, use_doas))
+        .with_context(|| format!("Failed to install {} on {}", dest_path, host))
+}
+
+/// Upload in-memory content byte for byte, see `upload_file`. The local
+/// scratch copy is only readable by the current user.
+pub fn upload_bytes(host: &str, content: &[u8], dest_path: &str, mode: u32, owner: Option<&str>, use_doas: bool) -> Result<()> {
+    let local_path = std::env::temp_dir().join(upload_temp_name());
+    let result = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&local_path)
+        .and_then(|mut file| file.write_all(content))
+        .with_context(|| format!("Failed to write {:?}", local_path))
+        .and_then(|_| upload_file(host, &local_path, dest_path, mode, owner, use_doas));
+    std::fs::remove_file(&local_path).ok();
+    result
+}
+
+pub fn sync(host: &str, src: &str, dest: &str, excludes: &[String], options: &SyncConfig, use_doas: bool) -> Result<()> {
+    traced(
+        host,
+        &format!("rsync {} -> {}", src, dest),
+        || with_executor(|e| e.sync(host, src, dest, excludes, options, use_doas)),
+        |_| None,
+    )
+}
+
+/// Upload the committed tree (`git archive HEAD` of the current directory)
+/// and unpack it into `dest` in a single stream. `excludes` are paths
+/// relative to the repository root that are not extracted.
+pub fn sync_git_archive(host: &str, dest: &str, excludes: &[String], use_doas: bool) -> Result<()> {
+    traced(
+        host,
+        &format!("git archive HEAD -> {}", dest),
+        || with_executor(|e| e.sync_git_archive(host, dest, excludes, use_doas)),
+        |_| None,
+    )
+}
+
+fn sync_git_archive_untraced(host: &str, dest: &str, excludes: &[String], use_doas: bool) -> Result<()> {
+    debug!("Extracting git archive of HEAD to {}:{}", host, dest);
+
+    let mut archive = Command::new("git")
+        .args(["archive", "--format=tar", "HEAD"])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| "Failed to execute git archive")?;
+
+    let cmd_prefix = shell::escalation_prefix(use_doas);
+    let mut tar_cmd = format!("{}tar -xf - -C {}", cmd_prefix, shell::escape(dest));
+    for ex in excludes {
+        tar_cmd.push_str(&format!(" --exclude {}", shell::escape(ex)));
+    }
+
+    let mut archive_stdout = archive.stdout.take().context("git archive has no stdout")?;
+    let (success, stderr) = if native_ssh(host) {
+        run_native_io(host, &tar_cmd, &mut archive_stdout, Timeout::Build.duration(), &mut |_, _| Ok(()))?
+    } else {
+        let output = ssh_command(host)
+            .arg(&tar_cmd)
+            .stdin(Stdio::from(archive_stdout))
+            .output()
+            .with_context(|| format!("Failed to execute ssh command on {}", host))?;
+        (output.status.success(), String::from_utf8_lossy(&output.stderr).into_owned())
+    };
+
+    let archive_output = archive.wait_with_output().with_context(|| "Failed to wait for git archive")?;
+    if !archive_output.status.success() {
+        let stderr = String::from_utf8_lossy(&archive_output.stderr);
+        return Err(anyhow!("git archive failed: {}", stderr.trim()));
+    }
+    if !success {
+        return Err(anyhow!("Failed to extract git archive on {}: {}", host, stderr.trim()));
+    }
+    Ok(())
+}
+
+/// rsync arguments from the `sync` config. Compression is only used over
+/// the network.
+fn sync_options(options: &SyncConfig, compress: bool) -> Vec<String> {
+    let mut args = Vec::new();
+    if compress {
+        match options.compress_level {
+            Some(0) => {}
+            // A non-zero level implies --compress
+            Some(level) => args.push(format!("--compress-level={}", level)),
+            None => args.push("-z".to_string()),
+        }
+    }
+    if let Some(bwlimit) = &options.bwlimit {
+        args.push(format!("--bwlimit={}", bwlimit));
+    }
+    if options.checksum {
+        args.push("--checksum".to_string());
+    }
+    args.extend(options.extra_args.iter().cloned());
+    args
+}
+
+fn sync_untraced(host: &str, src: &str, dest: &str, excludes: &[String], options: &SyncConfig, use_doas: bool) -> Result<()> {
+    debug!("Syncing {} to {}:{}", src, host, dest);
+    // Ensure rsync is installed locally
+    let mut cmd = if is_local(host) && use_doas {
+        let mut words = shell::escalation_prefix(true).split_whitespace();
+        let mut cmd = Command::new(words.next().unwrap_or("rsync"));
+        cmd.args(words).arg("rsync");
+        cmd
+    } else {
+        Command::new("rsync")
+    };
+    cmd.arg("-a")
+       .arg("--delete-delay")      // Delete after transfer, not during (safer)
+       .arg("--timeout=30")         // Prevent hanging on network issues
+       .arg("--filter=:- .gitignore")
+       .arg("--exclude=.git")
+       .arg("--exclude=node_modules")
+       .arg("--exclude=tmp")
+       .arg("--exclude=log");
+    
+    for ex in excludes {
+        cmd.arg(format!("--exclude={}", ex));
+    }
+    
+    cmd.args(sync_options(options, !is_local(host)));
+
+    let target = if is_local(host) {
+        dest.to_string()
+    } else {
+        if use_doas {
+            cmd.arg(format!("--rsync-path={}rsync", shell::escalation_prefix(true)));
+        }
+        let options = connection_options(host);
+        if !options.is_empty() {
+            cmd.arg("-e").arg(format!("ssh {}", options.join(" ")));
+        }
+        format!("{}:{}", host, dest)
+    };
+
+    let output = cmd
+        .arg(src)
+        .arg(target)
+        .output() // Capture output
+        .with_context(|| "Failed to execute rsync")?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(anyhow!("Failed to sync files to {}: {}", host, stderr.trim()));
+    }
+    Ok(())
+}
+
+/// Detect if a path is on a ZFS dataset and return the dataset name
+pub fn get_zfs_dataset(host: &str, path: &str) -> Result<Option<String>> {
+    // 1. Find the mountpoint for the path using df
+    // df -p is POSIX but might not give exactly what we want.
+    // On FreeBSD, 'df <path>' shows the mountpoint in the first column if it's a device/dataset.
+    let safe_path = shell::escape(path);
+    let df_cmd = format!("df {} | tail -n 1 | awk '{{print $1}}'", safe_path);
+    let dataset_candidate = match query(host, &df_cmd) {
+        Ok(out) => out.trim().to_string(),
+        Err(_) => return Ok(None),
+    };
+
+    if dataset_candidate.is_empty() || dataset_candidate.starts_with('/') {
+        // Not a ZFS dataset (likely a regular path or something else)
+        return Ok(None);
+    }
+
+    // 2. Verify it's a ZFS dataset
+    let safe_dataset = shell::escape(&dataset_candidate);
+    let zfs_cmd = format!("zfs list -H -o name {} 2>/dev/null", safe_dataset);
+    let output = match query(host, &zfs_cmd) {
+        Ok(out) => out,
+        Err(_) => {
+            let doas_cmd = format!("{}zfs list -H -o name {} 2>/dev/null", shell::escalation_prefix(true), safe_dataset);
+            match query(host, &doas_cmd) {
+                Ok(out) => out,
+                Err(_) => return Ok(None),
+            }
+        }
+    };
+
+    let name = output.trim().to_string();
+    if name.is_empty() {
+        Ok(None)
+    } else {
+        debug!("Detected ZFS dataset {} for path {}", name, path);
+        Ok(Some(name))
+    }
+}
+/// Run a command on the remote host and stream its stdout into a local file.
+/// Used for pulling large binary payloads (e.g. image tarballs) off a host.
+pub fn run_to_file(host: &str, command: &str, local_path: &Path) -> Result<()> {
+    traced(
+        host,
+        &format!("{} > {}", command, local_path.display()),
+        || with_executor(|e| e.run_to_file(host, command, local_path)),
+        |_| None,
+    )
+}
+
+fn run_to_file_untraced(host: &str, command: &str, local_path: &Path) -> Result<()> {
+    debug!("SSH [{}] Executing (to file {:?}): {}", host, local_path, command);
+
+    let mut file = File::create(local_path)
+        .with_context(|| format!("Failed to create local file {:?}", local_path))?;
+
+    if native_ssh(host) {
+        let (success, stderr) = run_native_io(host, command, &mut std::io::empty(), Timeout::Build.duration(), &mut |stream, data| {
+            if stream == Stream::Stdout {
+                file.write_all(data).with_context(|| format!("Failed to write local file {:?}", local_path))?;
+            }
+            Ok(())
+        })?;
+        if !success {
+            return Err(CommandError::new(host, command, &stderr).into());
+        }
+        return Ok(());
+    }
+
+    let mut child = ssh_command(host)
+        .arg(command)
+        .stdout(Stdio::from(file))
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Failed to execute ssh command on {}", host))?;
+
+    // Drain stderr in background to prevent pipe buffer deadlock
+    let stderr_handle = child.stderr.take();
+    let stderr_thread = std::thread::spawn(move || {
+        let mut stderr = String::new();
+        if let Some(mut err) = stderr_handle {
+            err.read_to_string(&mut stderr).ok();
+        }
+        stderr
+    });
+
+    let status = match child.wait_timeout(Timeout::Build.duration())
+        .with_context(|| format!("Failed to wait for ssh command on {}", host))?
+    {
+        Some(status) => status,
+        None => {
+            child.kill().ok();
+            child.wait().ok();
+            return Err(anyhow!("SSH command timed out after {:?} on {}: {}", Timeout::Build.duration(), host, command));
+        }
+    };
+
+    if !status.success() {
+        let stderr = stderr_thread.join().unwrap_or_default();
+        return Err(CommandError::new(host, command, &stderr).into());
+    }
+    Ok(())
+}
+
+/// Run a command on the remote host with a local file streamed to its stdin.
+/// Used for pushing large binary payloads (e.g. image tarballs) to a host.
+pub fn run_from_file(host: &str, local_path: &Path, command: &str) -> Result<()> {
+    traced(
+        host,
+        &format!("{} < {}", command, local_path.display()),
+        || with_executor(|e| e.run_from_file(host, local_path, command)),
+        |_| None,
+    )
+}
+
+fn run_from_file_untraced(host: &str, local_path: &Path, command: &str) -> Result<()> {
+    debug!("SSH [{}] Executing (from file {:?}): {}", host, local_path, command);
+
+    let mut file = File::open(local_path)
+        .with_context(|| format!("Failed to open local file {:?}", local_path))?;
+
+    if native_ssh(host) {
+        let (success, stderr) = run_native_io(host, command, &mut file, Timeout::Build.duration(), &mut |_, _| Ok(()))?;
+        if !success {
+            return Err(CommandError::new(host, command, &stderr).into());
+        }
+        return Ok(());
+    }
+
+    let mut child = ssh_command(host)
+        .arg(command)
+        .stdin(Stdio::from(file))
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Failed to execute ssh command on {}", host))?;
+
+    // Drain stderr in background to prevent pipe buffer deadlock
+    let stderr_handle = child.stderr.take();
+    let stderr_thread = std::thread::spawn(move || {
+        let mut stderr = String::new();
+        if let Some(mut err) = stderr_handle {
+            err.read_to_string(&mut stderr).ok();
+        }
+        stderr
+    });
+
+    let status = match child.wait_timeout(Timeout::Build.duration())
+        .with_context(|| format!("Failed to wait for ssh command on {}", host))?
+    {
+        Some(status) => status,
+        None => {
+            child.kill().ok();
+            child.wait().ok();
+            return Err(anyhow!("SSH command timed out after {:?} on {}: {}", Timeout::Build.duration(), host, command));
+        }
+    };
+
+    if !status.success() {
+        let stderr = stderr_thread.join().unwrap_or_default();
+        return Err(CommandError::new(host, command, &stderr).into());
+    }
+    Ok(())
+}
+
+/// Run a command on the remote host, passing each line of stdout and stderr
+/// to `on_line` as it arrives. Used for long-running build steps whose
+/// progress should be visible while they run.
+pub fn run_streaming<F: FnMut(&str)>(host: &str, command: &str, timeout: Timeout, mut on_line: F) -> Result<()> {
+    let capture = transcript::is_active();
+    traced(
+        host,
+        command,
+        || {
+            let mut output = String::new();
+            with_executor(|e| {
+                e.run_streaming(host, command, timeout, &mut |line| {
+                    if capture {
+                        output.push_str(line);
+                        output.push('\n');
+                    }
+                    on_line(line)
+                })
+            })?;
+            Ok(output)
+        },
+        |output| Some(output),
+    )
+    .map(|_| ())
+}
+
+fn run_streaming_once(host: &str, command: &str, timeout: Timeout, on_line: &mut dyn FnMut(&str)) -> Result<()> {
+    debug!("SSH [{}] Executing (streaming): {}", host, command);
+
+    if native_ssh(host) {
+        // Partial lines of stdout and stderr until their newline arrives
+        let mut partial = [Vec::new(), Vec::new()];
+        let (success, stderr) = run_native_io(host, command, &mut std::io::empty(), timeout.duration(), &mut |stream, data| {
+            let buffer = &mut partial[stream as usize];
+            buffer.extend_from_slice(data);
+            while let Some(end) = buffer.iter().position(|&b| b == b'\n') {
+                let line: Vec<u8> = buffer.drain(..=end).collect();
+                on_line(String::from_utf8_lossy(&line[..end]).trim_end_matches('\r'));
+            }
+            Ok(())
+        })?;
+        for rest in partial.iter().filter(|rest| !rest.is_empty()) {
+            on_line(&String::from_utf8_lossy(rest));
+        }
+        if !success {
+            return Err(CommandError::new(host, command, &stderr).into());
+        }
+        return Ok(());
+    }
+
+    let mut child = ssh_command(host)
+        .arg(command)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Failed to execute ssh command on {}", host))?;
+
+    // Forward both streams line by line; stderr is also kept for the error message
+    let (tx, rx) = mpsc::channel::<String>();
+
+    let stdout_handle = child.stdout.take();
+    let stdout_tx = tx.clone();
+    let stdout_thread = std::thread::spawn(move || {
+        if let Some(out) = stdout_handle {
+            for line in BufReader::new(out).lines().map_while(|l| l.ok()) {
+                stdout_tx.send(line).ok();
+            }
+        }
+    });
+
+    let stderr_handle = child.stderr.take();
+    let stderr_thread = std::thread::spawn(move || {
+        let mut stderr = String::new();
+        if let Some(err) = stderr_handle {
+            for line in BufReader::new(err).lines().map_while(|l| l.ok()) {
+                stderr.push_str(&line);
+                stderr.push('\n');
+                tx.send(line).ok();
+            }
+        }
+        stderr
+    });
+
+    let deadline = Instant::now() + timeout.duration();
+    loop {
+        if Instant::now() >= deadline {
+            child.kill().ok();
+            child.wait().ok();
+            return Err(anyhow!("SSH command timed out after {:?} on {}: {}", timeout.duration(), host, command));
+        }
+        match rx.recv_timeout(Duration::from_millis(200)) {
+            Ok(line) => on_line(&line),
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+
+    let status = child.wait()
+        .with_context(|| format!("Failed to wait for ssh command on {}", host))?;
+    stdout_thread.join().ok();
+    let stderr = stderr_thread.join().unwrap_or_default();
+
+    if !status.success() {
+        check_connection(host, status.code(), &stderr)?;
+        return Err(CommandError::new(host, command, &stderr).into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mock::MockExecutor;
+
+    #[test]
+    fn test_executor_reaches_threads_and_transfers() {
+        let mock = MockExecutor::new();
+        mock::with_executor(mock.clone(), || {
+            std::thread::spawn(|| run("web1", "uptime")).join().unwrap().unwrap();
+            upload_bytes("web1", b"secret", "/usr/local/etc/app.key", 0o600, None, true).unwrap();
+            sync("web1", ".", "/srv/app", &[], &SyncConfig::default(), true).unwrap();
+            sync_git_archive("web1", "/srv/app", &[], true).unwrap();
+        });
+        assert!(mock.ran("uptime"));
+        assert_eq!(mock.file("/usr/local/etc/app.key").as_deref(), Some("secret"));
+        assert!(mock.ran("rsync . -> /srv/app"));
+        assert!(mock.ran("git archive HEAD -> /srv/app"));
+    }
+
+    #[test]
+    fn test_is_local() {
+        assert!(is_local("local"));
+        assert!(!is_local("localhost"));
+        assert!(!is_local("deploy@localhost"));
+    }
+
+    #[test]
+    fn test_keepalive_options() {
+        assert_eq!(
+            keepalive_options(&SshConfig::default()),
+            ["-o", "ConnectTimeout=30", "-o", "ServerAliveInterval=15", "-o", "ServerAliveCountMax=4"]
+        );
+        let ssh = SshConfig { server_alive_interval: 0, ..SshConfig::default() };
+        assert_eq!(keepalive_options(&ssh), ["-o", "ConnectTimeout=30"]);
+    }
+
+    #[test]
+    fn test_env_options() {
+        let ssh = SshConfig {
+            forward_agent: true,
+            send_env: vec!["GITHUB_TOKEN".to_string()],
+            set_env: [("RAILS_ENV".to_string(), "production".to_string())].into(),
+            ..SshConfig::default()
+        };
+        assert_eq!(
+            env_options(&ssh),
+            ["-A", "-o", "SendEnv=GITHUB_TOKEN", "-o", "SetEnv=RAILS_ENV=\"production\""]
+        );
+        assert!(env_options(&SshConfig::default()).is_empty());
+    }
+
+    #[test]
+    fn test_sync_options() {
+        assert_eq!(sync_options(&SyncConfig::default(), true), ["-z"]);
+        assert!(sync_options(&SyncConfig::default(), false).is_empty());
+
+        let options = SyncConfig {
+            bwlimit: Some("2m".to_string()),
+            compress_level: Some(3),
+            checksum: true,
+            extra_args: vec!["--partial".to_string()],
+            ..SyncConfig::default()
+        };
+        assert_eq!(
+            sync_options(&options, true),
+            ["--compress-level=3", "--bwlimit=2m", "--checksum", "--partial"]
+        );
+        let options = SyncConfig { compress_level: Some(0), ..SyncConfig::default() };
+        assert!(sync_options(&options, true).is_empty());
+    }
+
+    #[test]
+    fn test_install_command() {
+        assert_eq!(
+            install_command("/tmp/bsdeploy-upload.Xa1", "/usr/local/etc/caddy/certs/app.key", 0o600, Some("www:www"), true),
+            "doas install -m 0600 -o www -g www /tmp/bsdeploy-upload.Xa1/upload /usr/local/etc/caddy/certs/app.key; rc=$?; rm -rf /tmp/bsdeploy-upload.Xa1; exit $rc"
+        );
+        assert_eq!(
+            install_command("/tmp/bsdeploy-upload.Xb2", "/srv/registry/abc.txz", 0o644, None, false),
+            "install -m 0644 /tmp/bsdeploy-upload.Xb2/upload /srv/registry/abc.txz; rc=$?; rm -rf /tmp/bsdeploy-upload.Xb2; exit $rc"
+        );
+    }
+
+    #[test]
+    fn test_is_connection_failure() {
+        assert!(is_connection_failure(Some(255), "ssh: connect to host example.com port 22: Connection refused"));
+        assert!(is_connection_failure(Some(255), "kex_exchange_identification: read: Connection reset by peer"));
+        // The command may already have run when an established session drops
+        assert!(!is_connection_failure(Some(255), "Connection closed by 192.0.2.1 port 22"));
+        assert!(!is_connection_failure(Some(255), "client_loop: send disconnect: Broken pipe"));
+        // The remote command failing is never retried, even with ssh-like output
+        assert!(!is_connection_failure(Some(1), "Connection refused"));
+        assert!(!is_connection_failure(Some(255), "pkg: No packages available to install"));
+        assert!(!is_connection_failure(None, "Connection reset"));
+    }
+}
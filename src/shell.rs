@@ -1,7 +1,48 @@
-/// Shell command building utilities for safe command construction.
-///
-/// This module provides utilities to safely construct shell commands
-/// by properly escaping user-controlled input to prevent command injection.
+//! Shell command building utilities for safe command construction.
+//!
+//! This module provides utilities to safely construct shell commands
+//! by properly escaping user-controlled input to prevent command injection.
+
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use crate::config::PrivilegeEscalation;
+
+/// Privilege escalation tool used for commands that need root
+static ESCALATION: AtomicU8 = AtomicU8::new(PrivilegeEscalation::Doas as u8);
+
+pub fn set_privilege_escalation(tool: PrivilegeEscalation) {
+    ESCALATION.store(tool as u8, Ordering::Relaxed);
+}
+
+fn prefix_for(tool: PrivilegeEscalation) -> &'static str {
+    match tool {
+        PrivilegeEscalation::Doas => "doas ",
+        // -n: fail instead of prompting for a password nobody can type
+        PrivilegeEscalation::Sudo => "sudo -n ",
+        PrivilegeEscalation::None => "",
+        // The only command the doas rule of `setup --install-doas` permits
+        PrivilegeEscalation::DoasHelper => "doas /usr/local/libexec/bsdeploy-helper exec ",
+    }
+}
+
+/// The configured privilege escalation tool
+pub fn privilege_escalation() -> PrivilegeEscalation {
+    match ESCALATION.load(Ordering::Relaxed) {
+        x if x == PrivilegeEscalation::Sudo as u8 => PrivilegeEscalation::Sudo,
+        x if x == PrivilegeEscalation::None as u8 => PrivilegeEscalation::None,
+        x if x == PrivilegeEscalation::DoasHelper as u8 => PrivilegeEscalation::DoasHelper,
+        _ => PrivilegeEscalation::Doas,
+    }
+}
+
+/// Prefix for a command that needs root (e.g. `"doas "`), or `""` when
+/// `escalate` is false.
+pub fn escalation_prefix(escalate: bool) -> &'static str {
+    if !escalate {
+        return "";
+    }
+    prefix_for(privilege_escalation())
+}
 
 /// Escape a string for safe use in a POSIX shell command.
 ///
@@ -59,6 +100,18 @@ pub fn escape_env_value(s: &str) -> String {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_prefix_for() {
+        assert_eq!(prefix_for(PrivilegeEscalation::Doas), "doas ");
+        assert_eq!(prefix_for(PrivilegeEscalation::Sudo), "sudo -n ");
+        assert_eq!(prefix_for(PrivilegeEscalation::None), "");
+        assert_eq!(
+            prefix_for(PrivilegeEscalation::DoasHelper),
+            format!("doas {} exec ", crate::constants::HELPER_PATH)
+        );
+        assert_eq!(escalation_prefix(false), "");
+    }
+
     #[test]
     fn test_escape_simple() {
         assert_eq!(escape("hello"), "hello");
@@ -0,0 +1,377 @@
+//! Built-in SSH transport on top of libssh2, used instead of the `ssh`
+//! binary when `ssh.transport: native` is configured.
+//!
+//! One session is kept per host. Every command opens its own channel on
+//! it, so commands to the same host run concurrently over one connection.
+//! Hosts must already be in ~/.ssh/known_hosts; authentication tries the
+//! SSH agent first, then the default key files.
+//!
+//! Limits: ~/.ssh/config is not read (no `Host` aliases, `HostName`,
+//! `User`, `Port`, `IdentityFile` or `ProxyJump`), agent forwarding is not
+//! supported, and rsync and interactive commands (`setup --install-doas
+//! su`) still run the `ssh` binary. The client is libssh2 through the
+//! `ssh2` crate.
+
+use anyhow::{Context, Result, anyhow, bail};
+use log::debug;
+use ssh2::{CheckResult, KnownHostFileKind, Session};
+use std::collections::HashMap;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+use std::time::{Duration, Instant};
+
+use crate::config::SshConfig;
+use crate::remote::{ConnectionError, Stream};
+
+/// SSH settings from the loaded configuration
+static SSH_CONFIG: RwLock<Option<SshConfig>> = RwLock::new(None);
+
+pub fn configure(ssh: &SshConfig) {
+    *SSH_CONFIG.write().unwrap() = Some(ssh.clone());
+}
+
+fn settings() -> SshConfig {
+    SSH_CONFIG.read().unwrap().clone().unwrap_or_default()
+}
+
+/// Key files tried (in order) when the agent cannot authenticate
+const DEFAULT_KEY_FILES: &[&str] = &["id_ed25519", "id_ecdsa", "id_rsa"];
+
+/// Result of a command run over the native transport
+pub struct Output {
+    pub success: bool,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+/// Per-host slot holding the session and its id. The slot's lock is only
+/// held while connecting, so a host is connected to once; commands run on
+/// clones of the session.
+type Slot = Arc<Mutex<Option<(u64, Session)>>>;
+
+static SESSIONS: OnceLock<Mutex<HashMap<String, Slot>>> = OnceLock::new();
+
+/// Source of session ids, so a failed command only drops its own session
+static NEXT_SESSION: AtomicU64 = AtomicU64::new(0);
+
+/// Split `[user@]host[:port]` into user, host and port (defaults: $USER, 22).
+fn parse_target(target: &str) -> (String, String, u16) {
+    let (user, rest) = match target.split_once('@') {
+        Some((user, rest)) => (user.to_string(), rest),
+        None => (std::env::var("USER").unwrap_or_else(|_| "root".to_string()), target),
+    };
+    match rest.rsplit_once(':') {
+        Some((host, port)) if !host.contains(':') => match port.parse() {
+            Ok(port) => (user, host.to_string(), port),
+            Err(_) => (user, rest.to_string(), 22),
+        },
+        _ => (user, rest.to_string(), 22),
+    }
+}
+
+fn ssh_dir() -> Result<PathBuf> {
+    std::env::var("HOME")
+        .map(|home| PathBuf::from(home).join(".ssh"))
+        .map_err(|_| anyhow!("HOME is not set; cannot locate ~/.ssh"))
+}
+
+/// Failures before the session is up are `ConnectionError`s, so they are
+/// retried like ssh's; host key and authentication failures are not.
+fn connect(target: &str) -> Result<Session> {
+    let (user, host, port) = parse_target(target);
+    debug!("SSH native: connecting to {}@{}:{}", user, host, port);
+    let ssh = settings();
+    let connect_timeout = Duration::from_secs(ssh.connect_timeout);
+    let unreachable = |reason: String| ConnectionError::new(target, &reason);
+
+    let addr = (host.as_str(), port)
+        .to_socket_addrs()
+        .map_err(|e| unreachable(format!("Could not resolve hostname {}: {}", host, e)))?
+        .next()
+        .ok_or_else(|| unreachable(format!("Could not resolve hostname {}: no address", host)))?;
+    let tcp = TcpStream::connect_timeout(&addr, connect_timeout)
+        .map_err(|e| unreachable(format!("connect to host {} port {}: {}", host, port, e)))?;
+
+    let mut session = Session::new().context("Failed to create SSH session")?;
+    session.set_tcp_stream(tcp);
+    session.set_timeout(connect_timeout.as_millis().min(u32::MAX as u128) as u32);
+    session
+        .handshake()
+        .map_err(|e| unreachable(format!("SSH handshake with {} failed: {}", host, e)))?;
+
+    verify_host_key(&session, &host, port)?;
+    authenticate(&session, &user, &host)?;
+
+    if ssh.server_alive_interval > 0 {
+        session.set_keepalive(true, ssh.server_alive_interval.min(u32::MAX as u64) as u32);
+    }
+    // Channels of concurrent commands share the session, so nothing may block on it
+    session.set_blocking(false);
+    Ok(session)
+}
+
+fn verify_host_key(session: &Session, host: &str, port: u16) -> Result<()> {
+    let mut known_hosts = session.known_hosts().context("Failed to initialize known hosts")?;
+    let known_hosts_file = ssh_dir()?.join("known_hosts");
+    if known_hosts_file.exists() {
+        known_hosts
+            .read_file(&known_hosts_file, KnownHostFileKind::OpenSSH)
+            .with_context(|| format!("Failed to read {}", known_hosts_file.display()))?;
+    }
+
+    let (key, _) = session
+        .host_key()
+        .ok_or_else(|| anyhow!("{} did not present a host key", host))?;
+    match known_hosts.check_port(host, port, key) {
+        CheckResult::Match => Ok(()),
+        CheckResult::Mismatch => bail!(
+            "Host key for {} does not match ~/.ssh/known_hosts (possible man-in-the-middle attack)",
+            host
+        ),
+        CheckResult::NotFound => bail!(
+            "Host {} is not in ~/.ssh/known_hosts; connect once with ssh to verify its key",
+            host
+        ),
+        CheckResult::Failure => bail!("Failed to check the host key of {}", host),
+    }
+}
+
+fn authenticate(session: &Session, user: &str, host: &str) -> Result<()> {
+    if session.userauth_agent(user).is_ok() && session.authenticated() {
+        return Ok(());
+    }
+
+    let ssh_dir = ssh_dir()?;
+    for name in DEFAULT_KEY_FILES {
+        let key = ssh_dir.join(name);
+        if key.exists() && session.userauth_pubkey_file(user, None, &key, None).is_ok() && session.authenticated() {
+            return Ok(());
+        }
+    }
+
+    bail!("Authentication failed for {}@{} (tried ssh-agent and default keys)", user, host)
+}
+
+/// The host's session slot, created empty on first use.
+fn slot(target: &str) -> Slot {
+    let sessions = SESSIONS.get_or_init(|| Mutex::new(HashMap::new()));
+    sessions.lock().unwrap().entry(target.to_string()).or_default().clone()
+}
+
+/// The host's session, connecting first if there is none.
+fn session(slot: &Slot, target: &str) -> Result<(u64, Session)> {
+    let mut current = slot.lock().unwrap();
+    if current.is_none() {
+        let session = connect(target)?;
+        *current = Some((NEXT_SESSION.fetch_add(1, Ordering::Relaxed), session));
+    }
+    Ok(current.clone().unwrap())
+}
+
+/// Run `command` on the host, optionally feeding `stdin`. The command
+/// fails when it makes no progress for `timeout`.
+pub fn exec(target: &str, command: &str, stdin: Option<&[u8]>, timeout: Duration) -> Result<Output> {
+    let (mut stdout, mut stderr) = (Vec::new(), Vec::new());
+    let mut input = stdin.unwrap_or_default();
+    let status = exec_io(target, command, &mut input, timeout, &mut |stream, data| {
+        match stream {
+            Stream::Stdout => stdout.extend_from_slice(data),
+            Stream::Stderr => stderr.extend_from_slice(data),
+        }
+        Ok(())
+    })?;
+    Ok(Output {
+        success: status == 0,
+        stdout: String::from_utf8_lossy(&stdout).into_owned(),
+        stderr: String::from_utf8_lossy(&stderr).into_owned(),
+    })
+}
+
+/// Run `command` on the host with `stdin` on its standard input, passing
+/// its output to `on_output` as it arrives. Returns the exit status. The
+/// command fails when it makes no progress for `timeout`.
+pub fn exec_io(
+    target: &str,
+    command: &str,
+    stdin: &mut dyn Read,
+    timeout: Duration,
+    on_output: &mut dyn FnMut(Stream, &[u8]) -> Result<()>,
+) -> Result<i32> {
+    let slot = slot(target);
+    let (id, session) = session(&slot, target)?;
+    let result = exec_on(&session, command, stdin, timeout, on_output);
+
+    // A failed channel usually means the connection is gone; reconnect next time
+    if result.is_err() {
+        let mut current = slot.lock().unwrap();
+        if current.as_ref().is_some_and(|(current_id, _)| *current_id == id) {
+            *current = None;
+        }
+    }
+    result.with_context(|| format!("SSH command failed on {}: {}", target, command))
+}
+
+fn would_block(e: &std::io::Error) -> bool {
+    e.kind() == ErrorKind::WouldBlock
+}
+
+/// Retry a non-blocking libssh2 call until it stops asking to.
+fn retry<T>(deadline: Instant, mut f: impl FnMut() -> std::result::Result<T, ssh2::Error>) -> Result<T> {
+    loop {
+        match f() {
+            Ok(value) => return Ok(value),
+            Err(e) => {
+                let e = std::io::Error::from(e);
+                if !would_block(&e) {
+                    return Err(e.into());
+                }
+                if Instant::now() > deadline {
+                    bail!("Timed out");
+                }
+                std::thread::sleep(POLL_INTERVAL);
+            }
+        }
+    }
+}
+
+/// Wait between polls of a channel that has nothing to read or write
+const POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+/// `ssh.send_env` values from the local environment and `ssh.set_env`,
+/// set on each channel like ssh's `SendEnv` and `SetEnv`.
+fn channel_env(ssh: &SshConfig) -> Vec<(String, String)> {
+    let sent = ssh.send_env.iter().filter_map(|name| Some((name.clone(), std::env::var(name).ok()?)));
+    sent.chain(ssh.set_env.iter().map(|(name, value)| (name.clone(), value.clone()))).collect()
+}
+
+fn exec_on(
+    session: &Session,
+    command: &str,
+    stdin: &mut dyn Read,
+    timeout: Duration,
+    on_output: &mut dyn FnMut(Stream, &[u8]) -> Result<()>,
+) -> Result<i32> {
+    let deadline = Instant::now() + timeout;
+    let mut channel = retry(deadline, || session.channel_session())?;
+    for (name, value) in channel_env(&settings()) {
+        // sshd refuses variables it does not accept; ssh ignores that too
+        if let Err(e) = retry(deadline, || channel.setenv(&name, &value)) {
+            debug!("SSH native: {} was not accepted: {}", name, e);
+        }
+    }
+    retry(deadline, || channel.exec(command))?;
+
+    pump(session, &mut channel, stdin, timeout, on_output)?;
+    retry(Instant::now() + timeout, || channel.wait_close())?;
+    Ok(channel.exit_status()?)
+}
+
+/// Feeds stdin and drains stdout and stderr together, so a command filling
+/// one stream while bsdeploy waits on the other cannot stall the channel.
+/// Keepalives are sent from here while a command runs.
+fn pump(
+    session: &Session,
+    channel: &mut ssh2::Channel,
+    stdin: &mut dyn Read,
+    timeout: Duration,
+    on_output: &mut dyn FnMut(Stream, &[u8]) -> Result<()>,
+) -> Result<()> {
+    let mut deadline = Instant::now() + timeout;
+    let mut buf = [0u8; 32 * 1024];
+    let mut pending = Vec::new();
+    let mut input_done = false;
+    let mut eof_sent = false;
+
+    loop {
+        let mut progress = false;
+        if pending.is_empty() && !input_done {
+            let n = stdin.read(&mut buf).context("Failed to read the command's input")?;
+            pending.extend_from_slice(&buf[..n]);
+            input_done = n == 0;
+            progress = true;
+        }
+        if !pending.is_empty() {
+            match channel.write(&pending) {
+                Ok(n) => {
+                    pending.drain(..n);
+                    progress = true;
+                }
+                Err(e) if would_block(&e) => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        if input_done && pending.is_empty() && !eof_sent {
+            retry(deadline, || channel.send_eof())?;
+            eof_sent = true;
+            progress = true;
+        }
+        for (stream, mut reader) in [(Stream::Stdout, channel.stream(0)), (Stream::Stderr, channel.stderr())] {
+            match reader.read(&mut buf) {
+                Ok(0) => {}
+                Ok(n) => {
+                    on_output(stream, &buf[..n])?;
+                    progress = true;
+                }
+                Err(e) if would_block(&e) => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        if channel.eof() && eof_sent && !progress {
+            return Ok(());
+        }
+        if let Err(e) = session.keepalive_send() {
+            let e = std::io::Error::from(e);
+            if !would_block(&e) {
+                return Err(e).context("Connection lost");
+            }
+        }
+
+        if progress {
+            deadline = Instant::now() + timeout;
+        } else if Instant::now() > deadline {
+            bail!("Timed out after {:?} without output", timeout);
+        } else {
+            std::thread::sleep(POLL_INTERVAL);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_target() {
+        assert_eq!(
+            parse_target("deploy@example.com"),
+            ("deploy".to_string(), "example.com".to_string(), 22)
+        );
+        assert_eq!(
+            parse_target("deploy@example.com:2222"),
+            ("deploy".to_string(), "example.com".to_string(), 2222)
+        );
+        let (_, host, port) = parse_target("10.0.0.5");
+        assert_eq!((host.as_str(), port), ("10.0.0.5", 22));
+    }
+
+    #[test]
+    fn test_connect_failure_is_retryable() {
+        // Nothing listens on port 1, so the connection is refused before any SSH traffic
+        let error = connect("deploy@127.0.0.1:1").err().unwrap();
+        assert!(error.is::<ConnectionError>());
+    }
+
+    #[test]
+    fn test_channel_env() {
+        let ssh = SshConfig {
+            send_env: vec!["BSDEPLOY_TEST_UNSET_VARIABLE".to_string()],
+            set_env: [("RAILS_ENV".to_string(), "production".to_string())].into(),
+            ..SshConfig::default()
+        };
+        // Unset local variables are not sent, as with ssh's SendEnv
+        assert_eq!(channel_env(&ssh), [("RAILS_ENV".to_string(), "production".to_string())]);
+    }
+}
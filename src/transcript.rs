@@ -0,0 +1,139 @@
+//! Session transcript: every remote command with its duration, outcome and
+//! (optionally) output, so failed runs can be debugged after the fact.
+
+use anyhow::{Context, Result, anyhow};
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Transcripts kept in the default directory; older ones are removed
+const KEEP_TRANSCRIPTS: usize = 50;
+
+struct Transcript {
+    file: File,
+    path: PathBuf,
+    output: bool,
+}
+
+static TRANSCRIPT: Mutex<Option<Transcript>> = Mutex::new(None);
+
+/// `$XDG_STATE_HOME/bsdeploy`, falling back to `~/.local/state/bsdeploy`.
+fn default_dir() -> Result<PathBuf> {
+    if let Ok(state) = std::env::var("XDG_STATE_HOME")
+        && !state.is_empty()
+    {
+        return Ok(PathBuf::from(state).join("bsdeploy"));
+    }
+    let home = std::env::var("HOME").map_err(|_| anyhow!("HOME is not set"))?;
+    Ok(PathBuf::from(home).join(".local/state/bsdeploy"))
+}
+
+/// Remove all but the newest `KEEP_TRANSCRIPTS` transcripts in `dir`.
+fn prune(dir: &Path) {
+    let Ok(entries) = fs::read_dir(dir) else { return };
+    // Names start with a timestamp, so they sort chronologically
+    let mut logs: Vec<PathBuf> = entries
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|p| p.extension().is_some_and(|ext| ext == "log"))
+        .collect();
+    logs.sort();
+    let excess = logs.len().saturating_sub(KEEP_TRANSCRIPTS);
+    for old in &logs[..excess] {
+        fs::remove_file(old).ok();
+    }
+}
+
+/// Start writing the transcript to `path`, or to a timestamped file in the
+/// default directory. `output` also records command output.
+pub fn start(path: Option<&Path>, output: bool, command: &str) -> Result<PathBuf> {
+    let path = match path {
+        Some(path) => path.to_path_buf(),
+        None => {
+            let dir = default_dir()?;
+            fs::create_dir_all(&dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+            prune(&dir);
+            let stamp = chrono::Local::now().format("%Y%m%d-%H%M%S");
+            dir.join(format!("{}-{}.log", stamp, command))
+        }
+    };
+
+    // Commands and their output may carry secrets
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&path)
+        .with_context(|| format!("Failed to create transcript {}", path.display()))?;
+    let args: Vec<String> = std::env::args().collect();
+    writeln!(file, "# bsdeploy {} ({})", args.join(" "), chrono::Local::now().to_rfc3339())?;
+
+    *TRANSCRIPT.lock().unwrap() = Some(Transcript {
+        file,
+        path: path.clone(),
+        output,
+    });
+    Ok(path)
+}
+
+/// Path of the active transcript, if any.
+pub fn path() -> Option<PathBuf> {
+    TRANSCRIPT.lock().unwrap().as_ref().map(|t| t.path.clone())
+}
+
+pub fn is_active() -> bool {
+    TRANSCRIPT.lock().unwrap().is_some()
+}
+
+fn format_entry(host: &str, command: &str, elapsed: Duration, error: Option<&str>, output: Option<&str>) -> String {
+    let mut entry = format!(
+        "[{}] {} $ {}\n",
+        chrono::Local::now().format("%H:%M:%S%.3f"),
+        host,
+        command
+    );
+    match error {
+        None => entry.push_str(&format!("  -> ok ({:.2}s)\n", elapsed.as_secs_f64())),
+        Some(error) => {
+            entry.push_str(&format!("  -> failed ({:.2}s)\n", elapsed.as_secs_f64()));
+            for line in error.lines() {
+                entry.push_str(&format!("  ! {}\n", line));
+            }
+        }
+    }
+    for line in output.unwrap_or_default().lines() {
+        entry.push_str(&format!("  | {}\n", line));
+    }
+    entry
+}
+
+/// Append a command to the transcript. `output` is dropped unless output
+/// recording was requested.
+pub fn record(host: &str, command: &str, elapsed: Duration, error: Option<&str>, output: Option<&str>) {
+    let mut transcript = TRANSCRIPT.lock().unwrap();
+    if let Some(t) = transcript.as_mut() {
+        let output = if t.output { output } else { None };
+        t.file
+            .write_all(format_entry(host, command, elapsed, error, output).as_bytes())
+            .ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_entry() {
+        let entry = format_entry("web1", "zfs list", Duration::from_millis(1500), None, Some("zroot\nzroot/jails"));
+        let lines: Vec<&str> = entry.lines().collect();
+        assert!(lines[0].ends_with("] web1 $ zfs list"));
+        assert_eq!(&lines[1..], ["  -> ok (1.50s)", "  | zroot", "  | zroot/jails"]);
+
+        let entry = format_entry("web1", "false", Duration::ZERO, Some("Command failed\nexit 1"), None);
+        assert!(entry.ends_with("  -> failed (0.00s)\n  ! Command failed\n  ! exit 1\n"));
+    }
+}
@@ -0,0 +1,201 @@
+//! Host tuning (`host.sysctls`, `host.loader`), kept in a marked block of
+//! /etc/sysctl.conf and /boot/loader.conf so it survives reboots. The
+//! block is rewritten on every `setup`, so settings removed from the
+//! configuration are dropped too. sysctls are also set right away.
+//!
+//! The ZFS properties of `host.zfs` are compared and repaired the same
+//! way on every `setup`.
+
+use anyhow::{Context, Result};
+use std::collections::BTreeMap;
+
+use crate::config::Config;
+use crate::{remote, shell};
+
+const SYSCTL_CONF: &str = "/etc/sysctl.conf";
+const LOADER_CONF: &str = "/boot/loader.conf";
+
+/// Script replacing the bsdeploy block of `path` with `block`, printing
+/// `changed <path>` if the file changed. `p` prefixes the install.
+fn replace_block(path: &str, block: &str, p: &str) -> String {
+    let block = if block.is_empty() { String::new() } else { format!("# BEGIN bsdeploy\n{}# END bsdeploy\n", block) };
+    format!(
+        r#"f={path}; t=$(mktemp -t bsdeploy-tuning)
+{{ sed '/^# BEGIN bsdeploy$/,/^# END bsdeploy$/d' "$f" 2>/dev/null; cat <<'EOF'
+{block}EOF
+}} > "$t"
+if cmp -s "$t" "$f" || {{ [ ! -f "$f" ] && [ ! -s "$t" ]; }}; then rm -f "$t"; else {p}install -m 644 "$t" "$f" && rm -f "$t" && echo "changed $f"; fi
+"#,
+        path = path,
+        block = block,
+        p = p
+    )
+}
+
+/// Root script dropping the bsdeploy blocks from both files, for
+/// `teardown-host`
+pub fn remove_script() -> String {
+    replace_block(SYSCTL_CONF, "", "") + &replace_block(LOADER_CONF, "", "")
+}
+
+/// Script writing both blocks and setting the sysctls, escalating both
+/// with `p`. Prints `failed <name>` for sysctls that cannot be set now.
+fn script(sysctls: &BTreeMap<String, String>, loader: &BTreeMap<String, String>, p: &str) -> String {
+    let sysctl_block: String = sysctls.iter().map(|(k, v)| format!("{}={}\n", k, v)).collect();
+    let loader_block: String = loader.iter().map(|(k, v)| format!("{}=\"{}\"\n", k, v)).collect();
+    let mut script = replace_block(SYSCTL_CONF, &sysctl_block, p);
+    script.push_str(&replace_block(LOADER_CONF, &loader_block, p));
+    for (name, value) in sysctls {
+        script.push_str(&format!(
+            "{}sysctl {} >/dev/null 2>&1 || echo \"failed {}\"\n",
+            p,
+            shell::escape(&format!("{}={}", name, value)),
+            name
+        ));
+    }
+    script
+}
+
+/// What applying the tuning did on a host
+pub struct Applied {
+    /// /boot/loader.conf changed, so a reboot is due
+    pub loader_changed: bool,
+    /// sysctls that could not be set on the running system
+    pub failed: Vec<String>,
+}
+
+fn parse(output: &str) -> Applied {
+    Applied {
+        loader_changed: output.lines().any(|l| l == format!("changed {}", LOADER_CONF)),
+        failed: output.lines().filter_map(|l| l.strip_prefix("failed ")).map(str::to_string).collect(),
+    }
+}
+
+pub fn apply(config: &Config, host: &str) -> Result<Applied> {
+    let script = script(&config.host.sysctls, &config.host.loader, shell::escalation_prefix(config.doas));
+    let command = format!("sh -c {}", shell::escape(&script));
+    let output = remote::run_with_output(host, &command).context("Failed to write the sysctl and loader settings")?;
+    Ok(parse(&output))
+}
+
+/// Shell functions `zfs_script` calls when applying the properties,
+/// escalating the changes with `p`. Each change is printed as
+/// `zfs: <what>`.
+fn zfs_apply(p: &str) -> String {
+    format!(
+        r#"lc() {{ echo "$1" | tr A-Z a-z; }}
+zset() {{ cur=$(zfs get -H -o value "$1" "$3") || return 0; [ "$(lc "$cur")" = "$(lc "$2")" ] || {{ {p}zfs set "$1=$2" "$3" && echo "zfs: set $3 $1=$2 (was $cur)"; }}; }}
+zinherit() {{ zfs get -H -r -s local -o name,value "$1" "$root" 2>/dev/null | while read -r n v; do [ "$n" = "$root" ] || [ "$(lc "$v")" = "$(lc "$2")" ] || {{ {p}zfs inherit "$1" "$n" && echo "zfs: inherit $1 on $n (was $v)"; }}; done; }}
+nodataset() {{ echo "zfs: nodataset $1"; }}
+"#,
+        p = p
+    )
+}
+
+/// The functions of `zfs_apply` reporting what they would change through `drift`,
+/// for `setup --check`
+pub const ZFS_CHECK: &str = r#"lc() { echo "$1" | tr A-Z a-z; }
+zset() { cur=$(zfs get -H -o value "$1" "$3" 2>/dev/null) || return 0; [ "$(lc "$cur")" = "$(lc "$2")" ] || drift "set $1=$2 on $3 (is $cur)"; }
+zinherit() { zfs get -H -r -s local -o name,value "$1" "$root" 2>/dev/null | while read -r n v; do [ "$n" = "$root" ] || [ "$(lc "$v")" = "$(lc "$2")" ] || drift "inherit $1 on $n (is $v)"; done; }
+nodataset() { :; }
+"#;
+
+/// Commands keeping `host.zfs` on the datasets: the properties on `$root`,
+/// with values set below it dropped so they are inherited, and the data
+/// properties on the datasets mounted at the data directories. Needs
+/// `$root` set and the functions of `zfs_apply` or `ZFS_CHECK`.
+pub fn zfs_script(config: &Config) -> String {
+    let zfs = &config.host.zfs;
+    let mut script = String::new();
+    for (name, value) in &zfs.properties {
+        script.push_str(&format!("zset {n} {v} \"$root\"; zinherit {n} {v}\n", n = name, v = shell::escape(value)));
+    }
+    if !zfs.data_properties.is_empty() {
+        for dir in &config.data_directories {
+            let (path, _) = dir.get_paths();
+            script.push_str(&format!(
+                "ds=$(zfs list -H -o name,mountpoint | awk -v p={} '$2 == p {{print $1; exit}}')\n",
+                shell::escape(&path)
+            ));
+            let sets: Vec<String> =
+                zfs.data_properties.iter().map(|(n, v)| format!("zset {} {} \"$ds\"", n, shell::escape(v))).collect();
+            script.push_str(&format!(
+                "if [ -n \"$ds\" ]; then {}; else nodataset {}; fi\n",
+                sets.join("; "),
+                shell::escape(&path)
+            ));
+        }
+    }
+    script
+}
+
+/// What keeping the ZFS properties changed on a host
+pub struct ZfsApplied {
+    /// Properties set or inherited again, e.g. `set zroot/bsdeploy atime=off (was on)`
+    pub changes: Vec<String>,
+    /// Data directories that are no dataset of their own
+    pub not_datasets: Vec<String>,
+}
+
+fn parse_zfs(output: &str) -> ZfsApplied {
+    let lines = || output.lines().filter_map(|l| l.strip_prefix("zfs: "));
+    ZfsApplied {
+        changes: lines().filter(|l| !l.starts_with("nodataset ")).map(str::to_string).collect(),
+        not_datasets: lines().filter_map(|l| l.strip_prefix("nodataset ")).map(str::to_string).collect(),
+    }
+}
+
+/// Set the properties of `host.zfs` on the bsdeploy dataset `root` and the
+/// data directories.
+pub fn apply_zfs(config: &Config, host: &str, root: &str) -> Result<ZfsApplied> {
+    let script = format!("root={}\n{}{}", shell::escape(root), zfs_apply(shell::escalation_prefix(config.doas)), zfs_script(config));
+    let command = format!("sh -c {}", shell::escape(&script));
+    let output = remote::run_with_output(host, &command).context("Failed to set the ZFS properties")?;
+    Ok(parse_zfs(&output))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_script() {
+        let sysctls = BTreeMap::from([
+            ("kern.ipc.somaxconn".to_string(), "1024".to_string()),
+            ("security.jail.allow_raw_sockets".to_string(), "1".to_string()),
+        ]);
+        let loader = BTreeMap::from([("kern.racct.enable".to_string(), "1".to_string())]);
+        let script = script(&sysctls, &loader, "doas ");
+        assert!(script.contains("cat <<'EOF'\n# BEGIN bsdeploy\nkern.ipc.somaxconn=1024\nsecurity.jail.allow_raw_sockets=1\n# END bsdeploy\nEOF\n"));
+        assert!(script.contains("cat <<'EOF'\n# BEGIN bsdeploy\nkern.racct.enable=\"1\"\n# END bsdeploy\nEOF\n"));
+        assert!(script.contains("else doas install -m 644 \"$t\" \"$f\" && rm -f \"$t\" && echo \"changed $f\"; fi\n"));
+        assert!(script.ends_with("doas sysctl 'security.jail.allow_raw_sockets=1' >/dev/null 2>&1 || echo \"failed security.jail.allow_raw_sockets\"\n"));
+        // Without settings the blocks are only removed
+        assert!(super::script(&BTreeMap::new(), &BTreeMap::new(), "").contains("cat <<'EOF'\nEOF\n"));
+
+        let applied = parse("changed /etc/sysctl.conf\nchanged /boot/loader.conf\nfailed kern.maxfiles\n");
+        assert!(applied.loader_changed);
+        assert_eq!(applied.failed, ["kern.maxfiles"]);
+    }
+
+    #[test]
+    fn test_zfs_script() {
+        let config = Config::from_str(
+            "service: app\nhosts: [a]\ndata_directories: [/var/db/postgres]\nhost:\n  zfs:\n    data_properties: { recordsize: 16K }\n",
+        )
+        .unwrap();
+        assert_eq!(
+            zfs_script(&config),
+            "zset atime off \"$root\"; zinherit atime off\n\
+             zset compression zstd \"$root\"; zinherit compression zstd\n\
+             ds=$(zfs list -H -o name,mountpoint | awk -v p=/var/db/postgres '$2 == p {print $1; exit}')\n\
+             if [ -n \"$ds\" ]; then zset recordsize 16K \"$ds\"; else nodataset /var/db/postgres; fi\n"
+        );
+
+        assert!(zfs_apply("doas ").contains("|| { doas zfs set \"$1=$2\" \"$3\" && echo"));
+
+        let applied = parse_zfs("zfs: set zroot/bsdeploy compression=zstd (was lz4)\nzfs: nodataset /var/db/postgres\n");
+        assert_eq!(applied.changes, ["set zroot/bsdeploy compression=zstd (was lz4)"]);
+        assert_eq!(applied.not_datasets, ["/var/db/postgres"]);
+    }
+}
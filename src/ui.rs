@@ -1,28 +1,462 @@
 use colored::*;
-use indicatif::{ProgressBar, ProgressStyle};
-use std::time::Duration;
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle, TermLike};
+use std::io::{self, IsTerminal};
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::{LazyLock, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use crate::ci;
+
+/// How much is printed, ordered from least to most
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Verbosity {
+    /// Only results: successes, warnings and errors
+    Quiet,
+    /// Steps and spinners
+    Normal,
+    /// Remote command output, streamed as it runs
+    Output,
+    /// Remote command lines too
+    Commands,
+}
+
+static VERBOSITY: AtomicU8 = AtomicU8::new(Verbosity::Normal as u8);
+
+/// Spinners are replaced by timestamped lines
+static PLAIN: AtomicBool = AtomicBool::new(false);
+
+/// Messages are JSON events instead of decorated text
+static JSON: AtomicBool = AtomicBool::new(false);
+
+/// Command being run and the configured hosts, for the JSON events
+static CONTEXT: OnceLock<(String, Vec<String>)> = OnceLock::new();
+
+/// How bsdeploy reports progress
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Format {
+    #[default]
+    Text,
+    /// One JSON object per message on stdout, without spinners
+    Json,
+}
+
+/// All spinners are drawn through this so output can be printed above them
+static PROGRESS: LazyLock<MultiProgress> = LazyLock::new(MultiProgress::new);
+
+/// Verbosity from `-q` and the number of `-v`.
+pub fn set_verbosity(quiet: bool, verbose: u8) {
+    let verbosity = match (quiet, verbose) {
+        (true, _) => Verbosity::Quiet,
+        (false, 0) => Verbosity::Normal,
+        (false, 1) => Verbosity::Output,
+        (false, _) => Verbosity::Commands,
+    };
+    VERBOSITY.store(verbosity as u8, Ordering::Relaxed);
+}
+
+fn verbosity() -> Verbosity {
+    match VERBOSITY.load(Ordering::Relaxed) {
+        0 => Verbosity::Quiet,
+        1 => Verbosity::Normal,
+        2 => Verbosity::Output,
+        _ => Verbosity::Commands,
+    }
+}
+
+/// Stream remote command output (`-v`)
+pub fn is_verbose() -> bool {
+    verbosity() >= Verbosity::Output
+}
+
+/// Print every remote command line (`-vv`)
+pub fn shows_commands() -> bool {
+    verbosity() >= Verbosity::Commands
+}
+
+fn is_quiet() -> bool {
+    verbosity() == Verbosity::Quiet
+}
+
+/// Print plain timestamped lines instead of spinners, when asked to or
+/// when stderr is not a terminal (CI logs).
+pub fn set_plain(no_progress: bool) {
+    PLAIN.store(no_progress || !io::stderr().is_terminal(), Ordering::Relaxed);
+}
+
+fn is_plain() -> bool {
+    PLAIN.load(Ordering::Relaxed)
+}
+
+/// Time prefix of messages in plain mode
+fn stamp() -> String {
+    if is_plain() {
+        format!("{} ", chrono::Local::now().format("%H:%M:%S").to_string().dimmed())
+    } else {
+        String::new()
+    }
+}
+
+pub fn set_format(format: Format) {
+    JSON.store(format == Format::Json, Ordering::Relaxed);
+}
+
+pub fn is_json() -> bool {
+    JSON.load(Ordering::Relaxed)
+}
+
+/// Name the command (the `phase` of JSON events) and the hosts whose
+/// names are recognized at the start of messages.
+pub fn set_context(command: &str, hosts: &[String]) {
+    CONTEXT.set((command.to_string(), hosts.to_vec())).ok();
+}
+
+/// Host a message is about, from a `[host] ` or `host: ` prefix, and the
+/// rest of the message.
+fn split_host<'a>(msg: &'a str, hosts: &[String]) -> (Option<&'a str>, &'a str) {
+    if let Some((host, rest)) = msg.strip_prefix('[').and_then(|m| m.split_once("] "))
+        && !host.contains(' ')
+    {
+        return (Some(host), rest);
+    }
+    if let Some((host, rest)) = msg.split_once(": ")
+        && hosts.iter().any(|h| h == host)
+    {
+        return (Some(host), rest);
+    }
+    (None, msg)
+}
+
+fn event(level: &str, msg: &str, host: Option<&str>) -> serde_json::Value {
+    let (phase, hosts) = match CONTEXT.get() {
+        Some((command, hosts)) => (Some(command.as_str()), hosts.as_slice()),
+        None => (None, &[][..]),
+    };
+    let (host, message) = match host {
+        Some(host) => (Some(host), msg),
+        None => split_host(msg, hosts),
+    };
+    serde_json::json!({
+        "timestamp": chrono::Local::now().to_rfc3339(),
+        "level": level,
+        "host": host,
+        "phase": phase,
+        "message": message,
+    })
+}
+
+fn print_event(level: &str, msg: &str, host: Option<&str>) {
+    println!("{}", event(level, msg, host));
+}
 
 pub fn print_step(msg: &str) {
-    println!("{} {}", "::".blue().bold(), msg.bold());
+    if is_quiet() {
+        return;
+    }
+    if is_json() {
+        return print_event("step", msg, None);
+    }
+    println!("{}{} {}", stamp(), "::".blue().bold(), msg.bold());
 }
 
 pub fn print_success(msg: &str) {
-    println!("{} {}", "✔".green().bold(), msg.green());
+    if is_json() {
+        return print_event("success", msg, None);
+    }
+    println!("{}{} {}", stamp(), "✔".green().bold(), msg.green());
 }
 
 pub fn print_error(msg: &str) {
-    eprintln!("{} {}", "✖".red().bold(), msg.red());
+    if is_json() {
+        return print_event("error", msg, None);
+    }
+    eprintln!("{}{} {}", stamp(), "✖".red().bold(), msg.red());
+    annotate("error", msg);
 }
 
+pub fn print_warning(msg: &str) {
+    if is_json() {
+        return print_event("warning", msg, None);
+    }
+    eprintln!("{}{} {}", stamp(), "!".yellow().bold(), msg.yellow());
+    annotate("warning", msg);
+}
+
+/// Show an error or warning as an annotation of the run on GitHub Actions.
+pub fn annotate(level: &str, msg: &str) {
+    if is_json() || ci::detect() != Some(ci::Ci::GitHub) {
+        return;
+    }
+    let hosts = CONTEXT.get().map_or(&[][..], |(_, hosts)| hosts.as_slice());
+    let (host, text) = split_host(msg, hosts);
+    println!("{}", ci::annotation(level, host, text));
+}
+
+/// Suggest how to fix the preceding error.
+pub fn print_hint(msg: &str) {
+    if is_json() {
+        return print_event("hint", msg, None);
+    }
+    eprintln!("{}{} {}", stamp(), "→".yellow().bold(), msg.yellow());
+}
+
+/// Print a line of command output, such as a table or report. In JSON
+/// mode stdout only carries events, so it goes to stderr there.
+pub fn print_output(line: &str) {
+    if is_json() {
+        eprintln!("{}", line);
+    } else {
+        println!("{}", line);
+    }
+}
+
+/// Print a line of remote output (verbose mode) above any active spinners.
+pub fn print_remote_line(host: &str, line: &str) {
+    if is_json() {
+        return print_event("output", line, Some(host));
+    }
+    let line = format!("  {} {}", format!("[{}]", host).dimmed(), line);
+    if PROGRESS.is_hidden() {
+        println!("{}", line);
+    } else {
+        PROGRESS.println(line).ok();
+    }
+}
+
+/// Draw target of spinners in plain and JSON mode: every new message is
+/// printed once, as a line or a `progress` event.
+#[derive(Debug, Default)]
+struct MessageLines {
+    /// What was drawn since the last flush
+    pending: Mutex<String>,
+    last: Mutex<String>,
+}
+
+impl MessageLines {
+    /// The message drawn since the last flush, unless it is empty or
+    /// unchanged.
+    fn take(&self) -> Option<String> {
+        let pending = std::mem::take(&mut *self.pending.lock().unwrap_or_else(|e| e.into_inner()));
+        let msg = pending.lines().map(|l| l.trim_matches(['\r', ' '])).rfind(|l| !l.is_empty())?;
+        let mut last = self.last.lock().unwrap_or_else(|e| e.into_inner());
+        if *last == msg {
+            return None;
+        }
+        *last = msg.to_string();
+        Some(last.clone())
+    }
+}
+
+impl TermLike for MessageLines {
+    fn width(&self) -> u16 {
+        u16::MAX
+    }
+
+    fn move_cursor_up(&self, _: usize) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn move_cursor_down(&self, _: usize) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn move_cursor_right(&self, _: usize) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn move_cursor_left(&self, _: usize) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn write_line(&self, s: &str) -> io::Result<()> {
+        let mut pending = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+        pending.push_str(s);
+        pending.push('\n');
+        Ok(())
+    }
+
+    fn write_str(&self, s: &str) -> io::Result<()> {
+        self.pending.lock().unwrap_or_else(|e| e.into_inner()).push_str(s);
+        Ok(())
+    }
+
+    fn clear_line(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        if let Some(msg) = self.take() {
+            if is_json() {
+                print_event("progress", &msg, None);
+            } else {
+                eprintln!("{}{} {}", stamp(), "·".blue(), msg);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Spinner showing `msg`. In plain and JSON mode its messages are printed
+/// as they change instead, and nothing is shown when quiet.
 pub fn create_spinner(msg: &str) -> ProgressBar {
-    let pb = ProgressBar::new_spinner();
+    spinner(msg, "{spinner:.blue} {msg}")
+}
+
+fn spinner(msg: &str, template: &str) -> ProgressBar {
+    if is_quiet() {
+        return ProgressBar::hidden();
+    }
+    if is_json() || is_plain() {
+        return message_bar(Box::new(MessageLines::default()), msg);
+    }
+    let pb = PROGRESS.add(ProgressBar::new_spinner());
     pb.set_style(
         ProgressStyle::default_spinner()
             .tick_chars("⠋⠙⠹⠸⠼⠴⠦⠧⠇⠏ ")
-            .template("{spinner:.blue} {msg}")
+            .template(template)
             .unwrap(),
     );
     pb.set_message(msg.to_string());
     pb.enable_steady_tick(Duration::from_millis(80));
     pb
 }
+
+/// Bar that draws every message to `term`. Unlike `term_like_with_hz`,
+/// `term_like` has no rate limit, so quick `set_message` calls are not
+/// coalesced and every step reaches the log.
+fn message_bar(term: Box<dyn TermLike>, msg: &str) -> ProgressBar {
+    let pb = ProgressBar::with_draw_target(None, ProgressDrawTarget::term_like(term));
+    pb.set_style(ProgressStyle::with_template("{msg}").unwrap());
+    pb.set_message(msg.to_string());
+    pb
+}
+
+/// One spinner per host, with the time spent on the host so far, for work
+/// running on several hosts at once. Collapses into a single line when
+/// finished.
+pub struct HostSpinners {
+    bars: Vec<(String, ProgressBar)>,
+    started: Instant,
+}
+
+impl HostSpinners {
+    pub fn new(hosts: &[String], msg: &str) -> Self {
+        let bars = hosts
+            .iter()
+            .map(|host| (host.clone(), spinner(&format!("[{}] {}", host, msg), "{spinner:.blue} {msg} {elapsed:.dim}")))
+            .collect();
+        Self { bars, started: Instant::now() }
+    }
+
+    /// The spinner of `host`, its time restarted when work on it begins.
+    pub fn start(&self, host: &str) -> &ProgressBar {
+        let bar = self.get(host);
+        bar.reset_elapsed();
+        bar
+    }
+
+    /// Stop the spinner of `host` on `msg`, or on failure.
+    pub fn done<T>(&self, host: &str, result: &anyhow::Result<T>, msg: &str) {
+        let outcome = if result.is_ok() { msg } else { "Failed" };
+        self.get(host).finish_with_message(format!("[{}] {}", host, outcome));
+    }
+
+    fn get(&self, host: &str) -> &ProgressBar {
+        let (_, bar) = self.bars.iter().find(|(h, _)| h == host).expect("spinner for every host");
+        bar
+    }
+
+    /// Replace the spinners with `msg` and the time taken.
+    pub fn finish(&self, msg: &str) {
+        self.clear();
+        print_success(&format!(
+            "{} ({} hosts, {})",
+            msg,
+            self.bars.len(),
+            indicatif::HumanDuration(self.started.elapsed())
+        ));
+    }
+
+    pub fn clear(&self) {
+        for (_, bar) in &self.bars {
+            bar.finish_and_clear();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_host() {
+        let hosts = vec!["web1".to_string()];
+        assert_eq!(split_host("[web1] Creating jail...", &hosts), (Some("web1"), "Creating jail..."));
+        assert_eq!(split_host("web1: certificates updated", &hosts), (Some("web1"), "certificates updated"));
+        assert_eq!(split_host("Transcript: /tmp/x.log", &hosts), (None, "Transcript: /tmp/x.log"));
+
+        let event = event("output", "line", Some("web2"));
+        assert_eq!(event["level"], "output");
+        assert_eq!(event["host"], "web2");
+        assert_eq!(event["message"], "line");
+    }
+
+    #[test]
+    fn test_message_lines() {
+        let lines = MessageLines::default();
+        lines.write_str("\r[web1] Creating jail...").unwrap();
+        assert_eq!(lines.take().as_deref(), Some("[web1] Creating jail..."));
+        lines.write_str("\r[web1] Creating jail...").unwrap();
+        assert_eq!(lines.take(), None);
+        lines.write_line("").unwrap();
+        assert_eq!(lines.take(), None);
+        lines.write_str("[web1] Starting services...").unwrap();
+        assert_eq!(lines.take().as_deref(), Some("[web1] Starting services..."));
+    }
+
+    #[derive(Debug, Default)]
+    struct Recorder(std::sync::Arc<MessageLines>, std::sync::Arc<Mutex<Vec<String>>>);
+
+    impl TermLike for Recorder {
+        fn width(&self) -> u16 {
+            u16::MAX
+        }
+        fn move_cursor_up(&self, _: usize) -> io::Result<()> {
+            Ok(())
+        }
+        fn move_cursor_down(&self, _: usize) -> io::Result<()> {
+            Ok(())
+        }
+        fn move_cursor_right(&self, _: usize) -> io::Result<()> {
+            Ok(())
+        }
+        fn move_cursor_left(&self, _: usize) -> io::Result<()> {
+            Ok(())
+        }
+        fn write_line(&self, s: &str) -> io::Result<()> {
+            self.0.write_line(s)
+        }
+        fn write_str(&self, s: &str) -> io::Result<()> {
+            self.0.write_str(s)
+        }
+        fn clear_line(&self) -> io::Result<()> {
+            Ok(())
+        }
+        fn flush(&self) -> io::Result<()> {
+            self.1.lock().unwrap().extend(self.0.take());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_message_bar_draws_every_message() {
+        let recorder = Recorder::default();
+        let printed = recorder.1.clone();
+        let pb = message_bar(Box::new(recorder), "step 0");
+        for i in 1..100 {
+            pb.set_message(format!("step {}", i));
+        }
+        let printed = printed.lock().unwrap();
+        assert_eq!(printed.len(), 100);
+        assert_eq!(printed[99], "step 99");
+    }
+}